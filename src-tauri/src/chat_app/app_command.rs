@@ -1,24 +1,312 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::SeekFrom,
+    mem,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use super::frontend_event::FrontendEvent;
+use super::identity;
 use super::AppState;
 use super::Invoke;
 use crate::{
-    error::NetworkError,
-    models::{GroupId, GroupInfo, Setting},
-    network::{message::Message, Client},
+    error::{ManagerError, NetworkError},
+    managers::{
+        direct::DirectMessageManager,
+        file::FileManager,
+        group::{GroupManager, SearchMessages, SearchResults},
+        latency::LatencyManager,
+        presence::PresenceManager,
+        reputation::ReputationManager,
+        user::UserManager,
+    },
+    models::{
+        AppSnapshot, ConnectionMode, DirectMessage, FileInfo, FileSource, GroupId, GroupInfo,
+        GroupInvite, GroupKey, GroupMember, GroupMessage, GroupReach, GroupState, HistoryMessage,
+        HistoryOrder, Manifest, MessageId, MessageStatus, NetworkMetrics, OperationInfo,
+        PeerConnectivity, PeerLatency, PeerReputation, Presence, PublishReceipt, QuietHours, Role,
+        Setting, Theme, TopicPeers, UserInfo, UserState,
+    },
+    network::{
+        message::{Message, PublishOptions, Request, Response, TYPING_TIMEOUT},
+        Client,
+    },
+};
+use chrono::{TimeZone, Utc};
+use futures::{future::try_join_all, stream, StreamExt};
+use libp2p::{
+    self,
+    multiaddr::Protocol,
+    multihash::{Code, MultihashDigest},
+    swarm::derive_prelude::ListenerId,
+    Multiaddr, PeerId,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncSeekExt, AsyncWriteExt},
+    sync::{broadcast, mpsc, Mutex},
+    time::Instant,
 };
-use libp2p::{self, multiaddr::Protocol, swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
+use uuid::Uuid;
+
+/// How many members `rotate_group_key` sends the new key to at the same time.
+const KEY_DISTRIBUTION_CONCURRENCY: usize = 16;
+/// How often inactive groups are checked for `auto_leave_after`.
+const AUTO_LEAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often `run_metrics` checks whether metrics were turned back on.
+const METRICS_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+/// How many of the latest messages per group `resync` re-emits.
+const RESYNC_RECENT_MESSAGES: usize = 50;
+/// How long `listen_on_relay` waits for the relay to accept a reservation.
+const RELAY_RESERVATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// `name` as a path below the download directory, or `None` if it could
+/// point outside of it.
+fn relative_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let mut components = path.components().peekable();
+    (components.peek().is_some() && components.all(|c| matches!(c, Component::Normal(_))))
+        .then(|| path.to_path_buf())
+}
+
+/// `providers` in the order to try them: the `preferred` ones first, in the
+/// order given and whether or not they are known providers, then the rest
+/// by reputation, best first.
+fn order_providers(
+    providers: Vec<PeerId>,
+    preferred: &[PeerId],
+    reputations: &HashMap<PeerId, PeerReputation>,
+) -> Vec<PeerId> {
+    let score = |peer_id: &PeerId| reputations.get(peer_id).map_or(0.5, PeerReputation::score);
+    let mut ordered = Vec::with_capacity(preferred.len() + providers.len());
+    for peer_id in preferred {
+        if !ordered.contains(peer_id) {
+            ordered.push(*peer_id);
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut rest = providers
+        .into_iter()
+        .filter(|peer_id| !preferred.contains(peer_id) && seen.insert(*peer_id))
+        .collect::<Vec<_>>();
+    rest.sort_by(|a, b| {
+        score(b)
+            .partial_cmp(&score(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    ordered.extend(rest);
+    ordered
+}
+
+/// The relays to try at startup, in order: the `last` one we held a
+/// reservation on, then the `configured` ones.
+fn relay_candidates(last: Option<&Multiaddr>, configured: &[Multiaddr]) -> Vec<Multiaddr> {
+    let mut candidates = last.into_iter().cloned().collect::<Vec<_>>();
+    for relay in configured {
+        if !candidates.contains(relay) {
+            candidates.push(relay.clone());
+        }
+    }
+    candidates
+}
+
+/// The content of `message` as a line of Markdown.
+fn markdown_content(message: &Message) -> String {
+    match message {
+        Message::Text(text) => text.clone(),
+        Message::Rich(rich) => rich.fallback.clone(),
+        Message::File(file) => format!("*File: {}*", file.name),
+        Message::Poll(poll) => format!("*Poll: {}* ({})", poll.question, poll.options.join(", ")),
+        Message::Location(location) => match &location.label {
+            Some(label) => format!("*Location: {label}*"),
+            None => format!("*Location: {}, {}*", location.latitude, location.longitude),
+        },
+        Message::Deleted {} => "*deleted*".to_string(),
+        _ => "*unsupported message*".to_string(),
+    }
+}
+
+/// A Markdown transcript of the thread rooted at `root`, replies nested
+/// under their parent as list items. `thread` holds the root and its
+/// replies, in the order to show them. Authors are named from `names`, or
+/// by peer ID. `None` if `root` isn't in `thread`.
+fn thread_markdown(
+    thread: &[GroupMessage],
+    root: &MessageId,
+    names: &HashMap<PeerId, String>,
+) -> Option<String> {
+    let root = thread.iter().find(|message| message.id == *root)?;
+    let mut replies = HashMap::<&MessageId, Vec<&GroupMessage>>::new();
+    for message in thread {
+        if let Some(parent) = &message.reply_to {
+            replies.entry(parent).or_default().push(message);
+        }
+    }
+    let mut markdown = String::new();
+    let mut pending = vec![(root, 0)];
+    while let Some((message, depth)) = pending.pop() {
+        let indent = "  ".repeat(depth);
+        let author = names
+            .get(&message.source)
+            .cloned()
+            .unwrap_or_else(|| message.source.to_string());
+        let timestamp = Utc
+            .timestamp_opt(message.timestamp, 0)
+            .single()
+            .map_or_else(String::new, |t| t.format("%Y-%m-%d %H:%M UTC").to_string());
+        markdown.push_str(&format!("{indent}- **{author}** {timestamp}"));
+        if message.edited_at.is_some() {
+            markdown.push_str(" (edited)");
+        }
+        markdown.push('\n');
+        for line in markdown_content(&message.message).lines() {
+            markdown.push_str(&format!("{indent}  {line}\n"));
+        }
+        for reply in replies.get(&message.id).into_iter().flatten().rev() {
+            pending.push((reply, depth + 1));
+        }
+    }
+    Some(markdown)
+}
+
+/// Create `dir` if it's missing and make sure files can be written there.
+async fn ensure_writable_dir(dir: &Path) -> Result<(), NetworkError> {
+    let invalid = |e: std::io::Error| NetworkError::InvalidPath {
+        path: dir.to_path_buf(),
+        reason: e.to_string(),
+    };
+    fs::create_dir_all(dir).await.map_err(invalid)?;
+    let probe = dir.join(".write-probe");
+    fs::write(&probe, b"").await.map_err(invalid)?;
+    fs::remove_file(&probe).await.map_err(invalid)
+}
+
+/// Progress of one download, shared by the providers working on it.
+struct Transfer<'a> {
+    file: &'a FileInfo,
+    chunk_size: u32,
+    /// Offsets of the chunks nobody is fetching yet.
+    pending: Mutex<VecDeque<u64>>,
+    out: Mutex<fs::File>,
+    received: AtomicU64,
+    cancelled: &'a AtomicBool,
+}
+
+/// A download being written next to where it goes. Deleted when dropped,
+/// including by a failed or dropped download, unless it was kept.
+struct PartialFile {
+    path: PathBuf,
+}
+
+impl PartialFile {
+    fn new(dest: &Path) -> Self {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let path = dest.with_file_name(format!(".{name}.{:08x}.part", rand::random::<u32>()));
+        Self { path }
+    }
+    /// Move the download to `dest`.
+    async fn keep(self, dest: &Path) -> io::Result<()> {
+        fs::rename(&self.path, dest).await
+    }
+    /// The name of the file `path` is a partial download of, if it is one.
+    fn target_name(path: &Path) -> Option<&str> {
+        let name = path.file_name()?.to_str()?;
+        let (name, suffix) = name
+            .strip_prefix('.')?
+            .strip_suffix(".part")?
+            .rsplit_once('.')?;
+        let is_random = suffix.len() == 8 && suffix.bytes().all(|b| b.is_ascii_hexdigit());
+        (is_random && !name.is_empty()).then(|| name)
+    }
+}
+
+/// Delete the partial downloads in `dir` left behind by downloads that
+/// never finished, e.g. when the app crashed, except those of the files in
+/// `downloading`. Returns the deleted paths.
+pub(super) async fn remove_orphaned_parts(
+    dir: &Path,
+    downloading: &HashSet<String>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let Some(name) = PartialFile::target_name(&path) else {
+            continue;
+        };
+        if downloading.contains(name) || !entry.file_type().await?.is_file() {
+            continue;
+        }
+        match fs::remove_file(&path).await {
+            Ok(()) => removed.push(path),
+            Err(e) => log::warn!("failed to remove {}: {e}", path.display()),
+        }
+    }
+    Ok(removed)
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
 #[derive(Clone)]
 pub struct AppCommandHandle {
     pub(crate) client: Client,
+    pub(crate) frontend_sender: mpsc::Sender<FrontendEvent>,
     pub(crate) state: AppState,
+    pub(crate) group: GroupManager,
+    pub(crate) user: UserManager,
+    pub(crate) file: FileManager,
+    pub(crate) direct: DirectMessageManager,
+    pub(crate) presence: PresenceManager,
+    pub(crate) latency: LatencyManager,
+    pub(crate) reputation: ReputationManager,
     pub(crate) managers: HashMap<String, Box<dyn Invoke>>,
 }
 
 impl AppCommandHandle {
+    /// A handle whose network commands give up after `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            client: self.client.with_timeout(timeout),
+            ..self.clone()
+        }
+    }
     pub async fn get_listeners(&self) -> HashMap<ListenerId, Vec<Multiaddr>> {
         self.client.listeners.lock().await.clone()
     }
+    /// Listeners keyed by the numbers the frontend knows them by.
+    pub async fn numbered_listeners(&self) -> HashMap<u64, Vec<Multiaddr>> {
+        let mut listeners = HashMap::new();
+        for (listener_id, addresses) in self.get_listeners().await {
+            listeners.insert(self.listener_number(listener_id).await, addresses);
+        }
+        listeners
+    }
+    /// The number the frontend knows `listener_id` by.
+    pub async fn listener_number(&self, listener_id: ListenerId) -> u64 {
+        self.state.listener_ids.number(listener_id).await
+    }
+    /// The listener the frontend knows as `number`.
+    pub async fn listener(&self, number: u64) -> Result<ListenerId, NetworkError> {
+        self.state
+            .listener_ids
+            .listener(number)
+            .await
+            .ok_or(NetworkError::UnknownListener(number))
+    }
+    /// Addresses to share with peers behind other NATs, unlike the local
+    /// ones listened on.
+    pub async fn external_addrs(&self) -> Result<Vec<Multiaddr>, NetworkError> {
+        self.client.external_addrs().await
+    }
     pub async fn start_listen(
         &self,
         listen_addr: Option<Multiaddr>,
@@ -27,6 +315,113 @@ impl AppCommandHandle {
             .start_listening(listen_addr.unwrap_or_else(|| "/ip4/0.0.0.0/tcp/0".parse().unwrap()))
             .await
     }
+    /// Reserve a slot on the relay at `relay_addr`, which must end in the
+    /// relay's `/p2p` component, and accept connections relayed through it.
+    /// Resolves once the relay accepted, and remembers it so it's tried
+    /// first on the next start. If the relay refuses or doesn't answer
+    /// within `RELAY_RESERVATION_TIMEOUT`, the listener is removed again.
+    ///
+    /// Peers reach us on the circuit address
+    /// `<relay_addr>/p2p-circuit/p2p/<our peer ID>`, which is what the
+    /// frontend should share, e.g.
+    /// `/ip4/198.51.100.1/tcp/4001/p2p/12D3KooWRelay/p2p-circuit/p2p/12D3KooWUs`.
+    pub async fn listen_on_relay(&self, relay_addr: Multiaddr) -> Result<ListenerId, NetworkError> {
+        let relay = match relay_addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).map_err(|_| {
+                NetworkError::InvalidAddress(format!("invalid relay peer ID in {relay_addr}"))
+            })?,
+            _ => {
+                return Err(NetworkError::InvalidAddress(
+                    "Expect relay multiaddr to contain peer ID.".to_string(),
+                ))
+            }
+        };
+        // Subscribe first so the outcome can't slip by before we wait.
+        let mut status = self.state.relay_status.subscribe();
+        let listener_id = self
+            .client
+            .start_listening(relay_addr.clone().with(Protocol::P2pCircuit))
+            .await?;
+        let outcome = tokio::time::timeout(RELAY_RESERVATION_TIMEOUT, async {
+            loop {
+                match status.recv().await {
+                    Ok((peer_id, error)) if peer_id == relay => return error,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Some("app is shutting down".to_string())
+                    }
+                }
+            }
+        })
+        .await;
+        let reason = match outcome {
+            Ok(None) => {
+                self.state.setting.lock().await.relay = Some(relay_addr);
+                self.state.save_setting().await?;
+                return Ok(listener_id);
+            }
+            Ok(Some(reason)) => reason,
+            Err(_) => format!("no answer within {RELAY_RESERVATION_TIMEOUT:?}"),
+        };
+        if let Err(e) = self.client.stop_listening(vec![listener_id]).await {
+            log::warn!("failed to stop listening on relay {relay}: {e}");
+        }
+        Err(NetworkError::RelayRefused { relay, reason })
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.is_shutting_down()
+    }
+    /// Stop listening, unsubscribe from every group and save persisted state,
+    /// then stop the network. The inbound and frontend event loops exit once
+    /// they've drained, and commands fail from then on. Calling it again
+    /// does nothing.
+    pub async fn shutdown(&self) -> Result<(), NetworkError> {
+        if self.state.shutdown.send_replace(true) {
+            return Ok(());
+        }
+        if let Err(e) = self.stop_listen(None).await {
+            log::warn!("failed to stop listening: {e}");
+        }
+        for group_id in self.group.get_groups().await.into_keys() {
+            if let Err(e) = self.client.unsubscribe(group_id.topic()).await {
+                log::warn!("failed to unsubscribe from {group_id}: {e}");
+            }
+        }
+        // Pending saves finish before the final ones below.
+        self.state.tasks.shutdown(super::SHUTDOWN_TIMEOUT).await;
+        if let Err(e) = self.state.save_setting().await {
+            log::error!("failed to save setting: {e}");
+        }
+        if let Err(e) = self.group.save_history().await {
+            log::error!("failed to save group history: {e}");
+        }
+        if let Err(e) = self.state.save_dm_outbox().await {
+            log::error!("failed to save queued direct messages: {e}");
+        }
+        if self.state.is_persistent() {
+            if let Err(e) = self.file.save_providers(&self.state.config_dir).await {
+                log::error!("failed to save provided files: {e}");
+            }
+            if let Err(e) = self.reputation.save(&self.state.config_dir).await {
+                log::error!("failed to save peer reputation: {e}");
+            }
+        }
+        self.client.shutdown().await
+    }
+    /// Reserve a slot on the last relay we used, falling back to the
+    /// configured ones in order until one accepts.
+    pub async fn listen_on_default_relay(self) {
+        let candidates = {
+            let setting = self.state.setting.lock().await;
+            relay_candidates(setting.relay.as_ref(), &setting.relays)
+        };
+        for relay in candidates {
+            match self.listen_on_relay(relay.clone()).await {
+                Ok(_) => return,
+                Err(e) => log::warn!("failed to listen on relay {relay}: {e}"),
+            }
+        }
+    }
     pub async fn stop_listen(&self, listen_id: Option<ListenerId>) -> Result<(), NetworkError> {
         let listener_id = if let Some(listen_id) = listen_id {
             vec![listen_id]
@@ -39,41 +434,1637 @@ impl AppCommandHandle {
     pub async fn setting(&self) -> Setting {
         self.state.setting.lock().await.to_owned()
     }
-    pub async fn dial(&self, addr: Multiaddr) -> Result<(), NetworkError> {
+    /// The setting with every fallback applied, as the app is running it.
+    pub async fn effective_setting(&self) -> Setting {
+        self.state.setting.lock().await.effective()
+    }
+    /// Whether settings and other state are being saved to disk, and if
+    /// not, why.
+    pub async fn persistence_status(&self) -> Result<(), String> {
+        match &*self.state.persistence_error.lock().await {
+            Some(e) => Err(format!(
+                "settings can't be saved and changes won't persist: {e}"
+            )),
+            None => Ok(()),
+        }
+    }
+    pub async fn set_connection_mode(
+        &self,
+        mode: ConnectionMode,
+        allowed: Option<HashSet<PeerId>>,
+    ) -> Result<(), NetworkError> {
+        let allowlist = {
+            let mut setting = self.state.setting.lock().await;
+            setting.connection_mode = mode;
+            if let Some(allowed) = allowed {
+                setting.allowed = allowed;
+            }
+            setting.allowlist()
+        };
+        self.client.set_allowlist(allowlist).await?;
+        self.state.save_setting().await
+    }
+    /// Block `peer_id`, which also stops reconnecting to it, returning the
+    /// updated blocklist.
+    pub async fn block_peer(&self, peer_id: PeerId) -> Result<HashSet<PeerId>, NetworkError> {
+        self.state.intended_peers.lock().await.remove(&peer_id);
+        let blocked = {
+            let mut setting = self.state.setting.lock().await;
+            setting.blocked.insert(peer_id);
+            setting.blocked.clone()
+        };
+        self.client.block_peer(peer_id).await?;
+        self.state.save_setting().await?;
+        Ok(blocked)
+    }
+    pub async fn unblock_peer(&self, peer_id: PeerId) -> Result<HashSet<PeerId>, NetworkError> {
+        let blocked = {
+            let mut setting = self.state.setting.lock().await;
+            setting.blocked.remove(&peer_id);
+            setting.blocked.clone()
+        };
+        self.client.unblock_peer(peer_id).await?;
+        self.state.save_setting().await?;
+        Ok(blocked)
+    }
+    pub async fn blocked_peers(&self) -> HashSet<PeerId> {
+        self.state.setting.lock().await.blocked.clone()
+    }
+    /// Dial `addr`, returning the peer reached. Without a trailing `/p2p`
+    /// component the peer ID is learned from the handshake. The peer is
+    /// redialed whenever the connection drops, until `disconnect`.
+    pub async fn dial(&self, addr: Multiaddr) -> Result<PeerId, NetworkError> {
         let peer_id = match addr.iter().last() {
-            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).expect("Valid hash."),
+            Some(Protocol::P2p(hash)) => {
+                let peer_id = PeerId::from_multihash(hash).map_err(|_| {
+                    NetworkError::InvalidAddress(format!("invalid peer ID in {addr}"))
+                })?;
+                self.state
+                    .operations
+                    .track("dial", self.client.dial(peer_id, addr))
+                    .await?;
+                peer_id
+            }
             _ => {
-                return Err(NetworkError::InvalidAddress(
-                    "Expect peer multiaddr to contain peer ID.".to_string(),
+                self.state
+                    .operations
+                    .track("dial", self.client.dial_addr(addr))
+                    .await?
+            }
+        };
+        self.state.intended_peers.lock().await.insert(peer_id);
+        Ok(peer_id)
+    }
+    /// An invite to the group through us, see `GroupInvite`. Addresses
+    /// AutoNAT confirmed go first, and loopback ones last as they only work
+    /// on this machine.
+    pub async fn group_invite(&self, group_id: GroupId) -> Result<String, NetworkError> {
+        let Some(info) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let mut listened = self
+            .get_listeners()
+            .await
+            .into_values()
+            .flatten()
+            .collect::<Vec<_>>();
+        listened.sort_by_key(|addr| match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+            Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+            _ => false,
+        });
+        let mut addrs = self.external_addrs().await?;
+        addrs.extend(listened);
+        let Some(mut addr) = addrs.into_iter().next() else {
+            return Err(NetworkError::InvalidAddress(
+                "not listening on any address".to_string(),
+            ));
+        };
+        if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+            addr.push(Protocol::P2p(self.client.local_peer_id().into()));
+        }
+        Ok(GroupInvite {
+            group_id,
+            name: info.name,
+            addr,
+        }
+        .to_string())
+    }
+    /// Join the group of an invite from `group_invite`, dialing the member
+    /// it names, who then sends us the group info.
+    pub async fn join_by_invite(&self, code: String) -> Result<GroupId, NetworkError> {
+        let invite = code
+            .parse::<GroupInvite>()
+            .map_err(NetworkError::InvalidInvite)?;
+        let inviter = match invite.addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        };
+        match inviter {
+            Some(peer_id) if peer_id == self.client.local_peer_id() => {}
+            Some(_) => {
+                self.dial(invite.addr).await?;
+            }
+            None => {
+                return Err(NetworkError::InvalidInvite(
+                    "invalid peer ID in address".to_string(),
                 ))
             }
+        }
+        self.subscribe(invite.group_id.clone()).await?;
+        Ok(invite.group_id)
+    }
+    /// Dial one peer at several candidate addresses, e.g. both its LAN and
+    /// WAN addresses, succeeding on the first that connects. Addresses
+    /// that carry a `/p2p` component must all name the same peer.
+    pub async fn dial_many(&self, addrs: Vec<Multiaddr>) -> Result<PeerId, NetworkError> {
+        let mut peer_id = None;
+        for addr in &addrs {
+            if let Some(Protocol::P2p(hash)) = addr.iter().last() {
+                let id = PeerId::from_multihash(hash).map_err(|_| {
+                    NetworkError::InvalidAddress(format!("invalid peer ID in {addr}"))
+                })?;
+                if peer_id.get_or_insert(id) != &id {
+                    return Err(NetworkError::InvalidAddress(
+                        "Expect every address to be of the same peer.".to_string(),
+                    ));
+                }
+            }
+        }
+        let peer_id = peer_id.ok_or_else(|| {
+            NetworkError::InvalidAddress(
+                "Expect at least one multiaddr to contain peer ID.".to_string(),
+            )
+        })?;
+        self.state
+            .operations
+            .track("dial", self.client.dial_many(peer_id, addrs))
+            .await?;
+        self.state.intended_peers.lock().await.insert(peer_id);
+        Ok(peer_id)
+    }
+    /// Close every connection to `peer_id` and stop reconnecting to it.
+    pub async fn disconnect(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        self.state.intended_peers.lock().await.remove(&peer_id);
+        self.client.disconnect(peer_id).await
+    }
+    /// Temporarily go offline without quitting. Messages published while
+    /// paused are sent after resuming, once peers reconnect.
+    pub async fn set_network_paused(&self, paused: bool) -> Result<(), NetworkError> {
+        self.state.network_paused.store(paused, Ordering::Relaxed);
+        self.client.set_paused(paused).await?;
+        self.frontend_sender
+            .send(FrontendEvent::NetworkPaused(paused))
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        Ok(())
+    }
+    pub fn is_network_paused(&self) -> bool {
+        self.state.network_paused.load(Ordering::Relaxed)
+    }
+    /// Start providing the file at `path` to peers that request it.
+    pub async fn start_provide(&self, path: PathBuf) -> Result<FileInfo, NetworkError> {
+        let file = self.file.add_local_file(path).await?;
+        if self.state.is_persistent() {
+            self.file.save_providers(&self.state.config_dir).await?;
+        }
+        // The DHT query can take a while, so don't hold up the caller.
+        let handle = self.clone();
+        let announced = file.clone();
+        self.state.tasks.spawn(async move {
+            if let Err(e) = handle.provide(announced.clone()).await {
+                log::warn!("failed to announce {}: {e}", announced.name);
+            }
+        });
+        Ok(file)
+    }
+    /// Share every file under the directory at `path`, announcing the
+    /// directory in the DHT by its manifest hash.
+    pub async fn start_provide_dir(&self, path: PathBuf) -> Result<Manifest, NetworkError> {
+        let manifest = self.file.add_local_dir(path).await?;
+        if self.state.is_persistent() {
+            self.file.save_providers(&self.state.config_dir).await?;
+        }
+        let client = self.client.clone();
+        let key = manifest.hash.clone().into_bytes();
+        let name = manifest.name.clone();
+        self.state.tasks.spawn(async move {
+            if let Err(e) = client.start_providing(key).await {
+                log::warn!("failed to announce {name}: {e}");
+            }
+        });
+        Ok(manifest)
+    }
+    /// Download the directory shared under `hash`, recreating its tree,
+    /// empty directories included, in `recv_path`.
+    pub async fn get_dir(&self, hash: String) -> Result<Manifest, NetworkError> {
+        let providers = self.find_providers(hash.clone()).await?;
+        let mut manifest = None;
+        for peer_id in &providers {
+            match self
+                .client
+                .request(*peer_id, Request::Manifest(hash.clone()))
+                .await
+            {
+                Ok(Response::Manifest(m)) if m.compute_hash() == hash => {
+                    manifest = Some(m);
+                    break;
+                }
+                Ok(_) => log::warn!("{peer_id} sent an invalid manifest for {hash}"),
+                Err(e) => log::warn!("{peer_id} failed to send manifest {hash}: {e}"),
+            }
+        }
+        let manifest = manifest.ok_or_else(|| NetworkError::NoProvider(hash))?;
+        let recv_path = self.state.setting.lock().await.recv_path.clone();
+        let invalid =
+            |name: &str| NetworkError::InvalidMessage(format!("invalid file name: {name}"));
+        for dir in &manifest.empty_dirs {
+            let path = relative_path(dir).ok_or_else(|| invalid(dir))?;
+            fs::create_dir_all(recv_path.join(path)).await?;
+        }
+        for file in &manifest.files {
+            let path =
+                recv_path.join(relative_path(&file.name).ok_or_else(|| invalid(&file.name))?);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            for peer_id in &providers {
+                self.file.add_remote_file(*peer_id, file.clone()).await;
+            }
+            self.get_to(file.clone(), path, &[]).await?;
+        }
+        Ok(manifest)
+    }
+    /// Stop sharing `file`, withdrawing its DHT provider record.
+    pub async fn unprovide(&self, file: FileInfo) -> Result<(), NetworkError> {
+        let Some(file) = self.file.remove_local_file(&file).await else {
+            return Err(NetworkError::NotProvided(file.name));
+        };
+        if self.state.is_persistent() {
+            self.file.save_providers(&self.state.config_dir).await?;
+        }
+        if let Some(hash) = file.hash {
+            self.client.stop_providing(hash.into_bytes()).await?;
+        }
+        Ok(())
+    }
+    /// Make identical files in `recv_path` share their storage, returning
+    /// the bytes reclaimed.
+    pub async fn deduplicate_storage(&self) -> Result<u64, NetworkError> {
+        let recv_path = self.state.setting.lock().await.recv_path.clone();
+        Ok(self.file.deduplicate(&recv_path).await?)
+    }
+    pub async fn provided_files(&self) -> Vec<FileInfo> {
+        self.file.list_provide().await
+    }
+    /// Announce in the DHT that we provide `file`, keyed by its hash, so
+    /// peers can find it without being told who has it.
+    pub async fn provide(&self, file: FileInfo) -> Result<(), NetworkError> {
+        let hash = file
+            .hash
+            .ok_or_else(|| NetworkError::InvalidMessage(format!("{} has no hash", file.name)))?;
+        self.client.start_providing(hash.into_bytes()).await
+    }
+    /// Look up the peers announcing a file with content `hash` in the DHT.
+    pub async fn find_providers(&self, hash: String) -> Result<HashSet<PeerId>, NetworkError> {
+        self.client.get_providers(hash.into_bytes()).await
+    }
+    /// Download `file` from a remote provider into `recv_path`, trying the
+    /// `preferred` providers first.
+    pub async fn get(&self, file: FileInfo, preferred: &[PeerId]) -> Result<(), NetworkError> {
+        // Only keep the last component so a peer can't name a file outside `recv_path`.
+        let name = Path::new(&file.name)
+            .file_name()
+            .ok_or_else(|| {
+                NetworkError::InvalidMessage(format!("invalid file name: {}", file.name))
+            })?
+            .to_owned();
+        let recv_path = self.state.setting.lock().await.recv_path.clone();
+        // It may have been removed since it was set.
+        ensure_writable_dir(&recv_path).await?;
+        self.get_to(file, recv_path.join(name), preferred).await
+    }
+    /// Download `file` from its remote providers to `path`, or complete
+    /// right away if we already hold its content under any name. The
+    /// `preferred` providers that are reachable fetch it alone, the others
+    /// take over if they all fail.
+    async fn get_to(
+        &self,
+        file: FileInfo,
+        path: PathBuf,
+        preferred: &[PeerId],
+    ) -> Result<(), NetworkError> {
+        if let Some(hash) = &file.hash {
+            if self.file.restore(hash, &path).await? {
+                return self.file_progress(&file, file.size, None).await;
+            }
+        }
+        let sources = match (self.file.get_sources(&file).await, &file.hash) {
+            (Some(sources), _) => sources,
+            // Nobody told us who has the file, so ask the DHT.
+            (None, Some(hash)) => {
+                let providers = self.find_providers(hash.clone()).await?;
+                for peer_id in &providers {
+                    self.file.add_remote_file(*peer_id, file.clone()).await;
+                }
+                providers.into_iter().map(FileSource::Remote).collect()
+            }
+            (None, None) if preferred.is_empty() => {
+                return Err(NetworkError::NoProvider(file.name))
+            }
+            (None, None) => Vec::new(),
+        };
+        let providers = sources
+            .iter()
+            .filter_map(|source| match source {
+                FileSource::Remote(peer_id) => Some(*peer_id),
+                FileSource::Local(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let providers = order_providers(
+            providers,
+            preferred,
+            &self.reputation.get_reputations().await,
+        );
+        if providers.is_empty() {
+            if sources.iter().any(FileSource::is_local) {
+                return Err(NetworkError::LocalFile(file.name));
+            }
+            return Err(NetworkError::NoProvider(file.name));
+        }
+        let connected = self.client.connected_peers().await?;
+        let mut peers = Vec::with_capacity(providers.len());
+        for peer_id in providers {
+            if connected.contains(&peer_id) {
+                peers.push(peer_id);
+                continue;
+            }
+            log::info!(
+                "provider {peer_id} of {} is disconnected, redialing",
+                file.name
+            );
+            match self.client.redial(peer_id).await {
+                Ok(()) => peers.push(peer_id),
+                Err(e) => log::warn!("failed to redial provider {peer_id}, skipping it: {e}"),
+            }
+        }
+        if peers.is_empty() {
+            return Err(NetworkError::RequestError(format!(
+                "no provider of {} is reachable",
+                file.name
+            )));
+        }
+        // Without a hash, chunks from providers with different files of
+        // the same name can't be told apart, so only one is asked.
+        let fallback = if file.hash.is_none() {
+            if peers.len() > 1 {
+                log::info!(
+                    "{} has no hash, only fetching it from {}",
+                    file.name,
+                    peers[0]
+                );
+                peers.truncate(1);
+            }
+            Vec::new()
+        } else if peers.iter().any(|peer_id| preferred.contains(peer_id)) {
+            let (preferred, rest) = peers
+                .into_iter()
+                .partition(|peer_id| preferred.contains(peer_id));
+            peers = preferred;
+            rest
+        } else {
+            Vec::new()
         };
-        self.client.dial(peer_id, addr).await
+        let cancelled = Arc::new(AtomicBool::new(false));
+        {
+            let mut downloads = self.state.downloads.lock().await;
+            if downloads.contains_key(&file) {
+                return Err(NetworkError::Downloading(file.name));
+            }
+            downloads.insert(file.clone(), cancelled.clone());
+        }
+        let res = self
+            .state
+            .operations
+            .track(
+                "get_file",
+                self.download(peers, fallback, &file, path, &cancelled),
+            )
+            .await;
+        self.state.downloads.lock().await.remove(&file);
+        res.map(|_| ())
     }
+    /// Stop downloading `file` after the chunk in flight and delete
+    /// what was written of it.
+    pub async fn cancel_get(&self, file: FileInfo) -> Result<(), NetworkError> {
+        match self.state.downloads.lock().await.get(&file) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(NetworkError::NotDownloading(file.name)),
+        }
+    }
+    /// Fetch `file` chunk by chunk from `peers` in parallel, so memory use
+    /// stays bounded and a slow provider only holds up its own chunks.
+    /// Chunks a provider fails to send go back to the remaining ones, or to
+    /// the `fallback` providers once none remain. Nothing shows up at
+    /// `path` unless the whole file arrived and checked out. Returns the
+    /// number of bytes written.
+    async fn download(
+        &self,
+        mut peers: Vec<PeerId>,
+        mut fallback: Vec<PeerId>,
+        file: &FileInfo,
+        path: PathBuf,
+        cancelled: &AtomicBool,
+    ) -> Result<u64, NetworkError> {
+        let chunk_size = self.state.setting.lock().await.effective().file_chunk_size;
+        let partial = PartialFile::new(&path);
+        let out = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial.path)
+            .await?;
+        out.set_len(file.size).await?;
+        let transfer = Transfer {
+            file,
+            chunk_size,
+            pending: Mutex::new((0..file.size).step_by(chunk_size as usize).collect()),
+            out: Mutex::new(out),
+            received: AtomicU64::new(0),
+            cancelled,
+        };
+        self.file_progress(file, 0, None).await?;
+        while !transfer.pending.lock().await.is_empty() && !cancelled.load(Ordering::Relaxed) {
+            if peers.is_empty() && !fallback.is_empty() {
+                log::info!("falling back to the other providers of {}", file.name);
+                peers = std::mem::take(&mut fallback);
+            }
+            if peers.is_empty() {
+                return Err(NetworkError::RequestError(format!(
+                    "no provider could send {}",
+                    file.name
+                )));
+            }
+            let healthy = try_join_all(
+                peers
+                    .iter()
+                    .map(|peer_id| self.fetch_chunks(*peer_id, &transfer)),
+            )
+            .await?;
+            peers = peers
+                .into_iter()
+                .zip(healthy)
+                .filter(|(_, healthy)| *healthy)
+                .map(|(peer_id, _)| peer_id)
+                .collect();
+        }
+        let received = transfer.received.into_inner();
+        let mut out = transfer.out.into_inner();
+        out.flush().await?;
+        drop(out);
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(received);
+        }
+        if let Some(expected) = &file.hash {
+            let actual = FileInfo::hash_file(&partial.path).await?;
+            if *expected != actual {
+                return Err(NetworkError::HashMismatch {
+                    name: file.name.clone(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        partial.keep(&path).await?;
+        if let Some(hash) = &file.hash {
+            self.file.add_stored(hash.clone(), path).await;
+        }
+        Ok(received)
+    }
+    /// Take pending chunks of `transfer` and write what `peer_id` sends for
+    /// them, until none are left. Returns `false` if the peer failed, after
+    /// putting the chunk it failed on back.
+    async fn fetch_chunks(
+        &self,
+        peer_id: PeerId,
+        transfer: &Transfer<'_>,
+    ) -> Result<bool, NetworkError> {
+        let file = transfer.file;
+        while !transfer.cancelled.load(Ordering::Relaxed) {
+            let Some(offset) = transfer.pending.lock().await.pop_front() else {
+                break;
+            };
+            let len = (file.size - offset).min(transfer.chunk_size as u64) as u32;
+            let request = Request::FileChunk {
+                file: file.clone(),
+                offset,
+                len,
+            };
+            match self.client.request(peer_id, request).await {
+                Ok(Response::FileChunk { data, .. }) if data.len() == len as usize => {
+                    let mut out = transfer.out.lock().await;
+                    out.seek(SeekFrom::Start(offset)).await?;
+                    out.write_all(&data).await?;
+                    drop(out);
+                    let received =
+                        transfer.received.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+                    self.file_progress(file, received, Some(peer_id)).await?;
+                }
+                res => {
+                    match res {
+                        Err(err) => {
+                            log::warn!("{peer_id} failed to send {} at {offset}: {err}", file.name)
+                        }
+                        Ok(_) => log::warn!(
+                            "{peer_id} sent an invalid chunk of {} at {offset}",
+                            file.name
+                        ),
+                    }
+                    transfer.pending.lock().await.push_back(offset);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// Report `received` bytes of `file`, the last chunk from `provider`.
+    async fn file_progress(
+        &self,
+        file: &FileInfo,
+        received: u64,
+        provider: Option<PeerId>,
+    ) -> Result<(), NetworkError> {
+        self.frontend_sender
+            .send(FrontendEvent::FileProgress {
+                file: file.clone(),
+                received,
+                total: file.size,
+                provider,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        Ok(())
+    }
+    /// Split `peers` into those we are connected to and those that still
+    /// need dialing, e.g. before creating a group with them.
+    pub async fn verify_peers(&self, peers: Vec<PeerId>) -> Result<PeerConnectivity, NetworkError> {
+        let connected_peers = self
+            .client
+            .connected_peers()
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        Ok(PeerConnectivity::check(peers, &connected_peers))
+    }
+    /// Publish `message` to the group, as a reply to `reply_to` if given.
+    /// Kept messages are numbered, see `GroupMessage::seq`.
     pub async fn publish_message(
         &self,
         group_id: GroupId,
         message: Message,
+        reply_to: Option<MessageId>,
+    ) -> Result<PublishReceipt, NetworkError> {
+        message.validate().map_err(NetworkError::InvalidMessage)?;
+        let (ttl, message_acks) = {
+            let setting = self.state.setting.lock().await;
+            (
+                setting.group_ttl.get(&group_id).copied(),
+                setting.message_acks,
+            )
+        };
+        let seq = if message.is_kept() {
+            Some(
+                self.group
+                    .next_seq(&group_id, &self.client.local_peer_id())
+                    .await,
+            )
+        } else {
+            None
+        };
+        let key = self
+            .group
+            .get_group_info(&group_id)
+            .await
+            .and_then(|info| info.keys.last().cloned());
+        let options = PublishOptions {
+            ttl,
+            reply_to,
+            seq,
+            want_ack: message_acks && message.is_kept(),
+            key,
+        };
+        let res = self
+            .client
+            .publish_with(group_id.topic(), message, options)
+            .await;
+        match (&res, seq) {
+            // A timed out message may still go out, so its number stays taken.
+            (Err(e), Some(seq)) if !matches!(e, NetworkError::Timeout(_)) => {
+                self.group.release_seq(&group_id, seq).await;
+            }
+            _ => {}
+        }
+        res
+    }
+    /// Tell the group whether we're typing. Only changes are published,
+    /// plus a refresh every half `TYPING_TIMEOUT` while typing goes on so
+    /// members don't expire the indicator.
+    pub async fn set_typing(&self, group_id: GroupId, is_typing: bool) -> Result<(), NetworkError> {
+        {
+            let mut typing = self.state.typing.lock().await;
+            if is_typing {
+                if typing
+                    .get(&group_id)
+                    .map_or(false, |since| since.elapsed() < TYPING_TIMEOUT / 2)
+                {
+                    return Ok(());
+                }
+                typing.insert(group_id.clone(), Instant::now());
+            } else if typing.remove(&group_id).is_none() {
+                return Ok(());
+            }
+        }
+        self.client
+            .publish(group_id.topic(), Message::Typing { is_typing })
+            .await?;
+        Ok(())
+    }
+    /// Tell the group we've read its messages up to `message_id`.
+    pub async fn mark_read(
+        &self,
+        group_id: GroupId,
+        message_id: MessageId,
+    ) -> Result<(), NetworkError> {
+        if !self.group.has_group(&group_id).await {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        }
+        let local_peer_id = self.client.local_peer_id();
+        if self
+            .group
+            .read_receipts(&group_id)
+            .await
+            .get(&local_peer_id)
+            == Some(&message_id)
+        {
+            return Ok(());
+        }
+        self.client
+            .publish(group_id.topic(), Message::Read { up_to: message_id })
+            .await?;
+        Ok(())
+    }
+    /// Send `content` privately to `peer_id`. If the peer can't be reached
+    /// the message is returned as `pending` and sent once the peer
+    /// connects again.
+    pub async fn send_dm(
+        &self,
+        peer_id: PeerId,
+        content: String,
+    ) -> Result<DirectMessage, NetworkError> {
+        let mut message = DirectMessage {
+            id: Uuid::new_v4(),
+            source: self.client.local_peer_id(),
+            timestamp: Utc::now().timestamp(),
+            content,
+            pending: false,
+            failed: false,
+            attempts: 0,
+        };
+        let request = Request::DirectMessage {
+            id: message.id,
+            content: message.content.clone(),
+        };
+        match self.client.request(peer_id, request).await {
+            Ok(Response::Delivered) => {}
+            Ok(res) => {
+                return Err(NetworkError::RequestError(format!(
+                    "unexpected response: {res:?}"
+                )))
+            }
+            Err(NetworkError::PeerUnreachable(_) | NetworkError::NetworkPaused) => {
+                message.pending = true;
+                let dropped = self.state.queue_dm(peer_id, message.clone()).await;
+                self.state.save_dm_outbox().await?;
+                if let Some(dropped) = dropped {
+                    let message_id = dropped.id.to_string();
+                    self.direct.mark_failed(peer_id, dropped).await;
+                    self.frontend_sender
+                        .send(FrontendEvent::MessageStatus {
+                            message_id,
+                            status: MessageStatus::Failed,
+                        })
+                        .await
+                        .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        self.direct.add_message(peer_id, message.clone()).await;
+        Ok(message)
+    }
+    /// Drop our messages to the group that were given up on, returning how
+    /// many there were.
+    pub async fn discard_failed(&self, group_id: GroupId) -> Result<usize, NetworkError> {
+        self.group
+            .discard_failed(&group_id)
+            .await
+            .ok_or_else(|| ManagerError::GroupNotExist(group_id).into())
+    }
+    /// Drop our direct messages to `peer_id` that were given up on,
+    /// returning how many there were.
+    pub async fn discard_failed_dms(&self, peer_id: PeerId) -> usize {
+        self.direct.discard_failed(&peer_id).await
+    }
+    /// Accept the direct messages held from `peer_id`, delivering its later
+    /// ones right away. Returns the held messages.
+    pub async fn accept_dm(&self, peer_id: PeerId) -> Vec<DirectMessage> {
+        self.direct.accept_requests(peer_id).await
+    }
+    /// Drop the direct messages held from `peer_id`.
+    pub async fn reject_dm(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        if !self.direct.reject_requests(&peer_id).await {
+            return Err(ManagerError::PeerNotExist(peer_id).into());
+        }
+        Ok(())
+    }
+    /// Pin or unpin `target` for every member of the group.
+    pub async fn pin_message(
+        &self,
+        group_id: GroupId,
+        target: MessageId,
+        pinned: bool,
+    ) -> Result<(), NetworkError> {
+        let Some(info) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        if !info.may_moderate(&self.client.local_peer_id()) {
+            return Err(ManagerError::NotPermitted("only admins can pin".to_string()).into());
+        }
+        self.client
+            .publish(group_id.topic(), Message::Pin { target, pinned })
+            .await?;
+        Ok(())
+    }
+    /// Encrypt the group's messages under a new key from now on, which turns
+    /// encryption on for groups without one. Turning it on gives the key to
+    /// the members subscribed right now; later keys go to the members we
+    /// gave a key before, see `share_group_key`. Returns the members that
+    /// took the key. Messages under the older keys stay readable.
+    pub async fn rotate_group_key(
+        &self,
+        group_id: GroupId,
+    ) -> Result<HashSet<PeerId>, NetworkError> {
+        let Some(info) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let local_peer_id = self.client.local_peer_id();
+        if !info.may_moderate(&local_peer_id) {
+            return Err(
+                ManagerError::NotPermitted("only admins can rotate the key".to_string()).into(),
+            );
+        }
+        let key = GroupKey::generate(info.keys.last().map_or(1, |key| key.id + 1));
+        let Some(group_info) = self
+            .group
+            .add_group_key(&group_id, &local_peer_id, key)
+            .await
+        else {
+            return Err(anyhow::anyhow!("group key was rotated concurrently").into());
+        };
+        self.frontend_sender
+            .send(FrontendEvent::GroupUpdate {
+                group_id: group_id.clone(),
+                group_info,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        let mut members = self
+            .group
+            .get_group_state(&group_id)
+            .await
+            .map(|state| state.subscribers)
+            .unwrap_or_default();
+        if !info.keys.is_empty() {
+            let key_holders = self.group.key_holders(&group_id).await;
+            members.retain(|peer_id| key_holders.contains(peer_id));
+        }
+        let requests = members
+            .into_iter()
+            .filter(|peer_id| *peer_id != local_peer_id)
+            .map(|peer_id| {
+                let group = self.group.clone();
+                let client = self.client.clone();
+                let group_id = group_id.clone();
+                async move {
+                    match group.share_key(&group_id, peer_id, &client).await {
+                        Ok(()) => Some(peer_id),
+                        Err(e) => {
+                            log::warn!("{peer_id} didn't get the key of {group_id}: {e}");
+                            None
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(stream::iter(requests)
+            .buffer_unordered(KEY_DISTRIBUTION_CONCURRENCY)
+            .filter_map(|delivered| async move { delivered })
+            .collect()
+            .await)
+    }
+    /// Let `peer_id` read the encrypted group by sending it the current key,
+    /// and the keys rotated after it.
+    pub async fn share_group_key(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
     ) -> Result<(), NetworkError> {
-        self.client.publish(group_id.topic(), message).await?;
+        let Some(info) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        if !info.may_moderate(&self.client.local_peer_id()) {
+            return Err(
+                ManagerError::NotPermitted("only admins can share the key".to_string()).into(),
+            );
+        }
+        self.group.share_key(&group_id, peer_id, &self.client).await
+    }
+    /// Give `peer_id` `role` in the group, if our own role allows it.
+    pub async fn set_role(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        role: Role,
+    ) -> Result<(), NetworkError> {
+        let Some(info) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let own_role = info.role(&self.client.local_peer_id());
+        if !own_role.may_assign(info.role(&peer_id), role) {
+            return Err(ManagerError::NotPermitted(format!(
+                "{own_role:?} can't make {peer_id} {role:?}"
+            ))
+            .into());
+        }
+        self.client
+            .publish(group_id.topic(), Message::SetRole { peer_id, role })
+            .await?;
         Ok(())
     }
-    pub async fn new_group(&self, group_info: GroupInfo) -> Result<GroupId, NetworkError> {
+    pub async fn pinned_messages(&self, group_id: GroupId) -> Result<Vec<MessageId>, NetworkError> {
+        match self.group.get_group_info(&group_id).await {
+            Some(info) => Ok(info.pinned),
+            None => Err(ManagerError::GroupNotExist(group_id).into()),
+        }
+    }
+    /// Create a group with us as its owner.
+    pub async fn new_group(&self, mut group_info: GroupInfo) -> Result<GroupId, NetworkError> {
         let new_group_id = GroupId::new();
+        group_info.roles = [(self.client.local_peer_id(), Role::Owner)].into();
 
         self.client
             .new_group(new_group_id.clone(), group_info)
             .await?;
         Ok(new_group_id)
     }
+    /// Edit a group's name and description and publish the edit to the
+    /// members. The version is bumped so members that were away pick it up
+    /// when they next see us subscribe. Only admins may edit; pins, roles
+    /// and keys are kept as they are.
+    pub async fn update_group_info(
+        &self,
+        group_id: GroupId,
+        group_info: GroupInfo,
+    ) -> Result<(), NetworkError> {
+        let Some(current) = self.group.get_group_info(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let local_peer_id = self.client.local_peer_id();
+        if !current.may_moderate(&local_peer_id) {
+            return Err(
+                ManagerError::NotPermitted("only admins can edit the group".to_string()).into(),
+            );
+        }
+        let version = current.version + 1;
+        let Some(updated) = self
+            .group
+            .apply_group_info(
+                &group_id,
+                &local_peer_id,
+                group_info.name.clone(),
+                group_info.description.clone(),
+                version,
+            )
+            .await
+        else {
+            return Err(anyhow::anyhow!("group info was edited concurrently").into());
+        };
+        self.frontend_sender
+            .send(FrontendEvent::GroupUpdate {
+                group_id: group_id.clone(),
+                group_info: updated,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        let edit = Message::EditGroup {
+            name: group_info.name,
+            description: group_info.description,
+            version,
+        };
+        self.client.publish(group_id.topic(), edit).await?;
+        Ok(())
+    }
     pub async fn subscribe(&self, group_id: GroupId) -> Result<(), NetworkError> {
-        self.client.subscribe(group_id.topic()).await?;
+        self.state
+            .operations
+            .track("subscribe", self.client.subscribe(group_id.topic()))
+            .await?;
         Ok(())
     }
     pub async fn unsubscribe(&self, group_id: GroupId) -> Result<(), NetworkError> {
         self.client.unsubscribe(group_id.topic()).await?;
         Ok(())
     }
+    pub async fn presence(&self, peer_id: PeerId) -> Presence {
+        self.presence.get_presence(&peer_id).await
+    }
+    pub async fn peer_latency(&self, peer_id: PeerId) -> Option<PeerLatency> {
+        self.latency.get_latency(&peer_id).await
+    }
+    pub async fn set_away_after(&self, after: u64) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.away_after = after;
+        self.state.save_setting().await
+    }
+    pub async fn set_auto_leave(&self, after: Option<u64>) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.auto_leave_after = after;
+        self.state.save_setting().await
+    }
+    /// Leave every group that has been quiet longer than `auto_leave_after`,
+    /// except pinned ones, warning the frontend before each.
+    pub async fn leave_inactive_groups(&self) -> Result<(), NetworkError> {
+        let (after, pinned) = {
+            let setting = self.state.setting.lock().await;
+            (setting.auto_leave_after, setting.group_order.clone())
+        };
+        let Some(after) = after else {
+            return Ok(());
+        };
+        let since = Utc::now().timestamp().saturating_sub(after as i64);
+        let inactive = self
+            .group
+            .inactive_groups(&self.client.local_peer_id(), since)
+            .await;
+        for group_id in inactive.into_iter().filter(|g| !pinned.contains(g)) {
+            self.frontend_sender
+                .send(FrontendEvent::AutoLeave {
+                    group_id: group_id.clone(),
+                })
+                .await
+                .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+            self.unsubscribe(group_id).await?;
+        }
+        Ok(())
+    }
+    pub async fn run_auto_leave(self) {
+        let mut interval = tokio::time::interval(AUTO_LEAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.state.shutdown_started() => break,
+            }
+            if let Err(e) = self.leave_inactive_groups().await {
+                log::warn!("failed to leave inactive groups: {e}");
+            }
+        }
+    }
+    pub async fn set_group_tags(
+        &self,
+        group_id: GroupId,
+        tags: Vec<String>,
+    ) -> Result<Vec<String>, NetworkError> {
+        if !self.group.is_group_exist(&group_id).await {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        }
+        let tags = self
+            .state
+            .setting
+            .lock()
+            .await
+            .set_group_tags(group_id, tags);
+        self.state.save_setting().await?;
+        Ok(tags)
+    }
+    pub async fn set_quiet_hours(
+        &self,
+        quiet_hours: Option<QuietHours>,
+    ) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.quiet_hours = quiet_hours;
+        self.state.save_setting().await
+    }
+    /// Set how long messages we publish to a group keep being relayed, or
+    /// remove the limit with `None`.
+    pub async fn set_group_ttl(
+        &self,
+        group_id: GroupId,
+        ttl: Option<u64>,
+    ) -> Result<(), NetworkError> {
+        {
+            let mut setting = self.state.setting.lock().await;
+            match ttl {
+                Some(ttl) => setting.group_ttl.insert(group_id, ttl),
+                None => setting.group_ttl.remove(&group_id),
+            };
+        }
+        self.state.save_setting().await
+    }
+    /// Apply `patch` to the setting, see `Setting::patched`, then save and
+    /// broadcast it. Changes to the allowlist, blocklist and topic filter
+    /// reach the network right away, as with their own commands. A new
+    /// `recv_path` is created if missing and must be writable, and a new
+    /// `user_info` is announced as by `update_user_info`.
+    pub async fn update_setting(&self, patch: serde_json::Value) -> Result<Setting, NetworkError> {
+        let (old, patched) = {
+            let setting = self.state.setting.lock().await;
+            (setting.clone(), setting.patched(patch.clone())?)
+        };
+        if patched.user_info != old.user_info {
+            patched
+                .user_info
+                .check_avatar()
+                .map_err(NetworkError::InvalidAvatar)?;
+        }
+        if patched.recv_path != old.recv_path {
+            ensure_writable_dir(&patched.recv_path).await?;
+        }
+        // The setting only changes once the network took the change, and
+        // what it took of a failed one is undone.
+        if let Err(e) = self.apply_network_setting(&old, &patched).await {
+            if let Err(e) = self.apply_network_setting(&patched, &old).await {
+                log::warn!("failed to roll back network setting: {e}");
+            }
+            return Err(e);
+        }
+        for peer_id in patched.blocked.difference(&old.blocked) {
+            self.state.intended_peers.lock().await.remove(peer_id);
+        }
+        let (old, setting) = {
+            let mut setting = self.state.setting.lock().await;
+            // Patched again in case something else changed it meanwhile.
+            let patched = setting.patched(patch)?;
+            (std::mem::replace(&mut *setting, patched.clone()), patched)
+        };
+        self.state.save_setting().await?;
+        if old.user_info != setting.user_info {
+            self.announce_user_info(setting.user_info.clone()).await;
+        }
+        let _ = self
+            .frontend_sender
+            .send(FrontendEvent::SettingChanged(Box::new(setting.clone())))
+            .await;
+        Ok(setting)
+    }
+    /// Bring the network from the allowlist, blocklist and topic filter of
+    /// `old` to those of `new`.
+    async fn apply_network_setting(
+        &self,
+        old: &Setting,
+        new: &Setting,
+    ) -> Result<(), NetworkError> {
+        if old.allowlist() != new.allowlist() {
+            self.client.set_allowlist(new.allowlist()).await?;
+        }
+        for peer_id in new.blocked.difference(&old.blocked) {
+            self.client.block_peer(*peer_id).await?;
+        }
+        for peer_id in old.blocked.difference(&new.blocked) {
+            self.client.unblock_peer(*peer_id).await?;
+        }
+        if old.allowed_groups != new.allowed_groups {
+            self.client.set_topic_filter(new.topic_filter()).await?;
+        }
+        Ok(())
+    }
+    /// Change our name and avatar, save them and tell every group we're in
+    /// so members refresh. The avatar must pass `UserInfo::check_avatar`.
+    pub async fn update_user_info(&self, mut user_info: UserInfo) -> Result<(), NetworkError> {
+        user_info
+            .check_avatar()
+            .map_err(NetworkError::InvalidAvatar)?;
+        user_info.status = UserState::Online;
+        self.state.setting.lock().await.user_info = user_info.clone();
+        self.state.save_setting().await?;
+        self.announce_user_info(user_info).await;
+        Ok(())
+    }
+    async fn announce_user_info(&self, user_info: UserInfo) {
+        self.state.set_local_user(&user_info).await;
+        let local_peer_id = self.client.local_peer_id();
+        self.user.add_user(local_peer_id, user_info.clone()).await;
+        for group_id in self.group.get_groups().await.into_keys() {
+            // Members that miss it still get it when they next ask for it.
+            if let Err(e) = self
+                .group
+                .announce_user_info(&group_id, user_info.clone(), &self.client)
+                .await
+            {
+                log::warn!("failed to announce user info to {group_id}: {e}");
+            }
+        }
+        let _ = self
+            .frontend_sender
+            .send(FrontendEvent::UserUpdate {
+                peer_id: local_peer_id,
+                user_info,
+            })
+            .await;
+    }
+    pub async fn set_theme(&self, theme: Theme) -> Result<(), NetworkError> {
+        let setting = {
+            let mut setting = self.state.setting.lock().await;
+            setting.theme = theme;
+            setting.clone()
+        };
+        self.state.save_setting().await?;
+        self.frontend_sender
+            .send(FrontendEvent::SettingChanged(Box::new(setting)))
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        Ok(())
+    }
+    /// Restrict which groups other peers' subscriptions can announce to us,
+    /// or accept any with `None`. Groups we are in stay accepted.
+    pub async fn set_topic_filter(
+        &self,
+        allowed_groups: Option<HashSet<GroupId>>,
+    ) -> Result<(), NetworkError> {
+        let topics = {
+            let mut setting = self.state.setting.lock().await;
+            setting.allowed_groups = allowed_groups;
+            setting.topic_filter()
+        };
+        self.client.set_topic_filter(topics).await?;
+        self.state.save_setting().await
+    }
+    pub async fn set_group_order(&self, order: Vec<GroupId>) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.set_group_order(order);
+        self.state.save_setting().await
+    }
+    /// All groups, pinned ones first, the rest by latest message.
+    pub async fn ordered_groups(&self) -> Vec<(GroupId, GroupInfo)> {
+        let mut groups = Vec::new();
+        for (group_id, group_info) in self.group.get_groups().await {
+            let last_active = self
+                .group
+                .get_group_state(&group_id)
+                .await
+                .and_then(|state| state.history.last().map(|m| m.timestamp))
+                .unwrap_or(i64::MIN);
+            groups.push((group_id, group_info, last_active));
+        }
+        self.state.setting.lock().await.sort_groups(&mut groups);
+        groups
+            .into_iter()
+            .map(|(group_id, group_info, _)| (group_id, group_info))
+            .collect()
+    }
+    pub async fn groups_by_tag(&self, tag: String) -> Vec<GroupId> {
+        self.state.setting.lock().await.groups_by_tag(&tag)
+    }
+    /// Estimate how many members of a group a message would currently reach.
+    pub async fn group_reach(&self, group_id: GroupId) -> Result<GroupReach, NetworkError> {
+        let Some(state) = self.group.get_group_state(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let local_peer_id = self.client.local_peer_id();
+        let roster = state
+            .subscribers
+            .into_iter()
+            .filter(|peer_id| *peer_id != local_peer_id)
+            .collect::<Vec<_>>();
+        let mut online = 0;
+        for peer_id in &roster {
+            if let Some(UserState::Online) =
+                self.user.get_user_info(peer_id).await.map(|u| u.status)
+            {
+                online += 1;
+            }
+        }
+        let mesh_peers = self.client.mesh_peers(group_id.topic().hash()).await?.len();
+        Ok(GroupReach::estimate(mesh_peers, roster.len(), online))
+    }
+
+    /// The group's subscribers our gossipsub knows of, mesh peers apart.
+    pub async fn topic_peers(&self, group_id: GroupId) -> Result<TopicPeers, NetworkError> {
+        if !self.group.is_group_exist(&group_id).await {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        }
+        self.client.topic_peers(group_id.topic().hash()).await
+    }
+
+    /// Ping every member of a group and return the peers that answered
+    /// within `timeout`.
+    pub async fn ping_group(
+        &self,
+        group_id: GroupId,
+        timeout: Duration,
+    ) -> Result<HashSet<PeerId>, NetworkError> {
+        if !self.group.is_group_exist(&group_id).await {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        }
+        let ping_id = Uuid::new_v4();
+        self.group.start_ping(ping_id).await;
+        if let Err(e) = self
+            .client
+            .publish(group_id.topic(), Message::Ping(ping_id))
+            .await
+        {
+            self.group.finish_ping(&ping_id).await;
+            return Err(e);
+        }
+        let waited = self
+            .state
+            .operations
+            .track("ping_group", async {
+                tokio::time::sleep(timeout).await;
+                Ok(())
+            })
+            .await;
+        let answered = self.group.finish_ping(&ping_id).await;
+        waited.map(|_| answered)
+    }
+
+    /// Rebuild a group's roster from the current mesh and the members that
+    /// answer a ping, dropping subscribers we missed an unsubscribe from.
+    pub async fn refresh_roster(
+        &self,
+        group_id: GroupId,
+        timeout: Duration,
+    ) -> Result<HashSet<PeerId>, NetworkError> {
+        let mut roster = self.ping_group(group_id.clone(), timeout).await?;
+        roster.extend(self.client.mesh_peers(group_id.topic().hash()).await?);
+        let local_peer_id = self.client.local_peer_id();
+        if self
+            .group
+            .get_group_state(&group_id)
+            .await
+            .map_or(false, |state| state.subscribers.contains(&local_peer_id))
+        {
+            roster.insert(local_peer_id);
+        }
+        self.group
+            .replace_subscribers(&group_id, roster.clone())
+            .await;
+        self.frontend_sender
+            .send(FrontendEvent::RosterSnapshot {
+                group_id,
+                peers: roster.clone(),
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        Ok(roster)
+    }
+
+    /// The group's subscribers, owners and admins first.
+    pub async fn group_members(&self, group_id: GroupId) -> Result<Vec<GroupMember>, NetworkError> {
+        let (Some(info), Some(state)) = (
+            self.group.get_group_info(&group_id).await,
+            self.group.get_group_state(&group_id).await,
+        ) else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let mut members = Vec::with_capacity(state.subscribers.len());
+        for peer_id in state.subscribers {
+            members.push(GroupMember {
+                peer_id,
+                user_info: self.user.get_user_info(&peer_id).await,
+                role: info.role(&peer_id),
+            });
+        }
+        members.sort_by_key(|member| (std::cmp::Reverse(member.role), member.peer_id));
+        Ok(members)
+    }
+
+    pub async fn list_operations(&self) -> Vec<OperationInfo> {
+        self.state.operations.list().await
+    }
+    /// Cancel a running operation; its caller gets a `Cancelled` error.
+    pub async fn cancel_operation(&self, id: Uuid) -> bool {
+        self.state.operations.cancel(&id).await
+    }
+
+    /// A group's state with its history in the configured `history_order`.
+    pub async fn group_state(&self, group_id: &GroupId) -> Option<GroupState> {
+        let order = self.state.setting.lock().await.history_order;
+        Some(self.group.get_group_state(group_id).await?.ordered(order))
+    }
+    pub async fn set_history_order(&self, order: HistoryOrder) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.history_order = order;
+        self.state.save_setting().await
+    }
+    /// A page of up to `limit` messages of the group, newest first in the
+    /// configured `history_order`. With `before`, the page starts at the
+    /// message after that one, so the UI can load older pages on scroll; a
+    /// cursor that isn't in the history yields an empty page.
+    pub async fn group_history(
+        &self,
+        group_id: GroupId,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, NetworkError> {
+        let Some(mut state) = self.group_state(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let history = mem::take(&mut state.history);
+        let mut history = history.into_iter().rev();
+        if let Some(before) = before {
+            history.by_ref().find(|message| message.id == before);
+        }
+        let local_peer_id = self.client.local_peer_id();
+        Ok(history
+            .take(limit)
+            .map(|message| HistoryMessage {
+                reactions: state.reaction_counts(&message.id, &local_peer_id),
+                failed: state.failed.contains(&message.id),
+                message,
+            })
+            .collect())
+    }
+    /// Who reacted to `message_id` with each emoji, left out of
+    /// `group_history` to keep pages small.
+    pub async fn reactions(
+        &self,
+        group_id: GroupId,
+        message_id: MessageId,
+    ) -> Result<HashMap<String, Vec<PeerId>>, NetworkError> {
+        self.group
+            .reaction_details(&group_id, &message_id)
+            .await
+            .ok_or_else(|| ManagerError::GroupNotExist(group_id).into())
+    }
+    /// `root` and every message whose `reply_to` chain leads to it, in the
+    /// configured `history_order`. Replies are linked by ID when the thread
+    /// is read, so ones that arrived before their parent still show up once
+    /// it is received.
+    pub async fn thread(
+        &self,
+        group_id: GroupId,
+        root: MessageId,
+    ) -> Result<Vec<GroupMessage>, NetworkError> {
+        let Some(state) = self.group_state(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        Ok(state.thread(&root))
+    }
+    /// Write the thread rooted at `root` to `path` as Markdown, for sharing
+    /// the discussion outside the app.
+    pub async fn export_thread(
+        &self,
+        group_id: GroupId,
+        root: MessageId,
+        path: PathBuf,
+    ) -> Result<(), NetworkError> {
+        let thread = self.thread(group_id.clone(), root.clone()).await?;
+        let mut names = self
+            .user
+            .get_users()
+            .await
+            .into_iter()
+            .map(|(peer_id, user_info)| (peer_id, user_info.name))
+            .collect::<HashMap<_, _>>();
+        names.insert(
+            self.client.local_peer_id(),
+            self.state.local_user.lock().await.name.clone(),
+        );
+        let Some(mut markdown) = thread_markdown(&thread, &root, &names) else {
+            return Err(ManagerError::MessageNotExist(root).into());
+        };
+        if let Some(info) = self.group.get_group_info(&group_id).await {
+            markdown = format!("# {}\n\n{markdown}", info.name);
+        }
+        fs::write(&path, markdown)
+            .await
+            .map_err(|e| NetworkError::InvalidPath {
+                path,
+                reason: e.to_string(),
+            })
+    }
+    /// Sequence numbers missing from each author's messages, for authors
+    /// with gaps. The UI can then ask for a resync.
+    pub async fn sequence_gaps(
+        &self,
+        group_id: GroupId,
+    ) -> Result<HashMap<PeerId, Vec<u64>>, NetworkError> {
+        match self.group.sequence_gaps(&group_id).await {
+            Some(gaps) => Ok(gaps),
+            None => Err(ManagerError::GroupNotExist(group_id).into()),
+        }
+    }
+    /// Messages containing `search.query`, ignoring case, newest first.
+    pub async fn search_messages(&self, search: SearchMessages) -> SearchResults {
+        self.group.search_messages(&search).await
+    }
+    /// Ask members to acknowledge the messages we publish from now on.
+    pub async fn set_message_acks(&self, enabled: bool) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.message_acks = enabled;
+        self.state.save_setting().await
+    }
+    pub async fn set_message_batch_window(&self, window_ms: u64) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.message_batch_window = window_ms;
+        self.state.save_setting().await
+    }
+    pub async fn metrics(&self) -> Result<NetworkMetrics, NetworkError> {
+        self.client.metrics().await
+    }
+    pub async fn set_metrics_interval(&self, interval: u64) -> Result<(), NetworkError> {
+        self.state.setting.lock().await.metrics_interval = interval;
+        self.state.save_setting().await
+    }
+    /// Emit `MetricsUpdate` every `metrics_interval` seconds until shutdown.
+    pub async fn run_metrics(self) {
+        loop {
+            let interval = self.state.setting.lock().await.metrics_interval;
+            let wait = match interval {
+                0 => METRICS_IDLE_INTERVAL,
+                secs => Duration::from_secs(secs),
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.state.shutdown_started() => break,
+            }
+            if interval == 0 {
+                continue;
+            }
+            match self.client.metrics().await {
+                Ok(metrics) => {
+                    let _ = self
+                        .frontend_sender
+                        .send(FrontendEvent::MetricsUpdate(metrics))
+                        .await;
+                }
+                Err(e) => log::debug!("failed to collect metrics: {e}"),
+            }
+        }
+    }
+    /// Members that acknowledged our message `message_id`.
+    pub async fn message_acks(&self, message_id: MessageId) -> HashSet<PeerId> {
+        self.group.acks(&message_id).await
+    }
+    /// The latest ping round trip to every connected peer, the one
+    /// `peer_latency` reports, or `None` for peers whose last ping failed
+    /// or that weren't pinged within `timeout`.
+    pub async fn ping_all(
+        &self,
+        timeout: Duration,
+    ) -> Result<HashMap<PeerId, Option<Duration>>, NetworkError> {
+        let peers = self.client.connected_peers().await?;
+        Ok(self.latency.latest_rtts(peers, timeout).await)
+    }
+
+    /// The current groups, users and listeners in one go, for the frontend
+    /// to load instead of waiting for `resync` events.
+    pub async fn snapshot(&self) -> AppSnapshot {
+        AppSnapshot {
+            groups: self.group.get_groups().await,
+            users: self.user.get_users().await,
+            listeners: self.numbered_listeners().await,
+        }
+    }
+    /// Re-emit the current listeners, groups and users as frontend events so a
+    /// freshly loaded UI can rebuild its view.
+    pub async fn resync(&self) -> Result<(), NetworkError> {
+        let mut events = Vec::new();
+        for (listener_id, addresses) in self.get_listeners().await {
+            events.push(FrontendEvent::Listen {
+                listener_id,
+                addresses,
+            });
+        }
+        for (peer_id, user_info) in self.user.get_users().await {
+            events.push(FrontendEvent::UserUpdate { peer_id, user_info });
+        }
+        for (group_id, group_info) in self.group.get_groups().await {
+            events.push(FrontendEvent::GroupUpdate {
+                group_id: group_id.clone(),
+                group_info,
+            });
+            let Some(state) = self.group_state(&group_id).await else {
+                continue;
+            };
+            for peer_id in state.subscribers {
+                events.push(FrontendEvent::Subscribed {
+                    group_id: group_id.clone(),
+                    peer_id,
+                });
+            }
+            let skip = state.history.len().saturating_sub(RESYNC_RECENT_MESSAGES);
+            for message in state.history.into_iter().skip(skip) {
+                // Re-emitted history has already been seen, never notify for it.
+                events.push(FrontendEvent::Message {
+                    group_id: group_id.clone(),
+                    message,
+                    suppress_notification: true,
+                });
+            }
+        }
+        for event in events {
+            self.frontend_sender
+                .send(event)
+                .await
+                .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+        }
+        Ok(())
+    }
+
+    /// Recover `author`'s messages in the group from `from_seq` on, e.g. to
+    /// fill a gap from `sequence_gaps`. Asks the author first, then the
+    /// other members, until one has messages we're missing. Returns the
+    /// recovered messages, which are also added to the history.
+    pub async fn resync_messages(
+        &self,
+        group_id: GroupId,
+        author: PeerId,
+        from_seq: u64,
+    ) -> Result<Vec<GroupMessage>, NetworkError> {
+        let Some(state) = self.group.get_group_state(&group_id).await else {
+            return Err(ManagerError::GroupNotExist(group_id).into());
+        };
+        let local_peer_id = self.client.local_peer_id();
+        let peers = std::iter::once(author)
+            .chain(state.subscribers.into_iter().filter(|p| p != &author))
+            .filter(|p| p != &local_peer_id);
+        let retention = self.state.setting.lock().await.history_retention;
+        let mut last_error = None;
+        let mut answered = false;
+        for peer_id in peers {
+            let request = Request::ResyncMessages {
+                group_id: group_id.clone(),
+                author,
+                from_seq,
+            };
+            let messages = match self.client.request(peer_id, request).await {
+                Ok(Response::Messages(messages)) => {
+                    answered = true;
+                    messages
+                }
+                Ok(res) => {
+                    log::warn!("unexpected response to resync from {peer_id}: {res:?}");
+                    continue;
+                }
+                Err(e) => {
+                    log::debug!("{peer_id} couldn't resync group {group_id}: {e}");
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            let mut recovered = Vec::new();
+            let now = Utc::now().timestamp();
+            for mut message in messages {
+                // Checked like gossiped messages: the ID is derived, the
+                // signature stands in for the gossipsub source and is checked
+                // when recording. Also check they are what was asked.
+                message.id = message.compute_id();
+                let invalid =
+                    if message.source != author || message.seq.map_or(true, |seq| seq < from_seq) {
+                        Some("not what was asked".to_string())
+                    } else {
+                        message.check(now).err()
+                    };
+                if let Some(reason) = invalid {
+                    log::warn!(
+                        "dropping resynced message {} from {peer_id}: {reason}",
+                        message.id
+                    );
+                    continue;
+                }
+                let message = match self.group.add_message(&group_id, message, retention).await {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if let Message::Poll(poll) = &message.message {
+                    self.group.add_poll(&group_id, poll.clone()).await;
+                }
+                self.frontend_sender
+                    .send(FrontendEvent::Message {
+                        group_id: group_id.clone(),
+                        message: message.clone(),
+                        suppress_notification: true,
+                    })
+                    .await
+                    .map_err(|_| anyhow::anyhow!("frontend event loop is closed"))?;
+                recovered.push(message);
+            }
+            if !recovered.is_empty() {
+                return Ok(recovered);
+            }
+        }
+        // Nothing new anywhere is fine, but not if nobody could be asked.
+        match last_error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(Vec::new()),
+        }
+    }
 
     pub async fn invoke_manager(
         &self,
@@ -93,7 +2084,303 @@ impl AppCommandHandle {
     pub fn get_managers(&self) -> Vec<String> {
         self.managers.keys().cloned().collect()
     }
+    /// A short fingerprint of a peer's identity that two users can compare
+    /// out-of-band, six groups of five digits like `01234 56789 ...`.
+    ///
+    /// Ed25519 peer ids embed the public key, so hashing the id hashes the key.
+    pub fn peer_fingerprint(&self, peer_id: &PeerId) -> String {
+        let digest = Code::Sha2_256.digest(&peer_id.to_bytes());
+        digest.digest()[..30]
+            .chunks(5)
+            .map(|chunk| {
+                let n = chunk.iter().fold(0u64, |n, b| n << 8 | u64::from(*b));
+                format!("{:05}", n % 100_000)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
     pub fn get_local_peer_id(&self) -> PeerId {
         self.client.local_peer_id()
     }
+    /// Our identity sealed with `passphrase`, for `import_identity` on
+    /// another device.
+    pub async fn export_identity(&self, passphrase: String) -> Result<String, NetworkError> {
+        let keypair = self.client.keypair().await?;
+        Ok(identity::export(&keypair, &passphrase).await?)
+    }
+    /// Replace our identity with one from `export_identity`, from the next
+    /// start on. Refused while connected, so peers don't see us change
+    /// `PeerId` mid-session.
+    pub async fn import_identity(
+        &self,
+        blob: String,
+        passphrase: String,
+    ) -> Result<PeerId, NetworkError> {
+        let connected = self.client.connected_peers().await?.len();
+        if connected > 0 {
+            return Err(NetworkError::StillConnected(connected));
+        }
+        let keypair = identity::import(&blob, &passphrase).await?;
+        let local_passphrase = std::env::var(identity::PASSPHRASE_ENV).ok();
+        identity::save(
+            &self.state.identity_path().await,
+            &keypair,
+            local_passphrase.as_deref(),
+        )
+        .await?;
+        let peer_id = keypair.public().to_peer_id();
+        let _ = self
+            .frontend_sender
+            .send(FrontendEvent::IdentityImported(peer_id))
+            .await;
+        Ok(peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{GroupInfo, GroupMessage, ReactionCount, Setting},
+        network,
+    };
+    use libp2p::identity::Keypair;
+
+    fn handle() -> (AppCommandHandle, mpsc::Receiver<FrontendEvent>) {
+        let network = network::new(Keypair::generate_ed25519(), &Setting::default()).unwrap();
+        let (frontend_sender, frontend) = mpsc::channel(256);
+        let handle = AppCommandHandle {
+            client: network.client,
+            frontend_sender,
+            state: AppState::new(std::env::temp_dir()),
+            group: GroupManager::new(),
+            user: UserManager::new(),
+            managers: HashMap::new(),
+            file: FileManager::new(),
+            reputation: ReputationManager::new(),
+            direct: DirectMessageManager::new(),
+            presence: PresenceManager::new(),
+            latency: LatencyManager::new(),
+        };
+        (handle, frontend)
+    }
+
+    #[tokio::test]
+    async fn resync_replays_groups_and_their_latest_messages() {
+        let (handle, mut frontend) = handle();
+        let group_id = GroupId::new();
+        let info = GroupInfo {
+            name: "test".to_string(),
+            description: None,
+            version: 0,
+            pinned: Vec::new(),
+            roles: HashMap::new(),
+            keys: Vec::new(),
+        };
+        handle.group.add_group(group_id.clone(), info).await;
+        let keypair = Keypair::generate_ed25519();
+        let member = keypair.public().to_peer_id();
+        handle.group.add_subscribe(&group_id, member).await;
+        for i in 0..RESYNC_RECENT_MESSAGES + 10 {
+            let mut message = GroupMessage::new(Message::Text(i.to_string()), member);
+            message.sign(&keypair);
+            handle.group.add_message(&group_id, message, None).await;
+        }
+        handle.resync().await.unwrap();
+        drop(handle);
+
+        let mut groups = 0;
+        let mut subscribers = Vec::new();
+        let mut messages = Vec::new();
+        while let Some(event) = frontend.recv().await {
+            match event {
+                FrontendEvent::GroupUpdate { .. } => groups += 1,
+                FrontendEvent::Subscribed { peer_id, .. } => subscribers.push(peer_id),
+                FrontendEvent::Message { message, .. } => messages.push(message.message),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        assert_eq!(groups, 1);
+        assert_eq!(subscribers, vec![member]);
+        assert_eq!(messages.len(), RESYNC_RECENT_MESSAGES);
+        assert_eq!(messages[0], Message::Text("10".to_string()));
+    }
+
+    #[tokio::test]
+    async fn fingerprints_are_stable_groups_of_digits() {
+        let (handle, _frontend) = handle();
+        let [peer, other] = [(); 2].map(|_| PeerId::random());
+        let fingerprint = handle.peer_fingerprint(&peer);
+        let groups: Vec<_> = fingerprint.split(' ').collect();
+        assert_eq!(groups.len(), 6);
+        assert!(groups
+            .iter()
+            .all(|group| group.len() == 5 && group.bytes().all(|b| b.is_ascii_digit())));
+        assert_eq!(handle.peer_fingerprint(&peer), fingerprint);
+        assert_ne!(handle.peer_fingerprint(&other), fingerprint);
+    }
+
+    #[tokio::test]
+    async fn theme_changes_reach_every_window() {
+        let (mut handle, mut frontend) = handle();
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        handle.state.config_dir = dir.clone();
+        handle.set_theme(Theme::Dark).await.unwrap();
+        assert_eq!(Setting::load(&dir).await.unwrap().theme, Theme::Dark);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert!(matches!(
+            frontend.try_recv(),
+            Ok(FrontendEvent::SettingChanged(setting)) if setting.theme == Theme::Dark
+        ));
+        assert!(serde_json::from_str::<Theme>("\"sepia\"").is_err());
+    }
+
+    #[test]
+    fn manifest_paths_stay_in_the_download_directory() {
+        assert_eq!(relative_path("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(
+            relative_path("docs/notes/a.txt"),
+            Some(PathBuf::from("docs/notes/a.txt"))
+        );
+        for name in [
+            "",
+            ".",
+            "../a.txt",
+            "docs/../../a.txt",
+            "/etc/passwd",
+            "./a.txt",
+        ] {
+            assert_eq!(relative_path(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn last_relay_is_tried_first() {
+        let relay = |port: u16| -> Multiaddr {
+            format!("/ip4/198.51.100.1/tcp/{port}/p2p/{}", PeerId::random())
+                .parse()
+                .unwrap()
+        };
+        let [last, first, second] = [relay(1), relay(2), relay(3)];
+        assert_eq!(
+            relay_candidates(Some(&last), &[first.clone(), last.clone(), second.clone()]),
+            vec![last, first.clone(), second.clone()]
+        );
+        assert_eq!(
+            relay_candidates(None, &[first.clone(), second.clone()]),
+            vec![first, second]
+        );
+    }
+
+    #[test]
+    fn preferred_providers_come_first() {
+        let [good, bad, unknown, preferred] = [(); 4].map(|_| PeerId::random());
+        let mut reputations = HashMap::new();
+        let mut reputation = PeerReputation::new(0);
+        reputation.successes = 9.0;
+        reputations.insert(good, reputation.clone());
+        reputation.successes = 0.0;
+        reputation.failures = 9.0;
+        reputations.insert(bad, reputation);
+        assert_eq!(
+            order_providers(vec![bad, unknown, good], &[], &reputations),
+            vec![good, unknown, bad]
+        );
+        assert_eq!(
+            order_providers(vec![bad, unknown, good], &[preferred, bad], &reputations),
+            vec![preferred, bad, good, unknown]
+        );
+    }
+
+    #[tokio::test]
+    async fn history_counts_reactions() {
+        let (handle, _frontend) = handle();
+        let group_id = GroupId::new();
+        let info = GroupInfo {
+            name: "test".to_string(),
+            description: None,
+            version: 0,
+            pinned: Vec::new(),
+            roles: HashMap::new(),
+            keys: Vec::new(),
+        };
+        handle.group.add_group(group_id.clone(), info).await;
+        let keypair = Keypair::generate_ed25519();
+        let member = keypair.public().to_peer_id();
+        let mut message = GroupMessage::new(Message::Text("hi".to_string()), member);
+        message.sign(&keypair);
+        let id = message.id.clone();
+        handle.group.add_message(&group_id, message, None).await;
+        let local_peer_id = handle.client.local_peer_id();
+        for peer_id in [member, local_peer_id] {
+            handle
+                .group
+                .record_reaction(&group_id, &id, peer_id, "👍".to_string(), false, 1)
+                .await
+                .unwrap();
+        }
+        handle
+            .group
+            .record_reaction(&group_id, &id, member, "🎉".to_string(), false, 1)
+            .await
+            .unwrap();
+
+        let history = handle
+            .group_history(group_id.clone(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].message.id, id);
+        assert_eq!(
+            history[0].reactions,
+            vec![
+                ReactionCount {
+                    emoji: "👍".to_string(),
+                    count: 2,
+                    reacted: true,
+                },
+                ReactionCount {
+                    emoji: "🎉".to_string(),
+                    count: 1,
+                    reacted: false,
+                },
+            ]
+        );
+        let details = handle.reactions(group_id, id).await.unwrap();
+        assert_eq!(details["🎉"], vec![member]);
+    }
+
+    #[test]
+    fn thread_is_exported_as_nested_markdown() {
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        let message = |text: &str, source, timestamp, reply_to: Option<&GroupMessage>| {
+            let mut message = GroupMessage::new(Message::Text(text.to_string()), source);
+            message.timestamp = timestamp;
+            message.reply_to = reply_to.map(|m| m.id.clone());
+            message.id = message.compute_id();
+            message
+        };
+        let root = message("Lunch?", alice, 0, None);
+        let reply = message("Sure\nwhere?", bob, 60, Some(&root));
+        let nested = message("The usual", alice, 120, Some(&reply));
+        let other = message("Me too", bob, 180, Some(&root));
+        let names = HashMap::from([(alice, "Alice".to_string())]);
+        let thread = [root.clone(), reply, nested, other];
+        assert_eq!(
+            thread_markdown(&thread, &root.id, &names).unwrap(),
+            [
+                "- **Alice** 1970-01-01 00:00 UTC".to_string(),
+                "  Lunch?".to_string(),
+                format!("  - **{bob}** 1970-01-01 00:01 UTC"),
+                "    Sure".to_string(),
+                "    where?".to_string(),
+                "    - **Alice** 1970-01-01 00:02 UTC".to_string(),
+                "      The usual".to_string(),
+                format!("  - **{bob}** 1970-01-01 00:03 UTC"),
+                "    Me too\n".to_string(),
+            ]
+            .join("\n")
+        );
+        assert_eq!(thread_markdown(&thread[1..], &root.id, &names), None);
+    }
 }
@@ -2,12 +2,37 @@ use tauri::{AppHandle, Manager};
 
 use crate::{
     error::NetworkError,
-    models::{GroupId, GroupInfo, GroupMessage, UserInfo},
+    models::{
+        DirectMessage, FileInfo, GroupId, GroupInfo, GroupMessage, MessageId, MessageStatus,
+        NetworkMetrics, PeerLatency, Presence, ReactionCount, Role, Setting, UserInfo,
+    },
 };
 use libp2p::{self, swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
-use tokio::sync::mpsc;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::{self, Instant},
+};
+use uuid::Uuid;
+
+use super::{
+    payload::{
+        DirectMessagePayload, FileProgressPayload, GroupUpdatePayload, HolePunchResultPayload,
+        LatencyPayload, ListenPayload, MessageAckedPayload, MessageDeletedPayload,
+        MessageEditedPayload, MessagePayload, MessagesBatchPayload, PeerConnectedPayload,
+        PeerDisconnectedPayload, PeerReconnectingPayload, PinUpdatePayload, PollUpdatePayload,
+        PresencePayload, ReadReceiptPayload, RelayStatusPayload, RoleUpdatePayload,
+        RosterSnapshotPayload, SubscriptionPayload, TypingPayload, UserUpdatePayload,
+    },
+    AppState,
+};
 
-use super::AppState;
+/// How long in-flight emits may take to finish once the event channel closes.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct FrontendEventLoop {
     pub(super) app: AppHandle,
@@ -23,6 +48,8 @@ pub enum FrontendEvent {
     Message {
         group_id: GroupId,
         message: GroupMessage,
+        /// Set during quiet hours so the frontend skips OS notifications.
+        suppress_notification: bool,
     },
     Subscribed {
         group_id: GroupId,
@@ -40,60 +67,471 @@ pub enum FrontendEvent {
         peer_id: PeerId,
         user_info: UserInfo,
     },
+    NetworkPaused(bool),
+    PollUpdate {
+        group_id: GroupId,
+        poll_id: Uuid,
+        tallies: Vec<usize>,
+    },
+    /// We are about to leave a group for inactivity.
+    AutoLeave {
+        group_id: GroupId,
+    },
+    /// The setting changed, so every window can pick it up.
+    SettingChanged(Box<Setting>),
+    /// The full, freshly rebuilt roster of a group.
+    RosterSnapshot {
+        group_id: GroupId,
+        peers: HashSet<PeerId>,
+    },
+    /// The author edited a message, which replaces the one with the same ID.
+    MessageEdited {
+        group_id: GroupId,
+        message: GroupMessage,
+    },
+    /// The author deleted a message, leaving a tombstone in the history.
+    MessageDeleted {
+        group_id: GroupId,
+        message_id: MessageId,
+    },
+    /// The reactions to a message changed.
+    ReactionUpdate {
+        group_id: GroupId,
+        message_id: MessageId,
+        reactions: Vec<ReactionCount>,
+    },
+    /// A peer started or stopped typing in a group.
+    Typing {
+        group_id: GroupId,
+        peer_id: PeerId,
+        is_typing: bool,
+    },
+    /// The group's pinned messages changed.
+    PinUpdate {
+        group_id: GroupId,
+        pinned: Vec<MessageId>,
+    },
+    /// A member's role in a group changed.
+    RoleUpdate {
+        group_id: GroupId,
+        peer_id: PeerId,
+        role: Role,
+    },
+    /// A peer sent us a private message.
+    DirectMessage {
+        peer_id: PeerId,
+        message: DirectMessage,
+    },
+    /// A peer we share no group with sent us a private message, held
+    /// until the user accepts or rejects them.
+    DirectMessageRequest {
+        peer_id: PeerId,
+        message: DirectMessage,
+    },
+    /// A queued direct message reached its peer.
+    MessageDelivered {
+        message_id: Uuid,
+    },
+    /// One of our queued group or direct messages was sent or given up on.
+    MessageStatus {
+        message_id: String,
+        status: MessageStatus,
+    },
+    /// A member acknowledged our message, `acks` members so far.
+    MessageAcked {
+        group_id: GroupId,
+        message_id: MessageId,
+        peer_id: PeerId,
+        acks: usize,
+    },
+    /// A member has read the group up to message `up_to`.
+    ReadReceipt {
+        group_id: GroupId,
+        peer_id: PeerId,
+        up_to: MessageId,
+    },
+    /// A download advanced, sent once when it starts and after each chunk.
+    FileProgress {
+        file: FileInfo,
+        received: u64,
+        total: u64,
+        /// Who sent the chunk, `None` when the download starts.
+        provider: Option<PeerId>,
+    },
+    /// Whether we hold a reservation on a relay, with the reason if not.
+    RelayStatus {
+        relay_peer_id: PeerId,
+        error: Option<String>,
+    },
+    /// A connection to the peer opened, making `num_established` in all.
+    PeerConnected {
+        peer_id: PeerId,
+        endpoint: Multiaddr,
+        num_established: u32,
+    },
+    /// A connection to the peer closed, leaving `num_established`; the
+    /// peer is offline once it is 0.
+    PeerDisconnected {
+        peer_id: PeerId,
+        cause: Option<String>,
+        num_established: u32,
+    },
+    PresenceUpdate {
+        peer_id: PeerId,
+        presence: Presence,
+    },
+    /// After every ping to the peer, answered or not.
+    LatencyUpdate {
+        peer_id: PeerId,
+        latency: PeerLatency,
+    },
+    /// A dropped peer is being redialed, `attempt` counting from 1.
+    PeerReconnecting {
+        peer_id: PeerId,
+        attempt: u32,
+    },
+    /// Whether a relayed connection could be upgraded to a direct one.
+    HolePunchResult {
+        peer_id: PeerId,
+        success: bool,
+    },
+    /// An address peers outside our network can reach us on.
+    ExternalAddr(Multiaddr),
+    /// A new identity was saved, taking over from the next start.
+    IdentityImported(PeerId),
+    /// Sent every `Setting::metrics_interval` seconds.
+    MetricsUpdate(NetworkMetrics),
     BackendError(NetworkError),
 }
 
 impl FrontendEventLoop {
-    pub async fn run(mut self) {
-        while let Some(event) = self.frontend_receiver.recv().await {
+    /// Emit events until `inbound_stopped` resolves and what's queued by then
+    /// is sent.
+    pub async fn run(mut self, mut inbound_stopped: oneshot::Receiver<()>) {
+        let mut tasks = JoinSet::new();
+        let mut batches = HashMap::new();
+        let mut flush_at = None;
+        let mut draining = false;
+        loop {
+            let flush = time::sleep_until(flush_at.unwrap_or_else(Instant::now));
+            let event = tokio::select! {
+                event = self.frontend_receiver.recv() => match event {
+                    Some(event) => event,
+                    // Event channel closed, thus shutting down the frontend event loop.
+                    None => break,
+                },
+                // Refuse new events, still taking those already queued.
+                _ = &mut inbound_stopped, if !draining => {
+                    draining = true;
+                    self.frontend_receiver.close();
+                    continue;
+                }
+                // The oldest held message has waited out the batch window.
+                _ = flush, if flush_at.is_some() => {
+                    flush_at = None;
+                    emit_batches(&self.app, &mut batches, &mut tasks);
+                    continue;
+                }
+                // Reap finished tasks so the set doesn't grow unbounded.
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+            };
+            let event = match event {
+                FrontendEvent::Message {
+                    group_id,
+                    message,
+                    suppress_notification,
+                } => {
+                    let window = self.state.setting.lock().await.message_batch_window;
+                    if window == 0 {
+                        FrontendEvent::Message {
+                            group_id,
+                            message,
+                            suppress_notification,
+                        }
+                    } else {
+                        let batch = batches.entry(group_id.clone()).or_insert_with(|| {
+                            MessagesBatchPayload {
+                                group_id,
+                                messages: Vec::new(),
+                                suppress_notification: true,
+                            }
+                        });
+                        batch.messages.push(message);
+                        batch.suppress_notification &= suppress_notification;
+                        flush_at
+                            .get_or_insert_with(|| Instant::now() + Duration::from_millis(window));
+                        continue;
+                    }
+                }
+                event => event,
+            };
             let app = self.app.clone();
-            tokio::spawn(async move {
+            let listener_ids = self.state.listener_ids.clone();
+            tasks.spawn(async move {
                 match event {
                     FrontendEvent::Listen {
                         listener_id,
-                        addresses: listen_addr,
+                        addresses,
                     } => {
-                        app.emit_all(
-                            "listen",
-                            (
-                                unsafe { std::mem::transmute::<ListenerId, u64>(listener_id) },
-                                listen_addr,
-                            ),
-                        )
-                        .unwrap();
-                    }
-                    FrontendEvent::Message { group_id, message } => {
-                        app.emit_all("message", (group_id, message)).unwrap();
+                        let payload = ListenPayload {
+                            listener_id: listener_ids.number(listener_id).await,
+                            addresses,
+                        };
+                        app.emit_all("listen", payload).unwrap();
+                    }
+                    FrontendEvent::Message {
+                        group_id,
+                        message,
+                        suppress_notification,
+                    } => {
+                        let payload = MessagePayload {
+                            group_id,
+                            message,
+                            suppress_notification,
+                        };
+                        app.emit_all("message", payload).unwrap();
                     }
                     FrontendEvent::BackendError(err) => {
                         log::error!("{err}");
                         app.emit_all("error", err.to_string()).unwrap()
                     }
-                    FrontendEvent::Subscribed {
-                        group_id: group,
-                        peer_id,
-                    } => {
-                        app.emit_all("subscribed", (group, peer_id)).unwrap();
+                    FrontendEvent::Subscribed { group_id, peer_id } => {
+                        let payload = SubscriptionPayload { group_id, peer_id };
+                        app.emit_all("subscribed", payload).unwrap();
                     }
-                    FrontendEvent::Unsubscribed {
-                        group_id: group,
-                        peer_id,
-                    } => {
-                        app.emit_all("unsubscribed", (group, peer_id)).unwrap();
+                    FrontendEvent::Unsubscribed { group_id, peer_id } => {
+                        let payload = SubscriptionPayload { group_id, peer_id };
+                        app.emit_all("unsubscribed", payload).unwrap();
                     }
                     FrontendEvent::GroupUpdate {
                         group_id,
                         group_info,
                     } => {
-                        app.emit_all(&format!("group-update"), (group_id, group_info))
-                            .unwrap();
+                        let payload = GroupUpdatePayload {
+                            group_id,
+                            group_info,
+                        };
+                        app.emit_all("group-update", payload).unwrap();
                     }
                     FrontendEvent::UserUpdate { peer_id, user_info } => {
-                        app.emit_all(&format!("user-update"), (peer_id, user_info))
+                        let payload = UserUpdatePayload { peer_id, user_info };
+                        app.emit_all("user-update", payload).unwrap();
+                    }
+                    FrontendEvent::AutoLeave { group_id } => {
+                        app.emit_all("auto-leave", group_id).unwrap();
+                    }
+                    FrontendEvent::PollUpdate {
+                        group_id,
+                        poll_id,
+                        tallies,
+                    } => {
+                        let payload = PollUpdatePayload {
+                            group_id,
+                            poll_id,
+                            tallies,
+                        };
+                        app.emit_all("poll-update", payload).unwrap();
+                    }
+                    FrontendEvent::NetworkPaused(paused) => {
+                        app.emit_all("network-paused", paused).unwrap();
+                    }
+                    FrontendEvent::SettingChanged(setting) => {
+                        app.emit_all("setting-changed", setting).unwrap();
+                    }
+                    FrontendEvent::RosterSnapshot { group_id, peers } => {
+                        let payload = RosterSnapshotPayload { group_id, peers };
+                        app.emit_all("roster-snapshot", payload).unwrap();
+                    }
+                    FrontendEvent::FileProgress {
+                        file,
+                        received,
+                        total,
+                        provider,
+                    } => {
+                        let payload = FileProgressPayload {
+                            file,
+                            received,
+                            total,
+                            provider,
+                        };
+                        app.emit_all("file-progress", payload).unwrap();
+                    }
+                    FrontendEvent::RelayStatus {
+                        relay_peer_id,
+                        error,
+                    } => {
+                        let payload = RelayStatusPayload {
+                            relay_peer_id,
+                            error,
+                        };
+                        app.emit_all("relay-status", payload).unwrap();
+                    }
+                    FrontendEvent::PeerReconnecting { peer_id, attempt } => {
+                        let payload = PeerReconnectingPayload { peer_id, attempt };
+                        app.emit_all("peer-reconnecting", payload).unwrap();
+                    }
+                    FrontendEvent::PresenceUpdate { peer_id, presence } => {
+                        let payload = PresencePayload { peer_id, presence };
+                        app.emit_all("presence-update", payload).unwrap();
+                    }
+                    FrontendEvent::LatencyUpdate { peer_id, latency } => {
+                        let payload = LatencyPayload { peer_id, latency };
+                        app.emit_all("latency-update", payload).unwrap();
+                    }
+                    FrontendEvent::HolePunchResult { peer_id, success } => {
+                        let payload = HolePunchResultPayload { peer_id, success };
+                        app.emit_all("hole-punch-result", payload).unwrap();
+                    }
+                    FrontendEvent::MessageEdited { group_id, message } => {
+                        let payload = MessageEditedPayload { group_id, message };
+                        app.emit_all("message-edited", payload).unwrap();
+                    }
+                    FrontendEvent::MessageDeleted {
+                        group_id,
+                        message_id,
+                    } => {
+                        let payload = MessageDeletedPayload {
+                            group_id,
+                            message_id,
+                        };
+                        app.emit_all("message-deleted", payload).unwrap();
+                    }
+                    FrontendEvent::ReactionUpdate {
+                        group_id,
+                        message_id,
+                        reactions,
+                    } => {
+                        app.emit_all("reaction-update", (group_id, message_id, reactions))
                             .unwrap();
                     }
+                    FrontendEvent::Typing {
+                        group_id,
+                        peer_id,
+                        is_typing,
+                    } => {
+                        let payload = TypingPayload {
+                            group_id,
+                            peer_id,
+                            is_typing,
+                        };
+                        app.emit_all("typing", payload).unwrap();
+                    }
+                    FrontendEvent::PinUpdate { group_id, pinned } => {
+                        let payload = PinUpdatePayload { group_id, pinned };
+                        app.emit_all("pin-update", payload).unwrap();
+                    }
+                    FrontendEvent::RoleUpdate {
+                        group_id,
+                        peer_id,
+                        role,
+                    } => {
+                        let payload = RoleUpdatePayload {
+                            group_id,
+                            peer_id,
+                            role,
+                        };
+                        app.emit_all("role-update", payload).unwrap();
+                    }
+                    FrontendEvent::DirectMessage { peer_id, message } => {
+                        let payload = DirectMessagePayload { peer_id, message };
+                        app.emit_all("direct-message", payload).unwrap();
+                    }
+                    FrontendEvent::DirectMessageRequest { peer_id, message } => {
+                        let payload = DirectMessagePayload { peer_id, message };
+                        app.emit_all("direct-message-request", payload).unwrap();
+                    }
+                    FrontendEvent::MessageDelivered { message_id } => {
+                        app.emit_all("message-delivered", message_id).unwrap();
+                    }
+                    FrontendEvent::MessageStatus { message_id, status } => {
+                        app.emit_all("message-status", (message_id, status))
+                            .unwrap();
+                    }
+                    FrontendEvent::PeerConnected {
+                        peer_id,
+                        endpoint,
+                        num_established,
+                    } => {
+                        let payload = PeerConnectedPayload {
+                            peer_id,
+                            endpoint,
+                            num_established,
+                        };
+                        app.emit_all("peer-connected", payload).unwrap();
+                    }
+                    FrontendEvent::PeerDisconnected {
+                        peer_id,
+                        cause,
+                        num_established,
+                    } => {
+                        let payload = PeerDisconnectedPayload {
+                            peer_id,
+                            cause,
+                            num_established,
+                        };
+                        app.emit_all("peer-disconnected", payload).unwrap();
+                    }
+                    FrontendEvent::MessageAcked {
+                        group_id,
+                        message_id,
+                        peer_id,
+                        acks,
+                    } => {
+                        let payload = MessageAckedPayload {
+                            group_id,
+                            message_id,
+                            peer_id,
+                            acks,
+                        };
+                        app.emit_all("message-acked", payload).unwrap();
+                    }
+                    FrontendEvent::ReadReceipt {
+                        group_id,
+                        peer_id,
+                        up_to,
+                    } => {
+                        let payload = ReadReceiptPayload {
+                            group_id,
+                            peer_id,
+                            up_to,
+                        };
+                        app.emit_all("read-receipt", payload).unwrap();
+                    }
+                    FrontendEvent::ExternalAddr(address) => {
+                        app.emit_all("external-addr", address).unwrap();
+                    }
+                    FrontendEvent::IdentityImported(peer_id) => {
+                        app.emit_all("identity-imported", peer_id).unwrap();
+                    }
+                    FrontendEvent::MetricsUpdate(metrics) => {
+                        app.emit_all("metrics-update", metrics).unwrap();
+                    }
                 }
             });
         }
+        emit_batches(&self.app, &mut batches, &mut tasks);
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+            log::warn!("frontend events still in flight after shutdown timeout, aborting");
+            tasks.abort_all();
+        }
     }
 }
+
+/// Emit every pending batch from one task, leaving `batches` empty.
+fn emit_batches(
+    app: &AppHandle,
+    batches: &mut HashMap<GroupId, MessagesBatchPayload>,
+    tasks: &mut JoinSet<()>,
+) {
+    if batches.is_empty() {
+        return;
+    }
+    let app = app.clone();
+    let batches: Vec<_> = batches.drain().map(|(_, batch)| batch).collect();
+    tasks.spawn(async move {
+        for batch in batches {
+            app.emit_all("messages-batch", batch).unwrap();
+        }
+    });
+}
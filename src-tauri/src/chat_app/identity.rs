@@ -0,0 +1,266 @@
+use std::{io, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use hmac::Hmac;
+use libp2p::identity::Keypair;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{fs, io::AsyncWriteExt, task};
+
+use crate::error::IdentityError;
+
+/// Environment variable holding the passphrase the identity is sealed with.
+pub const PASSPHRASE_ENV: &str = "P2PCHAT_PASSPHRASE";
+
+/// PBKDF2-HMAC-SHA256 iterations turning the passphrase into a key.
+const KDF_ROUNDS: u32 = 100_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityFile {
+    /// Protobuf encoding of the keypair, encrypted if `sealed` is set.
+    key: Vec<u8>,
+    #[serde(default)]
+    sealed: Option<Sealed>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    salt: [u8; 16],
+    nonce: Vec<u8>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+/// The cipher for `passphrase` and `salt`. Deriving its key takes a while,
+/// so it's done off the async runtime.
+async fn cipher(passphrase: &str, salt: [u8; 16]) -> XChaCha20Poly1305 {
+    let passphrase = passphrase.to_string();
+    let key = task::spawn_blocking(move || derive_key(&passphrase, &salt, KDF_ROUNDS))
+        .await
+        .expect("key derivation not to panic");
+    XChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+async fn seal(keypair: &Keypair, passphrase: Option<&str>) -> Result<IdentityFile, IdentityError> {
+    let encoded = keypair
+        .to_protobuf_encoding()
+        .map_err(|e| IdentityError::Invalid(e.to_string()))?;
+    let Some(passphrase) = passphrase else {
+        return Ok(IdentityFile {
+            key: encoded,
+            sealed: None,
+        });
+    };
+    let salt: [u8; 16] = rand::random();
+    let nonce: [u8; 24] = rand::random();
+    let key = cipher(passphrase, salt)
+        .await
+        .encrypt(XNonce::from_slice(&nonce), encoded.as_slice())
+        .expect("encryption to succeed");
+    Ok(IdentityFile {
+        key,
+        sealed: Some(Sealed {
+            salt,
+            nonce: nonce.to_vec(),
+        }),
+    })
+}
+
+async fn unseal(file: IdentityFile, passphrase: Option<&str>) -> Result<Keypair, IdentityError> {
+    let encoded = match (&file.sealed, passphrase) {
+        (None, _) => file.key,
+        (Some(_), None) => return Err(IdentityError::PassphraseRequired),
+        (Some(sealed), Some(passphrase)) => {
+            if sealed.nonce.len() != 24 {
+                return Err(IdentityError::Invalid("bad nonce".to_string()));
+            }
+            cipher(passphrase, sealed.salt)
+                .await
+                .decrypt(XNonce::from_slice(&sealed.nonce), file.key.as_slice())
+                .map_err(|_| IdentityError::WrongPassphrase)?
+        }
+    };
+    Keypair::from_protobuf_encoding(&encoded).map_err(|e| IdentityError::Invalid(e.to_string()))
+}
+
+/// Load the keypair saved at `path`, unsealing it with `passphrase` if it
+/// was sealed with one.
+pub async fn load(path: &Path, passphrase: Option<&str>) -> Result<Keypair, IdentityError> {
+    let file = serde_json::from_slice(&fs::read(path).await?)
+        .map_err(|e| IdentityError::Invalid(e.to_string()))?;
+    unseal(file, passphrase).await
+}
+
+/// Save `keypair` to `path`, sealed with `passphrase` if there is one, and
+/// readable by the owner only. Written to a new file that then replaces
+/// the old one, so a crash can't leave a truncated identity behind.
+pub async fn save(
+    path: &Path,
+    keypair: &Keypair,
+    passphrase: Option<&str>,
+) -> Result<(), IdentityError> {
+    let buf = serde_json::to_vec(&seal(keypair, passphrase).await?).map_err(io::Error::from)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp_path = path.with_extension(format!("{:08x}.tmp", rand::random::<u32>()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let res = async {
+        let mut file = options.open(&tmp_path).await?;
+        file.write_all(&buf).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    Ok(res?)
+}
+
+/// `keypair` sealed with `passphrase`, as base64 to carry to another device.
+pub async fn export(keypair: &Keypair, passphrase: &str) -> Result<String, IdentityError> {
+    let buf =
+        serde_json::to_vec(&seal(keypair, Some(passphrase)).await?).map_err(io::Error::from)?;
+    Ok(base64::encode(buf))
+}
+
+/// The keypair in a blob from `export`.
+pub async fn import(blob: &str, passphrase: &str) -> Result<Keypair, IdentityError> {
+    let buf = base64::decode(blob.trim()).map_err(|e| IdentityError::Invalid(e.to_string()))?;
+    let file = serde_json::from_slice(&buf).map_err(|e| IdentityError::Invalid(e.to_string()))?;
+    unseal(file, Some(passphrase)).await
+}
+
+/// The keypair saved at `path`, or a new Ed25519 one saved there on first
+/// launch. Failing to save only costs a stable identity, so it's logged
+/// rather than returned.
+pub async fn load_or_generate(
+    path: &Path,
+    passphrase: Option<&str>,
+    persistent: bool,
+) -> Result<Keypair, IdentityError> {
+    match load(path, passphrase).await {
+        Err(IdentityError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => {}
+        res => return res,
+    }
+    let keypair = Keypair::generate_ed25519();
+    if persistent {
+        if let Err(e) = save(path, &keypair, passphrase).await {
+            log::error!("failed to save identity, it will change on restart: {e}");
+        }
+    }
+    Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(key: [u8; 32]) -> String {
+        key.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    // The RFC 6070 inputs, with HMAC-SHA256 instead of HMAC-SHA1.
+    #[test]
+    fn derive_key_matches_test_vectors() {
+        assert_eq!(
+            hex(derive_key("password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            hex(derive_key("password", b"salt", 2)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+        assert_eq!(
+            hex(derive_key("password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+        assert_eq!(
+            hex(derive_key(
+                "passwordPASSWORDpassword",
+                b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096
+            )),
+            "348c89dbcbd32b2f32d814b8116e84cf2b17347ebc1800181c4e2a1fb8dd53e1"
+        );
+    }
+
+    #[tokio::test]
+    async fn sealed_identity_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let file = seal(&keypair, Some("secret")).await.unwrap();
+        assert!(file.sealed.is_some());
+        let unsealed = unseal(file, Some("secret")).await.unwrap();
+        assert_eq!(unsealed.public(), keypair.public());
+    }
+
+    #[tokio::test]
+    async fn sealed_identity_needs_its_passphrase() {
+        let keypair = Keypair::generate_ed25519();
+        let file = seal(&keypair, Some("secret")).await.unwrap();
+        assert!(matches!(
+            unseal(file, Some("wrong")).await,
+            Err(IdentityError::WrongPassphrase)
+        ));
+        let file = seal(&keypair, Some("secret")).await.unwrap();
+        assert!(matches!(
+            unseal(file, None).await,
+            Err(IdentityError::PassphraseRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsealed_identity_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let file = seal(&keypair, None).await.unwrap();
+        assert!(file.sealed.is_none());
+        let unsealed = unseal(file, Some("ignored")).await.unwrap();
+        assert_eq!(unsealed.public(), keypair.public());
+    }
+
+    #[tokio::test]
+    async fn exported_identity_imports() {
+        let keypair = Keypair::generate_ed25519();
+        let blob = export(&keypair, "secret").await.unwrap();
+        assert_eq!(
+            import(&blob, "secret").await.unwrap().public(),
+            keypair.public()
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_identity_replaces_the_old_one() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        let path = dir.join("identity.json");
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(&path, b"old").await.unwrap();
+        let keypair = Keypair::generate_ed25519();
+        save(&path, &keypair, None).await.unwrap();
+        assert_eq!(load(&path, None).await.unwrap().public(), keypair.public());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        let mut count = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,22 +1,65 @@
-use std::collections::hash_map;
+use std::{collections::hash_map, sync::atomic::Ordering, time::Duration};
 
 use crate::{
     error::NetworkError,
     managers::{AppManager, HandleInboundEvent},
     network::{
-        message::{self, InboundEvent},
+        message::{self, InboundEvent, Response},
         Client,
     },
 };
 
-use futures::{
-    future::{join_all, try_join_all},
-    FutureExt,
-};
-use tokio::sync::mpsc;
+use futures::{stream, FutureExt, StreamExt};
+use libp2p::PeerId;
+use tokio::{sync::mpsc, time};
 
 use super::{frontend_event::FrontendEvent, AppState};
 
+/// Wait before the first reconnect attempt, doubled after each failure.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Redial `peer_id` with exponential backoff until it's connected again or
+/// the user disconnects it. Attempts are skipped while the network is paused.
+async fn reconnect(
+    client: Client,
+    state: AppState,
+    frontend_sender: mpsc::Sender<FrontendEvent>,
+    peer_id: PeerId,
+) {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let mut attempt = 0;
+    loop {
+        time::sleep(backoff).await;
+        if !state.intended_peers.lock().await.contains(&peer_id) {
+            break;
+        }
+        if state.network_paused.load(Ordering::Relaxed) {
+            continue;
+        }
+        match client.connected_peers().await {
+            Ok(peers) if peers.contains(&peer_id) => break,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+        attempt += 1;
+        if frontend_sender
+            .send(FrontendEvent::PeerReconnecting { peer_id, attempt })
+            .await
+            .is_err()
+        {
+            break;
+        }
+        match client.redial(peer_id).await {
+            Ok(()) => break,
+            Err(NetworkError::Command(_)) => break,
+            Err(e) => log::debug!("reconnect attempt {attempt} to {peer_id} failed: {e}"),
+        }
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+    state.reconnecting.lock().await.remove(&peer_id);
+}
+
 pub struct InboundEventLoop {
     pub(super) client: Client,
     pub(super) inbound_event_receiver: mpsc::Receiver<message::InboundEvent>,
@@ -27,23 +70,32 @@ pub struct InboundEventLoop {
 
 impl InboundEventLoop {
     pub async fn run(mut self) -> Result<(), NetworkError> {
+        let concurrency = self.state.setting.lock().await.manager_concurrency.max(1);
         while let Some(event) = self.inbound_event_receiver.recv().await {
-            let iter = self.managers.iter_mut().map(|manager| {
-                let event = event.clone();
-                let client = self.client.clone();
-                let state = self.state.clone();
-                let sender = self.frontend_sender.clone();
-                async move {
-                    match manager.handle_event(event, client, state, sender).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            log::error!("{} manager occured an error: {}", manager.name(), err)
-                        }
-                    };
-                }
-                .boxed()
-            });
-            join_all(iter).await;
+            let handlers = self
+                .managers
+                .iter_mut()
+                .map(|manager| {
+                    let event = event.clone();
+                    let client = self.client.clone();
+                    let state = self.state.clone();
+                    let sender = self.frontend_sender.clone();
+                    async move {
+                        match manager.handle_event(event, client, state, sender).await {
+                            Ok(_) => {}
+                            Err(err) => {
+                                log::error!("{} manager occured an error: {}", manager.name(), err)
+                            }
+                        };
+                    }
+                    .boxed()
+                })
+                .collect::<Vec<_>>();
+            // Every manager still sees the event before the next one is taken.
+            stream::iter(handlers)
+                .buffer_unordered(concurrency)
+                .collect::<Vec<_>>()
+                .await;
             self.handle_event_default(event).await?;
         }
         Ok(())
@@ -51,9 +103,19 @@ impl InboundEventLoop {
 
     async fn handle_event_default(&mut self, event: InboundEvent) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => {
-                if let Some(_channel) = channel.lock().await.take() {
+            InboundEvent::InboundRequest {
+                request, channel, ..
+            } => {
+                // No manager answered, so fail the request fast instead of
+                // letting the requester wait for a timeout.
+                if let Some(channel) = channel.lock().await.take() {
                     log::warn!("request not handled {request:?}");
+                    self.client
+                        .response(
+                            Response::Error(format!("unsupported request: {request:?}")),
+                            channel,
+                        )
+                        .await?;
                 }
             }
             InboundEvent::NewListenAddr {
@@ -101,8 +163,167 @@ impl InboundEventLoop {
                     addr.is_empty().then(|| oe.remove());
                 }
             }
+            InboundEvent::ConnectionRefused { peer_id } => {
+                self.frontend_sender
+                    .send(FrontendEvent::BackendError(NetworkError::PeerNotAllowed(
+                        peer_id,
+                    )))
+                    .await
+                    .unwrap();
+            }
+            InboundEvent::RelayStatus {
+                relay_peer_id,
+                error,
+            } => {
+                let _ = self.state.relay_status.send((relay_peer_id, error.clone()));
+                self.frontend_sender
+                    .send(FrontendEvent::RelayStatus {
+                        relay_peer_id,
+                        error,
+                    })
+                    .await
+                    .unwrap();
+            }
+            InboundEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+            } => {
+                self.frontend_sender
+                    .send(FrontendEvent::PeerConnected {
+                        peer_id,
+                        endpoint,
+                        num_established,
+                    })
+                    .await
+                    .unwrap();
+            }
+            InboundEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                cause,
+            } => {
+                self.frontend_sender
+                    .send(FrontendEvent::PeerDisconnected {
+                        peer_id,
+                        cause,
+                        num_established,
+                    })
+                    .await
+                    .unwrap();
+                let reconnecting = num_established == 0
+                    && self.state.intended_peers.lock().await.contains(&peer_id)
+                    && self.state.reconnecting.lock().await.insert(peer_id);
+                if reconnecting {
+                    self.state.tasks.spawn_cancellable(reconnect(
+                        self.client.clone(),
+                        self.state.clone(),
+                        self.frontend_sender.clone(),
+                        peer_id,
+                    ));
+                }
+            }
+            InboundEvent::HolePunchResult { peer_id, success } => {
+                self.frontend_sender
+                    .send(FrontendEvent::HolePunchResult { peer_id, success })
+                    .await
+                    .unwrap();
+            }
+            InboundEvent::ExternalAddr { address } => {
+                self.frontend_sender
+                    .send(FrontendEvent::ExternalAddr(address))
+                    .await
+                    .unwrap();
+            }
             _ => {}
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ManagerError, managers::Invoke, models::Setting, network};
+    use async_trait::async_trait;
+    use libp2p::{identity::Keypair, PeerId};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Counts how many of its clones handle an event at the same time.
+    #[derive(Clone, Default)]
+    struct Probe {
+        running: Arc<AtomicUsize>,
+        most: Arc<AtomicUsize>,
+        handled: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl HandleInboundEvent for Probe {
+        async fn handle_event(
+            &mut self,
+            _event: InboundEvent,
+            _client: Client,
+            _state: AppState,
+            _sender: mpsc::Sender<FrontendEvent>,
+        ) -> Result<(), NetworkError> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.handled.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Invoke for Probe {
+        async fn invoke(
+            &self,
+            action: &str,
+            _params: Option<serde_json::Value>,
+        ) -> Result<serde_json::Value, ManagerError> {
+            Err(ManagerError::InvalidAction(action.to_string()))
+        }
+    }
+
+    impl AppManager for Probe {
+        fn name(&self) -> &'static str {
+            "probe"
+        }
+    }
+
+    #[tokio::test]
+    async fn managers_run_up_to_the_configured_concurrency() {
+        let network = network::new(Keypair::generate_ed25519(), &Setting::default()).unwrap();
+        let state = AppState::new(std::env::temp_dir());
+        state.setting.lock().await.manager_concurrency = 2;
+        let probe = Probe::default();
+        let (event_sender, inbound_event_receiver) = mpsc::channel(1);
+        let (frontend_sender, _frontend) = mpsc::channel(1);
+        let event_loop = InboundEventLoop {
+            client: network.client,
+            inbound_event_receiver,
+            frontend_sender,
+            state,
+            managers: (0..6)
+                .map(|_| Box::new(probe.clone()) as Box<dyn AppManager>)
+                .collect(),
+        };
+        event_sender
+            .send(InboundEvent::PeerDiscovered {
+                peer_id: PeerId::random(),
+            })
+            .await
+            .unwrap();
+        drop(event_sender);
+        event_loop.run().await.unwrap();
+        assert_eq!(probe.handled.load(Ordering::SeqCst), 6);
+        assert_eq!(probe.most.load(Ordering::SeqCst), 2);
+    }
+}
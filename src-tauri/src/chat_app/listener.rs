@@ -0,0 +1,37 @@
+use std::{collections::HashMap, sync::Arc};
+
+use libp2p::swarm::derive_prelude::ListenerId;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct Inner {
+    numbers: HashMap<ListenerId, u64>,
+    listeners: HashMap<u64, ListenerId>,
+    next: u64,
+}
+
+/// Numbers the frontend knows listeners by, as `ListenerId` has no public
+/// representation of its own. Numbers count up from 1 and are never reused.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerIds {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ListenerIds {
+    /// The number of `listener_id`, assigned on first use.
+    pub async fn number(&self, listener_id: ListenerId) -> u64 {
+        let mut inner = self.inner.lock().await;
+        if let Some(number) = inner.numbers.get(&listener_id) {
+            return *number;
+        }
+        inner.next += 1;
+        let number = inner.next;
+        inner.numbers.insert(listener_id, number);
+        inner.listeners.insert(number, listener_id);
+        number
+    }
+    /// The listener given `number`, if any was.
+    pub async fn listener(&self, number: u64) -> Option<ListenerId> {
+        self.inner.lock().await.listeners.get(&number).copied()
+    }
+}
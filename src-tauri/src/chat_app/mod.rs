@@ -1,36 +1,235 @@
-use std::{collections::HashMap, sync::Arc};
+use chrono::Utc;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tauri::AppHandle;
 
 use crate::{
-    managers::{group::GroupManager, user::UserManager, AppManager, Invoke},
-    models::{LocalUserInfo, Setting},
+    error::NetworkError,
+    managers::{
+        direct::DirectMessageManager, file::FileManager, group::GroupManager,
+        latency::LatencyManager, presence::PresenceManager, reputation::ReputationManager,
+        user::UserManager, AppManager, Invoke,
+    },
+    models::{DirectMessage, FileInfo, GroupId, LocalUserInfo, OutboxSetting, Setting, UserInfo},
     network::{self, EventLoop},
 };
 use tokio::{
     join,
-    sync::{mpsc, Mutex},
+    sync::{broadcast, mpsc, oneshot, watch, Mutex},
+    time::Instant,
 };
 
 pub mod app_command;
 pub mod frontend_event;
+pub mod identity;
 pub mod inbound_event;
+pub mod listener;
+pub mod operation;
+pub mod payload;
+pub mod tasks;
 
 use self::{
-    app_command::AppCommandHandle, frontend_event::FrontendEventLoop,
+    app_command::AppCommandHandle,
+    frontend_event::{FrontendEvent, FrontendEventLoop},
     inbound_event::InboundEventLoop,
+    listener::ListenerIds,
+    operation::Operations,
+    tasks::Tasks,
 };
+
+/// How many undelivered direct messages are kept per peer.
+const DM_OUTBOX_CAPACITY: usize = 100;
+/// How long background tasks may take to finish once the loops stopped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub(super) setting: Arc<Mutex<Setting>>,
     pub(super) local_user: Arc<Mutex<LocalUserInfo>>,
+    pub(super) config_dir: PathBuf,
+    pub(super) operations: Operations,
+    pub(super) tasks: Tasks,
+    /// Cleared when `config_dir` turns out not to be writable; saves are
+    /// then skipped and state lives in memory only.
+    pub(super) persistent: Arc<AtomicBool>,
+    /// Why `config_dir` isn't writable, kept for the frontend to ask on
+    /// startup since it isn't listening for events yet.
+    pub(super) persistence_error: Arc<Mutex<Option<String>>>,
+    pub(super) network_paused: Arc<AtomicBool>,
+    /// Cancellation flags of in-flight downloads.
+    pub(super) downloads: Arc<Mutex<HashMap<FileInfo, Arc<AtomicBool>>>>,
+    /// Peers the user dialed, reconnected to when their connection drops.
+    pub(super) intended_peers: Arc<Mutex<HashSet<PeerId>>>,
+    /// Peers with a reconnect already underway.
+    pub(super) reconnecting: Arc<Mutex<HashSet<PeerId>>>,
+    /// Outcomes of relay reservations, for commands waiting on one.
+    pub(super) relay_status: broadcast::Sender<(PeerId, Option<String>)>,
+    /// Groups we last told we're typing in, and when.
+    pub(super) typing: Arc<Mutex<HashMap<GroupId, Instant>>>,
+    /// Direct messages waiting for their peer to come online, oldest first.
+    pub(super) dm_outbox: Arc<Mutex<HashMap<PeerId, VecDeque<DirectMessage>>>>,
+    pub(super) listener_ids: ListenerIds,
+    /// Set once shutdown starts, for the long-running tasks to stop on.
+    pub(super) shutdown: Arc<watch::Sender<bool>>,
 }
 
-impl Default for AppState {
-    fn default() -> Self {
+impl AppState {
+    pub fn new(config_dir: PathBuf) -> Self {
         Self {
             setting: Arc::new(Mutex::new(Setting::default())),
             local_user: Arc::new(Mutex::new(LocalUserInfo::default())),
+            config_dir,
+            operations: Operations::default(),
+            tasks: Tasks::default(),
+            persistent: Arc::new(AtomicBool::new(true)),
+            persistence_error: Arc::new(Mutex::new(None)),
+            network_paused: Arc::new(AtomicBool::new(false)),
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+            intended_peers: Arc::new(Mutex::new(HashSet::new())),
+            reconnecting: Arc::new(Mutex::new(HashSet::new())),
+            relay_status: broadcast::channel(16).0,
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            dm_outbox: Arc::new(Mutex::new(HashMap::new())),
+            listener_ids: ListenerIds::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+    /// Load the persisted setting, keeping the defaults if there is none yet.
+    pub async fn load_setting(&self) -> Result<(), NetworkError> {
+        match Setting::load(&self.config_dir).await {
+            Ok(setting) => {
+                self.set_local_user(&setting.user_info).await;
+                *self.setting.lock().await = setting;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+    /// What we answer peers asking who we are.
+    pub(super) async fn set_local_user(&self, user_info: &UserInfo) {
+        let mut local_user = self.local_user.lock().await;
+        local_user.name = user_info.name.clone();
+        local_user.avatar = user_info.avatar.clone();
+    }
+    pub async fn save_setting(&self) -> Result<(), NetworkError> {
+        if !self.is_persistent() {
+            return Ok(());
+        }
+        self.setting.lock().await.save(&self.config_dir).await?;
+        Ok(())
+    }
+    /// Delete the partial downloads in `recv_path` that no download in
+    /// progress is writing to.
+    pub async fn remove_orphaned_parts(&self) -> io::Result<Vec<PathBuf>> {
+        let recv_path = self.setting.lock().await.recv_path.clone();
+        let downloading = self
+            .downloads
+            .lock()
+            .await
+            .keys()
+            .filter_map(|file| Path::new(&file.name).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        app_command::remove_orphaned_parts(&recv_path, &downloading).await
+    }
+    /// Where the identity keypair is kept, `identity.json` in `config_dir`
+    /// unless the setting says otherwise.
+    pub async fn identity_path(&self) -> PathBuf {
+        match &self.setting.lock().await.identity_path {
+            Some(path) => path.clone(),
+            None => self.config_dir.join("identity.json"),
+        }
+    }
+    fn dm_outbox_path(&self) -> PathBuf {
+        self.config_dir.join("dm_outbox.json")
+    }
+    pub async fn load_dm_outbox(&self) -> Result<(), io::Error> {
+        let buf = match tokio::fs::read(self.dm_outbox_path()).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        *self.dm_outbox.lock().await = serde_json::from_slice(&buf)?;
+        Ok(())
+    }
+    pub async fn save_dm_outbox(&self) -> Result<(), io::Error> {
+        if !self.is_persistent() {
+            return Ok(());
         }
+        let buf = serde_json::to_vec(&*self.dm_outbox.lock().await)?;
+        tokio::fs::write(self.dm_outbox_path(), buf).await
+    }
+    /// Hold `message` until `peer_id` connects, dropping and returning the
+    /// oldest one queued for the peer once `DM_OUTBOX_CAPACITY` is reached.
+    pub async fn queue_dm(&self, peer_id: PeerId, message: DirectMessage) -> Option<DirectMessage> {
+        let mut dm_outbox = self.dm_outbox.lock().await;
+        let queue = dm_outbox.entry(peer_id).or_default();
+        let dropped = if queue.len() >= DM_OUTBOX_CAPACITY {
+            log::warn!("outbox for {peer_id} is full, dropping the oldest message");
+            queue.pop_front()
+        } else {
+            None
+        };
+        queue.push_back(message);
+        dropped
+    }
+    /// Take the queued direct messages `outbox` gives up on at `now`.
+    pub async fn expire_dm_outbox(
+        &self,
+        outbox: &OutboxSetting,
+        now: i64,
+    ) -> Vec<(PeerId, DirectMessage)> {
+        let mut expired = Vec::new();
+        self.dm_outbox.lock().await.retain(|peer_id, queue| {
+            queue.retain(|message| {
+                let keep = !outbox.is_expired(message.attempts, message.timestamp, now);
+                if !keep {
+                    expired.push((*peer_id, message.clone()));
+                }
+                keep
+            });
+            !queue.is_empty()
+        });
+        expired
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+    /// Resolve once shutdown starts, right away if it already has.
+    pub async fn shutdown_started(&self) {
+        let mut receiver = self.shutdown.subscribe();
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+    pub fn is_persistent(&self) -> bool {
+        self.persistent.load(Ordering::Relaxed)
+    }
+    /// Probe whether `config_dir` can be written, switching to in-memory
+    /// operation if it can't.
+    pub async fn check_persistence(&self) -> Result<(), io::Error> {
+        let probe = self.config_dir.join(".write-probe");
+        let res = async {
+            tokio::fs::create_dir_all(&self.config_dir).await?;
+            tokio::fs::write(&probe, b"").await?;
+            tokio::fs::remove_file(&probe).await
+        }
+        .await;
+        self.persistent.store(res.is_ok(), Ordering::Relaxed);
+        *self.persistence_error.lock().await = res.as_ref().err().map(|e| e.to_string());
+        res
     }
 }
 
@@ -41,30 +240,108 @@ pub struct ChatApp {
     network_eventloop: Option<EventLoop>,
     inbound_eventloop: Option<InboundEventLoop>,
     frontend_eventloop: Option<FrontendEventLoop>,
+    frontend_sender: Option<mpsc::Sender<FrontendEvent>>,
+    group: GroupManager,
+    user: UserManager,
+    file: FileManager,
+    reputation: ReputationManager,
+    direct: DirectMessageManager,
+    presence: PresenceManager,
+    latency: LatencyManager,
     managers: HashMap<String, Box<dyn Invoke>>,
 }
 
 impl ChatApp {
     pub fn new(app: AppHandle) -> Self {
+        let config_dir = app
+            .path_resolver()
+            .app_config_dir()
+            .unwrap_or_else(|| PathBuf::from("."));
         Self {
             app,
-            state: AppState::default(),
+            state: AppState::new(config_dir),
             client: None,
             network_eventloop: None,
             inbound_eventloop: None,
             frontend_eventloop: None,
+            frontend_sender: None,
+            group: GroupManager::new(),
+            user: UserManager::new(),
+            file: FileManager::new(),
+            reputation: ReputationManager::new(),
+            direct: DirectMessageManager::new(),
+            presence: PresenceManager::new(),
+            latency: LatencyManager::new(),
             managers: HashMap::new(),
         }
     }
 
-    pub fn initialize(&mut self) -> anyhow::Result<()> {
-        let network = network::new(None)?;
+    pub async fn initialize(&mut self) -> anyhow::Result<()> {
+        if let Err(e) = self.state.check_persistence().await {
+            log::error!("config dir is not writable, running without persistence: {e}");
+        }
+        if let Err(e) = self.state.load_setting().await {
+            log::error!("failed to load setting: {e}");
+        }
+        match self.state.remove_orphaned_parts().await {
+            Ok(removed) if !removed.is_empty() => {
+                log::info!("removed {} orphaned partial downloads", removed.len());
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("failed to clean up partial downloads: {e}"),
+        }
+        if let Err(e) = self.reputation.load(&self.state.config_dir).await {
+            log::error!("failed to load peer reputation: {e}");
+        }
+        if let Err(e) = self.file.load_providers(&self.state.config_dir).await {
+            log::error!("failed to load provided files: {e}");
+        }
+        if let Err(e) = self.state.load_dm_outbox().await {
+            log::error!("failed to load queued direct messages: {e}");
+        }
+        let outbox = self.state.setting.lock().await.effective().outbox;
+        let expired = self
+            .state
+            .expire_dm_outbox(&outbox, Utc::now().timestamp())
+            .await;
+        if !expired.is_empty() {
+            log::warn!("giving up on {} queued direct messages", expired.len());
+            for (peer_id, message) in expired {
+                self.direct.mark_failed(peer_id, message).await;
+            }
+            if let Err(e) = self.state.save_dm_outbox().await {
+                log::warn!("failed to save queued direct messages: {e}");
+            }
+        }
+        if self.state.is_persistent() {
+            let retention = self.state.setting.lock().await.history_retention;
+            if let Err(e) = self
+                .group
+                .load_history(&self.state.config_dir, retention)
+                .await
+            {
+                log::error!("failed to load group history: {e}");
+            }
+        }
+        let passphrase = std::env::var(identity::PASSPHRASE_ENV).ok();
+        let id_keys = identity::load_or_generate(
+            &self.state.identity_path().await,
+            passphrase.as_deref(),
+            self.state.is_persistent(),
+        )
+        .await?;
+        let network = network::new(id_keys, &*self.state.setting.lock().await)?;
         self.client = Some(network.client.clone());
         self.network_eventloop = Some(network.event_loop);
         let (frontend_sender, frontend_receiver) = mpsc::channel(100);
 
-        let group = GroupManager::new();
-        let user = UserManager::new();
+        let group = self.group.clone();
+        let user = self.user.clone();
+        let file = self.file.clone();
+        let reputation = self.reputation.clone();
+        let direct = self.direct.clone();
+        let presence = self.presence.clone();
+        let latency = self.latency.clone();
         self.managers = [
             (
                 group.name().to_string(),
@@ -74,6 +351,26 @@ impl ChatApp {
                 user.name().to_string(),
                 Box::new(user.clone()) as Box<dyn Invoke>,
             ),
+            (
+                file.name().to_string(),
+                Box::new(file.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                reputation.name().to_string(),
+                Box::new(reputation.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                direct.name().to_string(),
+                Box::new(direct.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                presence.name().to_string(),
+                Box::new(presence.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                latency.name().to_string(),
+                Box::new(latency.clone()) as Box<dyn Invoke>,
+            ),
         ]
         .into();
 
@@ -82,16 +379,27 @@ impl ChatApp {
             inbound_event_receiver: network.event_receiver,
             frontend_sender: frontend_sender.clone(),
             state: self.state.clone(),
-            managers: vec![Box::new(group), Box::new(user)],
+            managers: vec![
+                Box::new(group),
+                Box::new(user),
+                Box::new(file),
+                Box::new(reputation),
+                Box::new(direct),
+                Box::new(presence),
+                Box::new(latency),
+            ],
         });
         self.frontend_eventloop = Some(FrontendEventLoop {
             app: self.app.clone(),
             frontend_receiver,
             state: self.state.clone(),
         });
+        self.frontend_sender = Some(frontend_sender);
         Ok(())
     }
     pub async fn run(self) -> anyhow::Result<()> {
+        let handle = self.command_handle()?;
+        let state = self.state.clone();
         let Some(network_eventloop) = self.network_eventloop else {
             anyhow::bail!("network event loop is not initialized");
         };
@@ -101,22 +409,107 @@ impl ChatApp {
         let Some(frontend_eventloop) = self.frontend_eventloop else {
             anyhow::bail!("frontend event loop is not initialized");
         };
-        let (_, _, _) = join![
+        // Inbound events still reach the frontend while they drain, so the
+        // frontend loop only stops after the inbound one.
+        let (inbound_done, inbound_stopped) = oneshot::channel();
+        let inbound = async move {
+            let res = inbound_event_loop.run().await;
+            let _ = inbound_done.send(());
+            res
+        };
+        let (_, _, _, _, _, _) = join![
             tokio::spawn(network_eventloop.run()),
-            tokio::spawn(inbound_event_loop.run()),
-            tokio::spawn(frontend_eventloop.run())
+            tokio::spawn(inbound),
+            tokio::spawn(frontend_eventloop.run(inbound_stopped)),
+            tokio::spawn(handle.clone().listen_on_default_relay()),
+            tokio::spawn(handle.clone().run_auto_leave()),
+            tokio::spawn(handle.run_metrics())
         ];
+        state.tasks.shutdown(SHUTDOWN_TIMEOUT).await;
         Ok(())
     }
     pub fn command_handle(&self) -> anyhow::Result<AppCommandHandle> {
         let  Some(client) = &self.client else {
             anyhow::bail!("client is not initialized");
         };
+        let Some(frontend_sender) = &self.frontend_sender else {
+            anyhow::bail!("frontend sender is not initialized");
+        };
 
         Ok(AppCommandHandle {
             client: client.clone(),
+            frontend_sender: frontend_sender.clone(),
             state: self.state.clone(),
+            group: self.group.clone(),
+            user: self.user.clone(),
+            file: self.file.clone(),
+            direct: self.direct.clone(),
+            presence: self.presence.clone(),
+            latency: self.latency.clone(),
+            reputation: self.reputation.clone(),
             managers: self.managers.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unwritable_config_dir_keeps_state_in_memory() {
+        let file = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        tokio::fs::write(&file, b"").await.unwrap();
+        // A directory can't be created under a regular file.
+        let state = AppState::new(file.join("config"));
+        assert!(state.check_persistence().await.is_err());
+        assert!(!state.is_persistent());
+        assert!(state.persistence_error.lock().await.is_some());
+        state.save_setting().await.unwrap();
+        tokio::fs::remove_file(&file).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        let state = AppState::new(dir.clone());
+        state.check_persistence().await.unwrap();
+        assert!(state.is_persistent());
+        state.save_setting().await.unwrap();
+        assert!(Setting::load(&dir).await.is_ok());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn orphaned_parts_are_removed_on_startup() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let [orphan, active, other] = [
+            ".crashed.txt.0badf00d.part",
+            ".active.txt.12345678.part",
+            ".notes.part",
+        ]
+        .map(|name| dir.join(name));
+        for path in [&orphan, &active, &other] {
+            tokio::fs::write(path, b"partial").await.unwrap();
+        }
+        let state = AppState::new(dir.clone());
+        state.setting.lock().await.recv_path = dir.clone();
+        let downloading = FileInfo {
+            name: "active.txt".to_string(),
+            size: 10,
+            file_type: None,
+            hash: None,
+        };
+        state
+            .downloads
+            .lock()
+            .await
+            .insert(downloading, Arc::new(AtomicBool::new(false)));
+        assert_eq!(
+            state.remove_orphaned_parts().await.unwrap(),
+            vec![orphan.clone()]
+        );
+        assert!(!orphan.exists());
+        assert!(active.exists());
+        assert!(other.exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
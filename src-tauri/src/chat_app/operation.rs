@@ -0,0 +1,100 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use chrono::Utc;
+use tokio::sync::{oneshot, Mutex};
+use uuid::Uuid;
+
+use crate::{error::NetworkError, models::OperationInfo};
+
+#[derive(Debug)]
+struct Operation {
+    kind: &'static str,
+    started_at: i64,
+    cancel: oneshot::Sender<()>,
+}
+
+/// Long-running commands that are still waiting on the network, so stuck
+/// ones can be listed and cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct Operations {
+    inner: Arc<Mutex<HashMap<Uuid, Operation>>>,
+}
+
+impl Operations {
+    /// Run `future` as a cancellable operation of `kind`.
+    pub async fn track<T>(
+        &self,
+        kind: &'static str,
+        future: impl Future<Output = Result<T, NetworkError>>,
+    ) -> Result<T, NetworkError> {
+        let id = Uuid::new_v4();
+        let (cancel, cancelled) = oneshot::channel();
+        self.inner.lock().await.insert(
+            id,
+            Operation {
+                kind,
+                started_at: Utc::now().timestamp_millis(),
+                cancel,
+            },
+        );
+        let res = tokio::select! {
+            res = future => res,
+            _ = cancelled => Err(NetworkError::Cancelled(id)),
+        };
+        self.inner.lock().await.remove(&id);
+        res
+    }
+    pub async fn list(&self) -> Vec<OperationInfo> {
+        let now = Utc::now().timestamp_millis();
+        self.inner
+            .lock()
+            .await
+            .iter()
+            .map(|(id, op)| OperationInfo {
+                id: *id,
+                kind: op.kind.to_string(),
+                age_ms: (now - op.started_at).max(0),
+            })
+            .collect()
+    }
+    /// Cancel an operation, returning whether it was still running.
+    pub async fn cancel(&self, id: &Uuid) -> bool {
+        match self.inner.lock().await.remove(id) {
+            Some(op) => op.cancel.send(()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+
+    #[tokio::test]
+    async fn cancelled_operations_fail_their_caller() {
+        let operations = Operations::default();
+        let stuck = tokio::spawn({
+            let operations = operations.clone();
+            async move {
+                operations
+                    .track("dial", future::pending::<Result<(), NetworkError>>())
+                    .await
+            }
+        });
+        let listed = loop {
+            let listed = operations.list().await;
+            if !listed.is_empty() {
+                break listed;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].kind, "dial");
+        assert!(operations.cancel(&listed[0].id).await);
+        assert!(!operations.cancel(&listed[0].id).await);
+        let res = stuck.await.unwrap();
+        assert!(matches!(res, Err(NetworkError::Cancelled(id)) if id == listed[0].id));
+        assert!(operations.list().await.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::models::{
+    DirectMessage, FileInfo, GroupId, GroupInfo, GroupMessage, MessageId, PeerLatency, Presence,
+    Role, UserInfo,
+};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenPayload {
+    pub listener_id: u64,
+    pub addresses: Vec<Multiaddr>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePayload {
+    pub group_id: GroupId,
+    pub message: GroupMessage,
+    pub suppress_notification: bool,
+}
+
+/// Messages that reached a group within one batch window, oldest first.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagesBatchPayload {
+    pub group_id: GroupId,
+    pub messages: Vec<GroupMessage>,
+    /// Set only if it was for every message in the batch.
+    pub suppress_notification: bool,
+}
+
+/// For both `subscribed` and `unsubscribed`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionPayload {
+    pub group_id: GroupId,
+    pub peer_id: PeerId,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupUpdatePayload {
+    pub group_id: GroupId,
+    pub group_info: GroupInfo,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdatePayload {
+    pub peer_id: PeerId,
+    pub user_info: UserInfo,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollUpdatePayload {
+    pub group_id: GroupId,
+    pub poll_id: Uuid,
+    pub tallies: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RosterSnapshotPayload {
+    pub group_id: GroupId,
+    pub peers: HashSet<PeerId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEditedPayload {
+    pub group_id: GroupId,
+    pub message: GroupMessage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDeletedPayload {
+    pub group_id: GroupId,
+    pub message_id: MessageId,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingPayload {
+    pub group_id: GroupId,
+    pub peer_id: PeerId,
+    pub is_typing: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinUpdatePayload {
+    pub group_id: GroupId,
+    pub pinned: Vec<MessageId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleUpdatePayload {
+    pub group_id: GroupId,
+    pub peer_id: PeerId,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessagePayload {
+    pub peer_id: PeerId,
+    pub message: DirectMessage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAckedPayload {
+    pub group_id: GroupId,
+    pub message_id: MessageId,
+    pub peer_id: PeerId,
+    /// Members that acknowledged the message so far.
+    pub acks: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadReceiptPayload {
+    pub group_id: GroupId,
+    pub peer_id: PeerId,
+    pub up_to: MessageId,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileProgressPayload {
+    pub file: FileInfo,
+    pub received: u64,
+    pub total: u64,
+    pub provider: Option<PeerId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayStatusPayload {
+    pub relay_peer_id: PeerId,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerConnectedPayload {
+    pub peer_id: PeerId,
+    pub endpoint: Multiaddr,
+    pub num_established: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerDisconnectedPayload {
+    pub peer_id: PeerId,
+    pub cause: Option<String>,
+    pub num_established: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresencePayload {
+    pub peer_id: PeerId,
+    pub presence: Presence,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyPayload {
+    pub peer_id: PeerId,
+    pub latency: PeerLatency,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerReconnectingPayload {
+    pub peer_id: PeerId,
+    pub attempt: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HolePunchResultPayload {
+    pub peer_id: PeerId,
+    pub success: bool,
+}
@@ -0,0 +1,107 @@
+use std::{
+    future::Future,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use tokio::{task::JoinSet, time};
+
+/// Tasks spawned to finish work in the background, so shutdown can wait for
+/// them instead of abandoning them halfway through.
+#[derive(Debug, Clone, Default)]
+pub struct Tasks {
+    inner: Arc<Mutex<TaskSets>>,
+}
+
+#[derive(Debug, Default)]
+struct TaskSets {
+    /// Waited for on shutdown.
+    awaited: JoinSet<()>,
+    /// Aborted on shutdown, for tasks that only wait for something to
+    /// happen.
+    cancellable: JoinSet<()>,
+}
+
+/// Drop the tasks of `set` that already finished, so it doesn't grow
+/// unbounded.
+fn reap(set: &mut JoinSet<()>) {
+    while let Some(Some(_)) = set.join_next().now_or_never() {}
+}
+
+impl Tasks {
+    /// Run `task` in the background; shutdown waits for it to finish.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut sets = self.inner.lock().unwrap();
+        reap(&mut sets.awaited);
+        sets.awaited.spawn(task);
+    }
+    /// Run `task` in the background; shutdown aborts it.
+    pub fn spawn_cancellable(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut sets = self.inner.lock().unwrap();
+        reap(&mut sets.cancellable);
+        sets.cancellable.spawn(task);
+    }
+    /// Abort the cancellable tasks and wait up to `timeout` for the others,
+    /// including those they spawn meanwhile, aborting what's left after.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let (mut awaited, mut cancellable) = {
+                let mut sets = self.inner.lock().unwrap();
+                (
+                    mem::take(&mut sets.awaited),
+                    mem::take(&mut sets.cancellable),
+                )
+            };
+            cancellable.shutdown().await;
+            if awaited.is_empty() {
+                return;
+            }
+            let drain = async { while awaited.join_next().await.is_some() {} };
+            if time::timeout_at(deadline, drain).await.is_err() {
+                log::warn!("background tasks still running after shutdown timeout, aborting");
+                awaited.shutdown().await;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn shutdown_waits_for_slow_tasks() {
+        let tasks = Tasks::default();
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        tasks.spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            finished.store(true, Ordering::SeqCst);
+        });
+        tasks.spawn_cancellable(time::sleep(Duration::from_secs(3600)));
+        time::timeout(
+            Duration::from_secs(5),
+            tasks.shutdown(Duration::from_secs(10)),
+        )
+        .await
+        .expect("cancellable tasks to be aborted");
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_after_the_timeout() {
+        let tasks = Tasks::default();
+        tasks.spawn(time::sleep(Duration::from_secs(3600)));
+        time::timeout(
+            Duration::from_secs(5),
+            tasks.shutdown(Duration::from_millis(100)),
+        )
+        .await
+        .expect("shutdown to stop waiting");
+    }
+}
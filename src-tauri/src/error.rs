@@ -1,4 +1,4 @@
-use std::{fmt::Display, io, path::PathBuf};
+use std::{fmt::Display, io, path::PathBuf, time::Duration};
 
 use libp2p::{
     gossipsub::error::{PublishError, SubscriptionError},
@@ -8,13 +8,17 @@ use libp2p::{
 };
 use serde::Serialize;
 use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::models::GroupId;
+use crate::models::{GroupId, MessageId};
+use uuid::Uuid;
 
 #[derive(Debug, Error, Serialize)]
 pub enum SettingErrorKind {
     #[error("invalid path: {0}")]
     InvalidPath(PathBuf),
+    #[error("invalid value: {0}")]
+    InvalidValue(String),
 }
 #[derive(Debug, Error, Serialize)]
 pub struct SettingError {
@@ -43,6 +47,22 @@ impl Display for SettingError {
     }
 }
 
+/// The network event loop went away while a command was in flight, e.g.
+/// during shutdown.
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("network event loop is closed")]
+    Closed,
+    #[error("network event loop dropped the command: {0}")]
+    Dropped(#[from] oneshot::error::RecvError),
+}
+
+impl<T> From<mpsc::error::SendError<T>> for CommandError {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        Self::Closed
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum NetworkError {
     #[error("Listening error: {}", .0)]
@@ -61,11 +81,59 @@ pub enum NetworkError {
     SettingError(#[from] SettingError),
     #[error("Manager error: {0}")]
     ManagerError(#[from] ManagerError),
+    #[error("invalid path {}: {reason}", .path.display())]
+    InvalidPath { path: PathBuf, reason: String },
     #[error("invalid address: {0}")]
     InvalidAddress(String),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("network is paused")]
+    NetworkPaused,
+    #[error("invalid message: {0}")]
+    InvalidMessage(String),
+    #[error("invalid invite: {0}")]
+    InvalidInvite(String),
+    #[error("invalid avatar: {0}")]
+    InvalidAvatar(String),
+    #[error("can't replace the identity while connected to {0} peers")]
+    StillConnected(usize),
+    #[error(transparent)]
+    Identity(#[from] IdentityError),
+    #[error("unknown listener: {0}")]
+    UnknownListener(u64),
+    #[error("no known address for peer: {0}")]
+    NoKnownAddress(PeerId),
+    #[error("peer not allowed: {0}")]
+    PeerNotAllowed(PeerId),
+    #[error("peer is blocked: {0}")]
+    PeerBlocked(PeerId),
+    #[error("peer is unreachable: {0}")]
+    PeerUnreachable(PeerId),
+    #[error("operation cancelled: {0}")]
+    Cancelled(Uuid),
+    #[error("no provider for file: {0}")]
+    NoProvider(String),
+    #[error("file is provided locally: {0}")]
+    LocalFile(String),
+    #[error("hash mismatch for {name}: expected {expected}, got {actual}")]
+    HashMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("file is not provided locally: {0}")]
+    NotProvided(String),
+    #[error("file is already downloading: {0}")]
+    Downloading(String),
+    #[error("relay {relay} refused the reservation: {reason}")]
+    RelayRefused { relay: PeerId, reason: String },
+    #[error("file is not downloading: {0}")]
+    NotDownloading(String),
     #[error("command not found: {0}")]
     CommandNotFound(String),
     #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
@@ -84,14 +152,34 @@ impl Serialize for NetworkError {
     }
 }
 
+/// The identity keypair couldn't be loaded or saved.
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("IO error: {0}")]
+    IOError(#[from] io::Error),
+    #[error("invalid identity file: {0}")]
+    Invalid(String),
+    #[error(
+        "identity is sealed, set {} to unlock it",
+        crate::chat_app::identity::PASSPHRASE_ENV
+    )]
+    PassphraseRequired,
+    #[error("wrong passphrase for identity")]
+    WrongPassphrase,
+}
+
 #[derive(Debug, Error)]
 pub enum ManagerError {
     #[error("Group not exist {0}")]
     GroupNotExist(GroupId),
     #[error("Peer not exist {0}")]
     PeerNotExist(PeerId),
+    #[error("Message not exist {0}")]
+    MessageNotExist(MessageId),
     #[error("invalid params: {0}")]
     InvalidParams(#[from] serde_json::Error),
     #[error("invalid action: {0}")]
     InvalidAction(String),
+    #[error("not permitted: {0}")]
+    NotPermitted(String),
 }
@@ -1,35 +1,52 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
 
-use libp2p::{swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
+use libp2p::{Multiaddr, PeerId};
 
 use crate::{
     chat_app::app_command::AppCommandHandle,
-    error::NetworkError,
-    models::{GroupId, GroupInfo, Setting},
+    error::{ManagerError, NetworkError},
+    managers::group::{SearchMessages, SearchResults},
+    models::{
+        AppSnapshot, ConnectionMode, DirectMessage, FileInfo, GroupId, GroupInfo, GroupMember,
+        GroupMessage, GroupReach, GroupState, HistoryMessage, HistoryOrder, Manifest, MessageId,
+        NetworkMetrics, OperationInfo, PeerConnectivity, PeerLatency, Presence, PublishReceipt,
+        QuietHours, Role, Setting, Theme, TopicPeers, UserInfo,
+    },
     network::message::Message,
 };
+use uuid::Uuid;
 
 #[tauri::command]
 pub async fn get_listeners(
     handle: tauri::State<'_, AppCommandHandle>,
 ) -> Result<HashMap<u64, Vec<Multiaddr>>, String> {
-    let listeners = handle
-        .get_listeners()
-        .await
-        .into_iter()
-        .map(|(k, v)| (unsafe { std::mem::transmute::<ListenerId, u64>(k) }, v))
-        .collect();
-    Ok(listeners)
+    Ok(handle.numbered_listeners().await)
+}
+#[tauri::command]
+pub async fn listen_on_relay(
+    handle: tauri::State<'_, AppCommandHandle>,
+    relay_addr: Multiaddr,
+) -> Result<u64, NetworkError> {
+    let listener_id = handle.listen_on_relay(relay_addr).await?;
+    Ok(handle.listener_number(listener_id).await)
+}
+#[tauri::command]
+pub async fn external_addrs(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Vec<Multiaddr>, NetworkError> {
+    handle.external_addrs().await
 }
 #[tauri::command]
 pub async fn start_listen(
     handle: tauri::State<'_, AppCommandHandle>,
     listen_addr: Option<Multiaddr>,
 ) -> Result<u64, NetworkError> {
-    handle.start_listen(listen_addr).await.map_or_else(
-        |e| Err(e),
-        |id| Ok(unsafe { std::mem::transmute::<ListenerId, u64>(id) }),
-    )
+    let listener_id = handle.start_listen(listen_addr).await?;
+    Ok(handle.listener_number(listener_id).await)
 }
 
 #[tauri::command]
@@ -37,28 +54,264 @@ pub async fn stop_listen(
     handle: tauri::State<'_, AppCommandHandle>,
     listener_id: Option<u64>,
 ) -> Result<(), NetworkError> {
-    handle
-        .stop_listen(listener_id.map(|id| unsafe { std::mem::transmute::<u64, ListenerId>(id) }))
-        .await
+    let listener_id = match listener_id {
+        Some(number) => Some(handle.listener(number).await?),
+        None => None,
+    };
+    handle.stop_listen(listener_id).await
+}
+#[tauri::command]
+pub async fn start_provide(
+    handle: tauri::State<'_, AppCommandHandle>,
+    path: PathBuf,
+) -> Result<FileInfo, NetworkError> {
+    handle.start_provide(path).await
+}
+#[tauri::command]
+pub async fn get_file(
+    handle: tauri::State<'_, AppCommandHandle>,
+    file: FileInfo,
+    providers: Option<Vec<PeerId>>,
+) -> Result<(), NetworkError> {
+    handle.get(file, &providers.unwrap_or_default()).await
+}
+#[tauri::command]
+pub async fn stop_provide(
+    handle: tauri::State<'_, AppCommandHandle>,
+    file: FileInfo,
+) -> Result<(), NetworkError> {
+    handle.unprovide(file).await
+}
+#[tauri::command]
+pub async fn list_provide(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Vec<FileInfo>, String> {
+    Ok(handle.provided_files().await)
+}
+#[tauri::command]
+pub async fn deduplicate_storage(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<u64, NetworkError> {
+    handle.deduplicate_storage().await
+}
+#[tauri::command]
+pub async fn provide(
+    handle: tauri::State<'_, AppCommandHandle>,
+    file: FileInfo,
+) -> Result<(), NetworkError> {
+    handle.provide(file).await
+}
+#[tauri::command]
+pub async fn find_providers(
+    handle: tauri::State<'_, AppCommandHandle>,
+    hash: String,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    handle.find_providers(hash).await
+}
+#[tauri::command]
+pub async fn start_provide_dir(
+    handle: tauri::State<'_, AppCommandHandle>,
+    path: PathBuf,
+) -> Result<Manifest, NetworkError> {
+    handle.start_provide_dir(path).await
+}
+#[tauri::command]
+pub async fn get_dir(
+    handle: tauri::State<'_, AppCommandHandle>,
+    hash: String,
+) -> Result<Manifest, NetworkError> {
+    handle.get_dir(hash).await
+}
+#[tauri::command]
+pub async fn cancel_get(
+    handle: tauri::State<'_, AppCommandHandle>,
+    file: FileInfo,
+) -> Result<(), NetworkError> {
+    handle.cancel_get(file).await
 }
 #[tauri::command]
 pub async fn setting(handle: tauri::State<'_, AppCommandHandle>) -> Result<Setting, String> {
     Ok(handle.setting().await)
 }
 #[tauri::command]
+pub async fn effective_setting(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Setting, String> {
+    Ok(handle.effective_setting().await)
+}
+#[tauri::command]
+pub async fn persistence_status(handle: tauri::State<'_, AppCommandHandle>) -> Result<(), String> {
+    handle.persistence_status().await
+}
+#[tauri::command]
+pub async fn set_connection_mode(
+    handle: tauri::State<'_, AppCommandHandle>,
+    mode: ConnectionMode,
+    allowed: Option<HashSet<PeerId>>,
+) -> Result<(), NetworkError> {
+    handle.set_connection_mode(mode, allowed).await
+}
+#[tauri::command]
 pub async fn dail(
     handle: tauri::State<'_, AppCommandHandle>,
     addr: Multiaddr,
+    timeout_ms: Option<u64>,
+) -> Result<PeerId, NetworkError> {
+    match timeout_ms {
+        Some(ms) => {
+            handle
+                .with_timeout(Duration::from_millis(ms))
+                .dial(addr)
+                .await
+        }
+        None => handle.dial(addr).await,
+    }
+}
+#[tauri::command]
+pub async fn dial_many(
+    handle: tauri::State<'_, AppCommandHandle>,
+    addrs: Vec<Multiaddr>,
+) -> Result<PeerId, NetworkError> {
+    handle.dial_many(addrs).await
+}
+#[tauri::command]
+pub async fn block_peer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    handle.block_peer(peer_id).await
+}
+#[tauri::command]
+pub async fn unblock_peer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    handle.unblock_peer(peer_id).await
+}
+#[tauri::command]
+pub async fn blocked_peers(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<HashSet<PeerId>, String> {
+    Ok(handle.blocked_peers().await)
+}
+#[tauri::command]
+pub async fn disconnect(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<(), NetworkError> {
+    handle.disconnect(peer_id).await
+}
+#[tauri::command]
+pub async fn update_group_info(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    group_info: GroupInfo,
+) -> Result<(), NetworkError> {
+    handle.update_group_info(group_id, group_info).await
+}
+#[tauri::command]
+pub async fn set_network_paused(
+    handle: tauri::State<'_, AppCommandHandle>,
+    paused: bool,
 ) -> Result<(), NetworkError> {
-    handle.dial(addr).await
+    handle.set_network_paused(paused).await
+}
+#[tauri::command]
+pub fn is_network_paused(handle: tauri::State<'_, AppCommandHandle>) -> Result<bool, String> {
+    Ok(handle.is_network_paused())
+}
+#[tauri::command]
+pub async fn verify_peers(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peers: Vec<PeerId>,
+) -> Result<PeerConnectivity, NetworkError> {
+    handle.verify_peers(peers).await
 }
 #[tauri::command]
 pub async fn publish_message(
     handle: tauri::State<'_, AppCommandHandle>,
     group_id: GroupId,
     message: Message,
+    reply_to: Option<MessageId>,
+) -> Result<PublishReceipt, NetworkError> {
+    handle.publish_message(group_id, message, reply_to).await
+}
+#[tauri::command]
+pub async fn set_typing(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    is_typing: bool,
+) -> Result<(), NetworkError> {
+    handle.set_typing(group_id, is_typing).await
+}
+#[tauri::command]
+pub async fn mark_read(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    message_id: MessageId,
 ) -> Result<(), NetworkError> {
-    handle.publish_message(group_id, message).await
+    handle.mark_read(group_id, message_id).await
+}
+#[tauri::command]
+pub async fn send_dm(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+    content: String,
+) -> Result<DirectMessage, NetworkError> {
+    handle.send_dm(peer_id, content).await
+}
+#[tauri::command]
+pub async fn discard_failed(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<usize, NetworkError> {
+    handle.discard_failed(group_id).await
+}
+#[tauri::command]
+pub async fn discard_failed_dms(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<usize, NetworkError> {
+    Ok(handle.discard_failed_dms(peer_id).await)
+}
+#[tauri::command]
+pub async fn accept_dm(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<Vec<DirectMessage>, NetworkError> {
+    Ok(handle.accept_dm(peer_id).await)
+}
+#[tauri::command]
+pub async fn reject_dm(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<(), NetworkError> {
+    handle.reject_dm(peer_id).await
+}
+#[tauri::command]
+pub async fn pin_message(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    target: MessageId,
+    pinned: bool,
+) -> Result<(), NetworkError> {
+    handle.pin_message(group_id, target, pinned).await
+}
+#[tauri::command]
+pub async fn set_role(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    peer_id: PeerId,
+    role: Role,
+) -> Result<(), NetworkError> {
+    handle.set_role(group_id, peer_id, role).await
+}
+#[tauri::command]
+pub async fn pinned_messages(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<Vec<MessageId>, NetworkError> {
+    handle.pinned_messages(group_id).await
 }
 #[tauri::command]
 pub async fn new_group(
@@ -81,6 +334,321 @@ pub async fn unsubscribe(
 ) -> Result<(), NetworkError> {
     handle.unsubscribe(group_id).await
 }
+#[tauri::command]
+pub async fn set_group_tags(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    tags: Vec<String>,
+) -> Result<Vec<String>, NetworkError> {
+    handle.set_group_tags(group_id, tags).await
+}
+#[tauri::command]
+pub async fn groups_by_tag(
+    handle: tauri::State<'_, AppCommandHandle>,
+    tag: String,
+) -> Result<Vec<GroupId>, NetworkError> {
+    Ok(handle.groups_by_tag(tag).await)
+}
+#[tauri::command]
+pub async fn set_quiet_hours(
+    handle: tauri::State<'_, AppCommandHandle>,
+    quiet_hours: Option<QuietHours>,
+) -> Result<(), NetworkError> {
+    handle.set_quiet_hours(quiet_hours).await
+}
+#[tauri::command]
+pub async fn update_setting(
+    handle: tauri::State<'_, AppCommandHandle>,
+    patch: serde_json::Value,
+) -> Result<Setting, NetworkError> {
+    handle.update_setting(patch).await
+}
+#[tauri::command]
+pub async fn update_user_info(
+    handle: tauri::State<'_, AppCommandHandle>,
+    user_info: UserInfo,
+) -> Result<(), NetworkError> {
+    handle.update_user_info(user_info).await
+}
+#[tauri::command]
+pub async fn set_theme(
+    handle: tauri::State<'_, AppCommandHandle>,
+    theme: Theme,
+) -> Result<(), NetworkError> {
+    handle.set_theme(theme).await
+}
+#[tauri::command]
+pub async fn set_topic_filter(
+    handle: tauri::State<'_, AppCommandHandle>,
+    allowed_groups: Option<HashSet<GroupId>>,
+) -> Result<(), NetworkError> {
+    handle.set_topic_filter(allowed_groups).await
+}
+#[tauri::command]
+pub async fn presence(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<Presence, String> {
+    Ok(handle.presence(peer_id).await)
+}
+#[tauri::command]
+pub async fn peer_latency(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<Option<PeerLatency>, String> {
+    Ok(handle.peer_latency(peer_id).await)
+}
+#[tauri::command]
+pub async fn set_away_after(
+    handle: tauri::State<'_, AppCommandHandle>,
+    after_secs: u64,
+) -> Result<(), NetworkError> {
+    handle.set_away_after(after_secs).await
+}
+#[tauri::command]
+pub async fn shutdown(handle: tauri::State<'_, AppCommandHandle>) -> Result<(), NetworkError> {
+    handle.shutdown().await
+}
+#[tauri::command]
+pub async fn set_auto_leave(
+    handle: tauri::State<'_, AppCommandHandle>,
+    after_secs: Option<u64>,
+) -> Result<(), NetworkError> {
+    handle.set_auto_leave(after_secs).await
+}
+#[tauri::command]
+pub async fn set_group_order(
+    handle: tauri::State<'_, AppCommandHandle>,
+    order: Vec<GroupId>,
+) -> Result<(), NetworkError> {
+    handle.set_group_order(order).await
+}
+#[tauri::command]
+pub async fn ordered_groups(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Vec<(GroupId, GroupInfo)>, String> {
+    Ok(handle.ordered_groups().await)
+}
+#[tauri::command]
+pub async fn set_group_ttl(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    ttl: Option<u64>,
+) -> Result<(), NetworkError> {
+    handle.set_group_ttl(group_id, ttl).await
+}
+#[tauri::command]
+pub async fn group_reach(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<GroupReach, NetworkError> {
+    handle.group_reach(group_id).await
+}
+#[tauri::command]
+pub async fn group_invite(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<String, NetworkError> {
+    handle.group_invite(group_id).await
+}
+#[tauri::command]
+pub async fn join_by_invite(
+    handle: tauri::State<'_, AppCommandHandle>,
+    code: String,
+) -> Result<GroupId, NetworkError> {
+    handle.join_by_invite(code).await
+}
+#[tauri::command]
+pub async fn topic_peers(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<TopicPeers, NetworkError> {
+    handle.topic_peers(group_id).await
+}
+#[tauri::command]
+pub async fn ping_group(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    timeout_ms: Option<u64>,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(5000));
+    handle.ping_group(group_id, timeout).await
+}
+#[tauri::command]
+pub async fn refresh_roster(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    timeout_ms: Option<u64>,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(5000));
+    handle.refresh_roster(group_id, timeout).await
+}
+#[tauri::command]
+pub async fn group_members(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<Vec<GroupMember>, NetworkError> {
+    handle.group_members(group_id).await
+}
+#[tauri::command]
+pub async fn group_state(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<GroupState, NetworkError> {
+    handle
+        .group_state(&group_id)
+        .await
+        .ok_or_else(|| ManagerError::GroupNotExist(group_id).into())
+}
+#[tauri::command]
+pub async fn set_history_order(
+    handle: tauri::State<'_, AppCommandHandle>,
+    order: HistoryOrder,
+) -> Result<(), NetworkError> {
+    handle.set_history_order(order).await
+}
+#[tauri::command]
+pub async fn group_history(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    before: Option<MessageId>,
+    limit: usize,
+) -> Result<Vec<HistoryMessage>, NetworkError> {
+    handle.group_history(group_id, before, limit).await
+}
+#[tauri::command]
+pub async fn sequence_gaps(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<HashMap<PeerId, Vec<u64>>, NetworkError> {
+    handle.sequence_gaps(group_id).await
+}
+#[tauri::command]
+pub async fn resync_messages(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    author: PeerId,
+    from_seq: u64,
+) -> Result<Vec<GroupMessage>, NetworkError> {
+    handle.resync_messages(group_id, author, from_seq).await
+}
+#[tauri::command]
+pub async fn search_messages(
+    handle: tauri::State<'_, AppCommandHandle>,
+    search: SearchMessages,
+) -> Result<SearchResults, NetworkError> {
+    Ok(handle.search_messages(search).await)
+}
+#[tauri::command]
+pub async fn thread(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    root: MessageId,
+) -> Result<Vec<GroupMessage>, NetworkError> {
+    handle.thread(group_id, root).await
+}
+#[tauri::command]
+pub async fn reactions(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    message_id: MessageId,
+) -> Result<HashMap<String, Vec<PeerId>>, NetworkError> {
+    handle.reactions(group_id, message_id).await
+}
+#[tauri::command]
+pub async fn rotate_group_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    handle.rotate_group_key(group_id).await
+}
+#[tauri::command]
+pub async fn share_group_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    peer_id: PeerId,
+) -> Result<(), NetworkError> {
+    handle.share_group_key(group_id, peer_id).await
+}
+#[tauri::command]
+pub async fn set_message_acks(
+    handle: tauri::State<'_, AppCommandHandle>,
+    enabled: bool,
+) -> Result<(), NetworkError> {
+    handle.set_message_acks(enabled).await
+}
+#[tauri::command]
+pub async fn set_message_batch_window(
+    handle: tauri::State<'_, AppCommandHandle>,
+    window_ms: u64,
+) -> Result<(), NetworkError> {
+    handle.set_message_batch_window(window_ms).await
+}
+#[tauri::command]
+pub async fn metrics(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<NetworkMetrics, NetworkError> {
+    handle.metrics().await
+}
+#[tauri::command]
+pub async fn set_metrics_interval(
+    handle: tauri::State<'_, AppCommandHandle>,
+    interval_secs: u64,
+) -> Result<(), NetworkError> {
+    handle.set_metrics_interval(interval_secs).await
+}
+#[tauri::command]
+pub async fn message_acks(
+    handle: tauri::State<'_, AppCommandHandle>,
+    message_id: MessageId,
+) -> Result<HashSet<PeerId>, NetworkError> {
+    Ok(handle.message_acks(message_id).await)
+}
+#[tauri::command]
+pub async fn export_thread(
+    handle: tauri::State<'_, AppCommandHandle>,
+    group_id: GroupId,
+    root: MessageId,
+    path: PathBuf,
+) -> Result<(), NetworkError> {
+    handle.export_thread(group_id, root, path).await
+}
+/// Round trips in milliseconds like `peer_latency`'s, `null` for peers
+/// without one.
+#[tauri::command]
+pub async fn ping_all(
+    handle: tauri::State<'_, AppCommandHandle>,
+    timeout_ms: Option<u64>,
+) -> Result<HashMap<PeerId, Option<f64>>, NetworkError> {
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(5000));
+    Ok(handle
+        .ping_all(timeout)
+        .await?
+        .into_iter()
+        .map(|(peer_id, rtt)| (peer_id, rtt.map(|rtt| rtt.as_secs_f64() * 1000.0)))
+        .collect())
+}
+#[tauri::command]
+pub async fn snapshot(handle: tauri::State<'_, AppCommandHandle>) -> Result<AppSnapshot, String> {
+    Ok(handle.snapshot().await)
+}
+#[tauri::command]
+pub async fn resync(handle: tauri::State<'_, AppCommandHandle>) -> Result<(), NetworkError> {
+    handle.resync().await
+}
+#[tauri::command]
+pub async fn list_operations(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Vec<OperationInfo>, String> {
+    Ok(handle.list_operations().await)
+}
+#[tauri::command]
+pub async fn cancel_operation(
+    handle: tauri::State<'_, AppCommandHandle>,
+    id: Uuid,
+) -> Result<bool, String> {
+    Ok(handle.cancel_operation(id).await)
+}
 
 #[tauri::command]
 pub async fn invoke_manager(
@@ -105,3 +673,25 @@ pub fn get_local_peer_id(
 ) -> Result<PeerId, NetworkError> {
     Ok(handle.get_local_peer_id())
 }
+#[tauri::command]
+pub async fn export_identity(
+    handle: tauri::State<'_, AppCommandHandle>,
+    passphrase: String,
+) -> Result<String, NetworkError> {
+    handle.export_identity(passphrase).await
+}
+#[tauri::command]
+pub async fn import_identity(
+    handle: tauri::State<'_, AppCommandHandle>,
+    blob: String,
+    passphrase: String,
+) -> Result<PeerId, NetworkError> {
+    handle.import_identity(blob, passphrase).await
+}
+#[tauri::command]
+pub fn peer_fingerprint(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<String, NetworkError> {
+    Ok(handle.peer_fingerprint(&peer_id))
+}
@@ -11,10 +11,10 @@ mod network;
 mod store;
 
 use anyhow::Context;
-use tauri::{generate_handler, Manager};
+use tauri::{generate_handler, Manager, RunEvent};
 use tokio::{join, task::LocalSet};
 
-use chat_app::ChatApp;
+use chat_app::{app_command::AppCommandHandle, ChatApp};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -45,27 +45,120 @@ async fn main() -> anyhow::Result<()> {
         })
         .invoke_handler(generate_handler![
             handlers::get_listeners,
+            handlers::external_addrs,
+            handlers::listen_on_relay,
             handlers::start_listen,
             handlers::stop_listen,
             handlers::setting,
             handlers::dail,
+            handlers::dial_many,
+            handlers::disconnect,
+            handlers::block_peer,
+            handlers::unblock_peer,
+            handlers::blocked_peers,
             handlers::publish_message,
+            handlers::set_typing,
+            handlers::mark_read,
+            handlers::send_dm,
+            handlers::discard_failed,
+            handlers::discard_failed_dms,
+            handlers::accept_dm,
+            handlers::reject_dm,
+            handlers::pin_message,
+            handlers::pinned_messages,
+            handlers::set_role,
             handlers::new_group,
             handlers::subscribe,
             handlers::unsubscribe,
+            handlers::group_reach,
+            handlers::topic_peers,
+            handlers::group_invite,
+            handlers::join_by_invite,
+            handlers::set_group_tags,
+            handlers::groups_by_tag,
+            handlers::set_quiet_hours,
+            handlers::ping_group,
+            handlers::snapshot,
+            handlers::resync,
+            handlers::list_operations,
+            handlers::cancel_operation,
+            handlers::verify_peers,
+            handlers::update_group_info,
+            handlers::set_group_ttl,
+            handlers::refresh_roster,
+            handlers::group_members,
+            handlers::set_group_order,
+            handlers::ordered_groups,
+            handlers::peer_fingerprint,
+            handlers::update_setting,
+            handlers::update_user_info,
+            handlers::set_theme,
+            handlers::effective_setting,
+            handlers::persistence_status,
+            handlers::set_network_paused,
+            handlers::is_network_paused,
+            handlers::set_topic_filter,
+            handlers::presence,
+            handlers::peer_latency,
+            handlers::set_away_after,
+            handlers::shutdown,
+            handlers::set_auto_leave,
+            handlers::ping_all,
+            handlers::group_state,
+            handlers::set_history_order,
+            handlers::rotate_group_key,
+            handlers::share_group_key,
+            handlers::set_message_acks,
+            handlers::set_message_batch_window,
+            handlers::metrics,
+            handlers::set_metrics_interval,
+            handlers::message_acks,
+            handlers::group_history,
+            handlers::thread,
+            handlers::reactions,
+            handlers::export_thread,
+            handlers::search_messages,
+            handlers::sequence_gaps,
+            handlers::resync_messages,
+            handlers::start_provide,
+            handlers::get_file,
+            handlers::cancel_get,
+            handlers::provide,
+            handlers::find_providers,
+            handlers::list_provide,
+            handlers::stop_provide,
+            handlers::deduplicate_storage,
+            handlers::start_provide_dir,
+            handlers::get_dir,
             handlers::invoke_manager,
             handlers::get_managers,
             handlers::get_local_peer_id,
+            handlers::export_identity,
+            handlers::import_identity,
+            handlers::set_connection_mode,
         ])
         .build(tauri::generate_context!())?;
 
     let mut chat_app = ChatApp::new(tauri_app.handle());
-    chat_app.initialize()?;
+    chat_app.initialize().await?;
     tauri_app.manage(chat_app.command_handle()?);
 
     local.spawn_local(async {
-        tauri_app.run(|_app_handle, event| match event {
-            _ => {}
+        tauri_app.run(|app_handle, event| {
+            // Hold the exit until the backend has shut down, then exit again.
+            if let RunEvent::ExitRequested { api, .. } = event {
+                let handle = app_handle.state::<AppCommandHandle>().inner().clone();
+                if !handle.is_shutting_down() {
+                    api.prevent_exit();
+                    let app_handle = app_handle.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle.shutdown().await {
+                            log::error!("failed to shut down cleanly: {e}");
+                        }
+                        app_handle.exit(0);
+                    });
+                }
+            }
         })
     });
 
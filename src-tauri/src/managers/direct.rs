@@ -0,0 +1,459 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError},
+    models::{DirectMessage, DirectMessagePolicy, MessageStatus},
+    network::{
+        message::{InboundEvent, Request, Response},
+        Client,
+    },
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use libp2p::{gossipsub::TopicHash, PeerId};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, Mutex};
+
+/// What became of a direct message that reached us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Received {
+    Delivered,
+    /// Kept as a request under `DirectMessagePolicy::Hold`.
+    Held,
+    Rejected,
+    /// Already delivered or held, e.g. resent after a lost answer.
+    Duplicate,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectMessageManager {
+    /// Conversations keyed by the other peer, oldest message first.
+    threads: Arc<Mutex<HashMap<PeerId, Vec<DirectMessage>>>>,
+    /// Held messages from unknown peers, oldest first.
+    requests: Arc<Mutex<HashMap<PeerId, Vec<DirectMessage>>>>,
+    /// Topics of our groups each peer is subscribed to.
+    shared_topics: Arc<Mutex<HashMap<PeerId, HashSet<TopicHash>>>>,
+}
+
+impl DirectMessageManager {
+    pub fn new() -> Self {
+        Self {
+            threads: Arc::new(Mutex::new(HashMap::new())),
+            requests: Arc::new(Mutex::new(HashMap::new())),
+            shared_topics: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    /// Whether we share a group or already have a conversation with
+    /// `peer_id`.
+    async fn is_known(&self, peer_id: &PeerId) -> bool {
+        self.shared_topics.lock().await.contains_key(peer_id)
+            || self.threads.lock().await.contains_key(peer_id)
+    }
+    /// Deliver, hold or reject `message` from `peer_id` according to
+    /// `policy`.
+    async fn receive(
+        &self,
+        peer_id: PeerId,
+        message: DirectMessage,
+        policy: DirectMessagePolicy,
+    ) -> Received {
+        let policy = if self.is_known(&peer_id).await {
+            DirectMessagePolicy::Accept
+        } else {
+            policy
+        };
+        match policy {
+            DirectMessagePolicy::Accept => {
+                if self.add_message(peer_id, message).await {
+                    Received::Delivered
+                } else {
+                    Received::Duplicate
+                }
+            }
+            DirectMessagePolicy::Reject => Received::Rejected,
+            DirectMessagePolicy::Hold => {
+                let mut requests = self.requests.lock().await;
+                let held = requests.entry(peer_id).or_default();
+                if held.iter().any(|m| m.id == message.id) {
+                    return Received::Duplicate;
+                }
+                held.push(message);
+                Received::Held
+            }
+        }
+    }
+    /// Move the messages held from `peer_id` into a conversation, so later
+    /// ones are delivered. Returns the moved messages.
+    pub async fn accept_requests(&self, peer_id: PeerId) -> Vec<DirectMessage> {
+        let held = self
+            .requests
+            .lock()
+            .await
+            .remove(&peer_id)
+            .unwrap_or_default();
+        let mut threads = self.threads.lock().await;
+        let thread = threads.entry(peer_id).or_default();
+        for message in &held {
+            if !thread.iter().any(|m| m.id == message.id) {
+                thread.push(message.clone());
+            }
+        }
+        held
+    }
+    /// Drop the messages held from `peer_id`. Returns whether there were any.
+    pub async fn reject_requests(&self, peer_id: &PeerId) -> bool {
+        self.requests.lock().await.remove(peer_id).is_some()
+    }
+    pub async fn get_requests(&self) -> HashMap<PeerId, Vec<DirectMessage>> {
+        self.requests.lock().await.clone()
+    }
+    /// Record a message of the conversation with `peer_id`, whichever side
+    /// sent it. Returns `false` if a message with the same ID is already
+    /// recorded.
+    pub async fn add_message(&self, peer_id: PeerId, message: DirectMessage) -> bool {
+        let mut threads = self.threads.lock().await;
+        let thread = threads.entry(peer_id).or_default();
+        if thread.iter().any(|m| m.id == message.id) {
+            return false;
+        }
+        thread.push(message);
+        true
+    }
+    /// Mark our queued `message` delivered. Queued messages
+    /// survive restarts but conversations don't, so it is recorded anew
+    /// if it isn't found.
+    async fn mark_delivered(&self, peer_id: PeerId, mut message: DirectMessage) {
+        message.pending = false;
+        let mut threads = self.threads.lock().await;
+        let thread = threads.entry(peer_id).or_default();
+        match thread.iter_mut().find(|m| m.id == message.id) {
+            Some(sent) => sent.pending = false,
+            None => thread.push(message),
+        }
+    }
+    /// Mark our `message` given up on, recording it anew like
+    /// `mark_delivered` if it isn't found.
+    pub async fn mark_failed(&self, peer_id: PeerId, mut message: DirectMessage) {
+        message.pending = false;
+        message.failed = true;
+        let mut threads = self.threads.lock().await;
+        let thread = threads.entry(peer_id).or_default();
+        match thread.iter_mut().find(|m| m.id == message.id) {
+            Some(sent) => *sent = message,
+            None => thread.push(message),
+        }
+    }
+    /// Drop our failed messages from the conversation with `peer_id`,
+    /// returning how many there were.
+    pub async fn discard_failed(&self, peer_id: &PeerId) -> usize {
+        let mut threads = self.threads.lock().await;
+        let Some(thread) = threads.get_mut(peer_id) else {
+            return 0;
+        };
+        let before = thread.len();
+        thread.retain(|m| !m.failed);
+        before - thread.len()
+    }
+    /// Give up on the queued messages that waited too long or failed too
+    /// often, telling the frontend about each.
+    async fn fail_expired(&self, state: &AppState, sender: &mpsc::Sender<FrontendEvent>) {
+        let outbox = state.setting.lock().await.effective().outbox;
+        let expired = state
+            .expire_dm_outbox(&outbox, Utc::now().timestamp())
+            .await;
+        if let Err(e) = state.save_dm_outbox().await {
+            log::warn!("failed to save queued direct messages: {e}");
+        }
+        for (peer_id, message) in expired {
+            let message_id = message.id.to_string();
+            self.mark_failed(peer_id, message).await;
+            let _ = sender
+                .send(FrontendEvent::MessageStatus {
+                    message_id,
+                    status: MessageStatus::Failed,
+                })
+                .await;
+        }
+    }
+    /// Send the messages queued for `peer_id` in order, stopping at the
+    /// first one that doesn't get through.
+    async fn flush_outbox(
+        self,
+        peer_id: PeerId,
+        client: Client,
+        state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        loop {
+            let Some(message) = state
+                .dm_outbox
+                .lock()
+                .await
+                .get(&peer_id)
+                .and_then(|queue| queue.front().cloned())
+            else {
+                return;
+            };
+            let request = Request::DirectMessage {
+                id: message.id,
+                content: message.content.clone(),
+            };
+            match client.request(peer_id, request).await {
+                Ok(Response::Delivered) => {}
+                Ok(res) => {
+                    log::warn!("unexpected response to direct message: {res:?}");
+                    return;
+                }
+                Err(e) => {
+                    log::debug!("direct messages for {peer_id} stay queued: {e}");
+                    if let Some(queued) = state
+                        .dm_outbox
+                        .lock()
+                        .await
+                        .get_mut(&peer_id)
+                        .and_then(|queue| queue.front_mut())
+                    {
+                        queued.attempts += 1;
+                    }
+                    self.fail_expired(&state, &sender).await;
+                    return;
+                }
+            }
+            {
+                let mut dm_outbox = state.dm_outbox.lock().await;
+                if let Some(queue) = dm_outbox.get_mut(&peer_id) {
+                    queue.retain(|m| m.id != message.id);
+                    if queue.is_empty() {
+                        dm_outbox.remove(&peer_id);
+                    }
+                }
+            }
+            if let Err(e) = state.save_dm_outbox().await {
+                log::warn!("failed to save queued direct messages: {e}");
+            }
+            let message_id = message.id;
+            self.mark_delivered(peer_id, message).await;
+            let _ = sender
+                .send(FrontendEvent::MessageDelivered { message_id })
+                .await;
+        }
+    }
+    pub async fn get_thread(&self, peer_id: &PeerId) -> Vec<DirectMessage> {
+        self.threads
+            .lock()
+            .await
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    pub async fn get_threads(&self) -> HashMap<PeerId, Vec<DirectMessage>> {
+        self.threads.lock().await.clone()
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for DirectMessageManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        client: Client,
+        state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::InboundRequest {
+                peer,
+                request: Request::DirectMessage { id, content },
+                channel,
+            } => {
+                let message = DirectMessage {
+                    id,
+                    source: peer,
+                    timestamp: Utc::now().timestamp(),
+                    content,
+                    pending: false,
+                    failed: false,
+                    attempts: 0,
+                };
+                let policy = state.setting.lock().await.dm_policy;
+                let received = self.receive(peer, message.clone(), policy).await;
+                // A resent message is acknowledged again, as the sender
+                // didn't get our first answer.
+                let response = match received {
+                    Received::Rejected => Response::Error(
+                        "direct messages from unknown peers are refused".to_string(),
+                    ),
+                    _ => Response::Delivered,
+                };
+                if let Some(channel) = channel.lock().await.take() {
+                    client.response(response, channel).await?;
+                }
+                let event = match received {
+                    Received::Delivered => FrontendEvent::DirectMessage {
+                        peer_id: peer,
+                        message,
+                    },
+                    Received::Held => FrontendEvent::DirectMessageRequest {
+                        peer_id: peer,
+                        message,
+                    },
+                    Received::Rejected | Received::Duplicate => return Ok(()),
+                };
+                sender.send(event).await.unwrap();
+            }
+            InboundEvent::Subscribed { peer_id, topic } => {
+                self.shared_topics
+                    .lock()
+                    .await
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(topic);
+            }
+            InboundEvent::Unsubscribed { peer_id, topic } => {
+                let mut shared_topics = self.shared_topics.lock().await;
+                if let Some(topics) = shared_topics.get_mut(&peer_id) {
+                    topics.remove(&topic);
+                    if topics.is_empty() {
+                        shared_topics.remove(&peer_id);
+                    }
+                }
+            }
+            InboundEvent::ConnectionEstablished { peer_id, .. } => {
+                let queued = state.dm_outbox.lock().await.contains_key(&peer_id);
+                if queued {
+                    // Requests are answered through the inbound loop, so
+                    // don't wait for them here.
+                    let tasks = state.tasks.clone();
+                    tasks.spawn(self.clone().flush_outbox(peer_id, client, state, sender));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for DirectMessageManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "get_thread" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                serde_json::to_value(self.get_thread(&peer_id).await)?
+            }
+            "get_threads" => serde_json::to_value(self.get_threads().await)?,
+            "get_requests" => serde_json::to_value(self.get_requests().await)?,
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for DirectMessageManager {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn message(source: PeerId) -> DirectMessage {
+        DirectMessage {
+            id: Uuid::new_v4(),
+            source,
+            timestamp: 0,
+            content: "hi".to_string(),
+            pending: false,
+            failed: false,
+            attempts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_policy_delivers() {
+        let direct = DirectMessageManager::new();
+        let peer = PeerId::random();
+        let first = message(peer);
+        let policy = DirectMessagePolicy::Accept;
+        assert_eq!(
+            direct.receive(peer, first.clone(), policy).await,
+            Received::Delivered
+        );
+        assert_eq!(
+            direct.receive(peer, first, policy).await,
+            Received::Duplicate
+        );
+        assert_eq!(direct.get_thread(&peer).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_policy_refuses_unknown_peers() {
+        let direct = DirectMessageManager::new();
+        let peer = PeerId::random();
+        let policy = DirectMessagePolicy::Reject;
+        assert_eq!(
+            direct.receive(peer, message(peer), policy).await,
+            Received::Rejected
+        );
+        assert!(direct.get_thread(&peer).await.is_empty());
+
+        direct
+            .shared_topics
+            .lock()
+            .await
+            .entry(peer)
+            .or_default()
+            .insert(TopicHash::from_raw("group"));
+        assert_eq!(
+            direct.receive(peer, message(peer), policy).await,
+            Received::Delivered
+        );
+    }
+
+    #[tokio::test]
+    async fn hold_policy_keeps_requests_until_accepted() {
+        let direct = DirectMessageManager::new();
+        let peer = PeerId::random();
+        let first = message(peer);
+        let policy = DirectMessagePolicy::Hold;
+        assert_eq!(
+            direct.receive(peer, first.clone(), policy).await,
+            Received::Held
+        );
+        assert_eq!(
+            direct.receive(peer, first.clone(), policy).await,
+            Received::Duplicate
+        );
+        assert!(direct.get_thread(&peer).await.is_empty());
+        let accepted = direct.accept_requests(peer).await;
+        assert_eq!(
+            accepted.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![first.id]
+        );
+        assert!(direct.get_requests().await.is_empty());
+        assert_eq!(
+            direct.receive(peer, message(peer), policy).await,
+            Received::Delivered
+        );
+        assert_eq!(direct.get_thread(&peer).await.len(), 2);
+
+        let other = PeerId::random();
+        assert_eq!(
+            direct.receive(other, message(other), policy).await,
+            Received::Held
+        );
+        assert!(direct.reject_requests(&other).await);
+        assert!(direct.get_requests().await.is_empty());
+        assert!(direct.get_thread(&other).await.is_empty());
+    }
+}
@@ -1,104 +1,471 @@
-// #[derive(Debug, Clone)]
-// pub struct FileManager {
-//     files: Arc<Mutex<HashMap<PeerId, Vec<FileInfo>>>>,
-//     file_resources: Arc<Mutex<HashMap<PeerId, Vec<FileSource>>>>,
-// }
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError},
+    models::{FileInfo, FileSource, Manifest},
+    network::{
+        message::{InboundEvent, Message, Request, Response, MAX_FILE_CHUNK_SIZE},
+        Client,
+    },
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+    sync::{mpsc, Mutex},
+};
 
-// impl FileManager {
-//     pub fn new() -> Self {
-//         Self {
-//             files: Arc::new(Mutex::new(HashMap::new())),
-//             file_resources: Arc::new(Mutex::new(HashMap::new())),
-//         }
-//     }
-//     pub async fn add_local_file<P: AsRef<Path>>(
-//         &self,
-//         peer_id: PeerId,
-//         path: P,
-//     ) -> Result<FileInfo, io::Error> {
-//         let path = path.as_ref();
-//         let file_info = FileInfo::from_path(path).await?;
-//         self.files
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(file_info.clone());
-//         self.file_resources
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(FileSource::Local(path.to_path_buf()));
-//         Ok(file_info)
-//     }
-//     pub async fn add_remote_file(&self, peer_id: PeerId, file_info: FileInfo) {
-//         self.files
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(file_info);
-//         self.file_resources
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(FileSource::Remote(peer_id));
-//     }
-//     pub async fn list_provide(&self, peer_id: &PeerId) -> Option<Vec<FileInfo>> {
-//         self.files
-//             .lock()
-//             .await
-//             .get(peer_id)
-//             .map(|files| files.clone())
-//     }
-// }
-// #[derive(Debug, Clone)]
-// pub struct Manager {
-//     group: GroupManager,
-//     user: UserManager,
-//     file: FileManager,
-// }
+/// Read up to `len` bytes of the file at `path` from `offset`, and whether
+/// that reached the end of the file.
+async fn read_chunk(path: &Path, offset: u64, len: u32) -> io::Result<(Vec<u8>, bool)> {
+    let mut file = fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut data = Vec::new();
+    file.take(len.min(MAX_FILE_CHUNK_SIZE) as u64)
+        .read_to_end(&mut data)
+        .await?;
+    let eof = offset + data.len() as u64 >= size;
+    Ok((data, eof))
+}
 
-// impl Manager {
-//     pub fn new() -> Self {
-//         Self {
-//             group: GroupManager::new(),
-//             user: UserManager::new(),
-//             file: FileManager::new(),
-//         }
-//     }
-//     pub fn group(&self) -> &GroupManager {
-//         &self.group
-//     }
-//     pub fn user(&self) -> &UserManager {
-//         &self.user
-//     }
-//     pub fn file(&self) -> &FileManager {
-//         &self.file
-//     }
-//     pub async fn get_user_not_subscribe(&self, peer_id: &PeerId) -> Option<Vec<GroupId>> {
-//         let user_subscribe = self.user.get_user_subscribe(peer_id).await?;
+/// Whether `a` and `b` are the metadata of the same file on disk, e.g.
+/// two hard links to it.
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
 
-//         let groups = self
-//             .group
-//             .get_groups()
-//             .await
-//             .keys()
-//             .filter(|group_id| !user_subscribe.contains(group_id))
-//             .cloned()
-//             .collect();
-//         Some(groups)
-//     }
-//     pub async fn subscribe(&self, peer_id: PeerId, group_id: GroupId) -> Result<(), ManagerError> {
-//         if !self.group.is_group_exist(&group_id).await {
-//             return Err(ManagerError::GroupNotExist(group_id));
-//         }
-//         self.group.add_subscribe(&group_id, peer_id).await;
-//         self.user.add_subscribe(peer_id.clone(), group_id).await
-//     }
-//     pub async fn unsubscribe(&self, peer_id: &PeerId, group_id: &GroupId) -> bool {
-//         self.user.remove_subscribe(peer_id, group_id).await
-//     }
-// }
+#[cfg(not(unix))]
+fn same_file(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Put a hard link to `src` at `dest`, replacing whatever is there only
+/// once the link exists.
+async fn link(src: &Path, dest: &Path) -> io::Result<()> {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dest.with_file_name(format!(".{name}.{:08x}.link", rand::random::<u32>()));
+    fs::hard_link(src, &tmp).await?;
+    if let Err(e) = fs::rename(&tmp, dest).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct FileManager {
+    /// Every known file and where it can be fetched from.
+    provide_list: Arc<Mutex<HashMap<FileInfo, Vec<FileSource>>>>,
+    /// Directories we share, by manifest hash.
+    manifests: Arc<Mutex<HashMap<String, Manifest>>>,
+    /// Downloaded files by content hash, so the same content under another
+    /// name can share their storage.
+    stored: Arc<Mutex<HashMap<String, PathBuf>>>,
+}
+
+impl FileManager {
+    pub fn new() -> Self {
+        Self {
+            provide_list: Arc::new(Mutex::new(HashMap::new())),
+            manifests: Arc::new(Mutex::new(HashMap::new())),
+            stored: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    fn providers_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join("providers.json")
+    }
+    /// Load the files we provided before a restart. Remote sources are
+    /// never persisted since they have likely gone stale, and files that
+    /// were moved or deleted since are dropped.
+    pub async fn load_providers<P: AsRef<Path>>(&self, dir: P) -> Result<(), io::Error> {
+        let buf = match fs::read(Self::providers_path(dir)).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let providers: Vec<(PathBuf, FileInfo)> = serde_json::from_slice(&buf)?;
+        let mut provide_list = self.provide_list.lock().await;
+        for (path, file_info) in providers {
+            if fs::metadata(&path).await.is_err() {
+                log::warn!("no longer providing {}: {path:?} is gone", file_info.name);
+                continue;
+            }
+            provide_list
+                .entry(file_info)
+                .or_default()
+                .push(FileSource::Local(path));
+        }
+        Ok(())
+    }
+    pub async fn save_providers<P: AsRef<Path>>(&self, dir: P) -> Result<(), io::Error> {
+        let providers = self
+            .provide_list
+            .lock()
+            .await
+            .iter()
+            .flat_map(|(file_info, sources)| {
+                sources.iter().filter_map(move |source| match source {
+                    FileSource::Local(path) => Some((path.clone(), file_info.clone())),
+                    FileSource::Remote(_) => None,
+                })
+            })
+            .collect::<Vec<_>>();
+        let buf = serde_json::to_vec(&providers)?;
+        fs::create_dir_all(&dir).await?;
+        fs::write(Self::providers_path(dir), buf).await
+    }
+    pub async fn add_local_file<P: AsRef<Path>>(&self, path: P) -> io::Result<FileInfo> {
+        let path = path.as_ref();
+        let file_info = FileInfo::from_path(path).await?;
+        let mut provide_list = self.provide_list.lock().await;
+        let sources = provide_list.entry(file_info.clone()).or_default();
+        sources.retain(FileSource::is_remote);
+        sources.push(FileSource::Local(path.to_path_buf()));
+        Ok(file_info)
+    }
+    /// Share every file under the directory at `path`. Symlinks are
+    /// skipped, since they could point outside of it.
+    pub async fn add_local_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<Manifest> {
+        let root = path.as_ref();
+        let root_name = root
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid directory name"))?
+            .to_string_lossy()
+            .to_string();
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        let mut dirs = vec![(root.to_path_buf(), root_name.clone())];
+        while let Some((dir, name)) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            let mut empty = true;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let entry_name = format!("{name}/{}", entry.file_name().to_string_lossy());
+                if file_type.is_dir() {
+                    dirs.push((entry.path(), entry_name));
+                    empty = false;
+                } else if file_type.is_file() {
+                    let mut file_info = FileInfo::from_path(entry.path()).await?;
+                    file_info.name = entry_name;
+                    files.push((entry.path(), file_info));
+                    empty = false;
+                }
+            }
+            if empty {
+                empty_dirs.push(name);
+            }
+        }
+        let manifest = Manifest::new(
+            root_name,
+            files
+                .iter()
+                .map(|(_, file_info)| file_info.clone())
+                .collect(),
+            empty_dirs,
+        );
+        let mut provide_list = self.provide_list.lock().await;
+        for (path, file_info) in files {
+            let sources = provide_list.entry(file_info).or_default();
+            sources.retain(FileSource::is_remote);
+            sources.push(FileSource::Local(path));
+        }
+        self.manifests
+            .lock()
+            .await
+            .insert(manifest.hash.clone(), manifest.clone());
+        Ok(manifest)
+    }
+    /// Stop serving `file` ourselves, returning the info it was provided
+    /// with, or `None` if we didn't provide it.
+    pub async fn remove_local_file(&self, file: &FileInfo) -> Option<FileInfo> {
+        let mut provide_list = self.provide_list.lock().await;
+        let (file_info, sources) = provide_list.remove_entry(file)?;
+        let remote = sources
+            .iter()
+            .filter(|source| source.is_remote())
+            .cloned()
+            .collect::<Vec<_>>();
+        let provided = remote.len() != sources.len();
+        if !remote.is_empty() {
+            provide_list.insert(file_info.clone(), remote);
+        }
+        provided.then(|| file_info)
+    }
+    pub async fn add_remote_file(&self, peer_id: PeerId, file_info: FileInfo) {
+        let mut provide_list = self.provide_list.lock().await;
+        let sources = provide_list.entry(file_info).or_default();
+        if !sources
+            .iter()
+            .any(|source| matches!(source, FileSource::Remote(p) if *p == peer_id))
+        {
+            sources.push(FileSource::Remote(peer_id));
+        }
+    }
+    /// Snapshot of the files we provide ourselves.
+    pub async fn list_provide(&self) -> Vec<FileInfo> {
+        self.provide_list
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, sources)| sources.iter().any(FileSource::is_local))
+            .map(|(file_info, _)| file_info.clone())
+            .collect()
+    }
+    /// Remember that the file at `path` has content `hash`.
+    pub async fn add_stored(&self, hash: String, path: PathBuf) {
+        self.stored.lock().await.insert(hash, path);
+    }
+    /// A file on disk with content `hash`, downloaded or provided under
+    /// any name.
+    async fn find_copy(&self, hash: &str) -> Option<PathBuf> {
+        let stored = self.stored.lock().await.get(hash).cloned();
+        let provided = self
+            .provide_list
+            .lock()
+            .await
+            .iter()
+            .filter(|(file_info, _)| file_info.hash.as_deref() == Some(hash))
+            .flat_map(|(_, sources)| sources.iter())
+            .filter_map(|source| match source {
+                FileSource::Local(path) => Some(path.clone()),
+                FileSource::Remote(_) => None,
+            })
+            .collect::<Vec<_>>();
+        for path in stored.into_iter().chain(provided) {
+            // It may have been moved or deleted since.
+            if fs::metadata(&path).await.is_ok() {
+                return Some(path);
+            }
+        }
+        None
+    }
+    /// Store the content `hash` at `dest` without downloading it, if we
+    /// already hold it. The two share storage where the filesystem allows
+    /// hard links, and are copies otherwise. Returns whether it was held.
+    pub async fn restore(&self, hash: &str, dest: &Path) -> io::Result<bool> {
+        let Some(src) = self.find_copy(hash).await else {
+            return Ok(false);
+        };
+        if src != dest {
+            if let Err(e) = link(&src, dest).await {
+                log::debug!("failed to link {src:?} to {dest:?}, copying it: {e}");
+                fs::copy(&src, dest).await?;
+            }
+        }
+        self.add_stored(hash.to_string(), dest.to_path_buf()).await;
+        Ok(true)
+    }
+    /// Replace the files under `dir` whose content another file there
+    /// already has with hard links to that file. Hidden files, which
+    /// include partial downloads, are left alone. Returns the bytes
+    /// reclaimed.
+    pub async fn deduplicate(&self, dir: &Path) -> io::Result<u64> {
+        let mut reclaimed = 0;
+        let mut originals: HashMap<String, (PathBuf, std::fs::Metadata)> = HashMap::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let path = entry.path();
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                if !file_type.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let hash = FileInfo::hash_file(&path).await?;
+                let metadata = entry.metadata().await?;
+                match originals.get(&hash) {
+                    None => {
+                        originals.insert(hash, (path, metadata));
+                    }
+                    Some((_, original)) if same_file(original, &metadata) => {}
+                    Some((original, _)) => match link(original, &path).await {
+                        Ok(()) => reclaimed += metadata.len(),
+                        Err(e) => log::warn!("failed to deduplicate {path:?}: {e}"),
+                    },
+                }
+            }
+        }
+        let mut stored = self.stored.lock().await;
+        for (hash, (path, _)) in originals {
+            stored.insert(hash, path);
+        }
+        Ok(reclaimed)
+    }
+    pub async fn get_sources(&self, file: &FileInfo) -> Option<Vec<FileSource>> {
+        self.provide_list.lock().await.get(file).cloned()
+    }
+    pub async fn get_local_path(&self, file: &FileInfo) -> Option<PathBuf> {
+        self.provide_list
+            .lock()
+            .await
+            .get(file)?
+            .iter()
+            .find_map(|source| match source {
+                FileSource::Local(path) => Some(path.clone()),
+                FileSource::Remote(_) => None,
+            })
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for FileManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        client: Client,
+        _state: AppState,
+        _sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::InboundRequest {
+                request: Request::File(file),
+                channel,
+                ..
+            } => {
+                // Files we only know a remote source for are left to the
+                // default handler, which answers with an error.
+                if let Some(path) = self.get_local_path(&file).await {
+                    if let Some(channel) = channel.lock().await.take() {
+                        let response = match fs::read(&path).await {
+                            Ok(data) => Response::File(data),
+                            Err(e) => Response::Error(format!("failed to read {}: {e}", file.name)),
+                        };
+                        client.response(response, channel).await?;
+                    }
+                }
+            }
+            InboundEvent::InboundRequest {
+                request: Request::FileChunk { file, offset, len },
+                channel,
+                ..
+            } => {
+                if let Some(path) = self.get_local_path(&file).await {
+                    if let Some(channel) = channel.lock().await.take() {
+                        let response = match read_chunk(&path, offset, len).await {
+                            Ok((data, eof)) => Response::FileChunk { data, eof },
+                            Err(e) => Response::Error(format!("failed to read {}: {e}", file.name)),
+                        };
+                        client.response(response, channel).await?;
+                    }
+                }
+            }
+            InboundEvent::InboundRequest {
+                request: Request::Manifest(hash),
+                channel,
+                ..
+            } => {
+                if let Some(manifest) = self.manifests.lock().await.get(&hash).cloned() {
+                    if let Some(channel) = channel.lock().await.take() {
+                        client
+                            .response(Response::Manifest(manifest), channel)
+                            .await?;
+                    }
+                }
+            }
+            InboundEvent::Message { message, .. } => {
+                if let Message::File(file) = message.message {
+                    if message.source != client.local_peer_id() {
+                        self.add_remote_file(message.source, file).await;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for FileManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "get_sources" if params.is_some() => {
+                let file = serde_json::from_value::<FileInfo>(params.unwrap())?;
+                serde_json::to_value(self.get_sources(&file).await.unwrap_or_default())?
+            }
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for FileManager {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunks_stop_at_the_end_of_the_file() {
+        let path = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        fs::write(&path, b"0123456789").await.unwrap();
+        assert_eq!(
+            read_chunk(&path, 0, 4).await.unwrap(),
+            (b"0123".to_vec(), false)
+        );
+        assert_eq!(
+            read_chunk(&path, 4, 6).await.unwrap(),
+            (b"456789".to_vec(), true)
+        );
+        assert_eq!(
+            read_chunk(&path, 8, 100).await.unwrap(),
+            (b"89".to_vec(), true)
+        );
+        assert_eq!(read_chunk(&path, 20, 4).await.unwrap(), (Vec::new(), true));
+        fs::remove_file(&path).await.unwrap();
+        assert!(read_chunk(&path, 0, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn two_names_for_one_hash_share_storage() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("sub")).await.unwrap();
+        let [first, second, other] = [dir.join("a.txt"), dir.join("sub/b.txt"), dir.join("c.txt")];
+        fs::write(&first, b"same content").await.unwrap();
+        fs::write(&second, b"same content").await.unwrap();
+        fs::write(&other, b"other content").await.unwrap();
+        let file = FileManager::new();
+        assert_eq!(file.deduplicate(&dir).await.unwrap(), 12);
+        // Already shared, nothing left to reclaim.
+        #[cfg(unix)]
+        assert_eq!(file.deduplicate(&dir).await.unwrap(), 0);
+
+        let hash = FileInfo::hash_file(&first).await.unwrap();
+        let third = dir.join("d.txt");
+        assert!(file.restore(&hash, &third).await.unwrap());
+        assert_eq!(fs::read(&third).await.unwrap(), b"same content");
+        #[cfg(unix)]
+        {
+            let metadata = fs::metadata(&first).await.unwrap();
+            for path in [&second, &third] {
+                assert!(same_file(&metadata, &fs::metadata(path).await.unwrap()));
+            }
+            let other = fs::metadata(&other).await.unwrap();
+            assert!(!same_file(&metadata, &other));
+        }
+        let unknown = "0".repeat(64);
+        assert!(!file.restore(&unknown, &dir.join("e.txt")).await.unwrap());
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,22 +1,114 @@
 use super::{AppManager, HandleInboundEvent, Invoke};
 use crate::{
-    chat_app::{frontend_event::FrontendEvent, AppState},
+    chat_app::{frontend_event::FrontendEvent, tasks::Tasks, AppState},
     error::{ManagerError, NetworkError},
-    models::{GroupId, GroupInfo, GroupMessage, GroupState},
+    models::{
+        GroupId, GroupInfo, GroupKey, GroupMessage, GroupState, MessageId, MessageStatus,
+        PollState, Reactions, Role, UserInfo, MAX_GROUP_KEYS,
+    },
     network::{
-        message::{InboundEvent, Request, Response},
+        message::{
+            InboundEvent, Message, Poll, Request, Response, MAX_RESYNC_MESSAGES, TYPING_TIMEOUT,
+        },
         Client,
     },
 };
 use async_trait::async_trait;
+use chrono::Utc;
 use libp2p::{gossipsub::TopicHash, PeerId};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+use uuid::Uuid;
+
+/// How long subscriptions to a group are collected before we announce our
+/// user info to it once for all of them.
+const ANNOUNCE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchMessages {
+    pub query: String,
+    pub group_id: Option<GroupId>,
+    /// Only match messages sent at or after this unix timestamp.
+    pub after: Option<i64>,
+    /// Only match messages sent at or before this unix timestamp.
+    pub before: Option<i64>,
+    /// Return at most this many of the newest matches.
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    /// How many messages matched, including those beyond `limit`.
+    pub total: usize,
+    /// Matches newest first.
+    pub hits: Vec<(GroupId, GroupMessage)>,
+}
 
 #[derive(Debug, Clone)]
 pub struct GroupManager {
     group_state: Arc<Mutex<HashMap<GroupId, GroupState>>>,
     groups: Arc<Mutex<HashMap<GroupId, GroupInfo>>>,
+    pending_pings: Arc<Mutex<HashMap<Uuid, HashSet<PeerId>>>>,
+    /// When each group was last joined or saw a message.
+    last_activity: Arc<Mutex<HashMap<GroupId, i64>>>,
+    /// Where each group's messages are appended once history is loaded.
+    history_dir: Arc<Mutex<Option<PathBuf>>>,
+    /// Loaded history of groups we haven't rejoined yet.
+    saved_history: Arc<Mutex<HashMap<GroupId, Vec<GroupMessage>>>>,
+    /// Lines in each group's message log, to tell when it's due for
+    /// compaction.
+    log_lines: Arc<Mutex<HashMap<GroupId, usize>>>,
+    /// Peers shown as typing, and when they last said so.
+    typing: Arc<Mutex<HashMap<(GroupId, PeerId), Instant>>>,
+    /// Members that acknowledged each of our messages.
+    acks: Arc<Mutex<HashMap<MessageId, HashSet<PeerId>>>>,
+    /// The last sequence number we gave our own messages, per group.
+    sent_seq: Arc<Mutex<HashMap<GroupId, u64>>>,
+    /// The latest message each member has read, per group.
+    read_receipts: Arc<Mutex<HashMap<GroupId, HashMap<PeerId, MessageId>>>>,
+    /// Groups with an announcement of our user info already scheduled.
+    announcing: Arc<Mutex<HashSet<GroupId>>>,
+    /// Hash of the avatar we last announced to each group, if we had one.
+    announced_avatar: Arc<Mutex<HashMap<GroupId, Option<u64>>>>,
+    /// Hash of the user info each member of each group last got from us,
+    /// so reconnects don't announce it again.
+    announced_to: Arc<Mutex<HashMap<(GroupId, PeerId), u64>>>,
+    /// Members we gave each group's key to, who get its later keys too.
+    key_holders: Arc<Mutex<HashMap<GroupId, HashSet<PeerId>>>>,
+}
+
+/// Keep only the last `retention` messages, or all of them when `None`.
+fn trim_history(history: &mut Vec<GroupMessage>, retention: Option<usize>) -> bool {
+    match retention {
+        Some(retention) if history.len() > retention => {
+            history.drain(..history.len() - retention);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Tells whether a member already has our name and avatar.
+fn user_info_hash(user_info: &UserInfo) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    user_info.name.hash(&mut hasher);
+    user_info.avatar.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl GroupManager {
@@ -24,29 +116,482 @@ impl GroupManager {
         Self {
             group_state: Arc::new(Mutex::new(HashMap::new())),
             groups: Arc::new(Mutex::new(HashMap::new())),
+            pending_pings: Arc::new(Mutex::new(HashMap::new())),
+            last_activity: Arc::new(Mutex::new(HashMap::new())),
+            history_dir: Arc::new(Mutex::new(None)),
+            saved_history: Arc::new(Mutex::new(HashMap::new())),
+            log_lines: Arc::new(Mutex::new(HashMap::new())),
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            read_receipts: Arc::new(Mutex::new(HashMap::new())),
+            sent_seq: Arc::new(Mutex::new(HashMap::new())),
+            acks: Arc::new(Mutex::new(HashMap::new())),
+            announcing: Arc::new(Mutex::new(HashSet::new())),
+            announced_avatar: Arc::new(Mutex::new(HashMap::new())),
+            announced_to: Arc::new(Mutex::new(HashMap::new())),
+            key_holders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    fn history_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join("history")
+    }
+    /// Load each group's message log, one JSON message per line, and keep
+    /// appending to it from then on. Logs longer than `retention` or with
+    /// lines that didn't load are compacted.
+    pub async fn load_history<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        retention: Option<usize>,
+    ) -> Result<(), io::Error> {
+        let dir = Self::history_path(dir);
+        fs::create_dir_all(&dir).await?;
+        let mut entries = fs::read_dir(&dir).await?;
+        let mut saved_history = HashMap::new();
+        let mut compact = false;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let group_id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|_| path.extension() == Some("jsonl".as_ref()))
+                .and_then(|stem| serde_json::from_value::<GroupId>(stem.into()).ok());
+            let Some(group_id) = group_id else {
+                log::warn!("skipping unknown history file {path:?}");
+                continue;
+            };
+            let buf = fs::read_to_string(&path).await?;
+            let mut history: Vec<GroupMessage> = Vec::new();
+            let mut lines = 0;
+            for line in buf.lines().filter(|line| !line.is_empty()) {
+                lines += 1;
+                match serde_json::from_str::<GroupMessage>(line) {
+                    Ok(mut message) => {
+                        // Edits and deletions are logged as they arrive and
+                        // replayed here.
+                        match message.message {
+                            Message::Edit { ref target, .. } => {
+                                if let Some(original) = history.iter_mut().find(|m| &m.id == target)
+                                {
+                                    original.revise(&message);
+                                }
+                                continue;
+                            }
+                            Message::Delete { ref target } => {
+                                if let Some(original) = history.iter_mut().find(|m| &m.id == target)
+                                {
+                                    original.revise(&message);
+                                }
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        // Compacted logs keep their IDs, which edits change.
+                        if message.id == MessageId::default() {
+                            message.id = message.compute_id();
+                        }
+                        history.push(message);
+                    }
+                    // Likely a write cut short by a crash.
+                    Err(e) => log::warn!("skipping corrupt message in {path:?}: {e}"),
+                }
+            }
+            compact |= trim_history(&mut history, retention) || lines > history.len();
+            self.log_lines.lock().await.insert(group_id.clone(), lines);
+            saved_history.insert(group_id, history);
+        }
+        let mut group_state = self.group_state.lock().await;
+        for (group_id, history) in saved_history {
+            match group_state.get_mut(&group_id) {
+                Some(state) => state.set_history(history),
+                None => {
+                    self.saved_history.lock().await.insert(group_id, history);
+                }
+            }
+        }
+        drop(group_state);
+        *self.history_dir.lock().await = Some(dir);
+        if compact {
+            self.save_history().await?;
+        }
+        Ok(())
+    }
+    /// Rewrite every group's message log from memory.
+    pub async fn save_history(&self) -> Result<(), io::Error> {
+        let Some(dir) = self.history_dir.lock().await.clone() else {
+            return Ok(());
+        };
+        let mut histories = self.saved_history.lock().await.clone();
+        for (group_id, state) in self.group_state.lock().await.iter() {
+            histories.insert(group_id.clone(), state.history.clone());
+        }
+        for (group_id, history) in histories {
+            self.write_history(&dir, &group_id, &history).await?;
+        }
+        Ok(())
+    }
+    /// Replace the group's message log with `history`, through a new file
+    /// so a crash can't cut it short.
+    async fn write_history(
+        &self,
+        dir: &Path,
+        group_id: &GroupId,
+        history: &[GroupMessage],
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for message in history {
+            serde_json::to_writer(&mut buf, message)?;
+            buf.push(b'\n');
+        }
+        let path = dir.join(format!("{group_id}.jsonl"));
+        let tmp_path = path.with_extension(format!("{:08x}.tmp", rand::random::<u32>()));
+        fs::write(&tmp_path, buf).await?;
+        if let Err(e) = fs::rename(&tmp_path, &path).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+        self.log_lines
+            .lock()
+            .await
+            .insert(group_id.clone(), history.len());
+        Ok(())
+    }
+    /// Append `message` to the group's log, returning how many lines the
+    /// log has now.
+    async fn append_history(
+        &self,
+        group_id: &GroupId,
+        message: &GroupMessage,
+    ) -> io::Result<usize> {
+        let Some(dir) = self.history_dir.lock().await.clone() else {
+            return Ok(0);
+        };
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{group_id}.jsonl")))
+            .await?
+            .write_all(&line)
+            .await?;
+        let mut log_lines = self.log_lines.lock().await;
+        let lines = log_lines.entry(group_id.clone()).or_default();
+        *lines += 1;
+        Ok(*lines)
+    }
+    /// Rewrite the group's log from memory once trimming left most of it
+    /// behind, which keeps it under twice `retention` lines.
+    async fn compact_history(&self, group_id: &GroupId, lines: usize, retention: Option<usize>) {
+        if retention.map_or(true, |retention| lines <= retention.max(1) * 2) {
+            return;
+        }
+        let Some(dir) = self.history_dir.lock().await.clone() else {
+            return;
+        };
+        let history = match self.group_state.lock().await.get(group_id) {
+            Some(state) => state.history.clone(),
+            None => return,
+        };
+        if let Err(e) = self.write_history(&dir, group_id, &history).await {
+            log::warn!("failed to compact history of group {group_id}: {e}");
         }
     }
     pub async fn add_group(&self, group_id: GroupId, group_info: GroupInfo) {
+        self.touch(&group_id).await;
         self.groups
             .lock()
             .await
             .insert(group_id.clone(), group_info);
+        let mut group_state = GroupState::new();
+        if let Some(history) = self.saved_history.lock().await.remove(&group_id) {
+            group_state.set_history(history);
+        }
+        self.group_state.lock().await.insert(group_id, group_state);
+    }
+    async fn touch(&self, group_id: &GroupId) {
+        self.last_activity
+            .lock()
+            .await
+            .insert(group_id.clone(), Utc::now().timestamp());
+    }
+    /// Whether `peer_id` is subscribed to the group, as far as we know.
+    pub async fn is_subscriber(&self, group_id: &GroupId, peer_id: &PeerId) -> bool {
+        self.group_state
+            .lock()
+            .await
+            .get(group_id)
+            .map_or(false, |state| state.subscribers.contains(peer_id))
+    }
+    /// Groups `peer_id` is subscribed to that have been quiet since `since`.
+    pub async fn inactive_groups(&self, peer_id: &PeerId, since: i64) -> Vec<GroupId> {
+        let last_activity = self.last_activity.lock().await;
         self.group_state
             .lock()
             .await
-            .insert(group_id, GroupState::new());
+            .iter()
+            .filter(|(group_id, state)| {
+                state.subscribers.contains(peer_id)
+                    && last_activity.get(*group_id).map_or(true, |t| *t < since)
+            })
+            .map(|(group_id, _)| group_id.clone())
+            .collect()
     }
     pub async fn remove_group(&self, group_id: &GroupId) {
         self.groups.lock().await.remove(group_id);
         self.group_state.lock().await.remove(group_id);
     }
+    /// Take `editor`'s name and description of the group if `version` is
+    /// newer than the info we have and `editor` moderates the group,
+    /// returning the updated info. Pins, roles and keys are left alone; they
+    /// only change through their own messages and requests.
+    pub async fn apply_group_info(
+        &self,
+        group_id: &GroupId,
+        editor: &PeerId,
+        name: String,
+        description: Option<String>,
+        version: u64,
+    ) -> Option<GroupInfo> {
+        let mut groups = self.groups.lock().await;
+        let info = groups.get_mut(group_id)?;
+        if version <= info.version {
+            return None;
+        }
+        if !info.may_moderate(editor) {
+            log::warn!("rejecting info of group {group_id} from {editor}, who isn't an admin");
+            return None;
+        }
+        info.name = name;
+        info.description = description;
+        info.version = version;
+        Some(info.clone())
+    }
+    /// Add `key` as the newest key of the group on behalf of `rotator`, who
+    /// must moderate it. Returns the updated info, or `None` if the key isn't
+    /// newer than the ones we have.
+    pub async fn add_group_key(
+        &self,
+        group_id: &GroupId,
+        rotator: &PeerId,
+        key: GroupKey,
+    ) -> Option<GroupInfo> {
+        let mut groups = self.groups.lock().await;
+        let info = groups.get_mut(group_id)?;
+        if !info.may_moderate(rotator) {
+            log::warn!("rejecting key of group {group_id} from {rotator}, who isn't an admin");
+            return None;
+        }
+        if info
+            .keys
+            .last()
+            .map_or(false, |current| current.id >= key.id)
+        {
+            return None;
+        }
+        info.keys.push(key);
+        if info.keys.len() > MAX_GROUP_KEYS {
+            info.keys.remove(0);
+        }
+        Some(info.clone())
+    }
+    /// Send the group's current key to `peer_id`, who is remembered as a key
+    /// holder once it took the key.
+    pub async fn share_key(
+        &self,
+        group_id: &GroupId,
+        peer_id: PeerId,
+        client: &Client,
+    ) -> Result<(), NetworkError> {
+        let key = self
+            .get_group_info(group_id)
+            .await
+            .and_then(|info| info.keys.last().cloned())
+            .ok_or_else(|| anyhow::anyhow!("group {group_id} isn't encrypted"))?;
+        let request = Request::GroupKey {
+            group_id: group_id.clone(),
+            key,
+        };
+        match client.request(peer_id, request).await? {
+            Response::Delivered => {
+                self.key_holders
+                    .lock()
+                    .await
+                    .entry(group_id.clone())
+                    .or_default()
+                    .insert(peer_id);
+                Ok(())
+            }
+            res => Err(NetworkError::RequestError(format!(
+                "{peer_id} didn't take the group key: {res:?}"
+            ))),
+        }
+    }
+    pub async fn key_holders(&self, group_id: &GroupId) -> HashSet<PeerId> {
+        self.key_holders
+            .lock()
+            .await
+            .get(group_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// Decrypt `message` with the group key it names, leaving messages that
+    /// aren't encrypted as they are. `None` if we don't have the key or the
+    /// content doesn't decrypt.
+    async fn decrypt(&self, group_id: &GroupId, mut message: GroupMessage) -> Option<GroupMessage> {
+        let Message::Encrypted {
+            key_id,
+            nonce,
+            ciphertext,
+        } = &message.message
+        else {
+            return Some(message);
+        };
+        let key = self
+            .groups
+            .lock()
+            .await
+            .get(group_id)?
+            .keys
+            .iter()
+            .find(|key| key.id == *key_id)
+            .cloned();
+        let Some(key) = key else {
+            log::warn!(
+                "can't decrypt message {}: key {key_id} of group {group_id} is unknown",
+                message.id
+            );
+            return None;
+        };
+        let plaintext = match key.decrypt(nonce, ciphertext) {
+            Some(Message::Encrypted { .. }) | None => {
+                log::warn!("dropping message {} that doesn't decrypt", message.id);
+                return None;
+            }
+            Some(plaintext) => plaintext,
+        };
+        if let Err(e) = plaintext.validate() {
+            log::warn!("dropping invalid message {}: {e}", message.id);
+            return None;
+        }
+        message.message = plaintext;
+        // Derived from the content like for plain messages, which is also
+        // what the signature covers.
+        message.id = message.compute_id();
+        Some(message)
+    }
+    /// Pin or unpin `target` on behalf of `pinner`, returning the new pins
+    /// if they changed.
+    pub async fn apply_pin(
+        &self,
+        group_id: &GroupId,
+        pinner: &PeerId,
+        target: MessageId,
+        pinned: bool,
+    ) -> Option<Vec<MessageId>> {
+        let mut groups = self.groups.lock().await;
+        let info = groups.get_mut(group_id)?;
+        if !info.may_moderate(pinner) {
+            log::warn!("rejecting pin in group {group_id} by {pinner}, who isn't an admin");
+            return None;
+        }
+        let position = info.pinned.iter().position(|id| id == &target);
+        match (pinned, position) {
+            (true, None) => info.pinned.push(target),
+            (false, Some(position)) => {
+                info.pinned.remove(position);
+            }
+            _ => return None,
+        }
+        Some(info.pinned.clone())
+    }
+    /// Give `peer_id` `role` if `assigner`'s own role allows it, returning
+    /// whether the role changed.
+    pub async fn apply_role(
+        &self,
+        group_id: &GroupId,
+        assigner: &PeerId,
+        peer_id: PeerId,
+        role: Role,
+    ) -> bool {
+        let mut groups = self.groups.lock().await;
+        let Some(info) = groups.get_mut(group_id) else {
+            return false;
+        };
+        let current = info.role(&peer_id);
+        if !info.role(assigner).may_assign(current, role) {
+            log::warn!("rejecting role change in group {group_id} by {assigner}");
+            return false;
+        }
+        if current == role {
+            return false;
+        }
+        if role == Role::Member {
+            info.roles.remove(&peer_id);
+        } else {
+            info.roles.insert(peer_id, role);
+        }
+        true
+    }
     pub async fn get_groups(&self) -> HashMap<GroupId, GroupInfo> {
         self.groups.lock().await.clone()
     }
-    pub async fn add_message(&self, group_id: &GroupId, message: GroupMessage) {
-        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id.as_ref()) {
-            group_status.history.push(message);
+    /// Record a message, appending it to the group's log on disk, and drop
+    /// the oldest ones beyond `retention`. Returns the message as recorded,
+    /// with `verified` set, or `None` without recording anything if its
+    /// signature is invalid or a message with the same ID is already in the
+    /// history, e.g. because gossipsub delivered it twice during churn.
+    pub async fn add_message(
+        &self,
+        group_id: &GroupId,
+        mut message: GroupMessage,
+        retention: Option<usize>,
+    ) -> Option<GroupMessage> {
+        // Only a revision may replace what the author signed.
+        if message.revision.is_none() {
+            message.edited_at = None;
+        }
+        if message.verify_signature() != Some(true) {
+            log::warn!("dropping message {} without a valid signature", message.id);
+            return None;
+        }
+        message.verified = true;
+        // The content of a resynced message that was edited or deleted is
+        // only as good as the revision, so apply it again.
+        if let Some(revision) = message.revision.clone() {
+            let revision = revision.message;
+            if revision.verify_signature() != Some(true) || !message.revise(&revision) {
+                log::warn!("dropping message {} with an invalid revision", message.id);
+                return None;
+            }
+        }
+        self.touch(group_id).await;
+        {
+            let mut group_state = self.group_state.lock().await;
+            let group_status = group_state.get_mut(group_id.as_ref())?;
+            if !group_status.push(message.clone(), retention) {
+                return None;
+            }
         }
+        match self.append_history(group_id, &message).await {
+            Ok(lines) => self.compact_history(group_id, lines, retention).await,
+            Err(e) => log::warn!("failed to save message of group {group_id}: {e}"),
+        }
+        Some(message)
+    }
+    /// Apply `update` to message `target`, returning the message if it
+    /// was changed.
+    async fn update_message(
+        &self,
+        group_id: &GroupId,
+        target: &MessageId,
+        update: impl FnOnce(&mut GroupMessage) -> bool,
+    ) -> Option<GroupMessage> {
+        let mut group_state = self.group_state.lock().await;
+        let original = group_state
+            .get_mut(group_id)?
+            .history
+            .iter_mut()
+            .find(|m| &m.id == target)?;
+        update(original).then(|| original.clone())
     }
     pub async fn get_group_info(&self, group_id: &GroupId) -> Option<GroupInfo> {
         self.groups.lock().await.get(group_id).cloned()
@@ -87,6 +632,472 @@ impl GroupManager {
             false
         }
     }
+    /// Replace the group's subscribers, e.g. after rebuilding a drifted roster.
+    pub async fn replace_subscribers(&self, group_id: &GroupId, subscribers: HashSet<PeerId>) {
+        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id) {
+            group_status.subscribers = subscribers;
+        }
+    }
+    pub async fn add_poll(&self, group_id: &GroupId, poll: Poll) {
+        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id) {
+            group_status
+                .polls
+                .entry(poll.id)
+                .or_insert_with(|| PollState::new(poll));
+        }
+    }
+    /// Count a vote, returning the new tallies if they may have changed.
+    pub async fn record_vote(
+        &self,
+        group_id: &GroupId,
+        poll_id: Uuid,
+        peer_id: PeerId,
+        option: usize,
+        timestamp: i64,
+    ) -> Option<Vec<usize>> {
+        let mut group_state = self.group_state.lock().await;
+        let poll = group_state.get_mut(group_id)?.polls.get_mut(&poll_id)?;
+        poll.vote(peer_id, option, timestamp)
+            .then(|| poll.tallies())
+    }
+    /// Record a reaction to `target`, returning the message's reactions if
+    /// they may have changed.
+    pub async fn record_reaction(
+        &self,
+        group_id: &GroupId,
+        target: &MessageId,
+        peer_id: PeerId,
+        emoji: String,
+        remove: bool,
+        timestamp: i64,
+    ) -> Option<Reactions> {
+        let mut group_state = self.group_state.lock().await;
+        let reactions = group_state
+            .get_mut(group_id)?
+            .reactions
+            .entry(target.clone())
+            .or_default();
+        reactions
+            .react(peer_id, emoji, remove, timestamp)
+            .then(|| reactions.clone())
+    }
+    /// Who reacted to `target` with each emoji.
+    pub async fn reaction_details(
+        &self,
+        group_id: &GroupId,
+        target: &MessageId,
+    ) -> Option<HashMap<String, Vec<PeerId>>> {
+        let group_state = self.group_state.lock().await;
+        let state = group_state.get(group_id)?;
+        Some(
+            state
+                .reactions
+                .get(target)
+                .map(Reactions::details)
+                .unwrap_or_default(),
+        )
+    }
+    /// Remember that our message `message_id` never reached anyone.
+    pub async fn mark_failed(&self, group_id: &GroupId, message_id: MessageId) {
+        if let Some(state) = self.group_state.lock().await.get_mut(group_id) {
+            state.failed.insert(message_id);
+        }
+    }
+    /// Drop our failed messages from the group's history and its log,
+    /// returning how many there were.
+    pub async fn discard_failed(&self, group_id: &GroupId) -> Option<usize> {
+        let (failed, history) = {
+            let mut group_state = self.group_state.lock().await;
+            let state = group_state.get_mut(group_id)?;
+            let failed = mem::take(&mut state.failed);
+            let history: Vec<_> = mem::take(&mut state.history)
+                .into_iter()
+                .filter(|message| !failed.contains(&message.id))
+                .collect();
+            state.set_history(history.clone());
+            (failed, history)
+        };
+        let dir = self.history_dir.lock().await.clone();
+        if let (Some(dir), false) = (dir, failed.is_empty()) {
+            if let Err(e) = self.write_history(&dir, group_id, &history).await {
+                log::warn!("failed to save history of group {group_id}: {e}");
+            }
+        }
+        Some(failed.len())
+    }
+    /// Start collecting pongs for a ping with the given id.
+    pub async fn start_ping(&self, ping_id: Uuid) {
+        self.pending_pings
+            .lock()
+            .await
+            .insert(ping_id, HashSet::new());
+    }
+    /// Stop collecting pongs and return the peers that answered.
+    pub async fn finish_ping(&self, ping_id: &Uuid) -> HashSet<PeerId> {
+        self.pending_pings
+            .lock()
+            .await
+            .remove(ping_id)
+            .unwrap_or_default()
+    }
+    /// Publish our user info to the group after `ANNOUNCE_DEBOUNCE`, so
+    /// `peer_id`, who just subscribed, shows our name right away. Skipped
+    /// if it already got our current info, as when it reconnects; peers
+    /// that lost it ask with `Request::User`. Subscriptions arriving
+    /// meanwhile share the one announcement.
+    async fn schedule_announce(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        client: Client,
+        state: AppState,
+    ) {
+        if peer_id != client.local_peer_id() {
+            let user_info = state.local_user.lock().await.clone().into();
+            let announced = self
+                .announced_to
+                .lock()
+                .await
+                .get(&(group_id.clone(), peer_id))
+                .copied();
+            if announced == Some(user_info_hash(&user_info)) {
+                return;
+            }
+        }
+        if !self.announcing.lock().await.insert(group_id.clone()) {
+            return;
+        }
+        let group = self.clone();
+        let tasks = state.tasks.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(ANNOUNCE_DEBOUNCE).await;
+            group.announcing.lock().await.remove(&group_id);
+            let user_info = state.local_user.lock().await.clone().into();
+            if let Err(e) = group
+                .announce_user_info(&group_id, user_info, &client)
+                .await
+            {
+                log::debug!("failed to announce user info to {group_id}: {e}");
+            }
+        });
+    }
+    /// Publish our user info to the group, leaving out the avatar if the
+    /// group already got it. Members that joined since fetch it with
+    /// `Request::User`.
+    pub async fn announce_user_info(
+        &self,
+        group_id: &GroupId,
+        mut user_info: UserInfo,
+        client: &Client,
+    ) -> Result<(), NetworkError> {
+        let info_hash = user_info_hash(&user_info);
+        let avatar = user_info.avatar.as_ref().map(|avatar| {
+            let mut hasher = DefaultHasher::new();
+            avatar.hash(&mut hasher);
+            hasher.finish()
+        });
+        let avatar_omitted = self.announced_avatar.lock().await.get(group_id) == Some(&avatar);
+        if avatar_omitted {
+            user_info.avatar = None;
+        }
+        let message = Message::UserInfo {
+            user_info,
+            avatar_omitted,
+        };
+        client.publish(group_id.topic(), message).await?;
+        self.announced_avatar
+            .lock()
+            .await
+            .insert(group_id.clone(), avatar);
+        let subscribers = self
+            .group_state
+            .lock()
+            .await
+            .get(group_id)
+            .map(|state| state.subscribers.clone())
+            .unwrap_or_default();
+        let mut announced_to = self.announced_to.lock().await;
+        for peer_id in subscribers {
+            announced_to.insert((group_id.clone(), peer_id), info_hash);
+        }
+        Ok(())
+    }
+    async fn record_pong(&self, ping_id: &Uuid, peer_id: PeerId) {
+        if let Some(peers) = self.pending_pings.lock().await.get_mut(ping_id) {
+            peers.insert(peer_id);
+        }
+    }
+    /// Forward a peer's typing state when it changes. Typing expires after
+    /// `TYPING_TIMEOUT` unless the peer refreshes it, so one that crashed
+    /// mid-sentence doesn't stay typing forever.
+    async fn record_typing(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        is_typing: bool,
+        sender: mpsc::Sender<FrontendEvent>,
+        tasks: &Tasks,
+    ) {
+        let key = (group_id, peer_id);
+        if is_typing {
+            let since = Instant::now();
+            let was_typing = self
+                .typing
+                .lock()
+                .await
+                .insert(key.clone(), since)
+                .is_some();
+            let typing = self.typing.clone();
+            let expired = key.clone();
+            let expiry_sender = sender.clone();
+            tasks.spawn_cancellable(async move {
+                tokio::time::sleep(TYPING_TIMEOUT).await;
+                let mut typing = typing.lock().await;
+                if typing.get(&expired) != Some(&since) {
+                    return;
+                }
+                typing.remove(&expired);
+                drop(typing);
+                let (group_id, peer_id) = expired;
+                let _ = expiry_sender
+                    .send(FrontendEvent::Typing {
+                        group_id,
+                        peer_id,
+                        is_typing: false,
+                    })
+                    .await;
+            });
+            if was_typing {
+                return;
+            }
+        } else if self.typing.lock().await.remove(&key).is_none() {
+            return;
+        }
+        let (group_id, peer_id) = key;
+        sender
+            .send(FrontendEvent::Typing {
+                group_id,
+                peer_id,
+                is_typing,
+            })
+            .await
+            .unwrap();
+    }
+    /// The sequence number of the next kept message `author` publishes to
+    /// the group. Continues from the history after a restart.
+    pub async fn next_seq(&self, group_id: &GroupId, author: &PeerId) -> u64 {
+        let in_history = self
+            .group_state
+            .lock()
+            .await
+            .get(group_id)
+            .and_then(|state| {
+                state
+                    .history
+                    .iter()
+                    .filter(|m| &m.source == author)
+                    .filter_map(|m| m.seq)
+                    .max()
+            })
+            .unwrap_or(0);
+        let mut sent_seq = self.sent_seq.lock().await;
+        let seq = sent_seq.entry(group_id.clone()).or_default();
+        *seq = (*seq).max(in_history) + 1;
+        *seq
+    }
+    /// Give back `seq`, taken for a message that failed to publish, unless
+    /// a later one was taken since.
+    pub async fn release_seq(&self, group_id: &GroupId, seq: u64) {
+        if let Some(sent) = self.sent_seq.lock().await.get_mut(group_id) {
+            if *sent == seq {
+                *sent -= 1;
+            }
+        }
+    }
+    /// Sequence numbers missing from each author's messages in the group,
+    /// between the first and last one we have. Messages sent while we
+    /// were away, or trimmed by retention, aren't counted.
+    pub async fn sequence_gaps(&self, group_id: &GroupId) -> Option<HashMap<PeerId, Vec<u64>>> {
+        let group_state = self.group_state.lock().await;
+        let mut seqs = HashMap::<PeerId, Vec<u64>>::new();
+        for message in &group_state.get(group_id)?.history {
+            if let Some(seq) = message.seq {
+                seqs.entry(message.source).or_default().push(seq);
+            }
+        }
+        Some(
+            seqs.into_iter()
+                .filter_map(|(author, mut seqs)| {
+                    seqs.sort_unstable();
+                    seqs.dedup();
+                    let missing = seqs
+                        .windows(2)
+                        .flat_map(|pair| pair[0] + 1..pair[1])
+                        .collect::<Vec<_>>();
+                    if missing.is_empty() {
+                        None
+                    } else {
+                        Some((author, missing))
+                    }
+                })
+                .collect(),
+        )
+    }
+    /// Record `peer_id`'s ack of `target`, returning how many members have
+    /// acknowledged it if this one is new. Acks are only counted for our
+    /// own messages that asked for them.
+    async fn record_ack(
+        &self,
+        group_id: &GroupId,
+        target: &MessageId,
+        peer_id: PeerId,
+        local_peer_id: &PeerId,
+    ) -> Option<usize> {
+        let wanted = self
+            .group_state
+            .lock()
+            .await
+            .get(group_id)?
+            .history
+            .iter()
+            .any(|m| &m.id == target && &m.source == local_peer_id && m.want_ack);
+        if !wanted {
+            return None;
+        }
+        let mut acks = self.acks.lock().await;
+        let peers = acks.entry(target.clone()).or_default();
+        if peers.insert(peer_id) {
+            Some(peers.len())
+        } else {
+            None
+        }
+    }
+    /// Members that acknowledged our message `message_id`.
+    pub async fn acks(&self, message_id: &MessageId) -> HashSet<PeerId> {
+        self.acks
+            .lock()
+            .await
+            .get(message_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// `author`'s messages in the group from `from_seq` on, oldest first and
+    /// at most `MAX_RESYNC_MESSAGES`, or `None` for a group we aren't in.
+    pub async fn messages_since(
+        &self,
+        group_id: &GroupId,
+        author: &PeerId,
+        from_seq: u64,
+    ) -> Option<Vec<GroupMessage>> {
+        let mut messages = self
+            .group_state
+            .lock()
+            .await
+            .get(group_id)?
+            .history
+            .iter()
+            .filter(|m| &m.source == author && m.seq.map_or(false, |seq| seq >= from_seq))
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.seq);
+        messages.truncate(MAX_RESYNC_MESSAGES);
+        Some(messages)
+    }
+    /// Move `peer_id`'s read receipt to `up_to`, returning whether it moved.
+    /// Receipts only go forward: one for a message older than the current
+    /// one is ignored. A message we haven't received yet can't be compared
+    /// and is taken as is.
+    async fn record_read(&self, group_id: &GroupId, peer_id: PeerId, up_to: MessageId) -> bool {
+        let group_state = self.group_state.lock().await;
+        let sent_at = |id: &MessageId| {
+            group_state
+                .get(group_id)?
+                .history
+                .iter()
+                .find(|m| &m.id == id)
+                .map(|m| m.timestamp)
+        };
+        let mut read_receipts = self.read_receipts.lock().await;
+        let receipts = read_receipts.entry(group_id.clone()).or_default();
+        if let Some(current) = receipts.get(&peer_id) {
+            if current == &up_to {
+                return false;
+            }
+            if let (Some(current), Some(new)) = (sent_at(current), sent_at(&up_to)) {
+                if new < current {
+                    return false;
+                }
+            }
+        }
+        receipts.insert(peer_id, up_to);
+        true
+    }
+    /// The latest message each member of the group has read.
+    pub async fn read_receipts(&self, group_id: &GroupId) -> HashMap<PeerId, MessageId> {
+        self.read_receipts
+            .lock()
+            .await
+            .get(group_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    pub async fn search_messages(&self, search: &SearchMessages) -> SearchResults {
+        let mut hits = self
+            .group_state
+            .lock()
+            .await
+            .iter()
+            .filter(|(group_id, _)| search.group_id.as_ref().map_or(true, |id| id == *group_id))
+            .flat_map(|(group_id, state)| state.history.iter().map(move |m| (group_id, m)))
+            .filter(|(_, m)| search.after.map_or(true, |after| m.timestamp >= after))
+            .filter(|(_, m)| search.before.map_or(true, |before| m.timestamp <= before))
+            .filter(|(_, m)| m.message.contains(&search.query))
+            .map(|(group_id, m)| (group_id.clone(), m.clone()))
+            .collect::<Vec<_>>();
+        hits.sort_by_key(|(_, m)| std::cmp::Reverse(m.timestamp));
+        let total = hits.len();
+        if let Some(limit) = search.limit {
+            hits.truncate(limit);
+        }
+        SearchResults { total, hits }
+    }
+    /// Fetch the group's info from `peer_id` and apply it if it is newer.
+    async fn sync_group_info(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        let group_info = match client
+            .request(peer_id, Request::GroupInfo(group_id.clone()))
+            .await
+        {
+            Ok(Response::GroupInfo(group_info)) => group_info,
+            Ok(_) => return,
+            Err(e) => {
+                log::warn!("failed to sync group {group_id:?} from {peer_id}: {e}");
+                return;
+            }
+        };
+        if let Some(group_info) = self
+            .apply_group_info(
+                &group_id,
+                &peer_id,
+                group_info.name,
+                group_info.description,
+                group_info.version,
+            )
+            .await
+        {
+            let _ = sender
+                .send(FrontendEvent::GroupUpdate {
+                    group_id,
+                    group_info,
+                })
+                .await;
+        }
+    }
     pub async fn has_any_subscriber(&self, group_id: &GroupId) -> bool {
         if let Some(group_status) = self.group_state.lock().await.get(group_id) {
             !group_status.subscribers.is_empty()
@@ -106,19 +1117,87 @@ impl HandleInboundEvent for GroupManager {
         sender: mpsc::Sender<FrontendEvent>,
     ) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => match request {
+            InboundEvent::InboundRequest {
+                peer,
+                request,
+                channel,
+            } => match request {
                 Request::Group(topic_hash) => {
                     if let Some(group) = self.get_group_by_hash(&topic_hash).await {
-                        let info = self.get_group_info(&group).await.unwrap();
+                        let response = match self.get_group_info(&group).await {
+                            Some(mut info) => {
+                                // Keys are only handed out by admins, see
+                                // `share_key`.
+                                info.keys.clear();
+                                Response::Group((group, info))
+                            }
+                            // Left since the lookup.
+                            None => Response::Error(format!("not in group {group}")),
+                        };
                         if let Some(channel) = channel.lock().await.take() {
-                            client
-                                .response(Response::Group((group, info)), channel)
-                                .await;
+                            client.response(response, channel).await?;
                         }
                     } else {
                         log::warn!("group not found {topic_hash:?}");
                     }
                 }
+                Request::GroupInfo(group_id) => {
+                    let response = match self.get_group_info(&group_id).await {
+                        Some(mut info) => {
+                            info.keys.clear();
+                            Response::GroupInfo(info)
+                        }
+                        None => Response::Error(format!("not in group {group_id}")),
+                    };
+                    if let Some(channel) = channel.lock().await.take() {
+                        client.response(response, channel).await?;
+                    }
+                }
+                Request::ResyncMessages {
+                    group_id,
+                    author,
+                    from_seq,
+                } => {
+                    let response = if !self.is_subscriber(&group_id, &peer).await {
+                        Response::Error(format!("{peer} isn't subscribed to group {group_id}"))
+                    } else {
+                        match self.messages_since(&group_id, &author, from_seq).await {
+                            Some(messages) => Response::Messages(messages),
+                            None => Response::Error(format!("not in group {group_id}")),
+                        }
+                    };
+                    if let Some(channel) = channel.lock().await.take() {
+                        client.response(response, channel).await?;
+                    }
+                }
+                Request::GroupKey { group_id, key } => {
+                    let key_id = key.id;
+                    let has_key = match self.add_group_key(&group_id, &peer, key).await {
+                        Some(group_info) => {
+                            sender
+                                .send(FrontendEvent::GroupUpdate {
+                                    group_id: group_id.clone(),
+                                    group_info,
+                                })
+                                .await
+                                .unwrap();
+                            true
+                        }
+                        // Answered the same for a resent key we already took.
+                        None => self
+                            .get_group_info(&group_id)
+                            .await
+                            .map_or(false, |info| info.keys.iter().any(|key| key.id == key_id)),
+                    };
+                    let response = if has_key {
+                        Response::Delivered
+                    } else {
+                        Response::Error(format!("key {key_id} of group {group_id} not accepted"))
+                    };
+                    if let Some(channel) = channel.lock().await.take() {
+                        client.response(response, channel).await?;
+                    }
+                }
                 _ => {}
             },
             InboundEvent::Message {
@@ -127,15 +1206,275 @@ impl HandleInboundEvent for GroupManager {
                 message,
             } => {
                 if let Some(group_id) = self.get_group_by_hash(&topic).await {
-                    self.add_message(&group_id, message.clone()).await;
-                    sender
-                        .send(FrontendEvent::Message { group_id, message })
-                        .await
-                        .unwrap();
+                    // A key we missed is resent by the admin who rotated it
+                    // once we subscribe again.
+                    let message = match self.decrypt(&group_id, message).await {
+                        Some(message) => message,
+                        None => return Ok(()),
+                    };
+                    if message.verify_signature() != Some(true) {
+                        log::warn!("dropping message {} without a valid signature", message.id);
+                        return Ok(());
+                    }
+                    match message.message {
+                        Message::Ping(ping_id) => {
+                            if message.source != client.local_peer_id() {
+                                // Publish from a separate task so the inbound loop isn't
+                                // blocked on the network loop.
+                                state.tasks.spawn(async move {
+                                    if let Err(e) = client
+                                        .publish(group_id.topic(), Message::Pong(ping_id))
+                                        .await
+                                    {
+                                        log::warn!("failed to answer ping {ping_id}: {e}");
+                                    }
+                                });
+                            }
+                        }
+                        Message::Pong(ping_id) => self.record_pong(&ping_id, message.source).await,
+                        Message::EditGroup {
+                            name,
+                            description,
+                            version,
+                        } => {
+                            if let Some(group_info) = self
+                                .apply_group_info(
+                                    &group_id,
+                                    &message.source,
+                                    name,
+                                    description,
+                                    version,
+                                )
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::GroupUpdate {
+                                        group_id,
+                                        group_info,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::Pin { target, pinned } => {
+                            if let Some(pinned) = self
+                                .apply_pin(&group_id, &message.source, target, pinned)
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::PinUpdate { group_id, pinned })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::SetRole { peer_id, role } => {
+                            if self
+                                .apply_role(&group_id, &message.source, peer_id, role)
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::RoleUpdate {
+                                        group_id,
+                                        peer_id,
+                                        role,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::Ack { target } => {
+                            let local_peer_id = client.local_peer_id();
+                            if let Some(acks) = self
+                                .record_ack(&group_id, &target, message.source, &local_peer_id)
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::MessageAcked {
+                                        group_id,
+                                        message_id: target,
+                                        peer_id: message.source,
+                                        acks,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::Read { up_to } => {
+                            if self
+                                .record_read(&group_id, message.source, up_to.clone())
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::ReadReceipt {
+                                        group_id,
+                                        peer_id: message.source,
+                                        up_to,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        // The user manager takes these.
+                        Message::UserInfo { .. } => {}
+                        Message::Typing { is_typing } => {
+                            if message.source != client.local_peer_id() {
+                                self.record_typing(
+                                    group_id,
+                                    message.source,
+                                    is_typing,
+                                    sender,
+                                    &state.tasks,
+                                )
+                                .await;
+                            }
+                        }
+                        Message::Vote { poll_id, option } => {
+                            if let Some(tallies) = self
+                                .record_vote(
+                                    &group_id,
+                                    poll_id,
+                                    message.source,
+                                    option,
+                                    message.timestamp,
+                                )
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::PollUpdate {
+                                        group_id,
+                                        poll_id,
+                                        tallies,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::React {
+                            target,
+                            emoji,
+                            remove,
+                        } => {
+                            if let Some(reactions) = self
+                                .record_reaction(
+                                    &group_id,
+                                    &target,
+                                    message.source,
+                                    emoji,
+                                    remove,
+                                    message.timestamp,
+                                )
+                                .await
+                            {
+                                sender
+                                    .send(FrontendEvent::ReactionUpdate {
+                                        group_id,
+                                        message_id: target,
+                                        reactions: reactions.counts(&client.local_peer_id()),
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::Edit { ref target, .. } => {
+                            if let Some(edited) = self
+                                .update_message(&group_id, target, |original| {
+                                    original.revise(&message)
+                                })
+                                .await
+                            {
+                                if let Err(e) = self.append_history(&group_id, &message).await {
+                                    log::warn!("failed to save edit of group {group_id}: {e}");
+                                }
+                                sender
+                                    .send(FrontendEvent::MessageEdited {
+                                        group_id,
+                                        message: edited,
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        Message::Delete { ref target } => {
+                            if self
+                                .update_message(&group_id, target, |original| {
+                                    original.revise(&message)
+                                })
+                                .await
+                                .is_some()
+                            {
+                                if let Err(e) = self.append_history(&group_id, &message).await {
+                                    log::warn!("failed to save deletion of group {group_id}: {e}");
+                                }
+                                sender
+                                    .send(FrontendEvent::MessageDeleted {
+                                        group_id,
+                                        message_id: target.clone(),
+                                    })
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        _ => {
+                            let retention = state.setting.lock().await.history_retention;
+                            let message_id = message.id.clone();
+                            let message =
+                                match self.add_message(&group_id, message, retention).await {
+                                    Some(message) => message,
+                                    None => {
+                                        log::debug!("not recording message {message_id}");
+                                        return Ok(());
+                                    }
+                                };
+                            if let Message::Poll(poll) = &message.message {
+                                self.add_poll(&group_id, poll.clone()).await;
+                            }
+                            if message.want_ack && message.source != client.local_peer_id() {
+                                let ack = Message::Ack {
+                                    target: message.id.clone(),
+                                };
+                                let topic = group_id.topic();
+                                let client = client.clone();
+                                state.tasks.spawn(async move {
+                                    if let Err(e) = client.publish(topic, ack).await {
+                                        log::warn!("failed to acknowledge message: {e}");
+                                    }
+                                });
+                            }
+                            let suppress_notification = state.setting.lock().await.is_quiet_now();
+                            sender
+                                .send(FrontendEvent::Message {
+                                    group_id,
+                                    message,
+                                    suppress_notification,
+                                })
+                                .await
+                                .unwrap();
+                        }
+                    }
                 }
             }
             InboundEvent::Subscribed { peer_id, topic } => {
                 let group_id = if let Some(group_id) = self.get_group_by_hash(&topic).await {
+                    if peer_id != client.local_peer_id() {
+                        // We may have missed edits while apart, catch up from the member directly.
+                        let group = self.clone();
+                        let client = client.clone();
+                        let sender = sender.clone();
+                        let group_id = group_id.clone();
+                        state.tasks.spawn(async move {
+                            // And the member may have missed a key we rotated.
+                            if group.key_holders(&group_id).await.contains(&peer_id) {
+                                if let Err(e) = group.share_key(&group_id, peer_id, &client).await {
+                                    log::debug!(
+                                        "failed to resend the key of {group_id} to {peer_id}: {e}"
+                                    );
+                                }
+                            }
+                            group
+                                .sync_group_info(group_id, peer_id, client, sender)
+                                .await;
+                        });
+                    }
                     group_id
                 } else {
                     let (group_id, group_info) = match client.pending_new_group.lock().await.take()
@@ -164,6 +1503,8 @@ impl HandleInboundEvent for GroupManager {
                 };
 
                 self.add_subscribe(&group_id, peer_id).await;
+                self.schedule_announce(group_id.clone(), peer_id, client, state)
+                    .await;
                 sender
                     .send(FrontendEvent::Subscribed { group_id, peer_id })
                     .await
@@ -179,6 +1520,24 @@ impl HandleInboundEvent for GroupManager {
                     }
                 }
             }
+            InboundEvent::QueuedMessage {
+                topic,
+                message_id,
+                status,
+            } => {
+                if let Some(group_id) = self.get_group_by_hash(&topic).await {
+                    if status == MessageStatus::Failed {
+                        self.mark_failed(&group_id, message_id.clone()).await;
+                    }
+                    sender
+                        .send(FrontendEvent::MessageStatus {
+                            message_id: message_id.to_string(),
+                            status,
+                        })
+                        .await
+                        .unwrap();
+                }
+            }
             _ => {}
         }
         Ok(())
@@ -196,7 +1555,19 @@ impl Invoke for GroupManager {
             "get_groups" => serde_json::to_value(self.get_groups().await)?,
             "get_group_state" if params.is_some() => {
                 let group_id = serde_json::from_value::<GroupId>(params.unwrap())?;
-                serde_json::to_value(self.get_group_state(&group_id).await.unwrap())?
+                let state = self
+                    .get_group_state(&group_id)
+                    .await
+                    .ok_or(ManagerError::GroupNotExist(group_id))?;
+                serde_json::to_value(state)?
+            }
+            "get_read_receipts" if params.is_some() => {
+                let group_id = serde_json::from_value::<GroupId>(params.unwrap())?;
+                serde_json::to_value(self.read_receipts(&group_id).await)?
+            }
+            "search_messages" if params.is_some() => {
+                let search = serde_json::from_value::<SearchMessages>(params.unwrap())?;
+                serde_json::to_value(self.search_messages(&search).await)?
             }
             c => return Err(ManagerError::InvalidAction(c.to_string())),
         };
@@ -209,3 +1580,404 @@ impl AppManager for GroupManager {
         "group"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Setting, network};
+    use libp2p::{gossipsub::MessageId, identity::Keypair};
+
+    fn group_info() -> GroupInfo {
+        GroupInfo {
+            name: "test".to_string(),
+            description: None,
+            version: 0,
+            pinned: Vec::new(),
+            roles: HashMap::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn search_matches_within_the_date_range() {
+        let group = GroupManager::new();
+        let [first, second] = [(); 2].map(|_| GroupId::new());
+        for group_id in [&first, &second] {
+            group.add_group(group_id.clone(), group_info()).await;
+        }
+        let keypair = Keypair::generate_ed25519();
+        let message = |text: &str, timestamp| {
+            let mut message = signed(&keypair, text, 1);
+            message.timestamp = timestamp;
+            message.sign(&keypair);
+            message
+        };
+        for (group_id, text, timestamp) in [
+            (&first, "Lunch?", 100),
+            (&first, "dinner", 150),
+            (&first, "lunch again", 200),
+            (&second, "LUNCH", 300),
+        ] {
+            group
+                .add_message(group_id, message(text, timestamp), None)
+                .await;
+        }
+
+        let search = |group_id: Option<&GroupId>, after, before| SearchMessages {
+            query: "lunch".to_string(),
+            group_id: group_id.cloned(),
+            after,
+            before,
+            limit: None,
+        };
+        let found = |results: SearchResults| {
+            results
+                .hits
+                .into_iter()
+                .map(|(_, message)| message.timestamp)
+                .collect::<Vec<_>>()
+        };
+        let everywhere = group.search_messages(&search(None, None, None)).await;
+        assert_eq!(everywhere.total, 3);
+        assert_eq!(found(everywhere), vec![300, 200, 100]);
+        let in_group = group
+            .search_messages(&search(Some(&first), None, None))
+            .await;
+        assert_eq!(found(in_group), vec![200, 100]);
+        let in_range = group
+            .search_messages(&search(None, Some(150), Some(250)))
+            .await;
+        assert_eq!(found(in_range), vec![200]);
+        let limited = group
+            .search_messages(&SearchMessages {
+                limit: Some(2),
+                ..search(None, None, None)
+            })
+            .await;
+        assert_eq!(limited.total, 3);
+        assert_eq!(found(limited), vec![300, 200]);
+    }
+
+    #[tokio::test]
+    async fn pongs_are_collected_while_pinging() {
+        let network = network::new(Keypair::generate_ed25519(), &Setting::default()).unwrap();
+        let (sender, mut frontend) = mpsc::channel(8);
+        let mut group = GroupManager::new();
+        let group_id = GroupId::new();
+        group.add_group(group_id.clone(), group_info()).await;
+        let ping_id = Uuid::new_v4();
+        group.start_ping(ping_id).await;
+        let keypair = Keypair::generate_ed25519();
+        let member = keypair.public().to_peer_id();
+        for id in [ping_id, Uuid::new_v4()] {
+            let mut message = GroupMessage::new(Message::Pong(id), member);
+            message.sign(&keypair);
+            let event = InboundEvent::Message {
+                message_id: MessageId::new(id.as_bytes()),
+                topic: group_id.topic().hash(),
+                message,
+            };
+            let state = AppState::new(std::env::temp_dir());
+            group
+                .handle_event(event, network.client.clone(), state, sender.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(group.finish_ping(&ping_id).await, HashSet::from([member]));
+        assert!(group.finish_ping(&ping_id).await.is_empty());
+        // Pongs aren't chat messages.
+        let history = group.get_group_state(&group_id).await.unwrap().history;
+        assert!(history.is_empty());
+        assert!(frontend.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replaced_roster_drops_stale_subscribers() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        group.add_group(group_id.clone(), group_info()).await;
+        let [stale, member] = [(); 2].map(|_| PeerId::random());
+        group.add_subscribe(&group_id, stale).await;
+        group.add_subscribe(&group_id, member).await;
+        group
+            .replace_subscribers(&group_id, HashSet::from([member]))
+            .await;
+        let subscribers = group.get_group_state(&group_id).await.unwrap().subscribers;
+        assert_eq!(subscribers, HashSet::from([member]));
+        // Groups we left stay gone.
+        let left = GroupId::new();
+        group
+            .replace_subscribers(&left, HashSet::from([member]))
+            .await;
+        assert!(group.get_group_state(&left).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_quiet_joined_groups_are_inactive() {
+        let group = GroupManager::new();
+        let keypair = Keypair::generate_ed25519();
+        let local = keypair.public().to_peer_id();
+        let [quiet, active, not_joined] = [(); 3].map(|_| GroupId::new());
+        for group_id in [&quiet, &active, &not_joined] {
+            group.add_group(group_id.clone(), group_info()).await;
+        }
+        group.add_subscribe(&quiet, local).await;
+        group.add_subscribe(&active, local).await;
+        group.add_subscribe(&not_joined, PeerId::random()).await;
+        let since = Utc::now().timestamp() - 60;
+        for group_id in [&quiet, &active, &not_joined] {
+            group
+                .last_activity
+                .lock()
+                .await
+                .insert(group_id.clone(), since - 1);
+        }
+        group
+            .add_message(&active, signed(&keypair, "hi", 1), None)
+            .await;
+        assert_eq!(group.inactive_groups(&local, since).await, vec![quiet]);
+    }
+
+    fn owned_by(owner: PeerId) -> GroupInfo {
+        GroupInfo {
+            roles: [(owner, Role::Owner)].into(),
+            ..group_info()
+        }
+    }
+
+    fn signed(keypair: &Keypair, text: &str, seq: u64) -> GroupMessage {
+        let mut message = GroupMessage::new(
+            Message::Text(text.to_string()),
+            keypair.public().to_peer_id(),
+        );
+        message.seq = Some(seq);
+        message.sign(keypair);
+        message
+    }
+
+    #[tokio::test]
+    async fn group_keys_rotate_out_oldest() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let owner = PeerId::random();
+        group.add_group(group_id.clone(), owned_by(owner)).await;
+        let rotations = MAX_GROUP_KEYS as u32 + 2;
+        for id in 1..=rotations {
+            assert!(group
+                .add_group_key(&group_id, &owner, GroupKey::generate(id))
+                .await
+                .is_some());
+        }
+        let keys = group.get_group_info(&group_id).await.unwrap().keys;
+        assert_eq!(keys.len(), MAX_GROUP_KEYS);
+        assert_eq!(keys.first().unwrap().id, 3);
+        assert_eq!(keys.last().unwrap().id, rotations);
+    }
+
+    #[tokio::test]
+    async fn group_keys_need_a_newer_id_and_an_admin() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let owner = PeerId::random();
+        group.add_group(group_id.clone(), owned_by(owner)).await;
+        assert!(group
+            .add_group_key(&group_id, &owner, GroupKey::generate(2))
+            .await
+            .is_some());
+        assert!(group
+            .add_group_key(&group_id, &owner, GroupKey::generate(2))
+            .await
+            .is_none());
+        assert!(group
+            .add_group_key(&group_id, &PeerId::random(), GroupKey::generate(3))
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn trim_history_keeps_the_newest() {
+        let keypair = Keypair::generate_ed25519();
+        let mut history = (1..=3)
+            .map(|seq| signed(&keypair, "a", seq))
+            .collect::<Vec<_>>();
+        assert!(!trim_history(&mut history, None));
+        assert!(!trim_history(&mut history, Some(3)));
+        assert!(trim_history(&mut history, Some(2)));
+        assert_eq!(
+            history.iter().map(|m| m.seq).collect::<Vec<_>>(),
+            vec![Some(2), Some(3)]
+        );
+        assert!(trim_history(&mut history, Some(0)));
+        assert!(history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_log_is_compacted() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        let group = GroupManager::new();
+        group.load_history(&dir, Some(2)).await.unwrap();
+        let group_id = GroupId::new();
+        let keypair = Keypair::generate_ed25519();
+        group
+            .add_group(group_id.clone(), owned_by(keypair.public().to_peer_id()))
+            .await;
+        for seq in 1..=10 {
+            let message = signed(&keypair, "a", seq);
+            assert!(group
+                .add_message(&group_id, message, Some(2))
+                .await
+                .is_some());
+        }
+        let log = GroupManager::history_path(&dir).join(format!("{group_id}.jsonl"));
+        let lines = fs::read_to_string(&log).await.unwrap().lines().count();
+        assert!(lines <= 4, "{lines} lines");
+
+        let reloaded = GroupManager::new();
+        reloaded.load_history(&dir, Some(2)).await.unwrap();
+        reloaded
+            .add_group(group_id.clone(), owned_by(keypair.public().to_peer_id()))
+            .await;
+        let history = reloaded.get_group_state(&group_id).await.unwrap().history;
+        assert_eq!(
+            history.iter().map(|m| m.seq).collect::<Vec<_>>(),
+            vec![Some(9), Some(10)]
+        );
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+    #[tokio::test]
+    async fn discarded_failures_leave_the_history_log() {
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        let group = GroupManager::new();
+        group.load_history(&dir, None).await.unwrap();
+        let group_id = GroupId::new();
+        group.add_group(group_id.clone(), group_info()).await;
+        let keypair = Keypair::generate_ed25519();
+        let [sent, failed] = [1, 2].map(|seq| signed(&keypair, "a", seq));
+        for message in [&sent, &failed] {
+            group.add_message(&group_id, message.clone(), None).await;
+        }
+        group.mark_failed(&group_id, failed.id.clone()).await;
+        assert_eq!(group.discard_failed(&group_id).await, Some(1));
+        assert_eq!(group.discard_failed(&group_id).await, Some(0));
+
+        let reloaded = GroupManager::new();
+        reloaded.load_history(&dir, None).await.unwrap();
+        reloaded.add_group(group_id.clone(), group_info()).await;
+        let history = reloaded.get_group_state(&group_id).await.unwrap().history;
+        assert_eq!(
+            history.iter().map(|m| &m.id).collect::<Vec<_>>(),
+            vec![&sent.id]
+        );
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sequence_gaps_per_author() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let alice = Keypair::generate_ed25519();
+        let bob = Keypair::generate_ed25519();
+        group
+            .add_group(group_id.clone(), owned_by(alice.public().to_peer_id()))
+            .await;
+        for message in [
+            signed(&alice, "a", 1),
+            signed(&alice, "b", 2),
+            signed(&alice, "c", 5),
+            signed(&bob, "d", 3),
+            signed(&bob, "e", 4),
+        ] {
+            assert!(group.add_message(&group_id, message, None).await.is_some());
+        }
+        let gaps = group.sequence_gaps(&group_id).await.unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[&alice.public().to_peer_id()], vec![3, 4]);
+        assert_eq!(group.sequence_gaps(&GroupId::new()).await, None);
+    }
+
+    #[tokio::test]
+    async fn identical_texts_get_their_own_ids() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let keypair = Keypair::generate_ed25519();
+        group
+            .add_group(group_id.clone(), owned_by(keypair.public().to_peer_id()))
+            .await;
+        let first = signed(&keypair, "same", 1);
+        let second = signed(&keypair, "same", 2);
+        assert_ne!(first.id, second.id);
+        assert!(group
+            .add_message(&group_id, first.clone(), None)
+            .await
+            .is_some());
+        assert!(group.add_message(&group_id, second, None).await.is_some());
+        assert!(group.add_message(&group_id, first, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn history_trims_oldest() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let keypair = Keypair::generate_ed25519();
+        group
+            .add_group(group_id.clone(), owned_by(keypair.public().to_peer_id()))
+            .await;
+        let first = signed(&keypair, "a", 1);
+        for message in [
+            first.clone(),
+            signed(&keypair, "b", 2),
+            signed(&keypair, "c", 3),
+        ] {
+            group.add_message(&group_id, message, Some(2)).await;
+        }
+        let state = group.get_group_state(&group_id).await.unwrap();
+        assert_eq!(state.history.len(), 2);
+        assert_eq!(state.history[0].seq, Some(2));
+        // Trimmed messages can come back, such as when resynced.
+        assert!(group.add_message(&group_id, first, Some(2)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn released_seq_is_reused() {
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        let author = PeerId::random();
+        assert_eq!(group.next_seq(&group_id, &author).await, 1);
+        assert_eq!(group.next_seq(&group_id, &author).await, 2);
+        group.release_seq(&group_id, 1).await;
+        assert_eq!(group.next_seq(&group_id, &author).await, 3);
+        group.release_seq(&group_id, 3).await;
+        assert_eq!(group.next_seq(&group_id, &author).await, 3);
+    }
+
+    #[tokio::test]
+    async fn resynced_edits_are_checked() {
+        let keypair = Keypair::generate_ed25519();
+        let author = keypair.public().to_peer_id();
+        let mut message = signed(&keypair, "hello", 1);
+        let mut edit = GroupMessage::new(
+            Message::Edit {
+                target: message.id.clone(),
+                new_content: "bye".to_string(),
+            },
+            author,
+        );
+        edit.sign(&keypair);
+        assert!(message.revise(&edit));
+
+        let group = GroupManager::new();
+        let group_id = GroupId::new();
+        group.add_group(group_id.clone(), owned_by(author)).await;
+        let mut forged = message.clone();
+        forged.revision = None;
+        assert!(group.add_message(&group_id, forged, None).await.is_none());
+        let mut forged = message.clone();
+        forged.revision.as_mut().unwrap().content_hash = "0".repeat(64);
+        assert!(group.add_message(&group_id, forged, None).await.is_none());
+        let mut forged = message.clone();
+        forged.message = Message::Text("evil".to_string());
+        let added = group.add_message(&group_id, forged, None).await.unwrap();
+        assert_eq!(added.message, Message::Text("bye".to_string()));
+        assert_eq!(added.id, message.id);
+    }
+}
@@ -0,0 +1,186 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError},
+    models::PeerLatency,
+    network::{message::InboundEvent, Client},
+};
+use async_trait::async_trait;
+use futures::future::join_all;
+use libp2p::PeerId;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Mutex, Notify},
+    time,
+};
+
+#[derive(Debug, Clone)]
+pub struct LatencyManager {
+    latencies: Arc<Mutex<HashMap<PeerId, PeerLatency>>>,
+    /// Woken whenever a ping completes.
+    updated: Arc<Notify>,
+}
+
+impl LatencyManager {
+    pub fn new() -> Self {
+        Self {
+            latencies: Arc::new(Mutex::new(HashMap::new())),
+            updated: Arc::new(Notify::new()),
+        }
+    }
+    /// `None` for peers we're not connected to or haven't pinged yet.
+    pub async fn get_latency(&self, peer_id: &PeerId) -> Option<PeerLatency> {
+        self.latencies.lock().await.get(peer_id).copied()
+    }
+    pub async fn get_latencies(&self) -> HashMap<PeerId, PeerLatency> {
+        self.latencies.lock().await.clone()
+    }
+    /// The latest round trip to each of `peers`, waiting up to `timeout`
+    /// for the first ping of those we haven't pinged yet. `None` for peers
+    /// whose last ping failed or that weren't pinged in time.
+    pub async fn latest_rtts(
+        &self,
+        peers: impl IntoIterator<Item = PeerId>,
+        timeout: Duration,
+    ) -> HashMap<PeerId, Option<Duration>> {
+        let rtts = peers.into_iter().map(|peer_id| async move {
+            let rtt = time::timeout(timeout, self.wait_for_latency(&peer_id))
+                .await
+                .ok()
+                .and_then(|latency| latency.latest_ms.filter(|_| latency.failures == 0))
+                .map(|ms| Duration::from_secs_f64(ms / 1000.0));
+            (peer_id, rtt)
+        });
+        join_all(rtts).await.into_iter().collect()
+    }
+    async fn wait_for_latency(&self, peer_id: &PeerId) -> PeerLatency {
+        loop {
+            // Created before the lookup so a ping completing in between
+            // still wakes us.
+            let updated = self.updated.notified();
+            if let Some(latency) = self.get_latency(peer_id).await {
+                return latency;
+            }
+            updated.await;
+        }
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for LatencyManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        _client: Client,
+        _state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::PingResult { peer_id, rtt } => {
+                let latency = {
+                    let mut latencies = self.latencies.lock().await;
+                    let latency = latencies.entry(peer_id).or_default();
+                    match rtt {
+                        Ok(rtt) => latency.record(rtt),
+                        Err(e) => {
+                            log::debug!("ping to {peer_id} failed: {e}");
+                            latency.failures += 1;
+                        }
+                    }
+                    *latency
+                };
+                self.updated.notify_waiters();
+                let _ = sender
+                    .send(FrontendEvent::LatencyUpdate { peer_id, latency })
+                    .await;
+            }
+            // Measured again from scratch on the next connection.
+            InboundEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.latencies.lock().await.remove(&peer_id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for LatencyManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "get_latency" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                serde_json::to_value(self.get_latency(&peer_id).await)?
+            }
+            "get_latencies" => serde_json::to_value(self.get_latencies().await)?,
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for LatencyManager {
+    fn name(&self) -> &'static str {
+        "latency"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Setting, network};
+    use libp2p::identity::Keypair;
+
+    #[tokio::test]
+    async fn rtts_come_from_the_latest_pings() {
+        let network = network::new(Keypair::generate_ed25519(), &Setting::default()).unwrap();
+        let (sender, _frontend) = mpsc::channel(16);
+        let latency = LatencyManager::new();
+        let [fast, slow, failed, late, silent] = [(); 5].map(|_| PeerId::random());
+        let ping = |peer_id, rtt| {
+            let mut latency = latency.clone();
+            let client = network.client.clone();
+            let sender = sender.clone();
+            async move {
+                let event = InboundEvent::PingResult { peer_id, rtt };
+                let state = AppState::new(std::env::temp_dir());
+                latency
+                    .handle_event(event, client, state, sender)
+                    .await
+                    .unwrap();
+            }
+        };
+        ping(fast, Ok(Duration::from_millis(20))).await;
+        ping(slow, Ok(Duration::from_millis(80))).await;
+        ping(failed, Ok(Duration::from_millis(20))).await;
+        ping(failed, Err("timeout".to_string())).await;
+
+        let late_ping = async {
+            time::sleep(Duration::from_millis(50)).await;
+            ping(late, Ok(Duration::from_millis(40))).await;
+        };
+        let (rtts, ()) = tokio::join!(
+            latency.latest_rtts(
+                [fast, slow, failed, late, silent],
+                Duration::from_millis(500)
+            ),
+            late_ping
+        );
+        let ms = |peer_id| rtts[&peer_id].map(|rtt: Duration| rtt.as_millis());
+        assert_eq!(ms(fast), Some(20));
+        assert_eq!(ms(slow), Some(80));
+        assert_eq!(ms(failed), None);
+        assert_eq!(ms(late), Some(40));
+        assert_eq!(ms(silent), None);
+        let reported = latency.get_latency(&slow).await.unwrap().latest_ms;
+        assert_eq!(rtts[&slow].map(|rtt| rtt.as_secs_f64() * 1000.0), reported);
+    }
+}
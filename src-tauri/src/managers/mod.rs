@@ -1,5 +1,9 @@
-mod file;
+pub mod direct;
+pub mod file;
 pub mod group;
+pub mod latency;
+pub mod presence;
+pub mod reputation;
 pub mod user;
 
 use crate::{
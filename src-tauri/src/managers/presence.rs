@@ -0,0 +1,203 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError},
+    models::Presence,
+    network::{message::InboundEvent, Client},
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{self, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+struct PeerPresence {
+    presence: Presence,
+    last_active: Instant,
+    /// We have a connection to the peer, rather than only hearing from it
+    /// through the mesh.
+    connected: bool,
+    /// An Away check is already scheduled.
+    scheduled: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PresenceManager {
+    peers: Arc<Mutex<HashMap<PeerId, PeerPresence>>>,
+}
+
+impl PresenceManager {
+    pub fn new() -> Self {
+        Self {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    /// Offline for peers we never saw.
+    pub async fn get_presence(&self, peer_id: &PeerId) -> Presence {
+        self.peers
+            .lock()
+            .await
+            .get(peer_id)
+            .map_or(Presence::Offline, |peer| peer.presence)
+    }
+    pub async fn get_presences(&self) -> HashMap<PeerId, Presence> {
+        self.peers
+            .lock()
+            .await
+            .iter()
+            .map(|(peer_id, peer)| (*peer_id, peer.presence))
+            .collect()
+    }
+    /// Mark the peer online and active now, and connected if `connected`.
+    /// It goes Away once `Setting::away_after` passes without further
+    /// activity, or Offline if we only hear from it through the mesh,
+    /// since nothing would tell us when it leaves.
+    async fn record_activity(
+        &self,
+        peer_id: PeerId,
+        connected: bool,
+        state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        let now = Instant::now();
+        let (previous, schedule) = {
+            let mut peers = self.peers.lock().await;
+            let peer = peers.entry(peer_id).or_insert(PeerPresence {
+                presence: Presence::Offline,
+                last_active: now,
+                connected: false,
+                scheduled: false,
+            });
+            let previous = peer.presence;
+            peer.presence = Presence::Online;
+            peer.last_active = now;
+            peer.connected |= connected;
+            let schedule = !peer.scheduled;
+            peer.scheduled = true;
+            (previous, schedule)
+        };
+        if previous != Presence::Online {
+            let _ = sender
+                .send(FrontendEvent::PresenceUpdate {
+                    peer_id,
+                    presence: Presence::Online,
+                })
+                .await;
+        }
+        if schedule {
+            // One check per peer, pushed back while it stays active, rather
+            // than a timer for every message.
+            let tasks = state.tasks.clone();
+            tasks.spawn_cancellable(self.clone().mark_away(peer_id, state, sender));
+        }
+    }
+    /// Wait for the peer to go `away_after` without activity, read anew
+    /// each time so a changed setting applies to peers already online.
+    async fn mark_away(
+        self,
+        peer_id: PeerId,
+        state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        let presence = loop {
+            let away_after = Duration::from_secs(state.setting.lock().await.away_after);
+            let mut peers = self.peers.lock().await;
+            let Some(peer) = peers.get_mut(&peer_id) else {
+                return;
+            };
+            if peer.presence != Presence::Online {
+                peer.scheduled = false;
+                return;
+            }
+            let deadline = peer.last_active + away_after;
+            if deadline > Instant::now() {
+                drop(peers);
+                time::sleep_until(deadline).await;
+                continue;
+            }
+            peer.presence = if peer.connected {
+                Presence::Away
+            } else {
+                Presence::Offline
+            };
+            peer.scheduled = false;
+            break peer.presence;
+        };
+        let _ = sender
+            .send(FrontendEvent::PresenceUpdate { peer_id, presence })
+            .await;
+    }
+    async fn set_offline(&self, peer_id: PeerId, sender: mpsc::Sender<FrontendEvent>) {
+        let changed = match self.peers.lock().await.get_mut(&peer_id) {
+            Some(peer) => {
+                peer.connected = false;
+                std::mem::replace(&mut peer.presence, Presence::Offline) != Presence::Offline
+            }
+            None => false,
+        };
+        if changed {
+            let _ = sender
+                .send(FrontendEvent::PresenceUpdate {
+                    peer_id,
+                    presence: Presence::Offline,
+                })
+                .await;
+        }
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for PresenceManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        client: Client,
+        state: AppState,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::ConnectionEstablished { peer_id, .. } => {
+                self.record_activity(peer_id, true, state, sender).await;
+            }
+            InboundEvent::Message { message, .. } if message.source != client.local_peer_id() => {
+                self.record_activity(message.source, false, state, sender)
+                    .await;
+            }
+            InboundEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => self.set_offline(peer_id, sender).await,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for PresenceManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "get_presence" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                serde_json::to_value(self.get_presence(&peer_id).await)?
+            }
+            "get_presences" => serde_json::to_value(self.get_presences().await)?,
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for PresenceManager {
+    fn name(&self) -> &'static str {
+        "presence"
+    }
+}
@@ -0,0 +1,171 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError},
+    models::{smoothed_rtt, PeerReputation},
+    network::{message::InboundEvent, Client},
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs, io,
+    sync::{mpsc, Mutex},
+};
+
+/// How long changes are collected before reputation.json is rewritten.
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ReputationManager {
+    reputations: Arc<Mutex<HashMap<PeerId, PeerReputation>>>,
+    /// Whether a save is already scheduled.
+    saving: Arc<Mutex<bool>>,
+}
+
+impl ReputationManager {
+    pub fn new() -> Self {
+        Self {
+            reputations: Arc::new(Mutex::new(HashMap::new())),
+            saving: Arc::new(Mutex::new(false)),
+        }
+    }
+    fn file_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join("reputation.json")
+    }
+    /// Load persisted reputations, decaying them for the time spent offline.
+    pub async fn load<P: AsRef<Path>>(&self, dir: P) -> Result<(), io::Error> {
+        let buf = match fs::read(Self::file_path(dir)).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut reputations: HashMap<PeerId, PeerReputation> = serde_json::from_slice(&buf)?;
+        let now = Utc::now().timestamp();
+        reputations.values_mut().for_each(|r| r.decay(now));
+        *self.reputations.lock().await = reputations;
+        Ok(())
+    }
+    pub async fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), io::Error> {
+        let buf = serde_json::to_vec(&*self.reputations.lock().await)?;
+        fs::create_dir_all(&dir).await?;
+        let path = Self::file_path(&dir);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, buf).await?;
+        fs::rename(&tmp, &path).await
+    }
+    /// Save after `SAVE_DEBOUNCE`, so a burst of connections and pings
+    /// rewrites the file once.
+    async fn schedule_save(&self, state: AppState) {
+        if !state.is_persistent() {
+            return;
+        }
+        {
+            let mut saving = self.saving.lock().await;
+            if *saving {
+                return;
+            }
+            *saving = true;
+        }
+        let reputation = self.clone();
+        let tasks = state.tasks.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(SAVE_DEBOUNCE).await;
+            *reputation.saving.lock().await = false;
+            if let Err(e) = reputation.save(&state.config_dir).await {
+                log::error!("failed to save peer reputation: {e}");
+            }
+        });
+    }
+    async fn update(&self, peer_id: PeerId, f: impl FnOnce(&mut PeerReputation)) {
+        let now = Utc::now().timestamp();
+        let mut reputations = self.reputations.lock().await;
+        let reputation = reputations
+            .entry(peer_id)
+            .or_insert_with(|| PeerReputation::new(now));
+        reputation.decay(now);
+        reputation.last_seen = now;
+        f(reputation);
+    }
+    pub async fn get_reputation(&self, peer_id: &PeerId) -> Option<PeerReputation> {
+        self.reputations.lock().await.get(peer_id).cloned()
+    }
+    pub async fn get_reputations(&self) -> HashMap<PeerId, PeerReputation> {
+        self.reputations.lock().await.clone()
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for ReputationManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        _client: Client,
+        state: AppState,
+        _sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::ConnectionEstablished { peer_id, .. } => {
+                self.update(peer_id, |_| {}).await;
+            }
+            InboundEvent::PingResult {
+                peer_id,
+                rtt: Ok(rtt),
+            } => {
+                let rtt_ms = rtt.as_secs_f64() * 1000.0;
+                self.update(peer_id, |r| {
+                    r.avg_rtt_ms = Some(smoothed_rtt(r.avg_rtt_ms, rtt_ms))
+                })
+                .await;
+            }
+            InboundEvent::PeerOutcome { peer_id, success } => {
+                self.update(peer_id, |r| {
+                    if success {
+                        r.successes += 1.0;
+                    } else {
+                        r.failures += 1.0;
+                    }
+                })
+                .await;
+            }
+            _ => return Ok(()),
+        }
+        self.schedule_save(state).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for ReputationManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "get_reputation" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                serde_json::to_value(
+                    self.get_reputation(&peer_id)
+                        .await
+                        .ok_or(ManagerError::PeerNotExist(peer_id))?,
+                )?
+            }
+            "get_reputations" => serde_json::to_value(self.get_reputations().await)?,
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for ReputationManager {
+    fn name(&self) -> &'static str {
+        "reputation"
+    }
+}
@@ -1,10 +1,10 @@
 use super::{AppManager, HandleInboundEvent, Invoke};
 use crate::{
-    chat_app::{frontend_event::FrontendEvent, AppState},
+    chat_app::{frontend_event::FrontendEvent, tasks::Tasks, AppState},
     error::{ManagerError, NetworkError},
     models::{UserInfo, UserState},
     network::{
-        message::{InboundEvent, Request, Response},
+        message::{InboundEvent, Message, Request, Response},
         Client,
     },
 };
@@ -13,13 +13,31 @@ use libp2p::{gossipsub::TopicHash, PeerId};
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::Duration,
 };
-use tokio::sync::{mpsc, Mutex};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+
+/// How long we wait before asking a peer that didn't answer who it is again.
+const USER_REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `user_info` from a peer, without an avatar the user couldn't have set.
+fn checked(peer_id: &PeerId, mut user_info: UserInfo) -> UserInfo {
+    if let Err(e) = user_info.check_avatar() {
+        log::warn!("dropping avatar of {peer_id}: {e}");
+        user_info.avatar = None;
+    }
+    user_info
+}
 
 #[derive(Debug, Clone)]
 pub struct UserManager {
     users: Arc<Mutex<HashMap<PeerId, UserInfo>>>,
     user_subscribe: Arc<Mutex<HashMap<PeerId, HashSet<TopicHash>>>>,
+    /// Unknown authors we asked for their user info, and when.
+    requested: Arc<Mutex<HashMap<PeerId, Instant>>>,
 }
 
 impl UserManager {
@@ -27,6 +45,7 @@ impl UserManager {
         Self {
             users: Arc::new(Mutex::new(HashMap::new())),
             user_subscribe: Arc::new(Mutex::new(HashMap::new())),
+            requested: Arc::new(Mutex::new(HashMap::new())),
         }
     }
     pub async fn add_user(&self, peer_id: PeerId, user_info: UserInfo) {
@@ -75,6 +94,42 @@ impl UserManager {
     pub async fn get_users(&self) -> HashMap<PeerId, UserInfo> {
         self.users.lock().await.clone()
     }
+    /// Ask `peer_id` for its user info in the background, at most once per
+    /// `USER_REQUEST_INTERVAL`. Peers that refuse or don't answer stay
+    /// unknown and are shown by their id.
+    async fn request_user_info(
+        &self,
+        peer_id: PeerId,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+        tasks: &Tasks,
+    ) {
+        {
+            let mut requested = self.requested.lock().await;
+            if requested
+                .get(&peer_id)
+                .map_or(false, |at| at.elapsed() < USER_REQUEST_INTERVAL)
+            {
+                return;
+            }
+            requested.insert(peer_id, Instant::now());
+        }
+        let user = self.clone();
+        tasks.spawn(async move {
+            match client.request(peer_id, Request::User(peer_id)).await {
+                Ok(Response::User(user_info)) => {
+                    let user_info = checked(&peer_id, user_info);
+                    user.requested.lock().await.remove(&peer_id);
+                    user.add_user(peer_id, user_info.clone()).await;
+                    let _ = sender
+                        .send(FrontendEvent::UserUpdate { peer_id, user_info })
+                        .await;
+                }
+                Ok(_) => log::debug!("unexpected response to user request from {peer_id}"),
+                Err(e) => log::debug!("failed to get user info of {peer_id}: {e}"),
+            }
+        });
+    }
 }
 
 #[async_trait]
@@ -87,11 +142,13 @@ impl HandleInboundEvent for UserManager {
         sender: mpsc::Sender<FrontendEvent>,
     ) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => {
+            InboundEvent::InboundRequest {
+                request, channel, ..
+            } => {
                 if let Request::User(peer_id) = request {
                     if let Some(user_info) = self.get_user_info(&peer_id).await {
                         if let Some(channel) = channel.lock().await.take() {
-                            client.response(Response::User(user_info), channel).await;
+                            client.response(Response::User(user_info), channel).await?;
                         }
                     } else if peer_id == client.local_peer_id() {
                         if let Some(channel) = channel.lock().await.take() {
@@ -100,7 +157,7 @@ impl HandleInboundEvent for UserManager {
                                     Response::User(state.local_user.lock().await.clone().into()),
                                     channel,
                                 )
-                                .await;
+                                .await?;
                         }
                     }
                 }
@@ -109,6 +166,7 @@ impl HandleInboundEvent for UserManager {
                 if !self.has_user(&peer_id).await {
                     match client.request(peer_id, Request::User(peer_id)).await {
                         Ok(Response::User(user_info)) => {
+                            let user_info = checked(&peer_id, user_info);
                             self.add_user(peer_id, user_info.clone()).await;
                             sender
                                 .send(FrontendEvent::UserUpdate { peer_id, user_info })
@@ -138,7 +196,9 @@ impl HandleInboundEvent for UserManager {
                 if !self.has_user(&peer_id).await {
                     let (peer_id, user_info) = if peer_id != client.local_peer_id() {
                         match client.request(peer_id, Request::User(peer_id)).await {
-                            Ok(Response::User(user_info)) => (peer_id, user_info),
+                            Ok(Response::User(user_info)) => {
+                                (peer_id, checked(&peer_id, user_info))
+                            }
                             _ => return Err(anyhow::anyhow!("user not found").into()),
                         }
                     } else {
@@ -158,6 +218,45 @@ impl HandleInboundEvent for UserManager {
             InboundEvent::Unsubscribed { peer_id, topic } => {
                 self.remove_subscribe(&peer_id, &topic).await;
             }
+            InboundEvent::Message { message, .. } if message.source != client.local_peer_id() => {
+                let peer_id = message.source;
+                match message.message {
+                    Message::UserInfo {
+                        user_info,
+                        avatar_omitted,
+                    } => {
+                        let mut user_info = checked(&peer_id, user_info);
+                        user_info.status = UserState::Online;
+                        let known = self.get_user_info(&peer_id).await;
+                        match &known {
+                            Some(known) if avatar_omitted => {
+                                user_info.avatar = known.avatar.clone();
+                            }
+                            // We never got the avatar, ask for all of it.
+                            None if avatar_omitted => {
+                                self.request_user_info(peer_id, client, sender, &state.tasks)
+                                    .await;
+                                return Ok(());
+                            }
+                            _ => {}
+                        }
+                        if known.as_ref() != Some(&user_info) {
+                            self.add_user(peer_id, user_info.clone()).await;
+                            sender
+                                .send(FrontendEvent::UserUpdate { peer_id, user_info })
+                                .await
+                                .unwrap();
+                        }
+                    }
+                    // An author we never saw subscribe, e.g. one whose messages
+                    // reach us through the mesh only.
+                    _ if !self.has_user(&peer_id).await => {
+                        self.request_user_info(peer_id, client, sender, &state.tasks)
+                            .await;
+                    }
+                    _ => {}
+                }
+            }
             _ => {}
         }
 
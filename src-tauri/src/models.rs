@@ -1,16 +1,30 @@
 use crate::{
     error::{SettingError, SettingErrorKind},
-    network::message::Message,
+    network::message::{Message, Poll, MAX_CLOCK_SKEW, MAX_FILE_CHUNK_SIZE},
 };
-use chrono::Utc;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use chrono::{Local, NaiveTime, Utc};
 use derive_more::Display;
-use libp2p::{gossipsub::Sha256Topic, PeerId};
+use libp2p::{
+    gossipsub::{Sha256Topic, TopicHash},
+    identity::{Keypair, PublicKey},
+    multiaddr::Protocol,
+    multihash::{Code, Hasher, MultihashDigest, Sha2_256},
+    Multiaddr, PeerId,
+};
 use mediatype::MediaTypeBuf;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
     hash::Hash,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 use tokio::{
     fs,
@@ -25,12 +39,15 @@ pub struct FileInfo {
     pub name: String,
     pub size: u64,
     pub file_type: Option<MediaTypeBuf>,
+    /// Hex SHA-256 of the content, computed when the file is provided.
     pub hash: Option<String>,
 }
 
+/// Files are told apart by name and content hash, so different files
+/// shared under the same name don't shadow each other.
 impl PartialEq for FileInfo {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.name == other.name && self.hash == other.hash
     }
 }
 
@@ -39,6 +56,7 @@ impl Eq for FileInfo {}
 impl Hash for FileInfo {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.name.hash(state);
+        self.hash.hash(state);
     }
 }
 
@@ -57,9 +75,66 @@ impl FileInfo {
                 .to_string(),
             size: data.len(),
             file_type,
-            hash: None,
+            hash: Some(Self::hash_file(path).await?),
         })
     }
+    /// Hex SHA-256 of the file at `path`, read in pieces rather than at once.
+    pub async fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+        let mut file = fs::File::open(path).await?;
+        let mut hasher = Sha2_256::default();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+}
+
+/// The files of a shared directory. Names are paths relative to the
+/// directory's parent, separated by `/`, so the tree can be recreated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub name: String,
+    pub hash: String,
+    pub files: Vec<FileInfo>,
+    /// Directories without any files or subdirectories, which would
+    /// otherwise be lost.
+    pub empty_dirs: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(name: String, mut files: Vec<FileInfo>, mut empty_dirs: Vec<String>) -> Self {
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        empty_dirs.sort();
+        let mut manifest = Self {
+            name,
+            hash: String::new(),
+            files,
+            empty_dirs,
+        };
+        manifest.hash = manifest.compute_hash();
+        manifest
+    }
+    /// Hex SHA-256 over the whole listing, including each file's hash.
+    pub fn compute_hash(&self) -> String {
+        let data = serde_json::to_vec(&(&self.name, &self.files, &self.empty_dirs))
+            .expect("manifest to serialize");
+        Code::Sha2_256
+            .digest(&data)
+            .digest()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,17 +142,273 @@ impl FileInfo {
 pub struct Setting {
     pub recv_path: PathBuf,
     pub user_info: UserInfo,
+    #[serde(default)]
+    pub connection_mode: ConnectionMode,
+    #[serde(default)]
+    pub allowed: HashSet<PeerId>,
+    /// Peers refused in every connection mode.
+    #[serde(default)]
+    pub blocked: HashSet<PeerId>,
+    /// Local-only labels for organizing groups, never broadcast.
+    #[serde(default)]
+    pub group_tags: HashMap<GroupId, Vec<String>>,
+    #[serde(default)]
+    pub gossipsub: GossipsubSetting,
+    /// How many managers may handle the same inbound event at once. Read at
+    /// startup.
+    #[serde(default = "default_manager_concurrency")]
+    pub manager_concurrency: usize,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// Per-group relay TTL in seconds for messages we publish.
+    #[serde(default)]
+    pub group_ttl: HashMap<GroupId, u64>,
+    /// Pinned groups, listed in this order before all others.
+    #[serde(default)]
+    pub group_order: Vec<GroupId>,
+    #[serde(default)]
+    pub theme: Theme,
+    /// Only learn about these groups from other peers' subscriptions, or
+    /// any group when unset.
+    #[serde(default)]
+    pub allowed_groups: Option<HashSet<GroupId>>,
+    /// Leave groups without messages for this many seconds, unless pinned.
+    #[serde(default)]
+    pub auto_leave_after: Option<u64>,
+    /// Bytes requested per chunk when downloading a file.
+    #[serde(default = "default_file_chunk_size")]
+    pub file_chunk_size: u32,
+    /// Seconds to wait for the network before a command fails, applied
+    /// on the next start.
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    /// Per-command timeouts in seconds overriding `command_timeout`, keyed by
+    /// the command, e.g. `dial`, `request` or `publish`.
+    #[serde(default)]
+    pub command_timeouts: HashMap<String, u64>,
+    /// Keep at most this many messages per group, or all when unset.
+    #[serde(default)]
+    pub history_retention: Option<usize>,
+    /// The last relay we held a reservation on, tried first at startup.
+    #[serde(default)]
+    pub relay: Option<Multiaddr>,
+    /// Relays to try in order at startup if `relay` is unset or refuses.
+    #[serde(default)]
+    pub relays: Vec<Multiaddr>,
+    /// When queued messages are given up on, applied on the next start.
+    #[serde(default)]
+    pub outbox: OutboxSetting,
+    /// What to do with direct messages from peers we share no group with.
+    #[serde(default)]
+    pub dm_policy: DirectMessagePolicy,
+    #[serde(default)]
+    pub history_order: HistoryOrder,
+    /// Ask members to acknowledge the group messages we publish.
+    #[serde(default)]
+    pub message_acks: bool,
+    /// Seconds without activity before a connected peer shows as away.
+    #[serde(default = "default_away_after")]
+    pub away_after: u64,
+    /// Milliseconds to collect a group's messages into one `messages-batch`
+    /// event, or 0 to emit each as its own `message` event.
+    #[serde(default = "default_message_batch_window")]
+    pub message_batch_window: u64,
+    /// Where the identity keypair is kept, applied on the next start.
+    #[serde(default)]
+    pub identity_path: Option<PathBuf>,
+    /// Seconds between `metrics-update` events, or 0 for none.
+    #[serde(default = "default_metrics_interval")]
+    pub metrics_interval: u64,
+}
+
+fn default_manager_concurrency() -> usize {
+    4
+}
+
+fn default_file_chunk_size() -> u32 {
+    256 * 1024
+}
+
+fn default_command_timeout() -> u64 {
+    60
+}
+
+fn default_away_after() -> u64 {
+    5 * 60
+}
+
+fn default_message_batch_window() -> u64 {
+    50
+}
+
+fn default_metrics_interval() -> u64 {
+    5
+}
+
+/// A daily local time window during which notifications are suppressed.
+/// `end` may be earlier than `start` for windows spanning midnight.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Gossipsub tuning, applied when the network behaviour is built.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GossipsubSetting {
+    /// Number of heartbeats messages are kept in the cache.
+    pub history_length: usize,
+    /// Number of past heartbeats whose messages are advertised in gossip.
+    pub history_gossip: usize,
+    /// Fraction of peers outside the mesh that gossip is sent to.
+    pub gossip_factor: f64,
+}
+
+impl GossipsubSetting {
+    pub fn validate(&self) -> Result<(), SettingError> {
+        let mut kinds = Vec::new();
+        if self.history_gossip == 0 || self.history_gossip > self.history_length {
+            kinds.push(SettingErrorKind::InvalidValue(format!(
+                "history gossip {} must be between 1 and history length {}",
+                self.history_gossip, self.history_length
+            )));
+        }
+        if !(self.gossip_factor > 0.0 && self.gossip_factor <= 1.0) {
+            kinds.push(SettingErrorKind::InvalidValue(format!(
+                "gossip factor {} must be in (0, 1]",
+                self.gossip_factor
+            )));
+        }
+        if !kinds.is_empty() {
+            return Err(SettingError::new(kinds));
+        }
+        if self.history_length > 60 {
+            log::warn!(
+                "gossipsub history length {} keeps a large message cache",
+                self.history_length
+            );
+        }
+        if self.gossip_factor > 0.75 {
+            log::warn!(
+                "gossipsub gossip factor {} will use a lot of bandwidth",
+                self.gossip_factor
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for GossipsubSetting {
+    fn default() -> Self {
+        Self {
+            history_length: 5,
+            history_gossip: 3,
+            gossip_factor: 0.25,
+        }
+    }
+}
+
+/// Limits on how long a message may wait in an outbox for its recipients.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxSetting {
+    /// Failed delivery attempts after which a message fails.
+    pub max_attempts: u32,
+    /// Seconds after which a queued message fails.
+    pub max_age: u64,
+}
+
+impl OutboxSetting {
+    /// Whether a message queued at unix time `queued_at` and tried
+    /// `attempts` times should be given up on at `now`.
+    pub fn is_expired(&self, attempts: u32, queued_at: i64, now: i64) -> bool {
+        attempts >= self.max_attempts || now.saturating_sub(queued_at) >= self.max_age as i64
+    }
+}
+
+impl Default for OutboxSetting {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// The maximum number of tags a single group may carry.
+pub const MAX_GROUP_TAGS: usize = 16;
+
+/// Apply a JSON merge patch (RFC 7396): objects merge key by key, `null`
+/// removes a key and anything else replaces the value.
+fn merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("target to be an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Put back the keys of `defaults` that `target` lacks, at any depth.
+fn fill_defaults(target: &mut serde_json::Value, defaults: serde_json::Value) {
+    let (serde_json::Value::Object(target), serde_json::Value::Object(defaults)) =
+        (target, defaults)
+    else {
+        return;
+    };
+    for (key, default) in defaults {
+        match target.get_mut(&key) {
+            Some(value) => fill_defaults(value, default),
+            None => {
+                target.insert(key, default);
+            }
+        }
+    }
 }
 
 impl Setting {
+    /// Written to a temporary file first and renamed over the old one, so
+    /// a crash mid-save never leaves a truncated setting behind.
     pub async fn save<P: AsRef<Path>>(&self, save_path: P) -> Result<(), io::Error> {
         let mut full_path = save_path.as_ref().to_path_buf();
         full_path.push(env!("CARGO_PKG_NAME"));
         full_path.set_extension("json");
+        // Unique, so saves racing each other don't write the same file.
+        let temp_path = full_path.with_extension(format!("json.{:08x}.tmp", rand::random::<u32>()));
+        fs::create_dir_all(save_path).await?;
         let buf = serde_json::to_vec(&self)?;
-        let mut file = fs::File::create(full_path).await?;
-        file.write_all(&buf).await?;
-        Ok(())
+        let res = async {
+            let mut file = fs::File::create(&temp_path).await?;
+            file.write_all(&buf).await?;
+            file.sync_all().await?;
+            fs::rename(&temp_path, full_path).await
+        }
+        .await;
+        if res.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        res
     }
     pub async fn load<P: AsRef<Path>>(load_path: P) -> Result<Setting, io::Error> {
         let mut full_path = load_path.as_ref().to_path_buf();
@@ -89,6 +420,105 @@ impl Setting {
         let setting = serde_json::from_slice(&buf)?;
         Ok(setting)
     }
+    /// The setting as actually applied, with invalid values replaced by the
+    /// fallbacks the app uses for them.
+    pub fn effective(&self) -> Setting {
+        let mut setting = self.clone();
+        if setting.gossipsub.validate().is_err() {
+            setting.gossipsub = GossipsubSetting::default();
+        }
+        setting.manager_concurrency = setting.manager_concurrency.max(1);
+        setting.file_chunk_size = setting.file_chunk_size.clamp(1, MAX_FILE_CHUNK_SIZE);
+        setting.command_timeout = setting.command_timeout.max(1);
+        for timeout in setting.command_timeouts.values_mut() {
+            *timeout = (*timeout).max(1);
+        }
+        setting.outbox.max_attempts = setting.outbox.max_attempts.max(1);
+        setting
+    }
+    /// The set of peers allowed to connect, or `None` if any peer may connect.
+    pub fn allowlist(&self) -> Option<HashSet<PeerId>> {
+        match self.connection_mode {
+            ConnectionMode::Open => None,
+            ConnectionMode::Allowlist => Some(self.allowed.clone()),
+        }
+    }
+    /// The gossipsub topics peers may pull us into, or `None` for any.
+    pub fn topic_filter(&self) -> Option<HashSet<TopicHash>> {
+        self.allowed_groups.as_ref().map(|groups| {
+            groups
+                .iter()
+                .map(|group_id| group_id.topic().hash())
+                .collect()
+        })
+    }
+    /// Replace the tags of a group, trimming, deduplicating and capping them
+    /// at [`MAX_GROUP_TAGS`]. Returns the tags actually stored.
+    pub fn set_group_tags(&mut self, group_id: GroupId, tags: Vec<String>) -> Vec<String> {
+        let mut normalized: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = tag.trim();
+            if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+                normalized.push(tag.to_string());
+            }
+        }
+        normalized.truncate(MAX_GROUP_TAGS);
+        if normalized.is_empty() {
+            self.group_tags.remove(&group_id);
+        } else {
+            self.group_tags.insert(group_id, normalized.clone());
+        }
+        normalized
+    }
+    /// Replace the pinned group order, dropping duplicates.
+    pub fn set_group_order(&mut self, order: Vec<GroupId>) {
+        let mut seen = HashSet::new();
+        self.group_order = order
+            .into_iter()
+            .filter(|group_id| seen.insert(group_id.clone()))
+            .collect();
+    }
+    /// Sort groups with pinned ones first in their pinned order, then the
+    /// rest by their latest activity, most recent first.
+    pub fn sort_groups<T>(&self, groups: &mut [(GroupId, T, i64)]) {
+        let rank = |group_id: &GroupId| self.group_order.iter().position(|id| id == group_id);
+        groups.sort_by(
+            |(a, _, a_active), (b, _, b_active)| match (rank(a), rank(b)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => b_active.cmp(a_active),
+            },
+        );
+    }
+    pub fn groups_by_tag(&self, tag: &str) -> Vec<GroupId> {
+        self.group_tags
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|t| t == tag))
+            .map(|(group_id, _)| group_id.clone())
+            .collect()
+    }
+    /// Whether notifications should be suppressed right now.
+    pub fn is_quiet_now(&self) -> bool {
+        self.quiet_hours.map_or(false, |quiet_hours| {
+            quiet_hours.contains(Local::now().time())
+        })
+    }
+    /// This setting with `patch`, a JSON merge patch over its camelCase
+    /// fields, applied. Only the fields the patch names change, and `null`
+    /// resets one to its default.
+    pub fn patched(&self, patch: serde_json::Value) -> Result<Setting, SettingError> {
+        let invalid = |e: serde_json::Error| {
+            SettingError::new(vec![SettingErrorKind::InvalidValue(e.to_string())])
+        };
+        let mut value = serde_json::to_value(self).map_err(invalid)?;
+        merge_patch(&mut value, patch);
+        fill_defaults(
+            &mut value,
+            serde_json::to_value(Setting::default()).map_err(invalid)?,
+        );
+        serde_json::from_value(value).map_err(invalid)
+    }
     pub fn merge(&mut self, other: Setting) -> Result<(), SettingError> {
         let mut kinds = Vec::new();
         if self.recv_path != other.recv_path {
@@ -111,10 +541,86 @@ impl Default for Setting {
         Self {
             recv_path: dirs::desktop_dir().unwrap_or_else(|| PathBuf::from(".")),
             user_info: UserInfo::default(),
+            connection_mode: ConnectionMode::default(),
+            allowed: HashSet::new(),
+            blocked: HashSet::new(),
+            group_tags: HashMap::new(),
+            gossipsub: GossipsubSetting::default(),
+            manager_concurrency: default_manager_concurrency(),
+            quiet_hours: None,
+            group_ttl: HashMap::new(),
+            group_order: Vec::new(),
+            theme: Theme::default(),
+            allowed_groups: None,
+            auto_leave_after: None,
+            file_chunk_size: default_file_chunk_size(),
+            command_timeout: default_command_timeout(),
+            command_timeouts: HashMap::new(),
+            history_retention: None,
+            relay: None,
+            relays: Vec::new(),
+            outbox: OutboxSetting::default(),
+            dm_policy: DirectMessagePolicy::default(),
+            history_order: HistoryOrder::default(),
+            message_acks: false,
+            away_after: default_away_after(),
+            message_batch_window: default_message_batch_window(),
+            identity_path: None,
+            metrics_interval: default_metrics_interval(),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionMode {
+    /// Any peer may connect.
+    #[default]
+    Open,
+    /// Only peers in `Setting::allowed` may connect.
+    Allowlist,
+}
+
+/// How direct messages from peers we share no group with are handled.
+/// Peers we already have a conversation with are always accepted.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DirectMessagePolicy {
+    #[default]
+    Accept,
+    /// Refuse them, the sender's request fails.
+    Reject,
+    /// Keep them as requests until the user accepts or rejects the peer.
+    Hold,
+}
+
+/// How group history is ordered when it is handed to the frontend.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryOrder {
+    /// The order messages reached this node. Reflects what we actually saw
+    /// and when, but differs between peers.
+    Arrival,
+    /// By the sender's timestamp. The same on every peer, but trusts the
+    /// senders' clocks, so skewed clocks can misplace replies.
+    #[default]
+    Timestamp,
+    /// Every message after the ones it follows from: the author's previous
+    /// message and the message it replies to. Ignores clocks except to break
+    /// ties, so skewed ones can't put a reply before its question.
+    Causal,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Theme {
+    /// Follow the operating system.
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
 #[derive(Debug, Clone, Display, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct GroupId(Uuid);
@@ -133,29 +639,646 @@ impl AsRef<GroupId> for &GroupId {
         &self
     }
 }
+impl FromStr for GroupId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
+
+/// An invitation to a group, shared as a
+/// `p2pchat:invite?group=<id>&name=<name>&addr=<multiaddr>` URL. `addr`
+/// ends in the `/p2p` component of the member to join through.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupInvite {
+    pub group_id: GroupId,
+    pub name: String,
+    pub addr: Multiaddr,
+}
+
+impl fmt::Display for GroupInvite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut url = Url::parse("p2pchat:invite").expect("valid invite URL");
+        url.query_pairs_mut()
+            .append_pair("group", &self.group_id.to_string())
+            .append_pair("name", &self.name)
+            .append_pair("addr", &self.addr.to_string());
+        write!(f, "{url}")
+    }
+}
+
+impl FromStr for GroupInvite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim()).map_err(|e| format!("not a URL: {e}"))?;
+        if url.scheme() != "p2pchat" || url.path() != "invite" {
+            return Err("not a p2pchat:invite URL".to_string());
+        }
+        let (mut group_id, mut name, mut addr) = (None, None, None);
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "group" => {
+                    group_id = Some(
+                        value
+                            .parse::<GroupId>()
+                            .map_err(|e| format!("invalid group ID: {e}"))?,
+                    )
+                }
+                "name" => name = Some(value.into_owned()),
+                "addr" => {
+                    addr = Some(
+                        value
+                            .parse::<Multiaddr>()
+                            .map_err(|e| format!("invalid address: {e}"))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        let group_id = group_id.ok_or_else(|| "missing group ID".to_string())?;
+        let name = name.ok_or_else(|| "missing group name".to_string())?;
+        let addr = addr.ok_or_else(|| "missing address".to_string())?;
+        if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+            return Err(format!("address has no peer ID: {addr}"));
+        }
+        Ok(Self {
+            group_id,
+            name,
+            addr,
+        })
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupInfo {
     pub name: String,
     pub description: Option<String>,
+    /// Bumped on every edit of the name or description, so peers can tell
+    /// which copy is newer.
+    #[serde(default)]
+    pub version: u64,
+    /// Pinned messages, oldest pin first. Changed through `Message::Pin`.
+    #[serde(default)]
+    pub pinned: Vec<MessageId>,
+    /// Members with a role above `Role::Member`. Changed through
+    /// `Message::SetRole`.
+    #[serde(default)]
+    pub roles: HashMap<PeerId, Role>,
+    /// Keys the message content is end-to-end encrypted with, oldest first.
+    /// New messages use the last one. Empty if the group isn't encrypted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<GroupKey>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+impl GroupInfo {
+    pub fn role(&self, peer_id: &PeerId) -> Role {
+        self.roles.get(peer_id).copied().unwrap_or_default()
+    }
+    /// Whether `peer_id` may moderate the group, e.g. pin messages. Groups
+    /// created before roles existed have no owner, so there every member
+    /// keeps the rights it had.
+    pub fn may_moderate(&self, peer_id: &PeerId) -> bool {
+        self.roles.is_empty() || self.role(peer_id) >= Role::Admin
+    }
+}
+
+/// How many keys a group keeps, so messages sent under a key shortly
+/// before it was rotated out can still be read.
+pub const MAX_GROUP_KEYS: usize = 8;
+
+/// A symmetric XChaCha20-Poly1305 key shared by the members of a group.
+/// `id` goes up with every rotation, so messages can name their key.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GroupKey {
+    pub id: u32,
+    key: [u8; 32],
+}
+
+impl fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GroupKey {
+    pub fn generate(id: u32) -> Self {
+        Self {
+            id,
+            key: rand::random(),
+        }
+    }
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+    pub fn encrypt(&self, message: &Message) -> Message {
+        let nonce: [u8; 24] = rand::random();
+        let plaintext = serde_json::to_vec(message).expect("message to serialize");
+        let ciphertext = self
+            .cipher()
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("encryption to succeed");
+        Message::Encrypted {
+            key_id: self.id,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        }
+    }
+    /// `None` if the ciphertext was tampered with or isn't a message.
+    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Option<Message> {
+        if nonce.len() != 24 {
+            return None;
+        }
+        let plaintext = self
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+}
+
+/// What a member may do in a group, from least to most.
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    #[default]
+    Member,
+    /// Moderates the group and appoints other admins.
+    Admin,
+    /// Created the group; may hand out any role, including its own.
+    Owner,
+}
+
+impl Role {
+    /// Whether a member with this role may change a `current` member's
+    /// role to `new`. Admins can't touch owners or make new ones.
+    pub fn may_assign(self, current: Role, new: Role) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Admin => current != Role::Owner && new != Role::Owner,
+            Role::Member => false,
+        }
+    }
+}
+
+/// Hex SHA-256 of a message's author, timestamp, sequence number, nonce and
+/// content hash, so every peer derives the same ID for the same message.
+#[derive(Debug, Clone, Display, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
+pub struct MessageId(String);
+
+impl MessageId {
+    fn compute(fields: &impl Serialize) -> Self {
+        let data = serde_json::to_vec(fields).expect("message to serialize");
+        Self(
+            Code::Sha2_256
+                .digest(&data)
+                .digest()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GroupMessage {
+    /// Empty for messages from peers that predate message IDs.
+    #[serde(default)]
+    pub id: MessageId,
     pub source: PeerId,
     pub timestamp: i64,
     pub message: Message,
+    /// Seconds after `timestamp` during which peers keep relaying the message,
+    /// unlimited when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// When the author last edited the message, if ever.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    /// The message this one answers, which may not have arrived yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+    /// Counts the author's kept messages in this group from 1, so missing
+    /// ones show up as gaps. Not set on other kinds of messages, nor by
+    /// peers that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// The author wants a `Message::Ack` from each member that gets it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub want_ack: bool,
+    /// Random, so identical messages sent in the same second get their own
+    /// IDs. Zero from peers that predate it. Fits in a JavaScript number.
+    #[serde(default)]
+    pub nonce: u32,
+    /// The author's signature of the ID, `reply_to`, `ttl` and `want_ack`.
+    /// Missing from peers that predate signing, whose messages we drop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
+    /// Whether we checked `signature` on receipt. Whatever a peer sends
+    /// here is overwritten.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verified: bool,
+    /// The author's latest edit or deletion of the message, kept so peers
+    /// the message is resynced to can check it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<Box<Revision>>,
+}
+
+/// An author's edit or deletion applied to one of their messages.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    /// The signed `Message::Edit` or `Message::Delete`.
+    pub message: GroupMessage,
+    /// Hash of the content it replaced, which the message's ID covers.
+    pub content_hash: String,
 }
 
 impl GroupMessage {
     pub fn new(message: Message, source: PeerId) -> Self {
-        Self {
+        let mut group_message = Self {
+            id: MessageId::default(),
             source,
             timestamp: Utc::now().timestamp(),
             message,
+            ttl: None,
+            edited_at: None,
+            reply_to: None,
+            seq: None,
+            want_ack: false,
+            nonce: rand::random(),
+            signature: None,
+            verified: false,
+            revision: None,
+        };
+        group_message.id = group_message.compute_id();
+        group_message
+    }
+    /// What the author signs. The ID covers the content and `seq`.
+    fn signed_data(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.id, &self.reply_to, self.ttl, self.want_ack))
+            .expect("message to serialize")
+    }
+    /// Sign the message as its author, which `keypair` must belong to.
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.id = self.compute_id();
+        match keypair.sign(&self.signed_data()) {
+            Ok(signature) => self.signature = Some(signature),
+            Err(e) => log::warn!("failed to sign message {}: {e}", self.id),
+        }
+    }
+    /// Check the signature against the author's public key, which peer
+    /// IDs of Ed25519 keys embed, and the ID against the rest of the
+    /// message. A revision is checked on its own. `None` if the message
+    /// isn't signed.
+    pub fn verify_signature(&self) -> Option<bool> {
+        let signature = self.signature.as_ref()?;
+        if self.id != self.compute_id() {
+            return Some(false);
+        }
+        let multihash = self.source.as_ref();
+        if multihash.code() != u64::from(Code::Identity) {
+            return Some(false);
+        }
+        let valid = PublicKey::from_protobuf_encoding(multihash.digest())
+            .map_or(false, |key| key.verify(&self.signed_data(), signature));
+        Some(valid)
+    }
+    /// The ID this message should have, whatever ID it arrived with.
+    pub fn compute_id(&self) -> MessageId {
+        let content_hash = match &self.revision {
+            Some(revision) => revision.content_hash.clone(),
+            None => MessageId::compute(&self.message).0,
+        };
+        MessageId::compute(&(
+            &self.source,
+            self.timestamp,
+            self.seq,
+            self.nonce,
+            content_hash,
+        ))
+    }
+    /// Check what neither the gossipsub source nor the signature cover:
+    /// that the message isn't from the future and its content is valid.
+    pub fn check(&self, now: i64) -> Result<(), String> {
+        if self.timestamp - now > MAX_CLOCK_SKEW {
+            // It would stay at the end of the history. Old ones are fine:
+            // messages held back while paused are sent late.
+            return Err(format!("sent in the future at {}", self.timestamp));
+        }
+        self.message.validate()
+    }
+    /// Apply an edit by `editor`, which must be the author, to a text
+    /// message. Returns whether it was applied.
+    pub fn apply_edit(&mut self, editor: &PeerId, new_content: String, edited_at: i64) -> bool {
+        if &self.source != editor {
+            log::warn!(
+                "rejecting edit of {} by {editor}, who isn't its author",
+                self.id
+            );
+            return false;
+        }
+        if !matches!(self.message, Message::Text(_)) {
+            log::warn!("rejecting edit of {}, which isn't a text message", self.id);
+            return false;
+        }
+        self.message = Message::Text(new_content);
+        self.edited_at = Some(edited_at);
+        true
+    }
+    /// Replace the content with a tombstone on behalf of `deleter`, which
+    /// must be the author. The ID and timestamp stay for ordering. Returns
+    /// whether it was applied.
+    pub fn apply_delete(&mut self, deleter: &PeerId) -> bool {
+        if &self.source != deleter {
+            log::warn!(
+                "rejecting deletion of {} by {deleter}, who isn't its author",
+                self.id
+            );
+            return false;
+        }
+        self.message = Message::Deleted {};
+        true
+    }
+    /// Apply `revision`, an `Edit` or `Delete` of this message, and keep it
+    /// as the message's revision unless a later one was applied already.
+    /// Returns whether it was applied.
+    pub fn revise(&mut self, revision: &GroupMessage) -> bool {
+        if self.revision.as_ref().map_or(false, |latest| {
+            latest.message.timestamp > revision.timestamp
+        }) {
+            return false;
+        }
+        let content_hash = match &self.revision {
+            Some(latest) => latest.content_hash.clone(),
+            None => MessageId::compute(&self.message).0,
+        };
+        let applied = match &revision.message {
+            Message::Edit {
+                target,
+                new_content,
+            } if target == &self.id => {
+                self.apply_edit(&revision.source, new_content.clone(), revision.timestamp)
+            }
+            Message::Delete { target } if target == &self.id => self.apply_delete(&revision.source),
+            _ => false,
+        };
+        if applied {
+            let mut revision = revision.clone();
+            revision.revision = None;
+            self.revision = Some(Box::new(Revision {
+                message: revision,
+                content_hash,
+            }));
+        }
+        applied
+    }
+    /// Whether the message is past its TTL and should no longer be relayed.
+    ///
+    /// Gossipsub messages are signed, so a hop count can't be decremented on
+    /// the way; the TTL bounds propagation by age instead.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.ttl.map_or(false, |ttl| {
+            let ttl = i64::try_from(ttl).unwrap_or(i64::MAX);
+            now >= self.timestamp.saturating_add(ttl)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReachConfidence {
+    Low,
+    Medium,
+    High,
+}
+
+/// A best-effort estimate of how many group members a message would reach.
+///
+/// Mesh peers receive messages directly; other online members are only
+/// reached if gossip forwards the message to them, so the estimate is less
+/// certain the fewer of them are in our mesh.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupReach {
+    pub mesh_peers: usize,
+    pub roster: usize,
+    pub online: usize,
+    pub estimate: usize,
+    pub confidence: ReachConfidence,
+}
+
+/// Peers we know are subscribed to a group's topic. Messages are only
+/// pushed to `mesh` peers; the others only get them through gossip.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicPeers {
+    pub mesh: Vec<PeerId>,
+    /// Subscribed but not in our mesh.
+    pub subscribed: Vec<PeerId>,
+}
+
+/// Bytes moved in each direction.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// What the network transferred since startup.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkMetrics {
+    /// Bytes on the wire, encryption and multiplexing included.
+    pub total: Traffic,
+    /// Payload of group messages. One we publish counts once, however many
+    /// peers it's pushed to.
+    pub gossipsub: Traffic,
+    /// Payload of requests and their responses, file transfers included.
+    pub request_response: Traffic,
+    pub connections: u32,
+}
+
+/// Which of a set of peers we currently have a connection to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerConnectivity {
+    pub connected: Vec<PeerId>,
+    pub missing: Vec<PeerId>,
+}
+
+impl PeerConnectivity {
+    pub fn check(peers: Vec<PeerId>, connected_peers: &HashSet<PeerId>) -> Self {
+        let (connected, missing) = peers
+            .into_iter()
+            .partition(|peer_id| connected_peers.contains(peer_id));
+        Self { connected, missing }
+    }
+}
+
+/// A private message exchanged with one peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessage {
+    pub id: Uuid,
+    pub source: PeerId,
+    /// When the message was sent, or received for incoming ones.
+    pub timestamp: i64,
+    pub content: String,
+    /// Ours and still waiting for the peer to come online.
+    #[serde(default)]
+    pub pending: bool,
+    /// Ours and given up on after waiting too long.
+    #[serde(default)]
+    pub failed: bool,
+    /// Times sending it failed while queued.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// A subscriber of a group, with what we know about them.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMember {
+    pub peer_id: PeerId,
+    /// `None` until the peer's user info reaches us.
+    pub user_info: Option<UserInfo>,
+    pub role: Role,
+}
+
+/// What the frontend needs to show on startup, copied out of the managers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppSnapshot {
+    pub groups: HashMap<GroupId, GroupInfo>,
+    pub users: HashMap<PeerId, UserInfo>,
+    /// Addresses of each listener, keyed like `get_listeners`.
+    pub listeners: HashMap<u64, Vec<Multiaddr>>,
+}
+
+/// The immediate result of publishing a group message.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishReceipt {
+    pub message_id: String,
+    pub message: GroupMessage,
+    /// Mesh peers the message was sent to at publish time.
+    pub peers: usize,
+    /// Nobody received it yet, because the mesh was empty or the network is paused.
+    pub pending: bool,
+}
+
+impl GroupReach {
+    pub fn estimate(mesh_peers: usize, roster: usize, online: usize) -> Self {
+        let estimate = online.max(mesh_peers);
+        let confidence = if estimate == 0 {
+            if roster == 0 {
+                ReachConfidence::High
+            } else {
+                ReachConfidence::Low
+            }
+        } else if mesh_peers == 0 {
+            ReachConfidence::Low
+        } else if mesh_peers >= online {
+            ReachConfidence::High
+        } else {
+            ReachConfidence::Medium
+        };
+        Self {
+            mesh_peers,
+            roster,
+            online,
+            estimate,
+            confidence,
+        }
+    }
+}
+
+/// A long-running command that has not finished yet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationInfo {
+    pub id: Uuid,
+    pub kind: String,
+    pub age_ms: i64,
+}
+
+/// Reputation counters halve over this many seconds.
+const REPUTATION_HALF_LIFE_SECS: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+
+/// Weight of each new round-trip time in the rolling average.
+const RTT_SMOOTHING: f64 = 0.2;
+
+/// `average` moved towards the new round-trip time `rtt_ms`, or `rtt_ms`
+/// for the first one.
+pub fn smoothed_rtt(average: Option<f64>, rtt_ms: f64) -> f64 {
+    average.map_or(rtt_ms, |average| {
+        average + RTT_SMOOTHING * (rtt_ms - average)
+    })
+}
+
+/// Round-trip times of the pings on our connections to a peer.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerLatency {
+    pub latest_ms: Option<f64>,
+    pub average_ms: Option<f64>,
+    /// Pings in a row that failed since the last answered one.
+    pub failures: u32,
+}
+
+impl PeerLatency {
+    pub fn record(&mut self, rtt: Duration) {
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+        self.latest_ms = Some(rtt_ms);
+        self.average_ms = Some(smoothed_rtt(self.average_ms, rtt_ms));
+        self.failures = 0;
+    }
+}
+
+/// How reliably a peer has behaved across sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerReputation {
+    pub successes: f64,
+    pub failures: f64,
+    pub avg_rtt_ms: Option<f64>,
+    pub last_seen: i64,
+    /// When the counters were last decayed.
+    pub decayed_at: i64,
+}
+
+impl PeerReputation {
+    pub fn new(now: i64) -> Self {
+        Self {
+            successes: 0.0,
+            failures: 0.0,
+            avg_rtt_ms: None,
+            last_seen: now,
+            decayed_at: now,
         }
     }
+    /// The share of outcomes that succeeded, smoothed so unknown peers
+    /// score 0.5.
+    pub fn score(&self) -> f64 {
+        (self.successes + 1.0) / (self.successes + self.failures + 2.0)
+    }
+    /// Exponentially decay the counters so old behaviour matters less.
+    pub fn decay(&mut self, now: i64) {
+        let elapsed = (now - self.decayed_at).max(0) as f64;
+        let factor = 0.5f64.powf(elapsed / REPUTATION_HALF_LIFE_SECS);
+        self.successes *= factor;
+        self.failures *= factor;
+        self.decayed_at = now;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -173,7 +1296,7 @@ impl FileSource {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum UserState {
     #[default]
@@ -181,6 +1304,16 @@ pub enum UserState {
     Offline,
 }
 
+/// Whether a peer is connected, and whether it has been active lately.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Presence {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalUserInfo {
     pub peer_id: Option<PeerId>,
@@ -208,7 +1341,7 @@ impl Default for LocalUserInfo {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct UserInfo {
     pub name: String,
     pub avatar: Option<Url>,
@@ -216,6 +1349,9 @@ pub struct UserInfo {
     pub status: UserState,
 }
 
+/// Largest avatar a user may set, in bytes of its data URL.
+pub const MAX_AVATAR_SIZE: usize = 256 * 1024;
+
 impl UserInfo {
     pub fn new(name: String, avatar: Option<Url>) -> Self {
         Self {
@@ -224,6 +1360,23 @@ impl UserInfo {
             status: UserState::Online,
         }
     }
+    /// Avatars must be data URLs, so showing one doesn't reach out to a
+    /// server, of at most `MAX_AVATAR_SIZE`.
+    pub fn check_avatar(&self) -> Result<(), String> {
+        let Some(avatar) = &self.avatar else {
+            return Ok(());
+        };
+        if avatar.scheme() != "data" {
+            return Err("not a data URL".to_string());
+        }
+        let size = avatar.as_str().len();
+        if size > MAX_AVATAR_SIZE {
+            return Err(format!(
+                "{size} bytes, at most {MAX_AVATAR_SIZE} are allowed"
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Default for UserInfo {
@@ -240,6 +1393,16 @@ impl Default for UserInfo {
 pub struct GroupState {
     pub history: Vec<GroupMessage>,
     pub subscribers: HashSet<PeerId>,
+    #[serde(default)]
+    pub polls: HashMap<Uuid, PollState>,
+    #[serde(default)]
+    pub reactions: HashMap<MessageId, Reactions>,
+    /// Our messages that never reached anyone, kept until discarded.
+    #[serde(default)]
+    pub failed: HashSet<MessageId>,
+    /// IDs of the messages in `history`, for spotting duplicates.
+    #[serde(skip)]
+    ids: HashSet<MessageId>,
 }
 
 impl GroupState {
@@ -247,6 +1410,894 @@ impl GroupState {
         Self {
             history: Vec::new(),
             subscribers: HashSet::new(),
+            polls: HashMap::new(),
+            reactions: HashMap::new(),
+            failed: HashSet::new(),
+            ids: HashSet::new(),
+        }
+    }
+    /// The reaction counts of `message`, from `local_peer_id`'s point of view.
+    pub fn reaction_counts(
+        &self,
+        message: &MessageId,
+        local_peer_id: &PeerId,
+    ) -> Vec<ReactionCount> {
+        self.reactions
+            .get(message)
+            .map(|reactions| reactions.counts(local_peer_id))
+            .unwrap_or_default()
+    }
+    /// Replace the history, such as with one loaded from disk.
+    pub fn set_history(&mut self, history: Vec<GroupMessage>) {
+        self.ids = history.iter().map(|message| message.id.clone()).collect();
+        self.history = history;
+    }
+    /// Append `message` unless it's in the history already, then keep only
+    /// the last `retention` messages. Returns whether it was appended.
+    pub fn push(&mut self, message: GroupMessage, retention: Option<usize>) -> bool {
+        if !self.ids.insert(message.id.clone()) {
+            return false;
+        }
+        self.history.push(message);
+        match retention {
+            Some(retention) if self.history.len() > retention => {
+                let excess = self.history.len() - retention;
+                for message in self.history.drain(..excess) {
+                    self.ids.remove(&message.id);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+    /// `root` and every message whose `reply_to` chain leads to it, in
+    /// history order.
+    pub fn thread(&self, root: &MessageId) -> Vec<GroupMessage> {
+        let mut replies = HashMap::<&MessageId, Vec<&MessageId>>::new();
+        for message in &self.history {
+            if let Some(parent) = &message.reply_to {
+                replies.entry(parent).or_default().push(&message.id);
+            }
+        }
+        let mut in_thread = HashSet::from([root]);
+        let mut pending = vec![root];
+        while let Some(parent) = pending.pop() {
+            for reply in replies.get(parent).into_iter().flatten() {
+                if in_thread.insert(*reply) {
+                    pending.push(*reply);
+                }
+            }
+        }
+        self.history
+            .iter()
+            .filter(|message| in_thread.contains(&message.id))
+            .cloned()
+            .collect()
+    }
+    /// Reorder the history, which is stored in arrival order.
+    pub fn ordered(mut self, order: HistoryOrder) -> Self {
+        match order {
+            HistoryOrder::Arrival => {}
+            // Messages from the same second are ordered by author, then by
+            // the author's sequence, so every peer shows the same order.
+            HistoryOrder::Timestamp => self
+                .history
+                .sort_by_key(|message| (message.timestamp, message.source, message.seq)),
+            HistoryOrder::Causal => {
+                let clocks = lamport_clocks(&self.history);
+                let mut history = clocks.into_iter().zip(self.history).collect::<Vec<_>>();
+                history.sort_by_key(|(clock, message)| {
+                    (*clock, message.timestamp, message.source, message.seq)
+                });
+                self.history = history.into_iter().map(|(_, message)| message).collect();
+            }
+        }
+        self
+    }
+}
+
+/// A Lamport clock for each message of `history`: one more than the
+/// largest clock of the messages it follows from, which are the author's
+/// message with the previous `seq` and the message it replies to. Links that
+/// would close a cycle, which only a forged `reply_to` can make, are ignored.
+fn lamport_clocks(history: &[GroupMessage]) -> Vec<u64> {
+    let index = history
+        .iter()
+        .enumerate()
+        .map(|(i, message)| (&message.id, i))
+        .collect::<HashMap<_, _>>();
+    let mut chains = HashMap::<PeerId, Vec<(u64, usize)>>::new();
+    for (i, message) in history.iter().enumerate() {
+        if let Some(seq) = message.seq {
+            chains.entry(message.source).or_default().push((seq, i));
+        }
+    }
+    let mut previous = vec![None; history.len()];
+    for chain in chains.values_mut() {
+        chain.sort_unstable();
+        for pair in chain.windows(2) {
+            previous[pair[1].1] = Some(pair[0].1);
+        }
+    }
+    // Clocks start at 1, so 0 marks one that isn't known yet.
+    let mut clocks = vec![0; history.len()];
+    let mut visiting = vec![false; history.len()];
+    for start in 0..history.len() {
+        let mut stack = vec![start];
+        while let Some(&i) = stack.last() {
+            if clocks[i] != 0 {
+                stack.pop();
+                continue;
+            }
+            visiting[i] = true;
+            let replied = history[i]
+                .reply_to
+                .as_ref()
+                .and_then(|id| index.get(id).copied());
+            let parents = [previous[i], replied];
+            let unknown = parents
+                .iter()
+                .flatten()
+                .find(|&&parent| clocks[parent] == 0 && !visiting[parent]);
+            if let Some(&parent) = unknown {
+                stack.push(parent);
+                continue;
+            }
+            let latest = parents.iter().flatten().map(|&parent| clocks[parent]).max();
+            clocks[i] = latest.unwrap_or(0) + 1;
+            visiting[i] = false;
+            stack.pop();
+        }
+    }
+    clocks
+}
+
+/// A poll and the latest vote of each peer, with the vote's timestamp.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollState {
+    pub poll: Poll,
+    pub votes: HashMap<PeerId, (usize, i64)>,
+}
+
+impl PollState {
+    pub fn new(poll: Poll) -> Self {
+        Self {
+            poll,
+            votes: HashMap::new(),
         }
     }
+    /// Record a vote, keeping only each peer's latest one. Returns whether
+    /// the tallies may have changed.
+    pub fn vote(&mut self, peer_id: PeerId, option: usize, timestamp: i64) -> bool {
+        if option >= self.poll.options.len()
+            || self
+                .poll
+                .deadline
+                .map_or(false, |deadline| timestamp > deadline)
+        {
+            return false;
+        }
+        match self.votes.get(&peer_id) {
+            Some((_, voted_at)) if *voted_at > timestamp => false,
+            _ => {
+                self.votes.insert(peer_id, (option, timestamp));
+                true
+            }
+        }
+    }
+    /// Number of votes for each option, in option order.
+    pub fn tallies(&self) -> Vec<usize> {
+        let mut tallies = vec![0; self.poll.options.len()];
+        for (option, _) in self.votes.values() {
+            tallies[*option] += 1;
+        }
+        tallies
+    }
+}
+
+/// The reactions to a message: each peer's latest take on each emoji,
+/// with its timestamp, so reactions arriving out of order settle the same
+/// way on every peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reactions {
+    pub reactions: HashMap<String, HashMap<PeerId, (bool, i64)>>,
+}
+
+/// How many peers reacted to a message with `emoji`, and whether we did.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: usize,
+    pub reacted: bool,
+}
+
+impl Reactions {
+    /// Record `peer_id` adding or, with `remove`, taking back `emoji`,
+    /// unless we already have a newer take of theirs on it. Returns whether
+    /// anything changed.
+    pub fn react(&mut self, peer_id: PeerId, emoji: String, remove: bool, timestamp: i64) -> bool {
+        let peers = self.reactions.entry(emoji).or_default();
+        match peers.get(&peer_id) {
+            Some((_, reacted_at)) if *reacted_at > timestamp => false,
+            Some((active, _)) if *active != remove => {
+                peers.insert(peer_id, (!remove, timestamp));
+                false
+            }
+            _ => {
+                peers.insert(peer_id, (!remove, timestamp));
+                true
+            }
+        }
+    }
+    /// The peers reacting with each emoji.
+    pub fn details(&self) -> HashMap<String, Vec<PeerId>> {
+        self.reactions
+            .iter()
+            .map(|(emoji, peers)| {
+                let peers = peers
+                    .iter()
+                    .filter(|(_, (active, _))| *active)
+                    .map(|(peer_id, _)| *peer_id)
+                    .collect::<Vec<_>>();
+                (emoji.clone(), peers)
+            })
+            .filter(|(_, peers)| !peers.is_empty())
+            .collect()
+    }
+    /// How many peers react with each emoji, most popular first, and
+    /// whether `local_peer_id` is one of them.
+    pub fn counts(&self, local_peer_id: &PeerId) -> Vec<ReactionCount> {
+        let mut counts = self
+            .details()
+            .into_iter()
+            .map(|(emoji, peers)| ReactionCount {
+                emoji,
+                count: peers.len(),
+                reacted: peers.contains(local_peer_id),
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.emoji.cmp(&b.emoji)));
+        counts
+    }
+}
+
+/// A message of a history page, with its reactions counted so the UI
+/// doesn't have to ask for them separately.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryMessage {
+    #[serde(flatten)]
+    pub message: GroupMessage,
+    pub reactions: Vec<ReactionCount>,
+    /// We gave up on delivering this message of ours.
+    pub failed: bool,
+}
+
+/// What became of one of our queued messages.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageStatus {
+    Delivered,
+    /// Given up on, so it has to be resent or discarded.
+    Failed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reach_confidence_follows_the_mesh() {
+        let confidence =
+            |mesh, roster, online| GroupReach::estimate(mesh, roster, online).confidence;
+        assert_eq!(confidence(0, 0, 0), ReachConfidence::High);
+        assert_eq!(confidence(0, 3, 0), ReachConfidence::Low);
+        assert_eq!(confidence(0, 3, 2), ReachConfidence::Low);
+        assert_eq!(confidence(1, 3, 2), ReachConfidence::Medium);
+        assert_eq!(confidence(2, 3, 2), ReachConfidence::High);
+        // Mesh peers missing from the roster still count as reached.
+        let reach = GroupReach::estimate(3, 2, 1);
+        assert_eq!(reach.estimate, 3);
+        assert_eq!(reach.confidence, ReachConfidence::High);
+    }
+
+    #[tokio::test]
+    async fn group_tags_are_normalized_and_persisted() {
+        let mut setting = Setting::default();
+        let [tagged, other] = [(); 2].map(|_| GroupId::new());
+        let tags = [" work ", "", "work", "family"].map(String::from).to_vec();
+        assert_eq!(
+            setting.set_group_tags(tagged.clone(), tags),
+            vec!["work".to_string(), "family".to_string()]
+        );
+        let many = (0..MAX_GROUP_TAGS + 4).map(|i| i.to_string()).collect();
+        assert_eq!(setting.set_group_tags(other, many).len(), MAX_GROUP_TAGS);
+        assert_eq!(setting.groups_by_tag("work"), vec![tagged.clone()]);
+
+        let dir = std::env::temp_dir().join(format!("p2pchat-{:08x}", rand::random::<u32>()));
+        setting.save(&dir).await.unwrap();
+        let loaded = Setting::load(&dir).await.unwrap();
+        assert_eq!(loaded.group_tags, setting.group_tags);
+        fs::remove_dir_all(&dir).await.unwrap();
+
+        setting.set_group_tags(tagged, Vec::new());
+        assert!(setting.groups_by_tag("work").is_empty());
+        assert_eq!(setting.group_tags.len(), 1);
+    }
+
+    #[test]
+    fn gossipsub_setting_is_validated() {
+        assert!(GossipsubSetting::default().validate().is_ok());
+        let setting = |history_length, history_gossip, gossip_factor| GossipsubSetting {
+            history_length,
+            history_gossip,
+            gossip_factor,
+        };
+        assert!(setting(10, 10, 1.0).validate().is_ok());
+        assert!(setting(5, 0, 0.25).validate().is_err());
+        assert!(setting(5, 6, 0.25).validate().is_err());
+        assert!(setting(5, 3, 0.0).validate().is_err());
+        assert!(setting(5, 3, f64::NAN).validate().is_err());
+        let errors = setting(5, 6, 1.5).validate().unwrap_err();
+        assert_eq!(errors.kinds().count(), 2);
+    }
+
+    #[test]
+    fn quiet_hours_may_span_midnight() {
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let day = QuietHours {
+            start: time(9),
+            end: time(17),
+        };
+        assert!(day.contains(time(9)));
+        assert!(day.contains(time(12)));
+        assert!(!day.contains(time(17)));
+        assert!(!day.contains(time(3)));
+        let night = QuietHours {
+            start: time(22),
+            end: time(7),
+        };
+        assert!(night.contains(time(22)));
+        assert!(night.contains(time(0)));
+        assert!(night.contains(time(6)));
+        assert!(!night.contains(time(7)));
+        assert!(!night.contains(time(12)));
+    }
+
+    #[test]
+    fn reputation_halves_every_half_life() {
+        let mut reputation = PeerReputation::new(0);
+        reputation.successes = 8.0;
+        reputation.failures = 2.0;
+        let half_life = REPUTATION_HALF_LIFE_SECS as i64;
+        reputation.decay(half_life);
+        assert!((reputation.successes - 4.0).abs() < 1e-9);
+        assert!((reputation.failures - 1.0).abs() < 1e-9);
+        assert_eq!(reputation.decayed_at, half_life);
+        // A clock going backwards doesn't grow the counters.
+        reputation.decay(0);
+        assert!((reputation.successes - 4.0).abs() < 1e-9);
+        reputation.decay(2 * half_life);
+        assert!((reputation.successes - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn peers_are_split_by_connectivity() {
+        let [online, offline] = [(); 2].map(|_| PeerId::random());
+        let connected_peers = HashSet::from([online, PeerId::random()]);
+        let connectivity = PeerConnectivity::check(vec![offline, online], &connected_peers);
+        assert_eq!(connectivity.connected, vec![online]);
+        assert_eq!(connectivity.missing, vec![offline]);
+    }
+
+    #[test]
+    fn messages_expire_after_their_ttl() {
+        let mut message = GroupMessage::new(Message::Text("hi".to_string()), PeerId::random());
+        message.timestamp = 1000;
+        assert!(!message.is_expired(i64::MAX));
+        message.ttl = Some(60);
+        assert!(!message.is_expired(1059));
+        assert!(message.is_expired(1060));
+        message.ttl = Some(0);
+        assert!(message.is_expired(1000));
+        message.ttl = Some(u64::MAX);
+        assert!(!message.is_expired(i64::MAX - 1));
+    }
+
+    #[test]
+    fn pinned_groups_come_first_in_their_order() {
+        let [first, second, recent, stale] = [(); 4].map(|_| GroupId::new());
+        let mut setting = Setting::default();
+        setting.set_group_order(vec![second.clone(), first.clone(), second.clone()]);
+        assert_eq!(setting.group_order, vec![second.clone(), first.clone()]);
+
+        let mut groups = vec![
+            (stale.clone(), (), 1),
+            (first.clone(), (), 0),
+            (recent.clone(), (), 2),
+            (second.clone(), (), 3),
+        ];
+        setting.sort_groups(&mut groups);
+        let order: Vec<_> = groups.into_iter().map(|(group_id, ..)| group_id).collect();
+        assert_eq!(order, vec![second, first, recent, stale]);
+    }
+
+    #[test]
+    fn effective_setting_applies_fallbacks() {
+        let setting = Setting {
+            gossipsub: GossipsubSetting {
+                history_length: 2,
+                history_gossip: 3,
+                gossip_factor: 0.5,
+            },
+            manager_concurrency: 0,
+            file_chunk_size: u32::MAX,
+            command_timeout: 0,
+            command_timeouts: HashMap::from([("dial".to_string(), 0)]),
+            ..Setting::default()
+        };
+        let effective = setting.effective();
+        assert_eq!(effective.gossipsub.history_length, 5);
+        assert_eq!(effective.gossipsub.history_gossip, 3);
+        assert_eq!(effective.manager_concurrency, 1);
+        assert_eq!(effective.file_chunk_size, MAX_FILE_CHUNK_SIZE);
+        assert_eq!(effective.command_timeout, 1);
+        assert_eq!(effective.command_timeouts["dial"], 1);
+    }
+
+    #[test]
+    fn polls_keep_each_peers_latest_vote() {
+        let mut state = PollState::new(Poll {
+            id: Uuid::new_v4(),
+            question: "Lunch?".to_string(),
+            options: vec!["pizza".to_string(), "sushi".to_string()],
+            deadline: Some(100),
+        });
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        assert!(state.vote(alice, 0, 10));
+        assert!(state.vote(bob, 0, 10));
+        assert_eq!(state.tallies(), vec![2, 0]);
+        assert!(state.vote(alice, 1, 20));
+        // An older vote arriving late doesn't undo the newer one.
+        assert!(!state.vote(alice, 0, 15));
+        assert_eq!(state.tallies(), vec![1, 1]);
+        assert!(!state.vote(bob, 2, 30));
+        assert!(!state.vote(bob, 1, 101));
+        assert!(state.vote(bob, 1, 100));
+        assert_eq!(state.tallies(), vec![0, 2]);
+    }
+
+    #[test]
+    fn files_differ_by_name_and_hash() {
+        let file = |name: &str, hash: Option<&str>| FileInfo {
+            name: name.to_string(),
+            size: 1,
+            file_type: None,
+            hash: hash.map(str::to_string),
+        };
+        let files = HashSet::from([
+            file("a.txt", Some("1")),
+            file("a.txt", Some("2")),
+            file("a.txt", None),
+            file("b.txt", Some("1")),
+        ]);
+        assert_eq!(files.len(), 4);
+        let mut other_size = file("a.txt", Some("1"));
+        other_size.size = 2;
+        assert!(files.contains(&other_size));
+    }
+
+    fn encrypted(key: &GroupKey, message: &Message) -> (u32, Vec<u8>, Vec<u8>) {
+        match key.encrypt(message) {
+            Message::Encrypted {
+                key_id,
+                nonce,
+                ciphertext,
+            } => (key_id, nonce, ciphertext),
+            other => panic!("not encrypted: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn group_key_round_trip() {
+        let key = GroupKey::generate(3);
+        let message = Message::Text("hello".to_string());
+        let (key_id, nonce, ciphertext) = encrypted(&key, &message);
+        assert_eq!(key_id, 3);
+        assert_eq!(key.decrypt(&nonce, &ciphertext), Some(message));
+    }
+
+    #[test]
+    fn group_key_rejects_other_key() {
+        let key = GroupKey::generate(1);
+        let (_, nonce, ciphertext) = encrypted(&key, &Message::Text("hello".to_string()));
+        assert_eq!(GroupKey::generate(1).decrypt(&nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn group_key_rejects_tampered_nonce() {
+        let key = GroupKey::generate(1);
+        let (_, mut nonce, ciphertext) = encrypted(&key, &Message::Text("hello".to_string()));
+        assert_eq!(key.decrypt(&nonce[..23], &ciphertext), None);
+        nonce[0] ^= 1;
+        assert_eq!(key.decrypt(&nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn encrypted_bytes_are_base64() {
+        let key = GroupKey::generate(1);
+        let message = key.encrypt(&Message::Text("hello".to_string()));
+        let value = serde_json::to_value(&message).unwrap();
+        assert!(value["encrypted"]["nonce"].is_string());
+        assert!(value["encrypted"]["ciphertext"].is_string());
+        assert_eq!(serde_json::from_value::<Message>(value).unwrap(), message);
+    }
+
+    fn signed(keypair: &Keypair, message: Message) -> GroupMessage {
+        let mut message = GroupMessage::new(message, keypair.public().to_peer_id());
+        message.seq = Some(1);
+        message.sign(keypair);
+        message
+    }
+
+    #[test]
+    fn signature_covers_metadata() {
+        let keypair = Keypair::generate_ed25519();
+        let message = signed(&keypair, Message::Text("hello".to_string()));
+        assert_eq!(message.verify_signature(), Some(true));
+        let mut tampered = message.clone();
+        tampered.seq = Some(2);
+        assert_eq!(tampered.verify_signature(), Some(false));
+        let mut tampered = message.clone();
+        tampered.want_ack = true;
+        assert_eq!(tampered.verify_signature(), Some(false));
+        let mut tampered = message.clone();
+        tampered.message = Message::Text("bye".to_string());
+        assert_eq!(tampered.verify_signature(), Some(false));
+        let mut unsigned = message;
+        unsigned.signature = None;
+        assert_eq!(unsigned.verify_signature(), None);
+    }
+
+    #[test]
+    fn signature_rejects_other_author() {
+        let keypair = Keypair::generate_ed25519();
+        let mut message = signed(&keypair, Message::Text("hello".to_string()));
+        message.source = Keypair::generate_ed25519().public().to_peer_id();
+        assert_eq!(message.verify_signature(), Some(false));
+    }
+
+    #[test]
+    fn revised_message_keeps_its_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let mut message = signed(&keypair, Message::Text("hello".to_string()));
+        let edit = signed(
+            &keypair,
+            Message::Edit {
+                target: message.id.clone(),
+                new_content: "bye".to_string(),
+            },
+        );
+        assert!(message.revise(&edit));
+        assert_eq!(message.message, Message::Text("bye".to_string()));
+        assert_eq!(message.verify_signature(), Some(true));
+        assert_eq!(message.revision.as_ref().map(|r| &r.message), Some(&edit));
+
+        let other = Keypair::generate_ed25519();
+        let forged = signed(
+            &other,
+            Message::Delete {
+                target: message.id.clone(),
+            },
+        );
+        assert!(!message.revise(&forged));
+        assert_eq!(message.revision.as_ref().map(|r| &r.message), Some(&edit));
+    }
+
+    #[test]
+    fn identical_messages_get_their_own_ids() {
+        let source = PeerId::random();
+        let first = GroupMessage::new(Message::Text("same".to_string()), source);
+        let mut second = GroupMessage::new(Message::Text("same".to_string()), source);
+        second.timestamp = first.timestamp;
+        second.id = second.compute_id();
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.compute_id(), first.id);
+    }
+
+    #[test]
+    fn only_authors_edit_their_text_messages() {
+        let author = PeerId::random();
+        let mut message = GroupMessage::new(Message::Text("helo".to_string()), author);
+        assert!(!message.apply_edit(&PeerId::random(), "hijacked".to_string(), 5));
+        assert_eq!(message.edited_at, None);
+        assert!(message.apply_edit(&author, "hello".to_string(), 5));
+        assert_eq!(message.message, Message::Text("hello".to_string()));
+        assert_eq!(message.edited_at, Some(5));
+
+        let mut poll = GroupMessage::new(
+            Message::Poll(Poll {
+                id: Uuid::new_v4(),
+                question: "Lunch?".to_string(),
+                options: vec!["pizza".to_string(), "sushi".to_string()],
+                deadline: None,
+            }),
+            author,
+        );
+        assert!(!poll.apply_edit(&author, "Dinner?".to_string(), 5));
+        assert!(matches!(poll.message, Message::Poll(_)));
+    }
+
+    #[test]
+    fn deleted_messages_keep_their_place() {
+        let author = PeerId::random();
+        let mut message = GroupMessage::new(Message::Text("oops".to_string()), author);
+        let (id, timestamp) = (message.id.clone(), message.timestamp);
+        assert!(!message.apply_delete(&PeerId::random()));
+        assert_eq!(message.message, Message::Text("oops".to_string()));
+        assert!(message.apply_delete(&author));
+        assert_eq!(message.message, Message::Deleted {});
+        assert_eq!((message.id, message.timestamp), (id, timestamp));
+    }
+
+    #[test]
+    fn thread_follows_reply_chains() {
+        let source = PeerId::random();
+        let message = |text: &str, reply_to: Option<&GroupMessage>| {
+            let mut message = GroupMessage::new(Message::Text(text.to_string()), source);
+            message.reply_to = reply_to.map(|m| m.id.clone());
+            message
+        };
+        let root = message("root", None);
+        let reply = message("reply", Some(&root));
+        let nested = message("nested", Some(&reply));
+        let unrelated = message("unrelated", None);
+        let aside = message("aside", Some(&unrelated));
+        let mut state = GroupState::new();
+        // The nested reply arrived before its parent.
+        state.set_history(vec![
+            nested.clone(),
+            root.clone(),
+            unrelated,
+            reply.clone(),
+            aside,
+        ]);
+        let ids =
+            |messages: Vec<GroupMessage>| messages.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(state.thread(&root.id)),
+            vec![nested.id.clone(), root.id, reply.id.clone()]
+        );
+        assert_eq!(ids(state.thread(&reply.id)), vec![nested.id, reply.id]);
+        assert!(state.thread(&MessageId::default()).is_empty());
+    }
+
+    #[test]
+    fn reactions_keep_each_peers_latest_take() {
+        let [local, other] = [(); 2].map(|_| PeerId::random());
+        let mut reactions = Reactions::default();
+        assert!(reactions.react(local, "👍".to_string(), false, 10));
+        assert!(reactions.react(other, "👍".to_string(), false, 10));
+        assert!(reactions.react(other, "🎉".to_string(), false, 10));
+        // Reacting twice with the same emoji counts once.
+        assert!(!reactions.react(other, "🎉".to_string(), false, 11));
+        assert!(reactions.react(other, "👍".to_string(), true, 20));
+        // An older reaction arriving late doesn't undo the removal.
+        assert!(!reactions.react(other, "👍".to_string(), false, 15));
+        let count = |emoji: &str, count, reacted| ReactionCount {
+            emoji: emoji.to_string(),
+            count,
+            reacted,
+        };
+        assert_eq!(
+            reactions.counts(&local),
+            vec![count("🎉", 1, false), count("👍", 1, true)]
+        );
+        assert_eq!(
+            reactions.details(),
+            HashMap::from([
+                ("👍".to_string(), vec![local]),
+                ("🎉".to_string(), vec![other]),
+            ])
+        );
+    }
+
+    #[test]
+    fn admins_cannot_touch_owners() {
+        use Role::*;
+        for current in [Member, Admin, Owner] {
+            for new in [Member, Admin, Owner] {
+                assert!(Owner.may_assign(current, new));
+                assert!(!Member.may_assign(current, new));
+                assert_eq!(
+                    Admin.may_assign(current, new),
+                    current != Owner && new != Owner
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn history_is_ordered_the_same_everywhere() {
+        let [alice, bob] = {
+            let mut peers = [PeerId::random(), PeerId::random()];
+            peers.sort();
+            peers
+        };
+        let message = |source, timestamp, seq| {
+            let mut message = GroupMessage::new(Message::Text("hi".to_string()), source);
+            message.timestamp = timestamp;
+            message.seq = Some(seq);
+            message.id = message.compute_id();
+            message
+        };
+        let arrival = vec![
+            message(bob, 20, 2),
+            message(alice, 20, 2),
+            message(bob, 10, 1),
+            message(alice, 20, 1),
+        ];
+        let mut state = GroupState::new();
+        state.set_history(arrival.clone());
+        let keys = |state: &GroupState| {
+            state
+                .history
+                .iter()
+                .map(|m| (m.timestamp, m.source, m.seq))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(&state.clone().ordered(HistoryOrder::Timestamp)),
+            vec![
+                (10, bob, Some(1)),
+                (20, alice, Some(1)),
+                (20, alice, Some(2)),
+                (20, bob, Some(2)),
+            ]
+        );
+        let mut reversed = GroupState::new();
+        reversed.set_history(arrival.iter().rev().cloned().collect());
+        assert_eq!(
+            keys(&reversed.ordered(HistoryOrder::Timestamp)),
+            keys(&state.clone().ordered(HistoryOrder::Timestamp))
+        );
+        assert_eq!(state.ordered(HistoryOrder::Arrival).history, arrival);
+    }
+
+    #[test]
+    fn causal_order_keeps_replies_after_skewed_questions() {
+        // Alice's clock runs an hour ahead of Bob's.
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        let message = |text: &str, source, timestamp, seq, reply_to: Option<&GroupMessage>| {
+            let mut message = GroupMessage::new(Message::Text(text.to_string()), source);
+            message.timestamp = timestamp;
+            message.seq = Some(seq);
+            message.reply_to = reply_to.map(|m| m.id.clone());
+            message.id = message.compute_id();
+            message
+        };
+        let question = message("question", alice, 3600, 1, None);
+        let answer = message("answer", bob, 60, 1, Some(&question));
+        let aside = message("aside", bob, 120, 2, None);
+        let thanks = message("thanks", alice, 3700, 2, Some(&answer));
+        let mut state = GroupState::new();
+        state.set_history(vec![
+            answer.clone(),
+            aside.clone(),
+            question.clone(),
+            thanks.clone(),
+        ]);
+        let texts = |order| {
+            state
+                .clone()
+                .ordered(order)
+                .history
+                .into_iter()
+                .map(|m| m.message)
+                .collect::<Vec<_>>()
+        };
+        let text = |m: &GroupMessage| m.message.clone();
+        assert_eq!(
+            texts(HistoryOrder::Arrival),
+            [&answer, &aside, &question, &thanks].map(text)
+        );
+        assert_eq!(
+            texts(HistoryOrder::Timestamp),
+            [&answer, &aside, &question, &thanks].map(text)
+        );
+        assert_eq!(
+            texts(HistoryOrder::Causal),
+            [&question, &answer, &aside, &thanks].map(text)
+        );
+
+        // A forged reply to itself doesn't stall the ordering.
+        let mut looped = message("loop", bob, 0, 3, None);
+        looped.reply_to = Some(looped.id.clone());
+        state.set_history(vec![looped, question]);
+        assert_eq!(state.ordered(HistoryOrder::Causal).history.len(), 2);
+    }
+
+    #[test]
+    fn messages_from_the_future_are_rejected() {
+        let mut message = GroupMessage::new(Message::Text("hi".to_string()), PeerId::random());
+        let now = message.timestamp;
+        assert!(message.check(now).is_ok());
+        message.timestamp = now - 24 * 60 * 60;
+        assert!(message.check(now).is_ok());
+        message.timestamp = now + MAX_CLOCK_SKEW;
+        assert!(message.check(now).is_ok());
+        message.timestamp = now + MAX_CLOCK_SKEW + 1;
+        assert!(message.check(now).is_err());
+        message.timestamp = now;
+        message.message = Message::Text(String::new());
+        assert!(message.check(now).is_err());
+    }
+
+    #[test]
+    fn group_invite_round_trip() {
+        let addr: Multiaddr = format!("/ip4/198.51.100.1/tcp/4001/p2p/{}", PeerId::random())
+            .parse()
+            .unwrap();
+        let invite = GroupInvite {
+            group_id: GroupId::new(),
+            name: "Rust & friends?".to_string(),
+            addr: addr.clone(),
+        };
+        let parsed = invite.to_string().parse::<GroupInvite>().unwrap();
+        assert_eq!(parsed.group_id, invite.group_id);
+        assert_eq!(parsed.name, invite.name);
+        assert_eq!(parsed.addr, addr);
+
+        let without_peer = format!(
+            "p2pchat:invite?group={}&name=x&addr=/ip4/198.51.100.1/tcp/4001",
+            invite.group_id
+        );
+        assert!(without_peer.parse::<GroupInvite>().is_err());
+        assert!("https://example.com/invite?group=x"
+            .parse::<GroupInvite>()
+            .is_err());
+        assert!("p2pchat:invite?name=x".parse::<GroupInvite>().is_err());
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut target = serde_json::json!({"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]});
+        merge_patch(
+            &mut target,
+            serde_json::json!({"a": "z", "c": {"f": null}, "h": [2, 3], "i": {"j": 1}}),
+        );
+        assert_eq!(
+            target,
+            serde_json::json!({"a": "z", "c": {"d": "e"}, "h": [2, 3], "i": {"j": 1}})
+        );
+        let mut target = serde_json::json!([1]);
+        merge_patch(&mut target, serde_json::json!({"a": 1}));
+        assert_eq!(target, serde_json::json!({"a": 1}));
+        merge_patch(&mut target, serde_json::json!("x"));
+        assert_eq!(target, serde_json::json!("x"));
+    }
+
+    #[test]
+    fn patched_setting_resets_nulls_to_defaults() {
+        let setting = Setting {
+            recv_path: PathBuf::from("/somewhere"),
+            command_timeout: 99,
+            ..Setting::default()
+        };
+        let patched = setting
+            .patched(serde_json::json!({"recvPath": null, "userInfo": null, "theme": "dark"}))
+            .unwrap();
+        assert_eq!(patched.recv_path, Setting::default().recv_path);
+        assert_eq!(patched.user_info.name, UserInfo::default().name);
+        assert_eq!(patched.theme, Theme::Dark);
+        assert_eq!(patched.command_timeout, 99);
+        let patched = setting
+            .patched(serde_json::json!({"commandTimeout": null}))
+            .unwrap();
+        assert_eq!(patched.command_timeout, default_command_timeout());
+        assert!(setting
+            .patched(serde_json::json!({"commandTimeout": "soon"}))
+            .is_err());
+    }
 }
@@ -1,22 +1,49 @@
-use super::message::{FileResponse, Request, Response};
+use super::{
+    message::{FileResponse, Request, Response},
+    metrics::ProtocolCounter,
+};
 use async_trait::async_trait;
 use derive_more::From;
 use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use libp2p::{
+    autonat,
     core::upgrade::{read_length_prefixed, read_varint, write_length_prefixed, write_varint},
-    gossipsub::{Gossipsub, GossipsubEvent, TopicHash},
-    mdns,
+    dcutr,
+    gossipsub::{
+        subscription_filter::TopicSubscriptionFilter, Gossipsub, GossipsubEvent, IdentityTransform,
+        TopicHash,
+    },
+    identify,
+    kad::{record::store::MemoryStore, Kademlia, KademliaEvent},
+    mdns, ping,
+    relay::v2::client as relay_client,
     request_response::{ProtocolName, RequestResponse, RequestResponseCodec, RequestResponseEvent},
     swarm::{keep_alive, NetworkBehaviour},
 };
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
 use tokio::io;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ComposedEvent")]
 pub struct ComposedBehaviour {
     pub request_response: RequestResponse<FileExchangeCodec>,
-    pub gossipsub: Gossipsub,
+    pub gossipsub: Gossipsub<IdentityTransform, GroupTopicFilter>,
     pub mdns: mdns::tokio::Behaviour,
+    /// Provider records for shared files, keyed by content hash.
+    pub kademlia: Kademlia<MemoryStore>,
+    /// Tells peers which address they reach us on.
+    pub identify: identify::Behaviour,
+    /// Probes which of the observed addresses are reachable from outside.
+    pub autonat: autonat::Behaviour,
+    /// Reserves slots on relays so peers behind NAT can reach us.
+    pub relay_client: relay_client::Client,
+    /// Upgrades relayed connections to direct ones by hole punching.
+    pub dcutr: dcutr::behaviour::Behaviour,
+    /// Measures round-trip times, and closes connections that stop answering.
+    pub ping: ping::Behaviour,
     pub keep_alive: keep_alive::Behaviour,
 }
 
@@ -25,13 +52,65 @@ pub enum ComposedEvent {
     RequestResponse(RequestResponseEvent<FileRequest, FileResponse>),
     Gossipsub(GossipsubEvent),
     Mdns(mdns::Event),
+    Kademlia(KademliaEvent),
+    Identify(identify::Event),
+    Autonat(autonat::Event),
+    RelayClient(relay_client::Event),
+    Dcutr(dcutr::behaviour::Event),
+    Ping(ping::Event),
     KeepAlive(void::Void),
 }
+/// Accepts gossipsub subscriptions only for the allowed topics and those we
+/// subscribed to, or for any topic when there is no allowlist. Shared so it
+/// can change at runtime.
+#[derive(Debug, Clone, Default)]
+pub struct GroupTopicFilter(Arc<RwLock<TopicFilter>>);
+
+#[derive(Debug, Default)]
+struct TopicFilter {
+    /// The allowlist set with `set`.
+    allowed: Option<HashSet<TopicHash>>,
+    /// Topics we subscribed to, accepted even when not in `allowed`.
+    subscribed: HashSet<TopicHash>,
+}
+
+impl GroupTopicFilter {
+    pub fn new(allowed: Option<HashSet<TopicHash>>) -> Self {
+        Self(Arc::new(RwLock::new(TopicFilter {
+            allowed,
+            subscribed: HashSet::new(),
+        })))
+    }
+    pub fn set(&self, allowed: Option<HashSet<TopicHash>>) {
+        self.0.write().unwrap().allowed = allowed;
+    }
+    /// Accept `topic` while we're subscribed to it.
+    pub fn subscribe(&self, topic: TopicHash) {
+        self.0.write().unwrap().subscribed.insert(topic);
+    }
+    /// Stop accepting `topic`, unless the allowlist has it.
+    pub fn unsubscribe(&self, topic: &TopicHash) {
+        self.0.write().unwrap().subscribed.remove(topic);
+    }
+}
+
+impl TopicSubscriptionFilter for GroupTopicFilter {
+    fn can_subscribe(&mut self, topic_hash: &TopicHash) -> bool {
+        let filter = self.0.read().unwrap();
+        filter.subscribed.contains(topic_hash)
+            || filter
+                .allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(topic_hash))
+    }
+}
+
 // Simple file exchange protocol
 #[derive(Debug, Clone)]
 pub struct FileExchangeProtocol();
+/// Counts the bytes of every request and response it encodes or decodes.
 #[derive(Clone)]
-pub struct FileExchangeCodec();
+pub struct FileExchangeCodec(pub Arc<ProtocolCounter>);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileRequest(pub Request);
@@ -57,6 +136,7 @@ impl RequestResponseCodec for FileExchangeCodec {
         T: AsyncRead + Unpin + Send,
     {
         let data = read_length_prefixed(io, 1_000_000).await?;
+        self.0.add_received(data.len());
 
         if data.is_empty() {
             return Err(io::ErrorKind::UnexpectedEof.into());
@@ -74,15 +154,44 @@ impl RequestResponseCodec for FileExchangeCodec {
                 |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
                 |file| Ok(FileRequest(Request::File(file))),
             ),
+            "/chunk" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |(file, offset, len)| Ok(FileRequest(Request::FileChunk { file, offset, len })),
+            ),
+            "/manifest" => Ok(FileRequest(Request::Manifest(
+                String::from_utf8_lossy(&data[space_pos + 1..]).to_string(),
+            ))),
             "/group" => {
                 let topic_hash =
                     TopicHash::from_raw(std::str::from_utf8(&data[space_pos + 1..]).unwrap());
                 Ok(FileRequest(Request::Group(topic_hash)))
             }
+            "/group-info" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |group_id| Ok(FileRequest(Request::GroupInfo(group_id))),
+            ),
             "/user" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
                 |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
                 |peer| Ok(FileRequest(Request::User(peer))),
             ),
+            "/resync" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |(group_id, author, from_seq)| {
+                    Ok(FileRequest(Request::ResyncMessages {
+                        group_id,
+                        author,
+                        from_seq,
+                    }))
+                },
+            ),
+            "/group-key" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |(group_id, key)| Ok(FileRequest(Request::GroupKey { group_id, key })),
+            ),
+            "/dm" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |(id, content)| Ok(FileRequest(Request::DirectMessage { id, content })),
+            ),
             err => Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
         }
     }
@@ -96,6 +205,7 @@ impl RequestResponseCodec for FileExchangeCodec {
         T: AsyncRead + Unpin + Send,
     {
         let data = read_length_prefixed(io, 500_000_000).await?; // update transfer maximum
+        self.0.add_received(data.len());
         if data.is_empty() {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
@@ -103,18 +213,37 @@ impl RequestResponseCodec for FileExchangeCodec {
         let string_part = std::str::from_utf8(&data[0..space_pos]).unwrap();
         match string_part {
             "/file" => Ok(FileResponse(Response::File(data[space_pos + 1..].to_vec()))),
+            "/chunk" => match data.get(space_pos + 1) {
+                Some(&eof) => Ok(FileResponse(Response::FileChunk {
+                    data: data[space_pos + 2..].to_vec(),
+                    eof: eof != 0,
+                })),
+                None => Err(io::ErrorKind::InvalidData.into()),
+            },
+            "/manifest" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |manifest| Ok(FileResponse(Response::Manifest(manifest))),
+            ),
             "/group" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
                 |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
                 |pair| Ok(FileResponse(Response::Group(pair))),
             ),
-            "/error" => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                std::str::from_utf8(&data[space_pos + 1..]).unwrap(),
-            )),
+            "/group-info" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |info| Ok(FileResponse(Response::GroupInfo(info))),
+            ),
+            "/error" => Ok(FileResponse(Response::Error(
+                String::from_utf8_lossy(&data[space_pos + 1..]).to_string(),
+            ))),
             "/user" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
                 |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
                 |peer| Ok(FileResponse(Response::User(peer))),
             ),
+            "/messages" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
+                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+                |messages| Ok(FileResponse(Response::Messages(messages))),
+            ),
+            "/delivered" => Ok(FileResponse(Response::Delivered)),
             _ => Err(io::ErrorKind::InvalidData.into()),
         }
     }
@@ -133,12 +262,38 @@ impl RequestResponseCodec for FileExchangeCodec {
                 let data = serde_json::to_vec(&file).unwrap();
                 [b"/file ", data.as_slice()].concat()
             }
+            Request::FileChunk { file, offset, len } => {
+                let data = serde_json::to_vec(&(file, offset, len)).unwrap();
+                [b"/chunk ", data.as_slice()].concat()
+            }
+            Request::Manifest(hash) => [b"/manifest ", hash.as_bytes()].concat(),
             Request::Group(topic_hash) => [b"/group ", topic_hash.as_str().as_bytes()].concat(),
+            Request::GroupInfo(group_id) => {
+                let data = serde_json::to_vec(&group_id).unwrap();
+                [b"/group-info ", data.as_slice()].concat()
+            }
             Request::User(peer) => {
                 let data = serde_json::to_vec(&peer).unwrap();
                 [b"/user ", data.as_slice()].concat()
             }
+            Request::ResyncMessages {
+                group_id,
+                author,
+                from_seq,
+            } => {
+                let data = serde_json::to_vec(&(group_id, author, from_seq)).unwrap();
+                [b"/resync ", data.as_slice()].concat()
+            }
+            Request::GroupKey { group_id, key } => {
+                let data = serde_json::to_vec(&(group_id, key)).unwrap();
+                [b"/group-key ", data.as_slice()].concat()
+            }
+            Request::DirectMessage { id, content } => {
+                let data = serde_json::to_vec(&(id, content)).unwrap();
+                [b"/dm ", data.as_slice()].concat()
+            }
         };
+        self.0.add_sent(req.len());
         write_length_prefixed(io, req).await?;
         io.close().await?;
 
@@ -156,16 +311,77 @@ impl RequestResponseCodec for FileExchangeCodec {
     {
         let resp_data = match resp {
             Response::File(data) => [b"/file ", data.as_slice()].concat(),
+            Response::FileChunk { data, eof } => {
+                [b"/chunk ".as_slice(), &[eof as u8], data.as_slice()].concat()
+            }
+            Response::Manifest(manifest) => [
+                b"/manifest ",
+                serde_json::to_vec(&manifest).unwrap().as_slice(),
+            ]
+            .concat(),
             Response::Group(pair) => {
                 [b"/group ", serde_json::to_vec(&pair).unwrap().as_slice()].concat()
             }
+            Response::GroupInfo(info) => [
+                b"/group-info ",
+                serde_json::to_vec(&info).unwrap().as_slice(),
+            ]
+            .concat(),
             Response::User(user) => {
                 [b"/user ", serde_json::to_vec(&user).unwrap().as_slice()].concat()
             }
+            Response::Messages(messages) => [
+                b"/messages ",
+                serde_json::to_vec(&messages).unwrap().as_slice(),
+            ]
+            .concat(),
+            Response::Delivered => b"/delivered ".to_vec(),
+            Response::Error(err) => [b"/error ", err.as_bytes()].concat(),
         };
+        self.0.add_sent(resp_data.len());
         write_length_prefixed(io, resp_data).await?;
         io.close().await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn error_responses_reach_the_requester() {
+        let mut buf = Cursor::new(Vec::new());
+        let response = FileResponse(Response::Error("unsupported request".to_string()));
+        let mut codec = FileExchangeCodec(Arc::default());
+        codec
+            .write_response(&FileExchangeProtocol(), &mut buf, response)
+            .await
+            .unwrap();
+        buf.set_position(0);
+        let FileResponse(response) = codec
+            .read_response(&FileExchangeProtocol(), &mut buf)
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Error(e) if e == "unsupported request"));
+    }
+
+    #[test]
+    fn unsubscribed_topics_fall_back_to_the_allowlist() {
+        let [allowed, joined] = ["allowed", "joined"].map(TopicHash::from_raw);
+        let mut filter = GroupTopicFilter::new(Some(HashSet::from([allowed.clone()])));
+        assert!(filter.can_subscribe(&allowed));
+        assert!(!filter.can_subscribe(&joined));
+        filter.subscribe(joined.clone());
+        filter.subscribe(allowed.clone());
+        assert!(filter.can_subscribe(&joined));
+        filter.unsubscribe(&joined);
+        filter.unsubscribe(&allowed);
+        assert!(!filter.can_subscribe(&joined));
+        assert!(filter.can_subscribe(&allowed));
+        filter.set(None);
+        assert!(filter.can_subscribe(&joined));
+    }
+}
@@ -1,30 +1,281 @@
 use libp2p::{
-    gossipsub::{MessageId, TopicHash},
+    gossipsub::{self, TopicHash},
     request_response::ResponseChannel,
     swarm::derive_prelude::ListenerId,
     Multiaddr, PeerId,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
-use crate::models::{FileInfo, GroupId, GroupInfo, GroupMessage, UserInfo};
+use crate::models::{
+    FileInfo, GroupId, GroupInfo, GroupKey, GroupMessage, Manifest, MessageId, MessageStatus, Role,
+    UserInfo,
+};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum Message {
     Text(String),
     File(FileInfo),
+    /// Liveness probe, answered by every member with a `Pong` of the same id.
+    Ping(Uuid),
+    Pong(Uuid),
+    Rich(RichMessage),
+    Location(Location),
+    Poll(Poll),
+    /// A vote for option `option` of poll `poll_id`, replacing earlier votes by the same peer.
+    #[serde(rename_all = "camelCase")]
+    Vote {
+        poll_id: Uuid,
+        option: usize,
+    },
+    /// Replace the text of our own earlier message `target`.
+    #[serde(rename_all = "camelCase")]
+    Edit {
+        target: MessageId,
+        new_content: String,
+    },
+    /// Retract our own earlier message `target`.
+    Delete {
+        target: MessageId,
+    },
+    /// React to message `target` with `emoji`, or take the reaction back.
+    React {
+        target: MessageId,
+        emoji: String,
+        #[serde(default)]
+        remove: bool,
+    },
+    /// What remains of a deleted message. Never published.
+    Deleted {},
+    /// An admin's new name and description of the group, applied if
+    /// `version` is newer than the info we have.
+    EditGroup {
+        name: String,
+        description: Option<String>,
+        version: u64,
+    },
+    /// Whether we're typing in the group. Shown to members but not kept in
+    /// the history, and considered stale after `TYPING_TIMEOUT`.
+    #[serde(rename_all = "camelCase")]
+    Typing {
+        is_typing: bool,
+    },
+    /// Pin or unpin message `target` for the whole group.
+    Pin {
+        target: MessageId,
+        pinned: bool,
+    },
+    /// Give `peer_id` a new role in the group. Only applied if the sender's
+    /// own role allows it.
+    #[serde(rename_all = "camelCase")]
+    SetRole {
+        peer_id: PeerId,
+        role: Role,
+    },
+    /// We received message `target`, whose author asked for it.
+    Ack {
+        target: MessageId,
+    },
+    /// Another message, encrypted with the group key `key_id`. Members
+    /// only ever see it decrypted.
+    #[serde(rename_all = "camelCase")]
+    Encrypted {
+        key_id: u32,
+        #[serde(with = "base64_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        ciphertext: Vec<u8>,
+    },
+    /// We've seen the group's messages up to and including `up_to`.
+    #[serde(rename_all = "camelCase")]
+    Read {
+        up_to: MessageId,
+    },
+    /// Our name or avatar changed. The avatar is left out while it's the
+    /// one we last announced to the group. Not kept in the history.
+    #[serde(rename_all = "camelCase")]
+    UserInfo {
+        user_info: UserInfo,
+        avatar_omitted: bool,
+    },
+}
+
+/// Bytes as a base64 string rather than a JSON array of numbers.
+mod base64_bytes {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        base64::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Most options a poll may offer.
+pub const MAX_POLL_OPTIONS: usize = 16;
+/// Longest reaction, in bytes, enough for emoji sequences like flags and
+/// skin tones.
+pub const MAX_REACTION_LEN: usize = 32;
+/// How long a peer counts as typing after its last `Message::Typing`.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+/// Furthest ahead of our clock a gossiped message's timestamp may be, in seconds.
+pub const MAX_CLOCK_SKEW: i64 = 10 * 60;
+/// Most messages served for one `Request::ResyncMessages`.
+pub const MAX_RESYNC_MESSAGES: usize = 100;
+/// Largest chunk a provider sends for one `Request::FileChunk`.
+pub const MAX_FILE_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Poll {
+    pub id: Uuid,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Unix timestamp after which votes are no longer counted.
+    pub deadline: Option<i64>,
+}
+
+/// A point on the map, in WGS 84 degrees.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub label: Option<String>,
+}
+
+/// Structured content such as a poll, location or contact card.
+///
+/// `kind` is a plain string so kinds this client doesn't know still decode,
+/// and can be shown as `fallback` text.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RichMessage {
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub fallback: String,
+}
+
+impl Message {
+    /// Check the content is well-formed before it is published or accepted.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Message::Text(text)
+            | Message::Edit {
+                new_content: text, ..
+            } if text.trim().is_empty() => Err("message is empty".to_string()),
+            Message::Location(Location {
+                latitude,
+                longitude,
+                ..
+            }) => {
+                if !(-90.0..=90.0).contains(latitude) {
+                    return Err(format!("latitude out of range: {latitude}"));
+                }
+                if !(-180.0..=180.0).contains(longitude) {
+                    return Err(format!("longitude out of range: {longitude}"));
+                }
+                Ok(())
+            }
+            Message::Poll(poll) => {
+                if poll.question.trim().is_empty() {
+                    return Err("poll question is empty".to_string());
+                }
+                if !(2..=MAX_POLL_OPTIONS).contains(&poll.options.len()) {
+                    return Err(format!(
+                        "poll needs 2 to {MAX_POLL_OPTIONS} options, got {}",
+                        poll.options.len()
+                    ));
+                }
+                Ok(())
+            }
+            Message::React { emoji, .. } => {
+                if emoji.trim().is_empty() {
+                    return Err("reaction is empty".to_string());
+                }
+                if emoji.len() > MAX_REACTION_LEN {
+                    return Err(format!("reaction is longer than {MAX_REACTION_LEN} bytes"));
+                }
+                Ok(())
+            }
+            Message::Deleted {} => Err("tombstones can't be published".to_string()),
+            _ => Ok(()),
+        }
+    }
+    /// Whether the message is an entry of the group history of its own,
+    /// rather than an update of other messages or of the group, or a
+    /// signal nobody keeps.
+    pub fn is_kept(&self) -> bool {
+        matches!(
+            self,
+            Message::Text(_)
+                | Message::File(_)
+                | Message::Rich(_)
+                | Message::Location(_)
+                | Message::Poll(_)
+        )
+    }
+    /// Whether the message content contains `query`, ignoring case.
+    pub fn contains(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        match self {
+            Message::Text(text) => text.to_lowercase().contains(&query),
+            Message::File(file) => file.name.to_lowercase().contains(&query),
+            Message::Rich(rich) => rich.fallback.to_lowercase().contains(&query),
+            Message::Poll(poll) => {
+                poll.question.to_lowercase().contains(&query)
+                    || poll
+                        .options
+                        .iter()
+                        .any(|option| option.to_lowercase().contains(&query))
+            }
+            Message::Vote { .. }
+            | Message::Edit { .. }
+            | Message::Delete { .. }
+            | Message::React { .. }
+            | Message::Deleted {}
+            | Message::EditGroup { .. }
+            | Message::Typing { .. }
+            | Message::Read { .. }
+            | Message::UserInfo { .. }
+            | Message::Pin { .. }
+            | Message::SetRole { .. }
+            | Message::Ack { .. }
+            | Message::Encrypted { .. } => false,
+            Message::Location(location) => location
+                .label
+                .as_ref()
+                .map_or(false, |label| label.to_lowercase().contains(&query)),
+            Message::Ping(_) | Message::Pong(_) => false,
+        }
+    }
+}
+
+/// Optional fields of a `GroupMessage` we publish.
+#[derive(Debug, Clone, Default)]
+pub struct PublishOptions {
+    /// See `GroupMessage::ttl`.
+    pub ttl: Option<u64>,
+    pub reply_to: Option<MessageId>,
+    pub seq: Option<u64>,
+    pub want_ack: bool,
+    /// Encrypt the content with this group key.
+    pub key: Option<GroupKey>,
 }
 
 #[derive(Debug, Clone)]
 pub enum InboundEvent {
     InboundRequest {
+        peer: PeerId,
         request: Request,
         channel: Arc<Mutex<Option<ResponseChannel<FileResponse>>>>,
     },
     Message {
-        message_id: MessageId,
+        message_id: gossipsub::MessageId,
         topic: TopicHash,
         message: GroupMessage,
     },
@@ -50,20 +301,175 @@ pub enum InboundEvent {
         listener_id: ListenerId,
         addresses: Vec<Multiaddr>,
     },
+    ConnectionRefused {
+        peer_id: PeerId,
+    },
+    /// A relay reservation was accepted, or failed with `error`.
+    RelayStatus {
+        relay_peer_id: PeerId,
+        error: Option<String>,
+    },
+    /// Upgrading a relayed connection to a direct one finished.
+    HolePunchResult {
+        peer_id: PeerId,
+        success: bool,
+    },
+    /// AutoNAT confirmed peers can reach us on `address`.
+    ExternalAddr {
+        address: Multiaddr,
+    },
+    /// A connection to the peer was set up, at `endpoint` on its side.
+    /// `num_established` counts it and the other open ones.
+    ConnectionEstablished {
+        peer_id: PeerId,
+        endpoint: Multiaddr,
+        num_established: u32,
+    },
+    /// A connection to the peer closed, the last one once
+    /// `num_established` is 0. `cause` is unset for a clean close.
+    ConnectionClosed {
+        peer_id: PeerId,
+        num_established: u32,
+        cause: Option<String>,
+    },
+    /// A ping we sent on a connection to the peer was answered after `rtt`,
+    /// or failed with the error.
+    PingResult {
+        peer_id: PeerId,
+        rtt: Result<Duration, String>,
+    },
+    /// A request to or dial of a peer succeeded or failed.
+    PeerOutcome {
+        peer_id: PeerId,
+        success: bool,
+    },
+    /// A message we published while paused was finally sent, or given up on.
+    QueuedMessage {
+        topic: TopicHash,
+        message_id: MessageId,
+        status: MessageStatus,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Request {
     File(FileInfo),
+    /// Up to `len` bytes of `file` starting at `offset`.
+    FileChunk {
+        file: FileInfo,
+        offset: u64,
+        len: u32,
+    },
+    /// The manifest of the directory shared under this hash.
+    Manifest(String),
+    /// The info of the group with this topic, for joining it.
     Group(TopicHash),
+    /// The current info of a group we're in, answered with
+    /// `Response::GroupInfo`, to catch up on edits we missed.
+    GroupInfo(GroupId),
     User(PeerId),
+    /// `author`'s kept messages in the group from `from_seq` on, answered
+    /// with `Response::Messages`.
+    ResyncMessages {
+        group_id: GroupId,
+        author: PeerId,
+        from_seq: u64,
+    },
+    /// A new key of the group from an admin, answered with
+    /// `Response::Delivered` once accepted.
+    GroupKey {
+        group_id: GroupId,
+        key: GroupKey,
+    },
+    /// A private message for the peer, answered with `Response::Delivered`.
+    /// `id` lets the peer drop a message resent after a lost answer.
+    DirectMessage {
+        id: Uuid,
+        content: String,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub enum Response {
     File(Vec<u8>),
+    /// `eof` is set once the chunk reaches the end of the file.
+    FileChunk {
+        data: Vec<u8>,
+        eof: bool,
+    },
+    Manifest(Manifest),
     Group((GroupId, GroupInfo)),
+    GroupInfo(GroupInfo),
     User(UserInfo),
+    /// At most `MAX_RESYNC_MESSAGES` messages, ordered by `seq`.
+    Messages(Vec<GroupMessage>),
+    /// A `Request::DirectMessage` reached the peer.
+    Delivered,
+    /// The responder could not serve the request.
+    Error(String),
 }
 #[derive(Debug, Clone)]
 pub struct FileResponse(pub Response);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_rich_kinds_still_decode() {
+        let json = r#"{"rich":{"kind":"sticker","payload":{"id":7},"fallback":"[Sticker]"}}"#;
+        let message = serde_json::from_str::<Message>(json).unwrap();
+        let Message::Rich(rich) = &message else {
+            panic!("not a rich message: {message:?}");
+        };
+        assert_eq!(rich.kind, "sticker");
+        assert_eq!(rich.payload["id"], 7);
+        assert!(message.contains("sticker"));
+        assert_eq!(
+            serde_json::from_str::<Message>(&serde_json::to_string(&message).unwrap()).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn locations_must_be_on_the_map() {
+        let location = |latitude, longitude| {
+            Message::Location(Location {
+                latitude,
+                longitude,
+                label: None,
+            })
+        };
+        assert!(location(90.0, -180.0).validate().is_ok());
+        assert!(location(-90.0, 180.0).validate().is_ok());
+        assert!(location(90.5, 0.0).validate().is_err());
+        assert!(location(0.0, -180.5).validate().is_err());
+        assert!(location(f64::NAN, 0.0).validate().is_err());
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert!(Message::Text(" \n".to_string()).validate().is_err());
+        assert!(Message::Text("hi".to_string()).validate().is_ok());
+        let edit = |new_content: &str| Message::Edit {
+            target: MessageId::default(),
+            new_content: new_content.to_string(),
+        };
+        assert!(edit("").validate().is_err());
+        assert!(edit("fixed").validate().is_ok());
+        let poll = |question: &str, options: usize| {
+            Message::Poll(Poll {
+                id: Uuid::new_v4(),
+                question: question.to_string(),
+                options: (0..options).map(|i| i.to_string()).collect(),
+                deadline: None,
+            })
+        };
+        assert!(poll("Lunch?", 2).validate().is_ok());
+        assert!(poll("Lunch?", MAX_POLL_OPTIONS).validate().is_ok());
+        assert!(poll("Lunch?", 1).validate().is_err());
+        assert!(poll("Lunch?", MAX_POLL_OPTIONS + 1).validate().is_err());
+        assert!(poll(" ", 2).validate().is_err());
+        assert!(Message::Deleted {}.validate().is_err());
+    }
+}
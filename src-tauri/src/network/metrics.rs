@@ -0,0 +1,59 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use libp2p::bandwidth::BandwidthSinks;
+
+use crate::models::{NetworkMetrics, Traffic};
+
+/// Payload bytes one protocol sent and received.
+#[derive(Debug, Default)]
+pub struct ProtocolCounter {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl ProtocolCounter {
+    pub fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    fn traffic(&self) -> Traffic {
+        Traffic {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Byte counts of the swarm, from the transport and from each protocol.
+#[derive(Clone)]
+pub struct Metrics {
+    transport: Arc<BandwidthSinks>,
+    pub gossipsub: Arc<ProtocolCounter>,
+    pub request_response: Arc<ProtocolCounter>,
+}
+
+impl Metrics {
+    pub fn new(transport: Arc<BandwidthSinks>, request_response: Arc<ProtocolCounter>) -> Self {
+        Self {
+            transport,
+            gossipsub: Arc::default(),
+            request_response,
+        }
+    }
+    pub fn snapshot(&self, connections: u32) -> NetworkMetrics {
+        NetworkMetrics {
+            total: Traffic {
+                sent: self.transport.total_outbound(),
+                received: self.transport.total_inbound(),
+            },
+            gossipsub: self.gossipsub.traffic(),
+            request_response: self.request_response.traffic(),
+            connections,
+        }
+    }
+}
@@ -1,35 +1,89 @@
 pub mod behaviour;
 pub mod message;
+pub mod metrics;
 
-use crate::error::NetworkError;
-use crate::models::{GroupId, GroupInfo, GroupMessage};
+use crate::error::{CommandError, NetworkError};
+use crate::models::{
+    GroupId, GroupInfo, GroupMessage, MessageStatus, NetworkMetrics, OutboxSetting, PublishReceipt,
+    Setting, TopicPeers, MAX_AVATAR_SIZE,
+};
 
 /// The network module, encapsulating all network related logic.
 use futures::StreamExt;
 
-use libp2p::gossipsub::{GossipsubEvent, MessageId, Sha256Topic};
-use libp2p::identity::ed25519;
+use chrono::Utc;
+use libp2p::core::{muxing::StreamMuxerBox, transport::Boxed, upgrade};
+use libp2p::gossipsub::{GossipsubEvent, MessageAcceptance, MessageId, Sha256Topic, TopicHash};
+use libp2p::kad::{
+    record::store::MemoryStore, GetProvidersOk, Kademlia, KademliaEvent, QueryId, QueryResult,
+};
+use libp2p::relay::v2::client::{self as relay_client, transport::ClientTransport};
 use libp2p::request_response::{
-    ProtocolSupport, RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage,
-    ResponseChannel,
+    OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseEvent,
+    RequestResponseMessage, ResponseChannel,
 };
 use libp2p::swarm::derive_prelude::ListenerId;
-use libp2p::swarm::{keep_alive, Swarm, SwarmBuilder, SwarmEvent};
-use libp2p::{gossipsub, mdns};
-use libp2p::{identity, Multiaddr, PeerId};
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{keep_alive, ConnectionLimits, DialError, Swarm, SwarmBuilder, SwarmEvent};
+use libp2p::{
+    autonat, dcutr, dns, gossipsub, identify, mdns, mplex, noise, ping, tcp, websocket, yamux,
+};
+use libp2p::{bandwidth::BandwidthSinks, identity, Multiaddr, PeerId, Transport, TransportExt};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{hash_map, HashMap};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::num::NonZeroU32;
 
 use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time;
 
 use self::behaviour::*;
 use self::message::*;
+use self::metrics::Metrics;
+
+/// How many outgoing dials may be in progress at once.
+const MAX_PENDING_DIALS: u32 = 16;
+/// How many dials may wait for a free slot before new ones are refused.
+const DIAL_QUEUE_CAPACITY: usize = 64;
+
+/// How many messages published while paused are kept for sending on resume.
+const PAUSED_OUTBOX_CAPACITY: usize = 1000;
+
+/// Pings in a row a connection may fail before it's closed as dead.
+const PING_MAX_FAILURES: u32 = 3;
+
+/// How long the swarm keeps running on shutdown, so queued messages such as
+/// our unsubscribes still reach peers before the connections close.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// Largest gossip message, with room for a `Message::UserInfo` carrying an
+/// avatar of `MAX_AVATAR_SIZE`.
+const MAX_TRANSMIT_SIZE: usize = 2 * MAX_AVATAR_SIZE;
+
+type DialSender = oneshot::Sender<Result<(), NetworkError>>;
+
+/// A message published while paused, waiting for peers to send it to.
+struct QueuedPublish {
+    topic: Sha256Topic,
+    data: Vec<u8>,
+    message_id: crate::models::MessageId,
+    /// Unix time it was published at.
+    queued_at: i64,
+    /// Times it found no peers to send it to.
+    attempts: u32,
+}
+
+/// To content-address message, we can take the hash of message and use it as an ID.
+fn message_id(data: &[u8]) -> MessageId {
+    let mut s = DefaultHasher::new();
+    data.hash(&mut s);
+    MessageId::from(s.finish().to_string())
+}
 
 pub struct Network {
     pub client: Client,
@@ -38,64 +92,107 @@ pub struct Network {
     pub event_receiver: mpsc::Receiver<InboundEvent>,
 }
 
-pub fn new(secret_key_seed: Option<u8>) -> anyhow::Result<Network> {
-    // Create a public/private key pair, either random or based on a seed.
-    let id_keys = match secret_key_seed {
-        Some(seed) => {
-            let mut bytes = [0u8; 32];
-            bytes[0] = seed;
-            let secret_key = ed25519::SecretKey::from_bytes(&mut bytes).expect(
-                "this returns `Err` only if the length is wrong; the length is correct; qed",
-            );
-            identity::Keypair::Ed25519(secret_key.into())
-        }
-        None => identity::Keypair::generate_ed25519(),
-    };
+type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+/// TCP and websocket transports, with relayed connections through
+/// `relay_transport` on top, and the count of bytes they carry.
+fn build_transport(
+    id_keys: &identity::Keypair,
+    relay_transport: ClientTransport,
+) -> std::io::Result<(BoxedTransport, Arc<BandwidthSinks>)> {
+    let dns_tcp =
+        dns::TokioDnsConfig::system(tcp::tokio::Transport::new(tcp::Config::new().nodelay(true)))?;
+    let ws_dns_tcp = websocket::WsConfig::new(dns::TokioDnsConfig::system(
+        tcp::tokio::Transport::new(tcp::Config::new().nodelay(true)),
+    )?);
+    let (transport, bandwidth) = relay_transport
+        .or_transport(dns_tcp.or_transport(ws_dns_tcp))
+        .with_bandwidth_logging();
+    let transport = transport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseAuthenticated::xx(id_keys).expect("Valid keypair"))
+        .multiplex(upgrade::SelectUpgrade::new(
+            yamux::YamuxConfig::default(),
+            mplex::MplexConfig::default(),
+        ))
+        .timeout(Duration::from_secs(20))
+        .boxed();
+    Ok((transport, bandwidth))
+}
+
+pub fn new(id_keys: identity::Keypair, setting: &Setting) -> anyhow::Result<Network> {
     let peer_id = id_keys.public().to_peer_id();
-    // To content-address message, we can take the hash of message and use it as an ID.
-    let message_id_fn = |message: &gossipsub::GossipsubMessage| {
-        let mut s = DefaultHasher::new();
-        message.data.hash(&mut s);
-        gossipsub::MessageId::from(s.finish().to_string())
-    };
+    let message_id_fn = |message: &gossipsub::GossipsubMessage| message_id(&message.data);
+    if let Err(e) = setting.gossipsub.validate() {
+        log::warn!("{e}, falling back to default gossipsub setting");
+    }
+    let gossipsub_setting = setting.effective().gossipsub;
     // Set a custom gossipsub configuration
     let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
         .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
+        .history_length(gossipsub_setting.history_length)
+        .history_gossip(gossipsub_setting.history_gossip)
+        .gossip_factor(gossipsub_setting.gossip_factor)
         .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
+        .validate_messages() // Messages are only forwarded once the event loop accepts them, see `GroupMessage::ttl`.
         .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
+        .max_transmit_size(MAX_TRANSMIT_SIZE)
         .build()
         .expect("Valid config");
 
     // build a gossipsub network behaviour
-    let gossipsub = gossipsub::Gossipsub::new(
+    let topic_filter = GroupTopicFilter::new(setting.topic_filter());
+    let gossipsub = gossipsub::Gossipsub::new_with_subscription_filter(
         gossipsub::MessageAuthenticity::Signed(id_keys.clone()),
         gossipsub_config,
+        None,
+        topic_filter.clone(),
     )
     .expect("Correct configuration");
 
     // Create a Request-Response protocol supporting the FileExchange protocol.
+    let request_response_counter = Arc::default();
     let request_response = RequestResponse::new(
-        FileExchangeCodec(),
+        FileExchangeCodec(Arc::clone(&request_response_counter)),
         std::iter::once((FileExchangeProtocol(), ProtocolSupport::Full)),
         Default::default(),
     );
     // Create a mdns behaviour
     let mdns = mdns::tokio::Behaviour::new(mdns::Config::default()).unwrap();
+    let kademlia = Kademlia::new(peer_id, MemoryStore::new(peer_id));
+    let identify = identify::Behaviour::new(identify::Config::new(
+        "/p2pchat/1.0.0".to_string(),
+        id_keys.public(),
+    ));
+    let autonat = autonat::Behaviour::new(peer_id, autonat::Config::default());
+    let ping = ping::Behaviour::new(ping::Config::new().with_max_failures(
+        NonZeroU32::new(PING_MAX_FAILURES).expect("PING_MAX_FAILURES to be non-zero"),
+    ));
+    let (relay_transport, relay_client) =
+        relay_client::Client::new_transport_and_behaviour(peer_id);
 
     let behaviour = ComposedBehaviour {
         mdns,
+        kademlia,
+        identify,
+        autonat,
+        relay_client,
+        // Attempts an upgrade on every relayed connection by itself.
+        dcutr: dcutr::behaviour::Behaviour::new(),
+        ping,
         request_response,
         gossipsub,
         keep_alive: keep_alive::Behaviour::default(),
     };
     // Build the Swarm, connecting the lower layer transport logic with the
     // higher layer network behaviour logic.
-    let swarm = SwarmBuilder::with_tokio_executor(
-        libp2p::tokio_development_transport(id_keys)?,
-        behaviour,
-        peer_id,
-    )
-    .build();
+    let (transport, bandwidth) = build_transport(&id_keys, relay_transport)?;
+    let metrics = Metrics::new(bandwidth, request_response_counter);
+    let swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id)
+        .connection_limits(
+            ConnectionLimits::default().with_max_pending_outgoing(Some(MAX_PENDING_DIALS)),
+        )
+        .build();
 
     let (command_sender, command_receiver) = mpsc::channel(100);
     let (event_sender, event_receiver) = mpsc::channel::<InboundEvent>(100);
@@ -106,9 +203,19 @@ pub fn new(secret_key_seed: Option<u8>) -> anyhow::Result<Network> {
             local_peer_id: peer_id,
             listeners: Arc::new(Mutex::new(HashMap::new())),
             pending_new_group: Arc::new(Mutex::new(None)),
+            timeouts: Arc::new(CommandTimeouts::from_setting(setting)),
+            timeout: None,
         },
         peer_id,
-        event_loop: EventLoop::new(swarm, command_receiver, event_sender),
+        event_loop: EventLoop::new(
+            swarm,
+            id_keys,
+            metrics,
+            command_receiver,
+            event_sender,
+            setting,
+            topic_filter,
+        ),
         event_receiver,
     };
 
@@ -121,9 +228,57 @@ pub struct Client {
     local_peer_id: PeerId,
     pub listeners: Arc<Mutex<HashMap<ListenerId, Vec<Multiaddr>>>>,
     pub pending_new_group: Arc<Mutex<Option<(GroupId, GroupInfo)>>>,
+    /// How long to wait for the event loop to answer each command.
+    timeouts: Arc<CommandTimeouts>,
+    /// Overrides `timeouts` for every command when set.
+    timeout: Option<Duration>,
+}
+
+/// Command timeouts taken from the setting.
+#[derive(Debug, Clone)]
+struct CommandTimeouts {
+    default: Duration,
+    commands: HashMap<String, Duration>,
+}
+
+impl CommandTimeouts {
+    fn from_setting(setting: &Setting) -> Self {
+        let setting = setting.effective();
+        Self {
+            default: Duration::from_secs(setting.command_timeout),
+            commands: setting
+                .command_timeouts
+                .into_iter()
+                .map(|(command, secs)| (command, Duration::from_secs(secs)))
+                .collect(),
+        }
+    }
+    fn get(&self, command: &str) -> Duration {
+        self.commands.get(command).copied().unwrap_or(self.default)
+    }
 }
 
 impl Client {
+    /// A client whose commands give up after `timeout` instead of the
+    /// defaults from the setting.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+    /// Wait for the event loop's reply to `command`.
+    async fn reply<T>(
+        &self,
+        command: &str,
+        receiver: oneshot::Receiver<T>,
+    ) -> Result<T, NetworkError> {
+        let timeout = self.timeout.unwrap_or_else(|| self.timeouts.get(command));
+        match time::timeout(timeout, receiver).await {
+            Ok(reply) => Ok(reply.map_err(CommandError::from)?),
+            Err(_) => Err(NetworkError::Timeout(timeout)),
+        }
+    }
     /// Listen for incoming connections on the given address.
     pub async fn start_listening(&self, addr: Multiaddr) -> Result<ListenerId, NetworkError> {
         let (sender, receiver) = oneshot::channel();
@@ -133,8 +288,8 @@ impl Client {
                 sender,
             })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("start_listening", receiver).await?
     }
     /// Stop listening.
     pub async fn stop_listening(&self, listeners: Vec<ListenerId>) -> Result<(), NetworkError> {
@@ -143,8 +298,8 @@ impl Client {
         self.sender
             .send(Command::StopListen { sender, listeners })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("stop_listening", receiver).await?
     }
     /// Dial the given peer at the given address.
     pub async fn dial(&self, peer_id: PeerId, addr: Multiaddr) -> Result<(), NetworkError> {
@@ -156,8 +311,78 @@ impl Client {
                 sender,
             })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("dial", receiver).await?
+    }
+
+    /// Dial an address without a `/p2p` component, returning the peer ID
+    /// learned during the handshake.
+    pub async fn dial_addr(&self, addr: Multiaddr) -> Result<PeerId, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::DialAddr { addr, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("dial_addr", receiver).await?
+    }
+
+    /// Dial `peer_id` at several candidate addresses at once. The first
+    /// connection wins and the remaining attempts are aborted; if all fail
+    /// the error lists each address with its failure.
+    pub async fn dial_many(
+        &self,
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+    ) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::DialMany {
+                peer_id,
+                addrs,
+                sender,
+            })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("dial_many", receiver).await?
+    }
+
+    /// Dial `peer_id` again at the address it was last reached on.
+    pub async fn redial(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Redial { peer_id, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("redial", receiver).await?
+    }
+
+    /// Announce in the DHT that we provide the content `key`.
+    pub async fn start_providing(&self, key: Vec<u8>) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::StartProviding { key, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("start_providing", receiver).await?
+    }
+
+    /// Withdraw our provider record for the content `key`.
+    pub async fn stop_providing(&self, key: Vec<u8>) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::StopProviding { key })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
+    }
+
+    /// Find the peers providing the content `key` in the DHT.
+    pub async fn get_providers(&self, key: Vec<u8>) -> Result<HashSet<PeerId>, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::GetProviders { key, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("get_providers", receiver).await
     }
 
     /// Request the content of the given file from the given peer.
@@ -170,34 +395,65 @@ impl Client {
                 sender,
             })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not be dropped.")
+            .map_err(CommandError::from)?;
+        match self.reply("request", receiver).await? {
+            Ok(Response::Error(err)) => Err(NetworkError::RequestError(err)),
+            res => res,
+        }
     }
 
     /// Respond with the provided file content to the given request.
-    pub async fn response(&self, response: Response, channel: ResponseChannel<FileResponse>) {
+    pub async fn response(
+        &self,
+        response: Response,
+        channel: ResponseChannel<FileResponse>,
+    ) -> Result<(), NetworkError> {
         self.sender
             .send(Command::Response { response, channel })
             .await
-            .expect("Command receiver not to be dropped.");
+            .map_err(CommandError::from)?;
+        Ok(())
     }
 
     pub async fn publish(
         &self,
         topic: Sha256Topic,
         message: Message,
-    ) -> Result<MessageId, NetworkError> {
+    ) -> Result<PublishReceipt, NetworkError> {
+        self.publish_with_ttl(topic, message, None).await
+    }
+    /// Publish a message that peers stop relaying `ttl` seconds after it was sent.
+    pub async fn publish_with_ttl(
+        &self,
+        topic: Sha256Topic,
+        message: Message,
+        ttl: Option<u64>,
+    ) -> Result<PublishReceipt, NetworkError> {
+        let options = PublishOptions {
+            ttl,
+            ..Default::default()
+        };
+        self.publish_with(topic, message, options).await
+    }
+    /// Publish a message with the given optional fields set.
+    pub async fn publish_with(
+        &self,
+        topic: Sha256Topic,
+        message: Message,
+        options: PublishOptions,
+    ) -> Result<PublishReceipt, NetworkError> {
         let (sender, receiver) = oneshot::channel();
         let _ = self
             .sender
             .send(Command::Publish {
                 topic,
                 message,
+                options,
                 sender,
             })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("publish", receiver).await?
     }
 
     pub async fn subscribe(&self, topic: Sha256Topic) -> Result<(), NetworkError> {
@@ -206,8 +462,8 @@ impl Client {
             .sender
             .send(Command::Subscribe { topic, sender })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("subscribe", receiver).await?
     }
 
     pub async fn unsubscribe(&self, topic: Sha256Topic) -> Result<(), NetworkError> {
@@ -216,19 +472,130 @@ impl Client {
             .sender
             .send(Command::Unsubscribe { topic, sender })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("unsubscribe", receiver).await?
     }
     pub fn local_peer_id(&self) -> PeerId {
         self.local_peer_id.clone()
     }
-    pub async fn connected_peers(&self) -> Vec<PeerId> {
+    pub async fn disconnect(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Disconnect { peer_id, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("disconnect", receiver).await
+    }
+    /// Our identity keypair, secret key included.
+    pub async fn keypair(&self) -> Result<identity::Keypair, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Keypair { sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("keypair", receiver).await
+    }
+    /// Bytes transferred since startup, see `NetworkMetrics`.
+    pub async fn metrics(&self) -> Result<NetworkMetrics, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Metrics { sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("metrics", receiver).await
+    }
+    pub async fn connected_peers(&self) -> Result<Vec<PeerId>, NetworkError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Command::ConnectedPeers { sender })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .map_err(CommandError::from)?;
+        self.reply("connected_peers", receiver).await
+    }
+    /// Addresses other peers observed us at, the ones AutoNAT confirmed to
+    /// be reachable first.
+    pub async fn external_addrs(&self) -> Result<Vec<Multiaddr>, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ExternalAddrs { sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("external_addrs", receiver).await
+    }
+    /// Peers in our gossipsub mesh for the given topic.
+    pub async fn mesh_peers(&self, topic: TopicHash) -> Result<Vec<PeerId>, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::MeshPeers { topic, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("mesh_peers", receiver).await
+    }
+    /// Subscribers of the given topic, split by whether they are in our mesh.
+    pub async fn topic_peers(&self, topic: TopicHash) -> Result<TopicPeers, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::TopicPeers { topic, sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("topic_peers", receiver).await
+    }
+    /// Only accept subscriptions to the given topics, or to any with `None`.
+    pub async fn set_topic_filter(
+        &self,
+        topics: Option<HashSet<TopicHash>>,
+    ) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::SetTopicFilter { topics })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
+    }
+    /// Pause all network activity: connections are closed and refused, and
+    /// published messages are kept until resumed.
+    pub async fn set_paused(&self, paused: bool) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::SetPaused { paused })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
+    }
+    /// Drop every connection to `peer_id` and refuse new ones, including
+    /// messages it publishes or forwards.
+    pub async fn block_peer(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::BlockPeer { peer_id })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
+    }
+    /// Stop the event loop, closing every connection. Commands fail afterwards,
+    /// and the inbound events end once those already queued are taken.
+    pub async fn shutdown(&self) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Shutdown { sender })
+            .await
+            .map_err(CommandError::from)?;
+        self.reply("shutdown", receiver).await
+    }
+    pub async fn unblock_peer(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::UnblockPeer { peer_id })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
+    }
+    /// Restrict connections to the given peers, or allow any peer with `None`.
+    pub async fn set_allowlist(
+        &self,
+        allowlist: Option<HashSet<PeerId>>,
+    ) -> Result<(), NetworkError> {
+        self.sender
+            .send(Command::SetAllowlist { allowlist })
+            .await
+            .map_err(CommandError::from)?;
+        Ok(())
     }
     pub async fn new_group(
         &self,
@@ -246,29 +613,188 @@ pub struct EventLoop {
     command_receiver: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<InboundEvent>,
     pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), NetworkError>>>,
+    /// Dials waiting for the pending dial limit to free up.
+    dial_queue: VecDeque<(PeerId, Multiaddr, DialSender)>,
     pending_request_file: HashMap<RequestId, oneshot::Sender<Result<Response, NetworkError>>>,
+    allowlist: Option<HashSet<PeerId>>,
+    /// Peers banned from the swarm and whose messages gossipsub drops.
+    blocked: HashSet<PeerId>,
+    topic_filter: GroupTopicFilter,
+    paused: bool,
+    /// Messages published while paused, sent once peers are reachable again.
+    paused_outbox: VecDeque<QueuedPublish>,
+    outbox_setting: OutboxSetting,
+    /// Dials of addresses without a peer ID, which learn it on connecting.
+    pending_dial_addr: HashMap<Multiaddr, oneshot::Sender<Result<PeerId, NetworkError>>>,
+    /// Where each peer we dialed was last reached, for redialing.
+    last_addr: HashMap<PeerId, Multiaddr>,
+    /// Our addresses AutoNAT confirmed to be reachable from outside.
+    external_addrs: HashSet<Multiaddr>,
+    pending_start_providing: HashMap<QueryId, oneshot::Sender<Result<(), NetworkError>>>,
+    pending_get_providers: HashMap<QueryId, oneshot::Sender<HashSet<PeerId>>>,
+    /// Our identity, to sign the group messages we publish.
+    keypair: identity::Keypair,
+    metrics: Metrics,
 }
 
 impl EventLoop {
     fn new(
-        swarm: Swarm<ComposedBehaviour>,
+        mut swarm: Swarm<ComposedBehaviour>,
+        keypair: identity::Keypair,
+        metrics: Metrics,
         command_receiver: mpsc::Receiver<Command>,
         event_sender: mpsc::Sender<InboundEvent>,
+        setting: &Setting,
+        topic_filter: GroupTopicFilter,
     ) -> Self {
+        let blocked = setting.blocked.clone();
+        for peer_id in &blocked {
+            swarm.ban_peer_id(*peer_id);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(peer_id);
+        }
         Self {
             swarm,
             command_receiver,
             event_sender,
             pending_dial: Default::default(),
+            dial_queue: Default::default(),
             pending_request_file: Default::default(),
+            allowlist: setting.allowlist(),
+            blocked,
+            topic_filter,
+            paused: false,
+            paused_outbox: Default::default(),
+            outbox_setting: setting.effective().outbox,
+            pending_dial_addr: Default::default(),
+            last_addr: Default::default(),
+            external_addrs: Default::default(),
+            pending_start_providing: Default::default(),
+            pending_get_providers: Default::default(),
+            keypair,
+            metrics,
         }
     }
 
+    /// Publish messages held back while paused. Those that still find no
+    /// peers stay queued for the next connection, unless they waited too
+    /// long.
+    async fn flush_paused_outbox(&mut self) {
+        let now = Utc::now().timestamp();
+        for _ in 0..self.paused_outbox.len() {
+            let Some(mut queued) = self.paused_outbox.pop_front() else {
+                break;
+            };
+            let status = match self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(queued.topic.clone(), queued.data.clone())
+            {
+                Ok(_) => {
+                    self.metrics.gossipsub.add_sent(queued.data.len());
+                    MessageStatus::Delivered
+                }
+                Err(gossipsub::error::PublishError::InsufficientPeers) => {
+                    queued.attempts += 1;
+                    if !self
+                        .outbox_setting
+                        .is_expired(queued.attempts, queued.queued_at, now)
+                    {
+                        self.paused_outbox.push_back(queued);
+                        continue;
+                    }
+                    log::warn!("giving up on message queued while paused");
+                    MessageStatus::Failed
+                }
+                Err(e) => {
+                    log::warn!("dropping message queued while paused: {e}");
+                    MessageStatus::Failed
+                }
+            };
+            self.report_queued(queued, status).await;
+        }
+    }
+
+    /// Tell the app what became of a message queued while paused.
+    async fn report_queued(&mut self, queued: QueuedPublish, status: MessageStatus) {
+        self.event_sender
+            .send(InboundEvent::QueuedMessage {
+                topic: queued.topic.hash(),
+                message_id: queued.message_id,
+                status,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Start dialing, queueing the dial instead of failing it when the pending
+    /// dial limit is reached.
+    fn start_dial(&mut self, peer_id: PeerId, addr: Multiaddr, sender: DialSender) {
+        match self.swarm.dial(addr.clone()) {
+            Ok(()) => {
+                self.pending_dial.insert(peer_id, sender);
+            }
+            Err(DialError::ConnectionLimit(_)) if self.dial_queue.len() < DIAL_QUEUE_CAPACITY => {
+                log::debug!("dial limit reached, queueing dial to {peer_id}");
+                self.dial_queue.push_back((peer_id, addr, sender));
+            }
+            Err(e) => {
+                let _ = sender.send(Err(e.into()));
+            }
+        }
+    }
+
+    /// Start queued dials until the pending dial limit is reached again.
+    fn drain_dial_queue(&mut self) {
+        while let Some((peer_id, addr, sender)) = self.dial_queue.pop_front() {
+            // The caller gave up while the dial was queued.
+            if sender.is_closed() {
+                continue;
+            }
+            match self.swarm.dial(addr.clone()) {
+                Ok(()) => {
+                    self.pending_dial.insert(peer_id, sender);
+                }
+                Err(DialError::ConnectionLimit(_)) => {
+                    self.dial_queue.push_front((peer_id, addr, sender));
+                    break;
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e.into()));
+                }
+            }
+        }
+    }
+
+    fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.allowlist
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(peer_id))
+    }
+
+    /// Whether `peer_id` is already being dialed or queued for dialing. A
+    /// pending dial whose caller was cancelled or timed out doesn't count.
+    fn is_dialing(&mut self, peer_id: &PeerId) -> bool {
+        if let Some(sender) = self.pending_dial.get(peer_id) {
+            if sender.is_closed() {
+                self.pending_dial.remove(peer_id);
+            }
+        }
+        self.dial_queue.retain(|(_, _, sender)| !sender.is_closed());
+        self.pending_dial.contains_key(peer_id)
+            || self.dial_queue.iter().any(|(queued, ..)| queued == peer_id)
+    }
+
     pub async fn run(mut self) {
         loop {
             tokio::select! {
                 event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await,
                 command = self.command_receiver.recv() => match command {
+                    Some(Command::Shutdown { sender }) => {
+                        self.wind_down().await;
+                        let _ = sender.send(());
+                        return;
+                    }
                     Some(c) => self.handle_command(c).await,
                     // Command channel closed, thus shutting down the network event loop.
                     None =>  return,
@@ -277,6 +803,12 @@ impl EventLoop {
         }
     }
 
+    /// Drive the swarm for `SHUTDOWN_GRACE`, dropping whatever it reports.
+    async fn wind_down(&mut self) {
+        let drive = async { while self.swarm.next().await.is_some() {} };
+        let _ = time::timeout(SHUTDOWN_GRACE, drive).await;
+    }
+
     async fn handle_event<THandlerErr: Debug + Error>(
         &mut self,
         event: SwarmEvent<ComposedEvent, THandlerErr>,
@@ -284,12 +816,70 @@ impl EventLoop {
         match event {
             SwarmEvent::Behaviour(ComposedEvent::Gossipsub(event)) => match event {
                 GossipsubEvent::Message {
-                    propagation_source: _,
+                    propagation_source,
                     message_id,
                     message,
                 } => {
-                    let group_message =
-                        serde_json::from_slice::<GroupMessage>(&message.data).unwrap();
+                    self.metrics.gossipsub.add_received(message.data.len());
+                    let mut group_message =
+                        match serde_json::from_slice::<GroupMessage>(&message.data) {
+                            Ok(group_message) => group_message,
+                            Err(e) => {
+                                log::warn!("dropping undecodable message {message_id}: {e}");
+                                let _ = self
+                                    .swarm
+                                    .behaviour_mut()
+                                    .gossipsub
+                                    .report_message_validation_result(
+                                        &message_id,
+                                        &propagation_source,
+                                        MessageAcceptance::Reject,
+                                    );
+                                return;
+                            }
+                        };
+                    // Fresh messages haven't been edited; edits arrive on their own.
+                    group_message.edited_at = None;
+                    group_message.revision = None;
+                    // Derived rather than trusted, so a peer can't claim the ID
+                    // of another message, and older peers' messages get one.
+                    group_message.id = group_message.compute_id();
+                    let now = Utc::now().timestamp();
+                    // Only the signed gossipsub source is trustworthy, so nobody
+                    // can post, edit or delete messages as someone else.
+                    let invalid = if message.source != Some(group_message.source) {
+                        Some(format!("claims to be from {}", group_message.source))
+                    } else {
+                        group_message.check(now).err()
+                    };
+                    if let Some(reason) = invalid {
+                        log::warn!("dropping invalid message {message_id}: {reason}");
+                        let _ = self
+                            .swarm
+                            .behaviour_mut()
+                            .gossipsub
+                            .report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                MessageAcceptance::Reject,
+                            );
+                        return;
+                    }
+                    // Expired messages are still delivered to us, just not relayed further.
+                    let acceptance = if group_message.is_expired(now) {
+                        MessageAcceptance::Ignore
+                    } else {
+                        MessageAcceptance::Accept
+                    };
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            acceptance,
+                        );
                     let _ = self
                         .event_sender
                         .send(InboundEvent::Message {
@@ -319,7 +909,7 @@ impl EventLoop {
                 GossipsubEvent::GossipsubNotSupported { .. } => {}
             },
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
-                RequestResponseEvent::Message { message, .. },
+                RequestResponseEvent::Message { peer, message },
             )) => match message {
                 RequestResponseMessage::Request {
                     request, channel, ..
@@ -327,6 +917,7 @@ impl EventLoop {
                     let _ = self
                         .event_sender
                         .send(InboundEvent::InboundRequest {
+                            peer,
                             request: request.0,
                             channel: Arc::new(Mutex::new(Some(channel))),
                         })
@@ -342,26 +933,190 @@ impl EventLoop {
                         .remove(&request_id)
                         .expect("Request to still be pending.")
                         .send(Ok(response.0));
+                    self.event_sender
+                        .send(InboundEvent::PeerOutcome {
+                            peer_id: peer,
+                            success: true,
+                        })
+                        .await
+                        .unwrap();
                 }
             },
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                 RequestResponseEvent::OutboundFailure {
-                    request_id, error, ..
+                    peer,
+                    request_id,
+                    error,
                 },
             )) => {
+                let error = match error {
+                    OutboundFailure::DialFailure => NetworkError::PeerUnreachable(peer),
+                    error => error.into(),
+                };
                 let _ = self
                     .pending_request_file
                     .remove(&request_id)
                     .expect("Request to still be pending.")
-                    .send(Err(error.into()));
+                    .send(Err(error));
+                self.event_sender
+                    .send(InboundEvent::PeerOutcome {
+                        peer_id: peer,
+                        success: false,
+                    })
+                    .await
+                    .unwrap();
             }
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                 RequestResponseEvent::ResponseSent { .. },
             )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed { id, result, .. },
+            )) => match result {
+                QueryResult::StartProviding(res) => {
+                    if let Some(sender) = self.pending_start_providing.remove(&id) {
+                        let _ = sender.send(
+                            res.map(|_| ())
+                                .map_err(|e| NetworkError::RequestError(e.to_string())),
+                        );
+                    }
+                }
+                QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders {
+                    providers, ..
+                })) => {
+                    if let Some(sender) = self.pending_get_providers.remove(&id) {
+                        let _ = sender.send(providers);
+                        // The first providers found are enough to start a download.
+                        if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id)
+                        {
+                            query.finish();
+                        }
+                    }
+                }
+                QueryResult::GetProviders(res) => {
+                    if let Err(e) = res {
+                        log::debug!("provider lookup failed: {e}");
+                    }
+                    if let Some(sender) = self.pending_get_providers.remove(&id) {
+                        let _ = sender.send(HashSet::new());
+                    }
+                }
+                _ => {}
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                log::debug!("{peer_id} observed us at {}", info.observed_addr);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Identify(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event { peer, result })) => {
+                let rtt = match result {
+                    Ok(ping::Success::Ping { rtt }) => Ok(rtt),
+                    // The peer pinging us.
+                    Ok(ping::Success::Pong) => return,
+                    Err(e) => Err(e.to_string()),
+                };
+                self.event_sender
+                    .send(InboundEvent::PingResult { peer_id: peer, rtt })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(autonat::Event::OutboundProbe(
+                autonat::OutboundProbeEvent::Response { address, .. },
+            ))) => {
+                if self.external_addrs.insert(address.clone()) {
+                    log::info!("Confirmed external address {address}");
+                    self.event_sender
+                        .send(InboundEvent::ExternalAddr { address })
+                        .await
+                        .unwrap();
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(autonat::Event::StatusChanged {
+                old,
+                new,
+            })) => {
+                log::info!("NAT status changed from {old:?} to {new:?}");
+                if new == autonat::NatStatus::Private {
+                    self.external_addrs.clear();
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(
+                relay_client::Event::ReservationReqAccepted { relay_peer_id, .. },
+            )) => {
+                self.event_sender
+                    .send(InboundEvent::RelayStatus {
+                        relay_peer_id,
+                        error: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(
+                relay_client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                },
+            )) => {
+                log::warn!("Relay {relay_peer_id} refused reservation: {error}");
+                self.event_sender
+                    .send(InboundEvent::RelayStatus {
+                        relay_peer_id,
+                        error: Some(error.to_string()),
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(event)) => {
+                log::debug!("{event:?}");
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Dcutr(
+                dcutr::behaviour::Event::DirectConnectionUpgradeSucceeded { remote_peer_id },
+            )) => {
+                log::info!("Hole punched to {remote_peer_id}");
+                self.event_sender
+                    .send(InboundEvent::HolePunchResult {
+                        peer_id: remote_peer_id,
+                        success: true,
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Dcutr(
+                dcutr::behaviour::Event::DirectConnectionUpgradeFailed {
+                    remote_peer_id,
+                    error,
+                },
+            )) => {
+                log::warn!("Failed to hole punch to {remote_peer_id}: {error:?}");
+                self.event_sender
+                    .send(InboundEvent::HolePunchResult {
+                        peer_id: remote_peer_id,
+                        success: false,
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Dcutr(event)) => {
+                log::debug!("{event:?}");
+            }
             SwarmEvent::Behaviour(ComposedEvent::Mdns(event)) => match event {
                 mdns::Event::Discovered(list) => {
                     for (peer_id, addr) in list {
                         log::debug!("Discovered {:?} at {:?}", peer_id, addr);
+                        if self.paused
+                            || !self.is_allowed(&peer_id)
+                            || self.blocked.contains(&peer_id)
+                        {
+                            continue;
+                        }
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, addr);
                         self.swarm
                             .behaviour_mut()
                             .gossipsub
@@ -432,20 +1187,134 @@ impl EventLoop {
             }
             SwarmEvent::IncomingConnection { .. } => {}
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
+                self.drain_dial_queue();
+                let addr_sender = if endpoint.is_dialer() {
+                    self.pending_dial_addr.remove(endpoint.get_remote_address())
+                } else {
+                    None
+                };
+                if self.paused {
+                    log::debug!("Closing connection with {peer_id}: network is paused");
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                        let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    }
+                    if let Some(sender) = addr_sender {
+                        let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    }
+                    return;
+                }
+                if !self.is_allowed(&peer_id) {
+                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                        let _ = sender.send(Err(NetworkError::PeerNotAllowed(peer_id)));
+                    }
+                    if let Some(sender) = addr_sender {
+                        let _ = sender.send(Err(NetworkError::PeerNotAllowed(peer_id)));
+                    }
+                    log::warn!("Refused connection with {peer_id}: not in allowlist");
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    self.event_sender
+                        .send(InboundEvent::ConnectionRefused { peer_id })
+                        .await
+                        .unwrap();
+                    return;
+                }
                 if endpoint.is_dialer() {
+                    let addr = endpoint.get_remote_address().clone();
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, addr.clone());
+                    self.last_addr.insert(peer_id, addr);
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Ok(()));
                     }
+                    if let Some(sender) = addr_sender {
+                        let _ = sender.send(Ok(peer_id));
+                    }
                 }
+                self.flush_paused_outbox().await;
+                self.event_sender
+                    .send(InboundEvent::ConnectionEstablished {
+                        peer_id,
+                        endpoint: endpoint.get_remote_address().clone(),
+                        num_established: num_established.get(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::BannedPeer { peer_id, endpoint } => {
+                log::debug!("Refused connection with {peer_id}: blocked");
+                if endpoint.is_dialer() {
+                    if let Some(sender) =
+                        self.pending_dial_addr.remove(endpoint.get_remote_address())
+                    {
+                        let _ = sender.send(Err(NetworkError::PeerBlocked(peer_id)));
+                    }
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                cause,
+                ..
+            } => {
+                self.event_sender
+                    .send(InboundEvent::ConnectionClosed {
+                        peer_id,
+                        num_established,
+                        cause: cause.map(|e| e.to_string()),
+                    })
+                    .await
+                    .unwrap();
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                self.drain_dial_queue();
+                let mut failed: Vec<(Multiaddr, String)> = match &error {
+                    DialError::Transport(errors) => errors
+                        .iter()
+                        .map(|(addr, e)| (addr.clone(), e.to_string()))
+                        .collect(),
+                    DialError::WrongPeerId { endpoint, .. } => {
+                        vec![(endpoint.get_remote_address().clone(), error.to_string())]
+                    }
+                    _ => Vec::new(),
+                };
+                // Addresses ending in /p2p/<peer id> failed along with the peer.
+                if let Some(peer_id) = peer_id {
+                    failed.extend(
+                        self.pending_dial_addr
+                            .keys()
+                            .filter(|addr| PeerId::try_from_multiaddr(addr) == Some(peer_id))
+                            .map(|addr| (addr.clone(), error.to_string())),
+                    );
+                }
+                for (addr, e) in failed {
+                    if let Some(sender) = self.pending_dial_addr.remove(&addr) {
+                        let _ =
+                            sender.send(Err(anyhow::anyhow!("failed to dial {addr}: {e}").into()));
+                    }
+                }
+                // Errors that name no address leave their entry behind, so drop
+                // the ones whose caller already gave up.
+                self.pending_dial_addr
+                    .retain(|_, sender| !sender.is_closed());
                 if let Some(peer_id) = peer_id {
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Err(error.into()));
                     }
+                    self.event_sender
+                        .send(InboundEvent::PeerOutcome {
+                            peer_id,
+                            success: false,
+                        })
+                        .await
+                        .unwrap();
                 }
             }
             SwarmEvent::IncomingConnectionError { .. } => {}
@@ -475,24 +1344,158 @@ impl EventLoop {
                 addr,
                 sender,
             } => {
-                if let hash_map::Entry::Vacant(e) = self.pending_dial.entry(peer_id) {
-                    match self.swarm.dial(addr) {
+                if self.paused {
+                    let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    return;
+                }
+                if !self.is_allowed(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerNotAllowed(peer_id)));
+                    return;
+                }
+                if self.blocked.contains(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerBlocked(peer_id)));
+                    return;
+                }
+                if self.is_dialing(&peer_id) {
+                    let _ = sender.send(Err(anyhow::anyhow!("already dialing {peer_id}").into()));
+                    return;
+                }
+                self.start_dial(peer_id, addr, sender);
+            }
+            Command::DialAddr { addr, sender } => {
+                if self.paused {
+                    let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    return;
+                }
+                if let Some(pending) = self.pending_dial_addr.get(&addr) {
+                    if pending.is_closed() {
+                        self.pending_dial_addr.remove(&addr);
+                    }
+                }
+                if self.pending_dial_addr.contains_key(&addr) {
+                    let _ = sender.send(Err(anyhow::anyhow!("already dialing {addr}").into()));
+                    return;
+                }
+                match self.swarm.dial(addr.clone()) {
+                    Ok(()) => {
+                        self.pending_dial_addr.insert(addr, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into()));
+                    }
+                }
+            }
+            Command::DialMany {
+                peer_id,
+                addrs,
+                sender,
+            } => {
+                if self.paused {
+                    let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    return;
+                }
+                if !self.is_allowed(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerNotAllowed(peer_id)));
+                    return;
+                }
+                if self.blocked.contains(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerBlocked(peer_id)));
+                    return;
+                }
+                if self.swarm.is_connected(&peer_id) {
+                    let _ = sender.send(Ok(()));
+                    return;
+                }
+                if self.is_dialing(&peer_id) {
+                    let _ = sender.send(Err(anyhow::anyhow!("already dialing {peer_id}").into()));
+                    return;
+                }
+                // The swarm dials the addresses concurrently and aborts the
+                // rest once one connects.
+                match self
+                    .swarm
+                    .dial(DialOpts::peer_id(peer_id).addresses(addrs).build())
+                {
+                    Ok(()) => {
+                        self.pending_dial.insert(peer_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into()));
+                    }
+                }
+            }
+            Command::Redial { peer_id, sender } => {
+                if self.paused {
+                    let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    return;
+                }
+                if !self.is_allowed(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerNotAllowed(peer_id)));
+                    return;
+                }
+                if self.blocked.contains(&peer_id) {
+                    let _ = sender.send(Err(NetworkError::PeerBlocked(peer_id)));
+                    return;
+                }
+                if self.is_dialing(&peer_id) {
+                    let _ = sender.send(Err(anyhow::anyhow!("already dialing {peer_id}").into()));
+                    return;
+                }
+                let Some(addr) = self.last_addr.get(&peer_id).cloned() else {
+                    // Never dialed, but the DHT may know where the peer is.
+                    match self.swarm.dial(peer_id) {
                         Ok(()) => {
-                            e.insert(sender);
+                            self.pending_dial.insert(peer_id, sender);
+                        }
+                        Err(DialError::NoAddresses) => {
+                            let _ = sender.send(Err(NetworkError::NoKnownAddress(peer_id)));
                         }
                         Err(e) => {
                             let _ = sender.send(Err(e.into()));
                         }
                     }
-                } else {
-                    log::warn!("Already dialing peer {peer_id}");
+                    return;
+                };
+                self.start_dial(peer_id, addr, sender);
+            }
+            Command::StartProviding { key, sender } => {
+                match self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .start_providing(key.into())
+                {
+                    Ok(query_id) => {
+                        self.pending_start_providing.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(NetworkError::RequestError(e.to_string())));
+                    }
                 }
             }
+            Command::StopProviding { key } => {
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .stop_providing(&key.into());
+            }
+            Command::GetProviders { key, sender } => {
+                let query_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_providers(key.into());
+                self.pending_get_providers.insert(query_id, sender);
+            }
             Command::Request {
                 peer,
                 request,
                 sender,
             } => {
+                if self.paused {
+                    let _ = sender.send(Err(NetworkError::NetworkPaused));
+                    return;
+                }
                 let request_id = self
                     .swarm
                     .behaviour_mut()
@@ -501,43 +1504,103 @@ impl EventLoop {
                 self.pending_request_file.insert(request_id, sender);
             }
             Command::Response { response, channel } => {
-                self.swarm
+                if self
+                    .swarm
                     .behaviour_mut()
                     .request_response
                     .send_response(channel, FileResponse(response))
-                    .expect("Connection to peer to be still open.");
+                    .is_err()
+                {
+                    log::warn!("Response dropped: connection to peer closed.");
+                }
             }
             Command::Publish {
                 topic,
                 message,
+                options,
                 sender,
             } => {
-                let group_message =
+                let mut group_message =
                     GroupMessage::new(message, self.swarm.local_peer_id().to_owned());
-                let res = self
-                    .swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(topic.clone(), serde_json::to_vec(&group_message).unwrap());
+                group_message.ttl = options.ttl;
+                group_message.reply_to = options.reply_to;
+                group_message.seq = options.seq;
+                group_message.want_ack = options.want_ack;
+                group_message.sign(&self.keypair);
+                // Signed in the clear, so members check the signature after
+                // decrypting. We keep and answer with the plain message.
+                let data = match &options.key {
+                    Some(key) => {
+                        let mut sealed = group_message.clone();
+                        sealed.message = key.encrypt(&group_message.message);
+                        serde_json::to_vec(&sealed)
+                    }
+                    None => serde_json::to_vec(&group_message),
+                }
+                .unwrap();
+                let res = if self.paused {
+                    if self.paused_outbox.len() >= PAUSED_OUTBOX_CAPACITY {
+                        log::warn!("paused outbox is full, dropping the oldest message");
+                        if let Some(queued) = self.paused_outbox.pop_front() {
+                            self.report_queued(queued, MessageStatus::Failed).await;
+                        }
+                    }
+                    let message_id = message_id(&data);
+                    self.paused_outbox.push_back(QueuedPublish {
+                        topic: topic.clone(),
+                        data,
+                        message_id: group_message.id.clone(),
+                        queued_at: group_message.timestamp,
+                        attempts: 0,
+                    });
+                    Ok(message_id)
+                } else {
+                    let len = data.len();
+                    let res = self
+                        .swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(topic.clone(), data);
+                    if res.is_ok() {
+                        self.metrics.gossipsub.add_sent(len);
+                    }
+                    res
+                };
 
                 let res = match res {
                     Ok(message_id) => {
+                        // Best effort: the mesh may change right after publishing.
+                        let peers = if self.paused {
+                            0
+                        } else {
+                            self.swarm
+                                .behaviour()
+                                .gossipsub
+                                .mesh_peers(&topic.hash())
+                                .count()
+                        };
                         self.event_sender
                             .send(InboundEvent::Message {
                                 message_id: message_id.clone(),
                                 topic: topic.hash(),
-                                message: group_message,
+                                message: group_message.clone(),
                             })
                             .await
                             .unwrap();
-                        Ok(message_id)
+                        Ok(PublishReceipt {
+                            message_id: message_id.to_string(),
+                            message: group_message,
+                            peers,
+                            pending: peers == 0,
+                        })
                     }
                     Err(e) => Err(e.into()),
                 };
 
-                sender.send(res).expect("Receiver not to be dropped");
+                let _ = sender.send(res);
             }
             Command::Subscribe { topic, sender } => {
+                self.topic_filter.subscribe(topic.hash());
                 match self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
                     Ok(res) => {
                         if !res {
@@ -559,6 +1622,7 @@ impl EventLoop {
                 }
             }
             Command::Unsubscribe { topic, sender } => {
+                self.topic_filter.unsubscribe(&topic.hash());
                 match self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
                     Ok(res) => {
                         if !res {
@@ -579,10 +1643,102 @@ impl EventLoop {
                     }
                 }
             }
+            Command::ExternalAddrs { sender } => {
+                let mut addrs = self.external_addrs.iter().cloned().collect::<Vec<_>>();
+                for record in self.swarm.external_addresses() {
+                    if !self.external_addrs.contains(&record.addr) {
+                        addrs.push(record.addr.clone());
+                    }
+                }
+                let _ = sender.send(addrs);
+            }
+            Command::Disconnect { peer_id, sender } => {
+                // Not being connected is as good as disconnecting.
+                let _ = self.swarm.disconnect_peer_id(peer_id);
+                let _ = sender.send(());
+            }
             Command::ConnectedPeers { sender } => {
                 let peers = self.swarm.connected_peers().cloned().collect();
                 let _ = sender.send(peers);
             }
+            Command::Keypair { sender } => {
+                let _ = sender.send(self.keypair.clone());
+            }
+            Command::Metrics { sender } => {
+                let connections = self
+                    .swarm
+                    .network_info()
+                    .connection_counters()
+                    .num_established();
+                let _ = sender.send(self.metrics.snapshot(connections));
+            }
+            Command::MeshPeers { topic, sender } => {
+                let peers = self
+                    .swarm
+                    .behaviour()
+                    .gossipsub
+                    .mesh_peers(&topic)
+                    .cloned()
+                    .collect();
+                let _ = sender.send(peers);
+            }
+            Command::TopicPeers { topic, sender } => {
+                let gossipsub = &self.swarm.behaviour().gossipsub;
+                let mut mesh = gossipsub.mesh_peers(&topic).cloned().collect::<Vec<_>>();
+                let mut subscribed = gossipsub
+                    .all_peers()
+                    .filter(|(peer_id, topics)| topics.contains(&&topic) && !mesh.contains(peer_id))
+                    .map(|(peer_id, _)| *peer_id)
+                    .collect::<Vec<_>>();
+                mesh.sort();
+                subscribed.sort();
+                let _ = sender.send(TopicPeers { mesh, subscribed });
+            }
+            Command::SetTopicFilter { topics } => {
+                self.topic_filter.set(topics);
+            }
+            Command::SetPaused { paused } => {
+                self.paused = paused;
+                if paused {
+                    let peers = self.swarm.connected_peers().cloned().collect::<Vec<_>>();
+                    for peer_id in peers {
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                } else {
+                    self.flush_paused_outbox().await;
+                }
+            }
+            Command::BlockPeer { peer_id } => {
+                if self.blocked.insert(peer_id) {
+                    self.swarm.ban_peer_id(peer_id);
+                    self.swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .blacklist_peer(&peer_id);
+                }
+            }
+            Command::Shutdown { .. } => unreachable!("shutdown is handled by the run loop"),
+            Command::UnblockPeer { peer_id } => {
+                if self.blocked.remove(&peer_id) {
+                    self.swarm.unban_peer_id(peer_id);
+                    self.swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .remove_blacklisted_peer(&peer_id);
+                }
+            }
+            Command::SetAllowlist { allowlist } => {
+                self.allowlist = allowlist;
+                let refused = self
+                    .swarm
+                    .connected_peers()
+                    .filter(|peer_id| !self.is_allowed(peer_id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for peer_id in refused {
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                }
+            }
         }
     }
 }
@@ -614,7 +1770,8 @@ enum Command {
     Publish {
         topic: Sha256Topic,
         message: Message,
-        sender: oneshot::Sender<Result<MessageId, NetworkError>>,
+        options: PublishOptions,
+        sender: oneshot::Sender<Result<PublishReceipt, NetworkError>>,
     },
     Subscribe {
         topic: Sha256Topic,
@@ -627,4 +1784,255 @@ enum Command {
     ConnectedPeers {
         sender: oneshot::Sender<Vec<PeerId>>,
     },
+    Keypair {
+        sender: oneshot::Sender<identity::Keypair>,
+    },
+    Metrics {
+        sender: oneshot::Sender<NetworkMetrics>,
+    },
+    Disconnect {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+    ExternalAddrs {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    DialAddr {
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<PeerId, NetworkError>>,
+    },
+    DialMany {
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+        sender: oneshot::Sender<Result<(), NetworkError>>,
+    },
+    Redial {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<(), NetworkError>>,
+    },
+    StartProviding {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Result<(), NetworkError>>,
+    },
+    StopProviding {
+        key: Vec<u8>,
+    },
+    GetProviders {
+        key: Vec<u8>,
+        sender: oneshot::Sender<HashSet<PeerId>>,
+    },
+    MeshPeers {
+        topic: TopicHash,
+        sender: oneshot::Sender<Vec<PeerId>>,
+    },
+    TopicPeers {
+        topic: TopicHash,
+        sender: oneshot::Sender<TopicPeers>,
+    },
+    SetPaused {
+        paused: bool,
+    },
+    SetTopicFilter {
+        topics: Option<HashSet<TopicHash>>,
+    },
+    SetAllowlist {
+        allowlist: Option<HashSet<PeerId>>,
+    },
+    BlockPeer {
+        peer_id: PeerId,
+    },
+    UnblockPeer {
+        peer_id: PeerId,
+    },
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::{ConnectedPoint, Endpoint};
+    use std::num::NonZeroU32;
+
+    fn event_loop() -> (EventLoop, mpsc::Receiver<InboundEvent>) {
+        let network = new(identity::Keypair::generate_ed25519(), &Setting::default()).unwrap();
+        (network.event_loop, network.event_receiver)
+    }
+
+    /// A connection we dialed to `peer_id` just came up.
+    fn dialed(peer_id: PeerId) -> SwarmEvent<ComposedEvent, std::io::Error> {
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            endpoint: ConnectedPoint::Dialer {
+                address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+                role_override: Endpoint::Dialer,
+            },
+            num_established: NonZeroU32::new(1).unwrap(),
+            concurrent_dial_errors: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_connections_fail_the_pending_dial() {
+        let peer_id = PeerId::random();
+        let (mut event_loop, _events) = event_loop();
+        event_loop.allowlist = Some(HashSet::new());
+        let (sender, mut receiver) = oneshot::channel();
+        event_loop.pending_dial.insert(peer_id, sender);
+        event_loop.handle_event(dialed(peer_id)).await;
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Err(NetworkError::PeerNotAllowed(refused))) if refused == peer_id
+        ));
+        assert!(!event_loop.pending_dial.contains_key(&peer_id));
+    }
+
+    #[tokio::test]
+    async fn undecodable_gossip_is_dropped() {
+        let (mut event_loop, mut events) = event_loop();
+        let source = PeerId::random();
+        let gossip = |data: Vec<u8>| -> SwarmEvent<ComposedEvent, std::io::Error> {
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(GossipsubEvent::Message {
+                propagation_source: source,
+                message_id: MessageId::new(&data),
+                message: gossipsub::GossipsubMessage {
+                    source: Some(source),
+                    data,
+                    sequence_number: None,
+                    topic: GroupId::new().topic().hash(),
+                },
+            }))
+        };
+        event_loop.handle_event(gossip(b"{not json".to_vec())).await;
+        assert!(events.try_recv().is_err());
+        // Expired messages aren't relayed, but are still ours to read.
+        let mut message = GroupMessage::new(Message::Text("hi".to_string()), source);
+        message.ttl = Some(0);
+        let data = serde_json::to_vec(&message).unwrap();
+        event_loop.handle_event(gossip(data)).await;
+        assert!(matches!(
+            events.try_recv(),
+            Ok(InboundEvent::Message { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn abandoned_dials_do_not_block_new_ones() {
+        let peer_id = PeerId::random();
+        let (mut event_loop, _events) = event_loop();
+        let (sender, receiver) = oneshot::channel();
+        event_loop.pending_dial.insert(peer_id, sender);
+        assert!(event_loop.is_dialing(&peer_id));
+        drop(receiver);
+        assert!(!event_loop.is_dialing(&peer_id));
+        assert!(!event_loop.pending_dial.contains_key(&peer_id));
+    }
+
+    #[tokio::test]
+    async fn dials_over_the_limit_are_queued() {
+        let (mut event_loop, _events) = event_loop();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/9".parse().unwrap();
+        let mut receivers = Vec::new();
+        let mut dial = |event_loop: &mut EventLoop| {
+            let peer_id = PeerId::random();
+            let (sender, receiver) = oneshot::channel();
+            event_loop.start_dial(peer_id, addr.clone(), sender);
+            receivers.push(receiver);
+            peer_id
+        };
+        for _ in 0..MAX_PENDING_DIALS {
+            dial(&mut event_loop);
+        }
+        assert!(event_loop.dial_queue.is_empty());
+        let queued = dial(&mut event_loop);
+        assert!(event_loop.is_dialing(&queued));
+        assert!(!event_loop.pending_dial.contains_key(&queued));
+        for _ in 1..DIAL_QUEUE_CAPACITY {
+            dial(&mut event_loop);
+        }
+        assert_eq!(event_loop.dial_queue.len(), DIAL_QUEUE_CAPACITY);
+        dial(&mut event_loop);
+        let mut refused = receivers.pop().unwrap();
+        assert!(matches!(refused.try_recv(), Ok(Err(_))));
+        // Queued dials the caller gave up on are dropped.
+        receivers.truncate(MAX_PENDING_DIALS as usize);
+        assert!(!event_loop.is_dialing(&queued));
+        assert!(event_loop.dial_queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_while_paused_fail_the_pending_dial() {
+        let peer_id = PeerId::random();
+        let (mut event_loop, _events) = event_loop();
+        event_loop.paused = true;
+        let (sender, mut receiver) = oneshot::channel();
+        event_loop.pending_dial.insert(peer_id, sender);
+        event_loop.handle_event(dialed(peer_id)).await;
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Err(NetworkError::NetworkPaused))
+        ));
+        assert!(!event_loop.pending_dial.contains_key(&peer_id));
+    }
+
+    #[tokio::test]
+    async fn paused_publishes_get_a_pending_receipt() {
+        let (mut event_loop, mut events) = event_loop();
+        event_loop.paused = true;
+        let (sender, mut receiver) = oneshot::channel();
+        let message = Message::Text("hi".to_string());
+        event_loop
+            .handle_command(Command::Publish {
+                topic: GroupId::new().topic(),
+                message: message.clone(),
+                options: PublishOptions::default(),
+                sender,
+            })
+            .await;
+        let receipt = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(receipt.peers, 0);
+        assert!(receipt.pending);
+        assert_eq!(receipt.message.message, message);
+        let queued = &event_loop.paused_outbox[0];
+        assert_eq!(receipt.message_id, message_id(&queued.data).to_string());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(InboundEvent::Message { message_id, .. }) if message_id.to_string() == receipt.message_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn queued_publishes_fail_after_max_attempts() {
+        let (mut event_loop, mut events) = event_loop();
+        event_loop.outbox_setting.max_attempts = 2;
+        event_loop.paused = true;
+        let topic = GroupId::new().topic();
+        let (sender, mut receiver) = oneshot::channel();
+        event_loop
+            .handle_command(Command::Publish {
+                topic: topic.clone(),
+                message: Message::Text("hi".to_string()),
+                options: PublishOptions::default(),
+                sender,
+            })
+            .await;
+        let receipt = receiver.try_recv().unwrap().unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(InboundEvent::Message { .. })
+        ));
+
+        event_loop.paused = false;
+        event_loop.flush_paused_outbox().await;
+        assert_eq!(event_loop.paused_outbox[0].attempts, 1);
+        assert!(events.try_recv().is_err());
+        event_loop.flush_paused_outbox().await;
+        assert!(event_loop.paused_outbox.is_empty());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(InboundEvent::QueuedMessage { topic: hash, message_id, status: MessageStatus::Failed })
+                if hash == topic.hash() && message_id == receipt.message.id
+        ));
+    }
 }
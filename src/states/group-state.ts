@@ -17,25 +17,28 @@ export const useGroupState = defineStore("group", () => {
     { shallow: false }
   );
 
-  AppEvent.onGroupUpdate((event) => {
-    groups.state.value[event.payload[0]] = event.payload[1];
-    console.log("group update", event.payload[0], event.payload[1]);
+  AppEvent.onGroupUpdate(({ payload: { groupId, groupInfo } }) => {
+    groups.state.value[groupId] = groupInfo;
+    console.log("group update", groupId, groupInfo);
   });
-  AppEvent.onSubscribed((event) => {
-    console.log("subscribe", event.payload[0], event.payload[1]);
+  AppEvent.onSubscribed(({ payload: { groupId, peerId } }) => {
+    console.log("subscribe", groupId, peerId);
 
-    groupStates.value[event.payload[0]].subscribers.push(event.payload[1]);
+    groupStates.value[groupId].subscribers.push(peerId);
   });
-  AppEvent.onUnsubscribe((event) => {
-    groupStates.value[event.payload[0]].subscribers = groupStates.value[
-      event.payload[0]
-    ].subscribers.filter((id) => id !== event.payload[1]);
-    if (groupStates.value[event.payload[0]].subscribers.length === 0) {
-      delete groupStates.value[event.payload[0]];
+  AppEvent.onUnsubscribe(({ payload: { groupId, peerId } }) => {
+    groupStates.value[groupId].subscribers = groupStates.value[
+      groupId
+    ].subscribers.filter((id) => id !== peerId);
+    if (groupStates.value[groupId].subscribers.length === 0) {
+      delete groupStates.value[groupId];
     }
   });
-  AppEvent.onMessage((event) => {
-    groupStates.value[event.payload[0]].history.push(event.payload[1]);
+  AppEvent.onMessage(({ payload: { groupId, message } }) => {
+    groupStates.value[groupId].history.push(message);
+  });
+  AppEvent.onMessagesBatch(({ payload: { groupId, messages } }) => {
+    groupStates.value[groupId].history.push(...messages);
   });
 
   return {
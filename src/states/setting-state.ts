@@ -7,8 +7,8 @@ export const useSettingState = defineStore("settings", () => {
   const isDark = useDark();
   const prefersDark = usePreferredDark();
   const listeners = useAsyncState(getListeners(), {}, { shallow: false });
-  AppEvent.onListen((event) => {
-    listeners.state.value[event.payload[0]] = event.payload[1];
+  AppEvent.onListen(({ payload: { listenerId, addresses } }) => {
+    listeners.state.value[listenerId] = addresses;
   });
   return { isDark, prefersDark, listeners: listeners.state };
 });
@@ -4,8 +4,8 @@ import { AppEvent } from "@/utils/app-event";
 export const useUserState = defineStore("user", () => {
   const users = useAsyncState(getUsers(), {}, { shallow: false });
   const localPeerId = useAsyncState(getLocalPeerId(), null);
-  AppEvent.onUserUpdate((event) => {
-    users.state.value[event.payload[0]] = event.payload[1];
+  AppEvent.onUserUpdate(({ payload: { peerId, userInfo } }) => {
+    users.state.value[peerId] = userInfo;
   });
   return {
     users: users.state,
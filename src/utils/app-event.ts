@@ -1,65 +1,326 @@
 import { Event, listen } from "@tauri-apps/api/event";
 import {
+  DirectMessagePayload,
+  FileProgressPayload,
   GroupId,
-  GroupInfo,
-  GroupMessage,
-  GroupState,
+  GroupUpdatePayload,
+  HolePunchResultPayload,
+  LatencyPayload,
+  ListenPayload,
+  MessageAckedPayload,
+  MessageDeletedPayload,
+  MessageEditedPayload,
+  MessagePayload,
+  MessagesBatchPayload,
   Multiaddr,
+  NetworkMetrics,
+  PeerConnectedPayload,
+  PeerDisconnectedPayload,
   PeerId,
-  UserInfo,
+  PeerReconnectingPayload,
+  PinUpdatePayload,
+  PollUpdatePayload,
+  PresencePayload,
+  ReadReceiptPayload,
+  RelayStatusPayload,
+  RoleUpdatePayload,
+  RosterSnapshotPayload,
+  Setting,
+  SubscriptionPayload,
+  TypingPayload,
+  UserUpdatePayload,
 } from "./types";
 
 export class AppEvent {
   static async onGroupUpdate(
-    callBackFn: (args: Event<[GroupId, GroupInfo]>) => void
+    callBackFn: (args: Event<GroupUpdatePayload>) => void
   ) {
     try {
-      return await listen<[GroupId, GroupInfo]>("group-update", callBackFn);
+      return await listen<GroupUpdatePayload>("group-update", callBackFn);
     } catch (err) {
       console.error(err);
     }
   }
-  static async onMessage(
-    callBackFn: (args: Event<[GroupId, GroupMessage]>) => void
+  static async onMessage(callBackFn: (args: Event<MessagePayload>) => void) {
+    try {
+      return await listen<MessagePayload>("message", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMessagesBatch(
+    callBackFn: (args: Event<MessagesBatchPayload>) => void
   ) {
     try {
-      return await listen<[GroupId, GroupMessage]>("message", callBackFn);
+      return await listen<MessagesBatchPayload>("messages-batch", callBackFn);
     } catch (err) {
       console.error(err);
     }
   }
   static async onUserUpdate(
-    callBackFn: (args: Event<[PeerId, UserInfo]>) => void
+    callBackFn: (args: Event<UserUpdatePayload>) => void
   ) {
     try {
-      return await listen<[PeerId, UserInfo]>("user-update", callBackFn);
+      return await listen<UserUpdatePayload>("user-update", callBackFn);
     } catch (err) {
       console.error(err);
     }
   }
   static async onSubscribed(
-    callBackFn: (args: Event<[GroupId, PeerId]>) => void
+    callBackFn: (args: Event<SubscriptionPayload>) => void
   ) {
     try {
-      return await listen<[GroupId, PeerId]>("subscribed", callBackFn);
+      return await listen<SubscriptionPayload>("subscribed", callBackFn);
     } catch (err) {
       console.error(err);
     }
   }
   static async onUnsubscribe(
-    callBackFn: (args: Event<[GroupId, PeerId]>) => void
+    callBackFn: (args: Event<SubscriptionPayload>) => void
   ) {
     try {
-      return await listen<[GroupId, PeerId]>("unsubscribe", callBackFn);
+      return await listen<SubscriptionPayload>("unsubscribe", callBackFn);
     } catch (err) {
       console.error(err);
     }
   }
-  static async onListen(
-    callBackFn: (args: Event<[number, Multiaddr[]]>) => void
+  static async onRosterSnapshot(
+    callBackFn: (args: Event<RosterSnapshotPayload>) => void
   ) {
     try {
-      return await listen<[number, Multiaddr[]]>("listen", callBackFn);
+      return await listen<RosterSnapshotPayload>("roster-snapshot", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onSettingChanged(callBackFn: (args: Event<Setting>) => void) {
+    try {
+      return await listen<Setting>("setting-changed", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onNetworkPaused(callBackFn: (args: Event<boolean>) => void) {
+    try {
+      return await listen<boolean>("network-paused", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onAutoLeave(callBackFn: (args: Event<GroupId>) => void) {
+    try {
+      return await listen<GroupId>("auto-leave", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMessageEdited(
+    callBackFn: (args: Event<MessageEditedPayload>) => void
+  ) {
+    try {
+      return await listen<MessageEditedPayload>("message-edited", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMessageDeleted(
+    callBackFn: (args: Event<MessageDeletedPayload>) => void
+  ) {
+    try {
+      return await listen<MessageDeletedPayload>("message-deleted", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTyping(callBackFn: (args: Event<TypingPayload>) => void) {
+    try {
+      return await listen<TypingPayload>("typing", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPinUpdate(
+    callBackFn: (args: Event<PinUpdatePayload>) => void
+  ) {
+    try {
+      return await listen<PinUpdatePayload>("pin-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onRoleUpdate(
+    callBackFn: (args: Event<RoleUpdatePayload>) => void
+  ) {
+    try {
+      return await listen<RoleUpdatePayload>("role-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onDirectMessage(
+    callBackFn: (args: Event<DirectMessagePayload>) => void
+  ) {
+    try {
+      return await listen<DirectMessagePayload>("direct-message", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  /** A peer we share no group with sent a message, held until accepted. */
+  static async onDirectMessageRequest(
+    callBackFn: (args: Event<DirectMessagePayload>) => void
+  ) {
+    try {
+      return await listen<DirectMessagePayload>(
+        "direct-message-request",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMessageDelivered(callBackFn: (args: Event<string>) => void) {
+    try {
+      return await listen<string>("message-delivered", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMessageAcked(
+    callBackFn: (args: Event<MessageAckedPayload>) => void
+  ) {
+    try {
+      return await listen<MessageAckedPayload>("message-acked", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onReadReceipt(
+    callBackFn: (args: Event<ReadReceiptPayload>) => void
+  ) {
+    try {
+      return await listen<ReadReceiptPayload>("read-receipt", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onFileProgress(
+    callBackFn: (args: Event<FileProgressPayload>) => void
+  ) {
+    try {
+      return await listen<FileProgressPayload>("file-progress", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onRelayStatus(
+    callBackFn: (args: Event<RelayStatusPayload>) => void
+  ) {
+    try {
+      return await listen<RelayStatusPayload>("relay-status", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPeerConnected(
+    callBackFn: (args: Event<PeerConnectedPayload>) => void
+  ) {
+    try {
+      return await listen<PeerConnectedPayload>("peer-connected", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPeerDisconnected(
+    callBackFn: (args: Event<PeerDisconnectedPayload>) => void
+  ) {
+    try {
+      return await listen<PeerDisconnectedPayload>(
+        "peer-disconnected",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPeerReconnecting(
+    callBackFn: (args: Event<PeerReconnectingPayload>) => void
+  ) {
+    try {
+      return await listen<PeerReconnectingPayload>(
+        "peer-reconnecting",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onLatencyUpdate(
+    callBackFn: (args: Event<LatencyPayload>) => void
+  ) {
+    try {
+      return await listen<LatencyPayload>("latency-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPresenceUpdate(
+    callBackFn: (args: Event<PresencePayload>) => void
+  ) {
+    try {
+      return await listen<PresencePayload>("presence-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onHolePunchResult(
+    callBackFn: (args: Event<HolePunchResultPayload>) => void
+  ) {
+    try {
+      return await listen<HolePunchResultPayload>(
+        "hole-punch-result",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onExternalAddr(callBackFn: (args: Event<Multiaddr>) => void) {
+    try {
+      return await listen<Multiaddr>("external-addr", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  /** A new identity was imported, prompt for a restart. */
+  static async onIdentityImported(callBackFn: (args: Event<PeerId>) => void) {
+    try {
+      return await listen<PeerId>("identity-imported", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onMetricsUpdate(
+    callBackFn: (args: Event<NetworkMetrics>) => void
+  ) {
+    try {
+      return await listen<NetworkMetrics>("metrics-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onPollUpdate(
+    callBackFn: (args: Event<PollUpdatePayload>) => void
+  ) {
+    try {
+      return await listen<PollUpdatePayload>("poll-update", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onListen(callBackFn: (args: Event<ListenPayload>) => void) {
+    try {
+      return await listen<ListenPayload>("listen", callBackFn);
     } catch (err) {
       console.error(err);
     }
@@ -1,16 +1,38 @@
 import { invoke } from "@tauri-apps/api";
 import {
+  AppSnapshot,
+  DirectMessage,
   FileInfo,
   GroupId,
   GroupInfo,
+  GroupMember,
   GroupMessage,
   GroupState,
+  HistoryOrder,
   Message,
+  NetworkMetrics,
   PeerId,
+  PeerLatency,
+  Presence,
+  PublishReceipt,
+  Role,
+  SearchMessages,
+  SearchResults,
   Setting,
+  TopicPeers,
   UserInfo,
 } from "./types";
 
+/** Rejects with the reason if settings and other state can't be saved. */
+export async function persistenceStatus() {
+  await invoke<void>("persistence_status");
+}
+
+/** Groups, users and listeners as of now, to show on startup. */
+export async function snapshot(): Promise<AppSnapshot> {
+  return await invoke<AppSnapshot>("snapshot");
+}
+
 export async function startListen(listenAddr?: string) {
   try {
     await invoke<string>("start_listen", {
@@ -24,6 +46,20 @@ export async function startListen(listenAddr?: string) {
 export async function getLocalPeerId(): Promise<string> {
   return await invoke<PeerId>("get_local_peer_id");
 }
+/** Our identity sealed with `passphrase`, to import on another device. */
+export async function exportIdentity(passphrase: string): Promise<string> {
+  return await invoke<string>("export_identity", { passphrase });
+}
+/**
+ * Replace our identity with an exported one from the next start on. Fails
+ * while connected to any peer.
+ */
+export async function importIdentity(
+  blob: string,
+  passphrase: string
+): Promise<PeerId> {
+  return await invoke<PeerId>("import_identity", { blob, passphrase });
+}
 export async function stopListen() {
   try {
     await invoke("stop_listen");
@@ -41,13 +77,68 @@ export async function getListeners(): Promise<{ [index: number]: string[] }> {
   }
 }
 
-export async function getFile(file: FileInfo) {
+/**
+ * Accept connections relayed through `relayAddr`, which must end in the
+ * relay's `/p2p/<id>`. Share `<relayAddr>/p2p-circuit/p2p/<our id>`.
+ * Resolves once the relay accepted, and rejects if it refused.
+ */
+export async function listenOnRelay(relayAddr: string): Promise<number> {
+  try {
+    return await invoke<number>("listen_on_relay", { relayAddr });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+export async function blockPeer(peerId: string): Promise<string[]> {
+  try {
+    return await invoke<string[]>("block_peer", { peerId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+export async function unblockPeer(peerId: string): Promise<string[]> {
+  try {
+    return await invoke<string[]>("unblock_peer", { peerId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+export async function blockedPeers(): Promise<string[]> {
+  try {
+    return await invoke<string[]>("blocked_peers");
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+export async function externalAddrs(): Promise<string[]> {
+  try {
+    return await invoke<string[]>("external_addrs");
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+/** `providers` are tried first, in order, before the other known ones. */
+export async function getFile(file: FileInfo, providers?: PeerId[]) {
   await invoke("get_file", {
     file,
+    providers,
   }).catch((err) => {
     console.error(err);
   });
 }
+export async function cancelGet(file: FileInfo) {
+  try {
+    await invoke("cancel_get", { file });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
 export async function startProvide(
   path: string,
   file?: FileInfo
@@ -89,6 +180,36 @@ export async function saveSetting(setting: Setting, savePath?: string) {
   }
 }
 
+/**
+ * Change only the fields in `patch`, `null` resetting one. Resolves to the
+ * setting as saved, or rejects with why, e.g. a `recvPath` that can't be
+ * written to.
+ */
+export async function updateSetting(
+  patch: Partial<Setting>
+): Promise<Setting> {
+  try {
+    return await invoke<Setting>("update_setting", { patch });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/**
+ * Change our name and avatar. Members of our groups get a `user-update`
+ * event, and so do we. Rejects an avatar that isn't a data URL of at most
+ * 256 KiB.
+ */
+export async function updateUserInfo(userInfo: UserInfo) {
+  try {
+    await invoke("update_user_info", { userInfo });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
 export async function listProvide(): Promise<FileInfo[]> {
   try {
     let providers = await invoke<FileInfo[]>("list_provide");
@@ -118,12 +239,37 @@ export async function dial(addr: string) {
   }
 }
 
+/** Publish to the group, as a reply to message `replyTo` if given. */
 export async function publishMessage(
   groupId: GroupId,
-  message: Message
-): Promise<void> {
+  message: Message,
+  replyTo?: string
+): Promise<PublishReceipt> {
+  try {
+    return await invoke<PublishReceipt>("publish_message", {
+      groupId,
+      message,
+      replyTo,
+    });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Cheap to call on every keystroke; only changes are published. */
+export async function setTyping(groupId: GroupId, isTyping: boolean) {
+  try {
+    await invoke("set_typing", { groupId, isTyping });
+  } catch (err) {
+    console.error(err);
+  }
+}
+
+/** Let the group know we've seen its messages up to `messageId`. */
+export async function markRead(groupId: GroupId, messageId: string) {
   try {
-    await invoke("publish_message", { groupId, message });
+    await invoke("mark_read", { groupId, messageId });
   } catch (err) {
     console.error(err);
     throw err;
@@ -150,17 +296,336 @@ export async function newGroup(groupInfo: GroupInfo): Promise<GroupId> {
   return await invoke<GroupId>("new_group", { groupInfo });
 }
 
+/** The group's state, its history in the configured `historyOrder`. */
 export async function getGroupState(groupId: GroupId): Promise<GroupState> {
-  return await invoke<GroupState>("invoke_manager", {
+  try {
+    return await invoke<GroupState>("group_state", { groupId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function setHistoryOrder(order: HistoryOrder) {
+  try {
+    await invoke("set_history_order", { order });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Newest first; pass the last message's id as `before` for older pages. */
+export async function groupHistory(
+  groupId: GroupId,
+  limit: number,
+  before?: string
+): Promise<GroupMessage[]> {
+  return await invoke<GroupMessage[]>("group_history", {
+    groupId,
+    before,
+    limit,
+  });
+}
+
+/** `root` and all replies leading to it, in history order. */
+export async function thread(
+  groupId: GroupId,
+  root: string
+): Promise<GroupMessage[]> {
+  return await invoke<GroupMessage[]>("thread", { groupId, root });
+}
+
+/** Write the thread under `root` to `path` as a Markdown transcript. */
+export async function exportThread(
+  groupId: GroupId,
+  root: string,
+  path: string
+) {
+  try {
+    await invoke("export_thread", { groupId, root, path });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Per author, the sequence numbers we never received. */
+export async function sequenceGaps(
+  groupId: GroupId
+): Promise<{ [index: PeerId]: number[] }> {
+  try {
+    return await invoke<{ [index: PeerId]: number[] }>("sequence_gaps", {
+      groupId,
+    });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Fetch `author`'s messages from `fromSeq` on from whoever has them. */
+export async function resyncMessages(
+  groupId: GroupId,
+  author: PeerId,
+  fromSeq: number
+): Promise<GroupMessage[]> {
+  try {
+    return await invoke<GroupMessage[]>("resync_messages", {
+      groupId,
+      author,
+      fromSeq,
+    });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function searchMessages(
+  search: SearchMessages
+): Promise<SearchResults> {
+  try {
+    return await invoke<SearchResults>("search_messages", { search });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function pinMessage(
+  groupId: GroupId,
+  target: string,
+  pinned: boolean
+) {
+  try {
+    await invoke("pin_message", { groupId, target, pinned });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Fails unless our own role in the group allows the change. */
+export async function setRole(groupId: GroupId, peerId: PeerId, role: Role) {
+  try {
+    await invoke("set_role", { groupId, peerId, role });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function pinnedMessages(groupId: GroupId): Promise<string[]> {
+  return await invoke<string[]>("pinned_messages", { groupId });
+}
+
+/**
+ * Encrypt the group's messages under a new key, turning encryption on if
+ * it was off. Resolves to the members the key reached right away.
+ */
+export async function rotateGroupKey(groupId: GroupId): Promise<PeerId[]> {
+  try {
+    return await invoke<PeerId[]>("rotate_group_key", { groupId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Send the group key to `peerId`, letting it read the encrypted group. */
+export async function shareGroupKey(groupId: GroupId, peerId: PeerId) {
+  try {
+    await invoke("share_group_key", { groupId, peerId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Ask members to acknowledge the messages we publish from now on. */
+export async function setMessageAcks(enabled: boolean) {
+  try {
+    await invoke("set_message_acks", { enabled });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Whether `peerId` is connected and has been active lately. */
+export async function presence(peerId: PeerId): Promise<Presence> {
+  return await invoke<Presence>("presence", { peerId });
+}
+
+/** Round-trip times to `peerId`, or null until a connection to it is pinged. */
+export async function peerLatency(peerId: PeerId): Promise<PeerLatency | null> {
+  return await invoke<PeerLatency | null>("peer_latency", { peerId });
+}
+
+/** Show connected peers as away after `afterSecs` without activity. */
+export async function setAwayAfter(afterSecs: number) {
+  try {
+    await invoke("set_away_after", { afterSecs });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/**
+ * Collect a group's messages for `windowMs` into one `messages-batch`
+ * event, or emit each on its own with 0.
+ */
+export async function setMessageBatchWindow(windowMs: number) {
+  try {
+    await invoke("set_message_batch_window", { windowMs });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Bytes sent and received since startup, in total and per protocol. */
+export async function metrics(): Promise<NetworkMetrics> {
+  try {
+    return await invoke<NetworkMetrics>("metrics");
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Get a `metrics-update` event every `intervalSecs`, or none with 0. */
+export async function setMetricsInterval(intervalSecs: number) {
+  try {
+    await invoke("set_metrics_interval", { intervalSecs });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Members that acknowledged our message `messageId`. */
+export async function messageAcks(messageId: string): Promise<PeerId[]> {
+  return await invoke<PeerId[]>("message_acks", { messageId });
+}
+
+/** The latest message each member has read. */
+export async function getReadReceipts(
+  groupId: GroupId
+): Promise<{ [index: PeerId]: string }> {
+  return await invoke<{ [index: PeerId]: string }>("invoke_manager", {
     name: "group",
-    action: "get_group_state",
+    action: "get_read_receipts",
     params: groupId,
   });
 }
 
+/** A `p2pchat:invite?...` code others can join the group with. */
+export async function groupInvite(groupId: GroupId): Promise<string> {
+  try {
+    return await invoke<string>("group_invite", { groupId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Rejects with a description if the code is malformed. */
+export async function joinByInvite(code: string): Promise<GroupId> {
+  try {
+    return await invoke<GroupId>("join_by_invite", { code });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/** Subscribers of the group, split by whether they are in our mesh. */
+export async function topicPeers(groupId: GroupId): Promise<TopicPeers> {
+  return await invoke<TopicPeers>("topic_peers", { groupId });
+}
+
+/** Subscribers of the group, owners and admins first. */
+export async function groupMembers(groupId: GroupId): Promise<GroupMember[]> {
+  try {
+    return await invoke<GroupMember[]>("group_members", { groupId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+/**
+ * An unreachable peer gets the message once it reconnects; until then it is
+ * returned as `pending`, see `AppEvent.onMessageDelivered`.
+ */
+export async function sendDm(
+  peerId: PeerId,
+  content: string
+): Promise<DirectMessage> {
+  try {
+    return await invoke<DirectMessage>("send_dm", { peerId, content });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function getDmThread(peerId: PeerId): Promise<DirectMessage[]> {
+  return await invoke<DirectMessage[]>("invoke_manager", {
+    name: "direct",
+    action: "get_thread",
+    params: peerId,
+  });
+}
+
+/** Held direct messages from peers we share no group with. */
+export async function getDmRequests(): Promise<{
+  [index: PeerId]: DirectMessage[];
+}> {
+  return await invoke<{ [index: PeerId]: DirectMessage[] }>("invoke_manager", {
+    name: "direct",
+    action: "get_requests",
+  });
+}
+
+/** Returns the held messages, now part of the conversation. */
+export async function acceptDm(peerId: PeerId): Promise<DirectMessage[]> {
+  try {
+    return await invoke<DirectMessage[]>("accept_dm", { peerId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
+export async function rejectDm(peerId: PeerId) {
+  try {
+    await invoke("reject_dm", { peerId });
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
+
 export async function getUsers(): Promise<{ [index: PeerId]: UserInfo }> {
   return await invoke<{ [index: PeerId]: UserInfo }>("invoke_manager", {
     name: "user",
     action: "get_users",
   });
 }
+
+/**
+ * Stop listening, leave every group and save state, then stop the backend.
+ * Other commands fail once this resolves.
+ */
+export async function shutdown() {
+  try {
+    await invoke("shutdown");
+  } catch (err) {
+    console.error(err);
+    throw err;
+  }
+}
@@ -7,43 +7,310 @@ export type Action = {
 export type FileInfo = {
   name: string;
   size: number;
+  hash?: string;
   createTime?: Date;
   modifyTime?: Date;
 };
+export type Manifest = {
+  name: string;
+  hash: string;
+  files: FileInfo[];
+  emptyDirs: string[];
+};
 export type FileSource = {
   local?: string;
   remote?: string;
 };
+export type Theme = "system" | "light" | "dark";
+
 export type Setting = {
   recvPath: string;
+  theme?: Theme;
+  /** Relays to try in order at startup if the last one used refuses. */
+  relays?: string[];
+  /** What to do with direct messages from peers we share no group with. */
+  dmPolicy?: DmPolicy;
+  historyOrder?: HistoryOrder;
+  messageAcks?: boolean;
+  awayAfter?: number;
+  messageBatchWindow?: number;
+  /** Where the identity keypair is kept, applied on the next start. */
+  identityPath?: string;
+  /** Seconds between `metrics-update` events, 0 for none. */
+  metricsInterval?: number;
 };
 
+export type DmPolicy = "accept" | "reject" | "hold";
+export type HistoryOrder = "arrival" | "timestamp" | "causal";
+
 export type GroupId = string;
 export type PeerId = string;
 export type GroupMessage = {
+  id?: string;
   message: Message;
   timestamp: number;
   source?: string;
+  ttl?: number;
+  editedAt?: number;
+  replyTo?: string;
+  /** The author's count of kept messages in the group, from 1. */
+  seq?: number;
+  /** The author asked members to acknowledge the message. */
+  wantAck?: boolean;
+  /** The author's signature checked out, as it must for kept messages. */
+  verified?: boolean;
+};
+
+export type SearchMessages = {
+  query: string;
+  groupId?: GroupId;
+  /** Unix timestamps bounding when matches were sent. */
+  after?: number;
+  before?: number;
+  limit?: number;
+};
+
+export type SearchResults = {
+  /** Matches found, including those cut off by `limit`. */
+  total: number;
+  /** Newest first. */
+  hits: [GroupId, GroupMessage][];
 };
 
 export type UserInfo = {
   name: string;
-  avatar: string;
+  /** A data URL of at most 256 KiB. */
+  avatar: string | null;
   status: "online" | "offline";
 };
 
+export type PublishReceipt = {
+  messageId: string;
+  message: GroupMessage;
+  peers: number;
+  pending: boolean;
+};
+
 export type GroupState = {
   subscribers: PeerId[];
   history: GroupMessage[];
 };
 
+export type RichMessage = {
+  kind: string;
+  payload: unknown;
+  fallback: string;
+};
+
+export type Location = {
+  latitude: number;
+  longitude: number;
+  label: string | null;
+};
+
+export type Poll = {
+  id: string;
+  question: string;
+  options: string[];
+  deadline: number | null;
+};
+
 export type Message = {
   text?: string;
   file?: FileInfo;
+  rich?: RichMessage;
+  location?: Location;
+  poll?: Poll;
+  vote?: { pollId: string; option: number };
+  edit?: { target: string; newContent: string };
+  delete?: { target: string };
+  typing?: { isTyping: boolean };
+  ack?: { target: string };
+  read?: { upTo: string };
+  userInfo?: { userInfo: UserInfo; avatarOmitted: boolean };
+  pin?: { target: string; pinned: boolean };
+  setRole?: { peerId: string; role: Role };
+  /** Left in place of a deleted message. */
+  deleted?: {};
+  editGroup?: { name: string; description: string | null; version: number };
 };
 
 export type GroupInfo = {
   name: string;
   description: string | null;
+  version?: number;
+  /** Pinned message ids, oldest pin first. */
+  pinned?: string[];
+  /** Members above `member`; everyone else is a member. */
+  roles?: { [index: PeerId]: Role };
+  /** Set once messages are end-to-end encrypted, the current key last. */
+  keys?: { id: number }[];
+};
+export type Role = "member" | "admin" | "owner";
+export type Presence = "online" | "away" | "offline";
+export type AppSnapshot = {
+  groups: { [index: GroupId]: GroupInfo };
+  users: { [index: PeerId]: UserInfo };
+  /** Addresses of each listener, keyed by listener id. */
+  listeners: { [index: number]: string[] };
+};
+/** Messages are pushed to `mesh` peers, the others only get gossip. */
+export type TopicPeers = {
+  mesh: PeerId[];
+  subscribed: PeerId[];
+};
+export type Traffic = {
+  sent: number;
+  received: number;
+};
+/** Bytes transferred since startup. */
+export type NetworkMetrics = {
+  /** On the wire, encryption and multiplexing included. */
+  total: Traffic;
+  /** Payload of group messages, each of ours counted once. */
+  gossipsub: Traffic;
+  /** Payload of requests and responses, file transfers included. */
+  requestResponse: Traffic;
+  connections: number;
+};
+export type DirectMessage = {
+  id: string;
+  source: PeerId;
+  /** Sent at for our own messages, received at for the peer's. */
+  timestamp: number;
+  content: string;
+  /** Ours and waiting for the peer to come online. */
+  pending?: boolean;
+};
+export type GroupMember = {
+  peerId: PeerId;
+  /** Null until the peer's user info reaches us. */
+  userInfo: UserInfo | null;
+  role: Role;
 };
 export type Multiaddr = string;
+
+/** Payloads of the events in `AppEvent`, named after their channel. */
+export type ListenPayload = {
+  listenerId: number;
+  addresses: Multiaddr[];
+};
+export type MessagePayload = {
+  groupId: GroupId;
+  message: GroupMessage;
+  /** Set during quiet hours, skip the OS notification. */
+  suppressNotification: boolean;
+};
+/** Messages that reached a group within one batch window, oldest first. */
+export type MessagesBatchPayload = {
+  groupId: GroupId;
+  messages: GroupMessage[];
+  /** Set only if it was for every message in the batch. */
+  suppressNotification: boolean;
+};
+/** For both `subscribed` and `unsubscribed`. */
+export type SubscriptionPayload = {
+  groupId: GroupId;
+  peerId: PeerId;
+};
+export type GroupUpdatePayload = {
+  groupId: GroupId;
+  groupInfo: GroupInfo;
+};
+export type UserUpdatePayload = {
+  peerId: PeerId;
+  userInfo: UserInfo;
+};
+export type PollUpdatePayload = {
+  groupId: GroupId;
+  pollId: string;
+  tallies: number[];
+};
+export type RosterSnapshotPayload = {
+  groupId: GroupId;
+  peers: PeerId[];
+};
+export type MessageEditedPayload = {
+  groupId: GroupId;
+  message: GroupMessage;
+};
+export type MessageDeletedPayload = {
+  groupId: GroupId;
+  messageId: string;
+};
+export type TypingPayload = {
+  groupId: GroupId;
+  peerId: PeerId;
+  isTyping: boolean;
+};
+export type PinUpdatePayload = {
+  groupId: GroupId;
+  pinned: string[];
+};
+export type RoleUpdatePayload = {
+  groupId: GroupId;
+  peerId: PeerId;
+  role: Role;
+};
+export type DirectMessagePayload = {
+  peerId: PeerId;
+  message: DirectMessage;
+};
+export type MessageAckedPayload = {
+  groupId: GroupId;
+  messageId: string;
+  peerId: PeerId;
+  /** Members that acknowledged the message so far. */
+  acks: number;
+};
+export type ReadReceiptPayload = {
+  groupId: GroupId;
+  peerId: PeerId;
+  upTo: string;
+};
+export type FileProgressPayload = {
+  file: FileInfo;
+  received: number;
+  total: number;
+  /** Who sent the latest chunk, null when the download starts. */
+  provider: PeerId | null;
+};
+export type RelayStatusPayload = {
+  relayPeerId: PeerId;
+  error: string | null;
+};
+export type PeerConnectedPayload = {
+  peerId: PeerId;
+  endpoint: Multiaddr;
+  /** Connections now open to the peer. */
+  numEstablished: number;
+};
+export type PeerDisconnectedPayload = {
+  peerId: PeerId;
+  cause: string | null;
+  /** The peer is offline once this reaches 0. */
+  numEstablished: number;
+};
+export type PresencePayload = {
+  peerId: PeerId;
+  presence: Presence;
+};
+/** Round-trip times of the pings to a connected peer. */
+export type PeerLatency = {
+  latestMs: number | null;
+  averageMs: number | null;
+  /** Pings in a row that failed since the last answered one. */
+  failures: number;
+};
+export type LatencyPayload = {
+  peerId: PeerId;
+  latency: PeerLatency;
+};
+export type PeerReconnectingPayload = {
+  peerId: PeerId;
+  attempt: number;
+};
+export type HolePunchResultPayload = {
+  peerId: PeerId;
+  success: boolean;
+};